@@ -9,7 +9,7 @@ use costaeres::manager::Manager;
 async fn prepare_bench() -> (Config, FileStore) {
     let _ = env_logger::try_init();
 
-    let path = format!("./bench-fixtures/");
+    let path = "./bench-fixtures/".to_string();
 
     let store = FileStore::new(
         &path,
@@ -23,6 +23,11 @@ async fn prepare_bench() -> (Config, FileStore) {
         db_path: format!("{}/manager.sqlite", &path),
         data_dir: ".".into(),
         metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
     };
 
     (config, store)
@@ -1,3 +1,3 @@
 fn main() {
-    println!("cargo:rerun-if-changed=./db/migrations/00001_main.sql");
+    println!("cargo:rerun-if-changed=./db/migrations");
 }
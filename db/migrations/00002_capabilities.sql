@@ -0,0 +1,8 @@
+
+CREATE TABLE IF NOT EXISTS capabilities
+(
+    token      TEXT     PRIMARY KEY NOT NULL, -- an opaque uuid handed to the delegate.
+    scope      TEXT     NOT NULL, -- the root of the subtree this capability applies to.
+    operations INTEGER  NOT NULL, -- bitmask of the allowed operations.
+    expiry     DATETIME
+);
@@ -0,0 +1,13 @@
+
+CREATE TABLE IF NOT EXISTS derived_variants
+(
+    id          TEXT    NOT NULL,
+    variant     TEXT    NOT NULL, -- the name of the derived variant.
+    source      TEXT    NOT NULL, -- the name of the variant it was generated from.
+    transformer TEXT    NOT NULL, -- the name of the transformer that generated it.
+    version     INTEGER NOT NULL, -- the version of that transformer.
+    stale       INTEGER NOT NULL DEFAULT 0, -- set when the derived variant needs to be regenerated.
+    PRIMARY KEY(id, variant)
+);
+
+CREATE INDEX IF NOT EXISTS idx_derived_source ON derived_variants(id, source);
@@ -0,0 +1,6 @@
+
+CREATE TABLE IF NOT EXISTS indexing_status
+(
+    id      TEXT    PRIMARY KEY NOT NULL,
+    version INTEGER NOT NULL -- the indexing version used when this resource was last indexed.
+);
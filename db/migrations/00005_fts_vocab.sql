@@ -0,0 +1,3 @@
+
+-- Read only view of the distinct words indexed in the fts table.
+CREATE VIRTUAL TABLE IF NOT EXISTS fts_vocab USING fts5vocab(fts, row);
@@ -0,0 +1,3 @@
+
+-- Read only view of each occurrence of the words indexed in the fts table.
+CREATE VIRTUAL TABLE IF NOT EXISTS fts_instances USING fts5vocab(fts, instance);
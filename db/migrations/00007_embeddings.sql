@@ -0,0 +1,6 @@
+
+CREATE TABLE IF NOT EXISTS embeddings
+(
+    id     TEXT PRIMARY KEY NOT NULL,
+    vector BLOB NOT NULL -- little endian f32 values.
+);
@@ -0,0 +1,2 @@
+-- Revision of each resource, used to detect concurrent updates.
+ALTER TABLE resources ADD COLUMN rev INTEGER NOT NULL DEFAULT 0;
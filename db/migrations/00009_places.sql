@@ -0,0 +1,9 @@
+-- Canonical url and origin of "Places" resources, filled by the places indexer.
+CREATE TABLE IF NOT EXISTS places
+(
+    id     TEXT PRIMARY KEY NOT NULL,
+    url    TEXT NOT NULL, -- the canonical url.
+    origin TEXT NOT NULL  -- the lower case host, without "www.".
+);
+
+CREATE INDEX IF NOT EXISTS idx_places_origin ON places(origin);
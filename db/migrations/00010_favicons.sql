@@ -0,0 +1,9 @@
+-- Last favicon fetch of each place, used to refresh them periodically.
+CREATE TABLE IF NOT EXISTS favicons
+(
+    id      TEXT     PRIMARY KEY NOT NULL,
+    url     TEXT     NOT NULL, -- the url the icon was fetched from, empty if none worked.
+    fetched DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_favicons_fetched ON favicons(fetched);
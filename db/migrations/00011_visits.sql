@@ -0,0 +1,10 @@
+-- Visits of each resource, kept beyond the last entries of the frecency scorer
+-- to build history views.
+CREATE TABLE IF NOT EXISTS visits
+(
+    id      TEXT     NOT NULL,
+    visited DATETIME NOT NULL,
+    PRIMARY KEY(id, visited)
+);
+
+CREATE INDEX IF NOT EXISTS idx_visits_visited ON visits(visited);
@@ -0,0 +1,4 @@
+-- Content hash of variants, see `VariantMetadata::hash()`.
+ALTER TABLE variants ADD COLUMN hash TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_variants_hash ON variants(hash);
@@ -0,0 +1,9 @@
+-- Results of operations run with an idempotency key, see `Manager::idempotent()`.
+CREATE TABLE IF NOT EXISTS idempotency_keys
+(
+    key     TEXT     PRIMARY KEY NOT NULL,
+    result  BLOB     NOT NULL, -- speedy encoded result of the operation.
+    created DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created ON idempotency_keys(created);
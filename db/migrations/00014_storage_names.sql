@@ -0,0 +1,10 @@
+-- Reverse index of the store file names, see `Manager::whois()`.
+CREATE TABLE IF NOT EXISTS storage_names
+(
+    name    TEXT NOT NULL, -- the file name in the store.
+    id      TEXT NOT NULL,
+    variant TEXT           -- NULL for the metadata file.
+);
+
+CREATE INDEX IF NOT EXISTS idx_storage_names_name ON storage_names(name);
+CREATE INDEX IF NOT EXISTS idx_storage_names_id ON storage_names(id);
@@ -0,0 +1,3 @@
+-- Derived variants removed to stay within the size budget, see
+-- `Manager::set_derived_variants_budget()`.
+ALTER TABLE derived_variants ADD COLUMN evicted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Whether the last session ended with `Manager::shutdown()`, checked at startup.
+CREATE TABLE IF NOT EXISTS shutdown_state
+(
+    id    INTEGER PRIMARY KEY NOT NULL CHECK (id = 0), -- Single row table.
+    clean INTEGER NOT NULL
+);
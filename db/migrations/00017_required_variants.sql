@@ -0,0 +1,7 @@
+-- Variants expected on the leaf children of containers, see `Manager::set_required_variants()`.
+CREATE TABLE IF NOT EXISTS required_variants
+(
+    id      TEXT NOT NULL, -- The container id.
+    variant TEXT NOT NULL,
+    PRIMARY KEY(id, variant)
+);
@@ -0,0 +1,4 @@
+-- The date of the last visit of each resource, see `Manager::recently_visited()`.
+ALTER TABLE resources ADD COLUMN last_visited DATETIME;
+
+CREATE INDEX IF NOT EXISTS idx_resources_last_visited ON resources(last_visited);
@@ -0,0 +1,7 @@
+-- The frecency of resources as of their last visit or the last call to
+-- `Manager::recompute_frecencies()`, to sort them without decoding the scorers.
+ALTER TABLE resources ADD COLUMN frecency INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS idx_resources_frecency ON resources(frecency);
+
+UPDATE resources SET frecency = frecency(scorer);
@@ -0,0 +1,6 @@
+-- The well-known containers shared by applications, see `Manager::system_container()`.
+CREATE TABLE IF NOT EXISTS system_containers
+(
+    kind TEXT NOT NULL PRIMARY KEY,
+    id   TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Trigram index of the text of resources, letting substring searches use an index instead
+-- of scanning the fts table. The fts table keeps the word index used by fts_vocab and fts_instances.
+CREATE VIRTUAL TABLE IF NOT EXISTS fts_trigrams USING fts5(id UNINDEXED, variant UNINDEXED, content, tokenize = 'trigram');
+INSERT INTO fts_trigrams ( id, variant, content ) SELECT id, variant, content FROM fts;
@@ -0,0 +1,10 @@
+-- Application private variants, see `Manager::set_sidecar()`.
+CREATE TABLE IF NOT EXISTS sidecar_variants
+(
+    id       TEXT NOT NULL,
+    variant  TEXT NOT NULL, -- The full variant name, see `VariantMetadata::sidecar_name()`.
+    app      TEXT NOT NULL,
+    no_quota INTEGER NOT NULL DEFAULT 0, -- Not counted by `Manager::quota_usage()`.
+    no_sync  INTEGER NOT NULL DEFAULT 0, -- Left out of `Manager::export_archive()`.
+    PRIMARY KEY(id, variant)
+);
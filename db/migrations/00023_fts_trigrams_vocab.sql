@@ -0,0 +1,2 @@
+-- Read only view of the distinct trigrams indexed in the fts_trigrams table.
+CREATE VIRTUAL TABLE IF NOT EXISTS fts_trigrams_vocab USING fts5vocab(fts_trigrams, row);
@@ -0,0 +1,7 @@
+-- The scorer settings the stored frecencies were computed with, to recompute
+-- them when a manager is opened with other settings.
+CREATE TABLE IF NOT EXISTS scorer_config
+(
+    id     INTEGER PRIMARY KEY NOT NULL CHECK (id = 0), -- Single row table.
+    config TEXT NOT NULL                                -- The json representation of the settings.
+);
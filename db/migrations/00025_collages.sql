@@ -0,0 +1,6 @@
+-- The thumbnails that the collage of each container was composed from, see `Manager::container_collage()`.
+CREATE TABLE IF NOT EXISTS collages
+(
+    id      TEXT NOT NULL PRIMARY KEY, -- The container id.
+    sources TEXT NOT NULL              -- The collage size, and the ids and modification dates of the children.
+);
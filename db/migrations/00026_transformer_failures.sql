@@ -0,0 +1,10 @@
+-- The transformers that failed on a resource, see `Manager::failed_transformations()`.
+CREATE TABLE IF NOT EXISTS transformer_failures
+(
+    id           TEXT     NOT NULL,
+    transformer  TEXT     NOT NULL, -- the name of the transformer that failed.
+    error        TEXT     NOT NULL, -- the error of the last attempt.
+    attempts     INTEGER  NOT NULL, -- the number of failed attempts.
+    last_attempt DATETIME NOT NULL,
+    PRIMARY KEY(id, transformer)
+);
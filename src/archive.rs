@@ -0,0 +1,408 @@
+/// Tar archives of resource subtrees, see `Manager::export_archive()`.
+///
+/// Each resource at path `P` in the archive is stored as:
+/// - a `P/` directory entry for containers, or a `P` file entry holding the
+///   default variant for leaves.
+/// - a `P/.metadata.json` (containers) or `P.metadata.json` (leaves) entry
+///   with the resource metadata.
+/// - a `P.variants/<variant>` entry for each other variant of leaves.
+///
+/// Only the subset of the ustar format that we need is supported, plus the GNU
+/// extension for names longer than 100 bytes. When reading, other entry types
+/// (links, devices, ...) are skipped.
+///
+/// Zip archives can be read too, with their entries either stored or deflated.
+/// Zip64 archives are not supported.
+use crate::common::{ResourceKind, ResourceMetadata, ResourceStoreError};
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const BLOCK_SIZE: usize = 512;
+
+const ZIP_LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const ZIP_CENTRAL_HEADER: &[u8] = b"PK\x01\x02";
+const ZIP_END_OF_DIRECTORY: &[u8] = b"PK\x05\x06";
+
+// Deflate can't compress more than this, so larger declared sizes are bogus.
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ArchivedVariant {
+    pub name: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    pub size: u32,
+}
+
+/// The metadata of a resource, as stored in archives.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ArchivedMetadata {
+    pub id: String,
+    pub name: String,
+    pub container: bool,
+    pub tags: Vec<String>,
+    pub variants: Vec<ArchivedVariant>,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+}
+
+impl From<&ResourceMetadata> for ArchivedMetadata {
+    fn from(meta: &ResourceMetadata) -> Self {
+        // Sorted so that archives don't depend on the order tags and variants were added in.
+        let mut tags = meta.tags().clone();
+        tags.sort();
+        let mut variants: Vec<ArchivedVariant> = meta
+            .variants()
+            .iter()
+            .map(|v| ArchivedVariant {
+                name: v.name(),
+                mime_type: v.mime_type(),
+                size: v.size(),
+            })
+            .collect();
+        variants.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            id: meta.id().into(),
+            name: meta.name(),
+            container: meta.kind() == ResourceKind::Container,
+            tags,
+            variants,
+            created: *meta.created(),
+            modified: *meta.modified(),
+        }
+    }
+}
+
+/// Returns a name usable as a path component.
+pub(crate) fn path_component(name: &str) -> String {
+    match name.replace('/', "_") {
+        name if name.is_empty() || name == "." || name == ".." => "_".into(),
+        name => name,
+    }
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let len = field.len() - 1;
+    let text = format!("{value:0len$o}");
+    field[..len].copy_from_slice(&text.as_bytes()[text.len() - len..]);
+    field[len] = 0;
+}
+
+fn header(name: &[u8], size: u64, mtime: i64, type_flag: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    let len = name.len().min(100);
+    header[..len].copy_from_slice(&name[..len]);
+    octal(
+        &mut header[100..108],
+        if type_flag == b'5' { 0o755 } else { 0o644 },
+    );
+    octal(&mut header[108..116], 0); // uid
+    octal(&mut header[116..124], 0); // gid
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+    octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+    header
+}
+
+pub(crate) struct TarWriter<'a, W: Write + Unpin> {
+    writer: &'a mut W,
+}
+
+impl<'a, W: Write + Unpin> TarWriter<'a, W> {
+    pub fn new(writer: &'a mut W) -> Self {
+        Self { writer }
+    }
+
+    async fn write_entry(
+        &mut self,
+        path: &str,
+        content: &[u8],
+        mtime: i64,
+        type_flag: u8,
+    ) -> Result<(), ResourceStoreError> {
+        let name = path.as_bytes();
+        if name.len() > 100 {
+            // GNU extension: the full name is the content of a preceding entry.
+            let mut long_name = name.to_vec();
+            long_name.push(0);
+            self.writer
+                .write_all(&header(b"././@LongLink", long_name.len() as _, 0, b'L'))
+                .await?;
+            self.write_padded(&long_name).await?;
+        }
+        self.writer
+            .write_all(&header(name, content.len() as _, mtime, type_flag))
+            .await?;
+        self.write_padded(content).await
+    }
+
+    async fn write_padded(&mut self, content: &[u8]) -> Result<(), ResourceStoreError> {
+        self.writer.write_all(content).await?;
+        let padding = (BLOCK_SIZE - content.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.writer.write_all(&vec![0; padding]).await?;
+        Ok(())
+    }
+
+    pub async fn append_dir(&mut self, path: &str, mtime: i64) -> Result<(), ResourceStoreError> {
+        self.write_entry(&format!("{path}/"), &[], mtime, b'5')
+            .await
+    }
+
+    pub async fn append_file(
+        &mut self,
+        path: &str,
+        content: &[u8],
+        mtime: i64,
+    ) -> Result<(), ResourceStoreError> {
+        self.write_entry(path, content, mtime, b'0').await
+    }
+
+    /// Writes the end of archive marker.
+    pub async fn finish(self) -> Result<(), ResourceStoreError> {
+        self.writer.write_all(&[0; 2 * BLOCK_SIZE]).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// A file or directory entry read from an archive.
+pub(crate) struct ArchiveEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub content: Vec<u8>,
+}
+
+pub(crate) fn invalid_archive() -> ResourceStoreError {
+    ResourceStoreError::Custom("InvalidArchive".into())
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64, ResourceStoreError> {
+    let text: String = field
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| *b as char)
+        .collect();
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid_archive())
+}
+
+fn parse_name(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into()
+}
+
+/// Reads a block, returning false at the end of the stream.
+async fn read_block<R: Read + Unpin>(
+    reader: &mut R,
+    block: &mut [u8; BLOCK_SIZE],
+) -> Result<bool, ResourceStoreError> {
+    let mut read = 0;
+    while read < BLOCK_SIZE {
+        match reader.read(&mut block[read..]).await? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(invalid_archive()),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Reads all the file and directory entries of a tar or zip archive.
+pub(crate) async fn read_entries<R: Read + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<ArchiveEntry>, ResourceStoreError> {
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer).await?;
+    if buffer.starts_with(ZIP_LOCAL_HEADER) || buffer.starts_with(ZIP_END_OF_DIRECTORY) {
+        read_zip_entries(&buffer)
+    } else {
+        read_tar_entries(&mut buffer.as_slice()).await
+    }
+}
+
+/// Reads all the file and directory entries of a tar archive.
+async fn read_tar_entries(reader: &mut &[u8]) -> Result<Vec<ArchiveEntry>, ResourceStoreError> {
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut block = [0u8; BLOCK_SIZE];
+    while read_block(reader, &mut block).await? {
+        // The archive ends with zero filled blocks.
+        if block.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let expected = parse_octal(&block[148..156])?;
+        let checksum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    *b as u64
+                }
+            })
+            .sum();
+        if checksum != expected {
+            return Err(invalid_archive());
+        }
+
+        let type_flag = block[156];
+        let header_path = {
+            let name = parse_name(&block[..100]);
+            let prefix = parse_name(&block[345..500]);
+            if &block[257..262] == b"ustar" && !prefix.is_empty() {
+                format!("{prefix}/{name}")
+            } else {
+                name
+            }
+        };
+
+        // Don't trust the size to allocate the content before checking it.
+        let size = parse_octal(&block[124..136])?;
+        if size > reader.len() as u64 {
+            return Err(invalid_archive());
+        }
+        let size = size as usize;
+        let mut content = vec![0; size];
+        reader
+            .read_exact(&mut content)
+            .await
+            .map_err(|_| invalid_archive())?;
+        let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        reader
+            .read_exact(&mut block[..padding])
+            .await
+            .map_err(|_| invalid_archive())?;
+
+        if type_flag == b'L' {
+            long_name = Some(parse_name(&content));
+            continue;
+        }
+        let path = long_name.take().unwrap_or(header_path);
+
+        match type_flag {
+            b'0' | 0 if !path.ends_with('/') => entries.push(ArchiveEntry {
+                path,
+                is_dir: false,
+                content,
+            }),
+            b'0' | 0 | b'5' => entries.push(ArchiveEntry {
+                path,
+                is_dir: true,
+                content: vec![],
+            }),
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn le_u16(buffer: &[u8], offset: usize) -> Result<usize, ResourceStoreError> {
+    buffer
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+        .ok_or_else(invalid_archive)
+}
+
+fn le_u32(buffer: &[u8], offset: usize) -> Result<u32, ResourceStoreError> {
+    buffer
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(invalid_archive)
+}
+
+/// Reads all the file and directory entries of a zip archive, as listed by its
+/// central directory.
+pub(crate) fn read_zip_entries(buffer: &[u8]) -> Result<Vec<ArchiveEntry>, ResourceStoreError> {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    // The end of central directory record is followed by a comment of up to 64KiB.
+    let min_end = buffer.len().saturating_sub(22 + u16::MAX as usize);
+    let end = (min_end..=buffer.len().saturating_sub(22))
+        .rev()
+        .find(|offset| buffer[*offset..].starts_with(ZIP_END_OF_DIRECTORY))
+        .ok_or_else(invalid_archive)?;
+    let count = le_u16(buffer, end + 10)?;
+    let mut offset = le_u32(buffer, end + 16)? as usize;
+
+    // Each entry takes at least 46 bytes of the central directory.
+    let mut entries = Vec::with_capacity(count.min(buffer.len() / 46));
+    for _ in 0..count {
+        if !buffer[offset.min(buffer.len())..].starts_with(ZIP_CENTRAL_HEADER) {
+            return Err(invalid_archive());
+        }
+        let method = le_u16(buffer, offset + 10)?;
+        let crc = le_u32(buffer, offset + 16)?;
+        let compressed_size = le_u32(buffer, offset + 20)? as usize;
+        let size = le_u32(buffer, offset + 24)? as usize;
+        let name_len = le_u16(buffer, offset + 28)?;
+        let extra_len = le_u16(buffer, offset + 30)?;
+        let comment_len = le_u16(buffer, offset + 32)?;
+        let local = le_u32(buffer, offset + 42)? as usize;
+        let path = buffer
+            .get(offset + 46..offset + 46 + name_len)
+            .map(String::from_utf8_lossy)
+            .ok_or_else(invalid_archive)?
+            .into_owned();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        if path.ends_with('/') {
+            entries.push(ArchiveEntry {
+                path,
+                is_dir: true,
+                content: vec![],
+            });
+            continue;
+        }
+
+        // The local header has its own name and extra fields, of possibly different lengths.
+        if !buffer[local.min(buffer.len())..].starts_with(ZIP_LOCAL_HEADER) {
+            return Err(invalid_archive());
+        }
+        let start = local + 30 + le_u16(buffer, local + 26)? + le_u16(buffer, local + 28)?;
+        let data = buffer
+            .get(start..start + compressed_size)
+            .ok_or_else(invalid_archive)?;
+        // Check the declared size before allocating it, and never inflate past it.
+        let content = match method {
+            0 if size == compressed_size => data.to_vec(),
+            8 if size <= compressed_size.saturating_mul(MAX_DEFLATE_RATIO) => {
+                let mut content = Vec::with_capacity(size);
+                DeflateDecoder::new(data)
+                    .take(size as u64 + 1)
+                    .read_to_end(&mut content)
+                    .map_err(|_| invalid_archive())?;
+                content
+            }
+            0 | 8 => return Err(invalid_archive()),
+            _ => return Err(ResourceStoreError::Custom("UnsupportedCompression".into())),
+        };
+        let mut actual = flate2::Crc::new();
+        actual.update(&content);
+        if content.len() != size || actual.sum() != crc {
+            return Err(invalid_archive());
+        }
+
+        entries.push(ArchiveEntry {
+            path,
+            is_dir: false,
+            content,
+        });
+    }
+    Ok(entries)
+}
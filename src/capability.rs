@@ -0,0 +1,71 @@
+/// Capabilities delegating limited access to a subtree of resources.
+///
+/// A capability is identified by an opaque token and grants a set of
+/// operations on a scope (a resource and all its descendants), optionally
+/// until an expiry date.
+/// When a token context is set on the Manager, each call is validated
+/// against the matching capability.
+use crate::common::ResourceId;
+use chrono::{DateTime, Utc};
+use std::ops::BitOr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operations(u8);
+
+impl Operations {
+    pub const NONE: Operations = Operations(0);
+    pub const READ: Operations = Operations(1);
+    pub const WRITE: Operations = Operations(2);
+    pub const LIST: Operations = Operations(4);
+    pub const ALL: Operations = Operations(7);
+
+    pub fn contains(&self, other: Operations) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+}
+
+impl BitOr for Operations {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capability {
+    pub token: String,
+    pub scope: ResourceId,
+    pub operations: Operations,
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl Capability {
+    pub fn new(scope: &ResourceId, operations: Operations, expiry: Option<DateTime<Utc>>) -> Self {
+        Self {
+            token: uuid::Uuid::new_v4().to_string(),
+            scope: scope.clone(),
+            operations,
+            expiry,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expiry {
+            Some(expiry) => expiry <= Utc::now(),
+            None => false,
+        }
+    }
+
+    pub fn allows(&self, operations: Operations) -> bool {
+        !self.is_expired() && self.operations.contains(operations)
+    }
+}
@@ -0,0 +1,178 @@
+/// Typed content for the well-known resource families.
+///
+/// Each codec maps a struct to the json format expected by the matching
+/// indexer of `crate::indexer`, so that apps don't have to build the json
+/// themselves. Use them with `Manager::create_typed()` and `Manager::get_typed()`.
+use crate::common::{ResourceId, ResourceStoreError};
+use crate::indexer::{canonical_url, create_contacts_indexer, create_places_indexer, Indexer};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub trait ResourceCodec: Serialize + DeserializeOwned {
+    /// The mime type of the default variant holding this content.
+    const MIME_TYPE: &'static str;
+
+    /// Returns the indexer for this content.
+    fn indexer() -> Box<dyn Indexer + Send + Sync>;
+
+    /// The name of the resource holding this content.
+    fn resource_name(&self) -> String;
+
+    fn encode(&self) -> Result<Vec<u8>, ResourceStoreError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    fn decode(content: &[u8]) -> Result<Self, ResourceStoreError> {
+        Ok(serde_json::from_slice(content)?)
+    }
+}
+
+/// Content that can be detected as a duplicate of another one.
+pub trait Deduplicate: ResourceCodec {
+    /// Normalized keys identifying this content: two values sharing
+    /// any key are considered duplicates.
+    fn duplicate_keys(&self) -> Vec<String>;
+}
+
+/// A "Places" object: { url: "...", title: "...", icon: "..." }
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Place {
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+impl ResourceCodec for Place {
+    const MIME_TYPE: &'static str = "application/x-places+json";
+
+    fn indexer() -> Box<dyn Indexer + Send + Sync> {
+        Box::new(create_places_indexer())
+    }
+
+    fn resource_name(&self) -> String {
+        if self.title.is_empty() {
+            self.url.clone()
+        } else {
+            self.title.clone()
+        }
+    }
+}
+
+/// A "Contacts" object: { name: "...", phone: "[...]", email: "[...]" }
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    #[serde(default)]
+    pub phone: Vec<String>,
+    #[serde(default)]
+    pub email: Vec<String>,
+}
+
+impl ResourceCodec for Contact {
+    const MIME_TYPE: &'static str = "application/x-contact+json";
+
+    fn indexer() -> Box<dyn Indexer + Send + Sync> {
+        Box::new(create_contacts_indexer())
+    }
+
+    fn resource_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl Deduplicate for Place {
+    fn duplicate_keys(&self) -> Vec<String> {
+        let url = canonical_url(&self.url);
+        if url.is_empty() {
+            vec![]
+        } else {
+            vec![url]
+        }
+    }
+}
+
+impl Deduplicate for Contact {
+    fn duplicate_keys(&self) -> Vec<String> {
+        let phones = self
+            .phone
+            .iter()
+            .map(|phone| normalize_phone(phone))
+            .filter(|phone| !phone.is_empty())
+            .map(|phone| format!("tel:{phone}"));
+        let emails = self
+            .email
+            .iter()
+            .map(|email| email.trim().to_lowercase())
+            .filter(|email| !email.is_empty())
+            .map(|email| format!("mailto:{email}"));
+        phones.chain(emails).collect()
+    }
+}
+
+/// Keeps the digits of a phone number, and the leading '+' of international numbers.
+fn normalize_phone(phone: &str) -> String {
+    let phone = phone.trim();
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    if phone.starts_with('+') && !digits.is_empty() {
+        format!("+{digits}")
+    } else {
+        digits
+    }
+}
+
+/// Groups the resources holding duplicate content. Duplicates are transitive: if
+/// A and B share a key and B and C share another one, all three are grouped.
+/// Only groups with at least two resources are returned, each one sorted by id.
+pub fn duplicate_groups<C: Deduplicate>(items: &[(ResourceId, C)]) -> Vec<Vec<ResourceId>> {
+    // Union-find over the item indices.
+    fn find(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+
+    let mut parents: Vec<usize> = (0..items.len()).collect();
+    let mut owners: HashMap<String, usize> = HashMap::new();
+    for (index, (_, value)) in items.iter().enumerate() {
+        for key in value.duplicate_keys() {
+            match owners.get(&key) {
+                Some(&owner) => {
+                    let (a, b) = (find(&mut parents, owner), find(&mut parents, index));
+                    parents[b] = a;
+                }
+                None => {
+                    owners.insert(key, index);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<ResourceId>> = HashMap::new();
+    for (index, (id, _)) in items.iter().enumerate() {
+        let root = find(&mut parents, index);
+        groups.entry(root).or_default().push(id.clone());
+    }
+
+    let mut groups: Vec<Vec<ResourceId>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort_by_key(|id| id.to_string());
+            group
+        })
+        .collect();
+    groups.sort_by_key(|group| group[0].to_string());
+    groups
+}
+
+#[test]
+fn normalized_phones() {
+    assert_eq!(normalize_phone(" +33 1 23-45.67 89"), "+33123456789");
+    assert_eq!(normalize_phone("(555) 123 4567"), "5551234567");
+    assert_eq!(normalize_phone("+"), "");
+}
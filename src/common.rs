@@ -108,11 +108,106 @@ where
     C: Context,
 {
     fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
-        let nanos = self.0.timestamp_nanos();
+        let nanos = self.0.timestamp_nanos_opt().unwrap_or_default();
         nanos.write_to(writer)
     }
 }
 
+/// A page of children of a container, see `Manager::children_page`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChildrenPage {
+    pub children: Vec<ResourceMetadata>,
+    pub cursor: Option<String>, // Set when more children are available.
+}
+
+/// A range of results to return from a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub offset: u32,
+    pub limit: u32,
+}
+
+impl Page {
+    pub fn new(offset: u32, limit: u32) -> Self {
+        Self { offset, limit }
+    }
+
+    /// A page holding all the results.
+    pub fn all() -> Self {
+        Self {
+            offset: 0,
+            limit: u32::MAX,
+        }
+    }
+}
+
+/// A page of results, with the total number of results of the query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    pub total: u32,
+}
+
+/// The attribute used to sort the children of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Created,
+    Modified,
+    Size, // The total size of all the variants.
+    Frecency,
+}
+
+/// How to sort the children of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
+    pub containers_first: bool, // List containers before leaves.
+}
+
+impl SortOrder {
+    pub fn new(key: SortKey) -> Self {
+        Self {
+            key,
+            descending: false,
+            containers_first: false,
+        }
+    }
+
+    pub fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    pub fn containers_first(mut self) -> Self {
+        self.containers_first = true;
+        self
+    }
+}
+
+/// Which resources are part of container listings, searches and frecency tops, depending
+/// on whether they are hidden, see `ResourceMetadata::is_hidden()`.
+#[derive(sqlx::Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Visibility {
+    #[default]
+    Visible, // Leaves hidden resources out.
+    Hidden, // Only hidden resources.
+    All,
+}
+
+impl Visibility {
+    /// Returns `true` if this resource is included.
+    pub fn includes(&self, metadata: &ResourceMetadata) -> bool {
+        match self {
+            Self::Visible => !metadata.is_hidden(),
+            Self::Hidden => metadata.is_hidden(),
+            Self::All => true,
+        }
+    }
+}
+
 #[derive(sqlx::FromRow, Clone, PartialEq, Eq, Debug)]
 pub struct IdFrec {
     pub id: ResourceId,
@@ -128,6 +223,73 @@ impl IdFrec {
     }
 }
 
+/// Describes how a derived variant was generated.
+#[derive(sqlx::FromRow, Clone, PartialEq, Eq, Debug)]
+pub struct DerivedVariant {
+    pub id: ResourceId,
+    pub variant: String,     // The name of the derived variant.
+    pub source: String,      // The name of the variant it was generated from.
+    pub transformer: String, // The name of the transformer that generated it.
+    pub version: u32,        // The version of that transformer.
+}
+
+/// A transformer that failed to generate the derived variants of a resource.
+#[derive(sqlx::FromRow, Clone, PartialEq, Eq, Debug)]
+pub struct TransformerFailure {
+    pub id: ResourceId,
+    pub transformer: String, // The name of the transformer that failed.
+    pub error: String,       // The error of the last attempt.
+    pub attempts: u32,       // The number of failed attempts.
+    pub last_attempt: DateTime<Utc>,
+}
+
+/// Selects the failed transformations to retry, see `Manager::retry_transformations()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransformationFilter {
+    pub id: Option<ResourceId>, // Only retry the failures of this resource.
+    pub transformer: Option<String>, // Only retry the failures of this transformer.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexingStatus {
+    Indexed,
+    Pending, // Not indexed yet with the current indexers, or with stale derived variants.
+}
+
+/// Aggregated indexing state of the leaves that have content.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexingProgress {
+    pub indexed: u32,
+    pub total: u32,
+}
+
+impl IndexingProgress {
+    /// Returns the percentage of indexed resources, 100 when there is nothing to index.
+    pub fn percent(&self) -> u32 {
+        if self.total == 0 {
+            return 100;
+        }
+        self.indexed * 100 / self.total
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.indexed == self.total
+    }
+}
+
+/// Receives progress reports from long operations, eg. to drive a progress bar.
+pub trait ProgressSink {
+    /// Called once `processed` out of `total` resources are done, `current` being
+    /// the last one.
+    fn progress(&mut self, processed: usize, total: usize, current: &ResourceId);
+}
+
+impl<F: FnMut(usize, usize, &ResourceId)> ProgressSink for F {
+    fn progress(&mut self, processed: usize, total: usize, current: &ResourceId) {
+        self(processed, total, current)
+    }
+}
+
 #[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq, Readable, Writable)]
 #[repr(u8)]
 pub enum ResourceKind {
@@ -145,11 +307,14 @@ impl From<i64> for ResourceKind {
     }
 }
 
+static SIDECAR_PREFIX: &str = "app.";
+
 #[derive(Clone, Debug, Readable, Writable, PartialEq, Eq)]
 pub struct VariantMetadata {
     name: String,
     mime_type: String,
     size: u32,
+    hash: Option<String>, // Hex encoded SHA-256 of the content, when known.
 }
 
 impl VariantMetadata {
@@ -158,6 +323,7 @@ impl VariantMetadata {
             name: name.into(),
             mime_type: mime_type.into(),
             size,
+            hash: None,
         }
     }
 
@@ -184,6 +350,38 @@ impl VariantMetadata {
     pub fn set_size(&mut self, size: u32) {
         self.size = size;
     }
+
+    /// The content hash, which can be compared to the one of some other content to
+    /// find out if it changed. It is computed by the store when writing the content.
+    pub fn hash(&self) -> Option<String> {
+        self.hash.clone()
+    }
+
+    pub fn set_hash(&mut self, hash: Option<&str>) {
+        self.hash = hash.map(|hash| hash.into());
+    }
+
+    /// Returns the name of the sidecar variant `name` of the application `app_id`, like
+    /// `app.notes.annotations`. Application ids are made of ascii alphanumeric characters,
+    /// `-` and `_`, so that the sidecars of different applications never collide.
+    pub fn sidecar_name(app_id: &str, name: &str) -> Result<String, ResourceStoreError> {
+        let valid_app = !app_id.is_empty()
+            && app_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_app || name.trim().is_empty() {
+            return Err(ResourceStoreError::InvalidVariant(format!(
+                "{SIDECAR_PREFIX}{app_id}.{name}"
+            )));
+        }
+        Ok(format!("{SIDECAR_PREFIX}{app_id}.{name}"))
+    }
+
+    /// Returns the application id of a sidecar variant, or None for other variants.
+    pub fn sidecar_app(&self) -> Option<String> {
+        let rest = self.name.strip_prefix(SIDECAR_PREFIX)?;
+        rest.split_once('.').map(|(app_id, _)| app_id.to_owned())
+    }
 }
 
 pub struct Variant {
@@ -195,9 +393,36 @@ impl Variant {
     pub fn new(metadata: VariantMetadata, reader: BoxedReader) -> Self {
         Variant { metadata, reader }
     }
+
+    /// Computes the hash of the content and sets it in the metadata. The reader is
+    /// positioned at the beginning of the content afterwards.
+    pub async fn update_hash(&mut self) -> Result<String, ResourceStoreError> {
+        use async_std::io::{prelude::SeekExt, ReadExt, SeekFrom};
+        use sha2::{Digest, Sha256};
+
+        self.reader.seek(SeekFrom::Start(0)).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = self.reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        self.reader.seek(SeekFrom::Start(0)).await?;
+
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        self.metadata.set_hash(Some(&hash));
+        Ok(hash)
+    }
 }
 
-#[derive(Clone, Debug, Readable, Writable, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ResourceMetadata {
     id: ResourceId,
     parent: ResourceId,
@@ -208,6 +433,92 @@ pub struct ResourceMetadata {
     created: DateTimeUtc,
     modified: DateTimeUtc,
     scorer: Scorer,
+    rev: u64, // Bumped on each update, to detect concurrent modifications.
+}
+
+// Encoded metadata starts with this marker followed by the format version. It can't
+// be mistaken for the unversioned layout, which starts with the length of the id.
+const METADATA_MARKER: u32 = u32::MAX;
+const METADATA_VERSION: u8 = 1;
+
+// The variant metadata of the unversioned layout, which had no hash.
+#[derive(Readable)]
+struct LegacyVariantMetadata {
+    name: String,
+    mime_type: String,
+    size: u32,
+}
+
+impl<'a, C> Readable<'a, C> for ResourceMetadata
+where
+    C: Context,
+{
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        let legacy = reader.peek_u32()? != METADATA_MARKER;
+        if !legacy {
+            reader.read_u32()?;
+            let version = reader.read_u8()?;
+            if version != METADATA_VERSION {
+                return Err(speedy::Error::custom(format!(
+                    "Unsupported metadata version: {version}"
+                ))
+                .into());
+            }
+        }
+
+        let id = ResourceId::read_from(reader)?;
+        let parent = ResourceId::read_from(reader)?;
+        let kind = ResourceKind::read_from(reader)?;
+        let name = String::read_from(reader)?;
+        let tags = Vec::<String>::read_from(reader)?;
+        let variants = if legacy {
+            Vec::<LegacyVariantMetadata>::read_from(reader)?
+                .into_iter()
+                .map(|variant| {
+                    VariantMetadata::new(&variant.name, &variant.mime_type, variant.size)
+                })
+                .collect()
+        } else {
+            Vec::<VariantMetadata>::read_from(reader)?
+        };
+        let created = DateTimeUtc::read_from(reader)?;
+        let modified = DateTimeUtc::read_from(reader)?;
+        let scorer = Scorer::read_from(reader)?;
+        let rev = if legacy { 0 } else { u64::read_from(reader)? };
+
+        Ok(Self {
+            id,
+            parent,
+            kind,
+            name,
+            tags,
+            variants,
+            created,
+            modified,
+            scorer,
+            rev,
+        })
+    }
+}
+
+impl<C> Writable<C> for ResourceMetadata
+where
+    C: Context,
+{
+    fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
+        writer.write_u32(METADATA_MARKER)?;
+        writer.write_u8(METADATA_VERSION)?;
+        self.id.write_to(writer)?;
+        self.parent.write_to(writer)?;
+        self.kind.write_to(writer)?;
+        self.name.write_to(writer)?;
+        self.tags.write_to(writer)?;
+        self.variants.write_to(writer)?;
+        self.created.write_to(writer)?;
+        self.modified.write_to(writer)?;
+        self.scorer.write_to(writer)?;
+        self.rev.write_to(writer)
+    }
 }
 
 impl ResourceMetadata {
@@ -229,6 +540,7 @@ impl ResourceMetadata {
             created: DateTimeUtc::now(),
             modified: DateTimeUtc::now(),
             scorer: Scorer::default(),
+            rev: 0,
         }
     }
 
@@ -239,6 +551,7 @@ impl ResourceMetadata {
         new_meta.created = DateTimeUtc::now();
         new_meta.modified = DateTimeUtc::now();
         new_meta.scorer = Scorer::default();
+        new_meta.rev = 0;
         new_meta
     }
 
@@ -285,6 +598,10 @@ impl ResourceMetadata {
         self.parent.clone()
     }
 
+    pub fn set_parent(&mut self, parent: &ResourceId) {
+        self.parent = parent.clone();
+    }
+
     pub fn kind(&self) -> ResourceKind {
         self.kind
     }
@@ -297,6 +614,13 @@ impl ResourceMetadata {
         self.name = name.to_owned();
     }
 
+    /// Hidden resources have a name starting with a dot, like sidecar files.
+    /// They are left out of listings and searches unless included by
+    /// `Manager::set_visibility()`, but can still be accessed directly.
+    pub fn is_hidden(&self) -> bool {
+        self.name.starts_with('.')
+    }
+
     pub fn scorer(&self) -> &Scorer {
         &self.scorer
     }
@@ -305,6 +629,13 @@ impl ResourceMetadata {
         self.scorer = scorer.clone();
     }
 
+    /// The frecency computed from the visits at call time, so it is accurate
+    /// regardless of how long this metadata has been cached. This uses the default
+    /// scorer settings, while managers use their own, see `scorer::Scoring`.
+    pub fn frecency(&self) -> u32 {
+        self.scorer.frecency()
+    }
+
     pub fn update_scorer(&mut self, entry: &VisitEntry) {
         self.scorer.add(entry);
     }
@@ -339,6 +670,18 @@ impl ResourceMetadata {
         self.modified = DateTimeUtc::now();
     }
 
+    pub fn rev(&self) -> u64 {
+        self.rev
+    }
+
+    pub fn set_rev(&mut self, rev: u64) {
+        self.rev = rev;
+    }
+
+    pub fn bump_rev(&mut self) {
+        self.rev += 1;
+    }
+
     pub fn tags(&self) -> &Vec<String> {
         &self.tags
     }
@@ -402,7 +745,7 @@ pub enum ResourceStoreError {
     #[error("Custom Error: {0}")]
     Custom(String),
     #[error("Sqlx error: {0}")]
-    Sql(#[from] sqlx::Error),
+    Sql(sqlx::Error),
     #[error("Serde JSON error: {0}")]
     Json(#[from] serde_json::Error),
     #[error("I/O Error: {0}")]
@@ -413,6 +756,41 @@ pub enum ResourceStoreError {
     InvalidResourceId,
     #[error("Speedy error: {0}")]
     Speedy(#[from] speedy::Error),
+    #[error("Permission Denied")]
+    PermissionDenied,
+    #[error("Rate Limited")]
+    RateLimited,
+    #[error("Revision Conflict")]
+    Conflict,
+    #[error("Database Busy")]
+    Busy,
+    #[error("Read Only")]
+    ReadOnly,
+    #[error("Writer Locked")]
+    WriterLocked,
+    #[error("Missing Required Variants: {0:?}")]
+    MissingVariants(Vec<String>),
+    #[error("Required Variant: {0}")]
+    RequiredVariant(String),
+    #[error("Evicted Variant: {0}")]
+    EvictedVariant(String),
+    #[error("Incompatible Database Version: found {found}, expected {expected}")]
+    IncompatibleDatabase { found: i64, expected: i64 },
+}
+
+impl From<sqlx::Error> for ResourceStoreError {
+    fn from(err: sqlx::Error) -> Self {
+        // Compare the primary result codes, leaving out the extended ones.
+        let code = match &err {
+            sqlx::Error::Database(err) => err.code().and_then(|code| code.parse::<i32>().ok()),
+            _ => None,
+        };
+        match code.map(|code| code & 0xff) {
+            Some(libsqlite3_sys::SQLITE_BUSY) | Some(libsqlite3_sys::SQLITE_LOCKED) => Self::Busy,
+            Some(libsqlite3_sys::SQLITE_READONLY) => Self::ReadOnly,
+            _ => Self::Sql(err),
+        }
+    }
 }
 
 impl PartialEq for ResourceStoreError {
@@ -426,8 +804,28 @@ impl PartialEq for ResourceStoreError {
             | (Self::Json(_), Self::Json(_))
             | (Self::Io(_), Self::Io(_))
             | (Self::InvalidContainerId, Self::InvalidContainerId)
-            | (Self::Speedy(_), Self::Speedy(_)) => true,
+            | (Self::InvalidResourceId, Self::InvalidResourceId)
+            | (Self::Speedy(_), Self::Speedy(_))
+            | (Self::PermissionDenied, Self::PermissionDenied)
+            | (Self::RateLimited, Self::RateLimited)
+            | (Self::Conflict, Self::Conflict)
+            | (Self::Busy, Self::Busy)
+            | (Self::ReadOnly, Self::ReadOnly)
+            | (Self::WriterLocked, Self::WriterLocked) => true,
             (Self::InvalidVariant(v1), Self::InvalidVariant(v2)) => v1 == v2,
+            (Self::MissingVariants(v1), Self::MissingVariants(v2)) => v1 == v2,
+            (Self::RequiredVariant(v1), Self::RequiredVariant(v2)) => v1 == v2,
+            (Self::EvictedVariant(v1), Self::EvictedVariant(v2)) => v1 == v2,
+            (
+                Self::IncompatibleDatabase {
+                    found: found1,
+                    expected: expected1,
+                },
+                Self::IncompatibleDatabase {
+                    found: found2,
+                    expected: expected2,
+                },
+            ) => found1 == found2 && expected1 == expected2,
             _ => false,
         }
     }
@@ -444,6 +842,7 @@ impl ReaderTrait for async_std::io::BufReader<async_std::fs::File> {}
 
 // Special case for slices.
 impl ReaderTrait for async_std::io::Cursor<&[u8]> {}
+impl ReaderTrait for async_std::io::Cursor<Vec<u8>> {}
 
 pub type BoxedReader = Box<dyn ReaderTrait + Unpin>;
 
@@ -453,19 +852,21 @@ pub trait ResourceStore {
     /// Creates a new resource with some metadata and an initial variant.
     /// This function will fail if a resource with the same id already exists.
     /// The variant passed must be in the metadata variant list.
+    /// Returns the hash of the variant content, if the store computes one.
     async fn create(
         &self,
         metadata: &ResourceMetadata,
         variant: Option<Variant>,
-    ) -> Result<(), ResourceStoreError>;
+    ) -> Result<Option<String>, ResourceStoreError>;
 
     /// Updates the metadata and variant for a resource.
     /// The variant passed must be in the metadata variant list.
+    /// Returns the hash of the variant content, if the store computes one.
     async fn update(
         &self,
         metadata: &ResourceMetadata,
         variant: Option<Variant>,
-    ) -> Result<(), ResourceStoreError>;
+    ) -> Result<Option<String>, ResourceStoreError>;
 
     /// Helper method to update the default variant using
     /// a slice as input.
@@ -505,12 +906,105 @@ pub trait ResourceStore {
 
     /// Returns the path for a given resource variant or None if the store implementation can't provide one.
     async fn get_native_path(&self, id: &ResourceId, variant: &str) -> Option<PathBuf>;
+
+    /// Appends content at the end of a variant and stores the updated metadata, which
+    /// already accounts for the new variant size.
+    /// The default implementation rewrites the whole variant; stores that can append
+    /// natively should override it.
+    async fn append_to_variant(
+        &self,
+        metadata: &ResourceMetadata,
+        variant: &str,
+        content: &[u8],
+    ) -> Result<(), ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let variant_metadata = metadata
+            .variants()
+            .iter()
+            .find(|v| v.name() == variant)
+            .cloned()
+            .ok_or_else(|| ResourceStoreError::InvalidVariant(variant.into()))?;
+
+        let mut buffer = vec![];
+        if let Ok(mut reader) = self.get_variant(&metadata.id(), variant).await {
+            reader.read_to_end(&mut buffer).await?;
+        }
+        buffer.extend_from_slice(content);
+
+        self.update(
+            metadata,
+            Some(Variant::new(
+                variant_metadata,
+                Box::new(async_std::io::Cursor::new(buffer)),
+            )),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the ids of all the resources in the store, parents being listed before
+    /// their children.
+    /// The default implementation walks the tree from the root using the children lists
+    /// of containers, so resources that are not reachable from the root are not listed.
+    async fn list_ids(&self) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let mut ids = vec![];
+        let mut seen = std::collections::HashSet::new();
+        let mut pending = vec![ROOT_ID.clone()];
+
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let metadata = match self.get_metadata(&id).await {
+                Ok(metadata) => metadata,
+                Err(ResourceStoreError::NoSuchResource) => continue,
+                Err(err) => return Err(err),
+            };
+            ids.push(id.clone());
+
+            if metadata.kind() == ResourceKind::Container {
+                if let Ok(mut reader) = self.get_variant(&id, "default").await {
+                    let mut buffer = vec![];
+                    reader.read_to_end(&mut buffer).await?;
+                    pending.extend(Vec::<ResourceId>::read_from_buffer(&buffer)?);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Hints that these resource variants are likely to be accessed soon, letting caching
+    /// or tiered stores fetch them ahead of time. Does nothing by default.
+    async fn prefetch(&self, _hints: &[(ResourceId, String)]) {}
+
+    /// Returns the names of the files holding this resource, with the variant stored in
+    /// each of them (None for the metadata). Stores that don't use files return nothing.
+    fn storage_names(&self, _metadata: &ResourceMetadata) -> Vec<(String, Option<String>)> {
+        vec![]
+    }
+
+    /// Returns a URL serving the content of a variant directly, valid for at least `ttl`,
+    /// for instance a presigned URL for object storage. Returns None by default.
+    async fn content_url(
+        &self,
+        _id: &ResourceId,
+        _variant: &str,
+        _ttl: std::time::Duration,
+    ) -> Option<String> {
+        None
+    }
 }
 
 /// A trait to implement that makes it possible to assign non-default
 /// names to resource files:
 /// - meta files.
 /// - variant files.
+///
 /// This is useful to make it harder to learn about the resource set
 /// based on file names only.
 pub trait ResourceNameProvider: Sync + Send {
@@ -519,6 +1013,11 @@ pub trait ResourceNameProvider: Sync + Send {
 
     // Provides the name for a variant file.
     fn variant_name(&self, id: &ResourceId, variant: &str) -> String;
+
+    /// Provides the name for the content shared by variants with the same hash.
+    fn blob_name(&self, hash: &str) -> String {
+        format!("{hash}.blob")
+    }
 }
 
 pub struct DefaultResourceNameProvider;
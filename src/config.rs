@@ -1,9 +1,52 @@
 /// Configuration file definition.
-use serde::Deserialize;
+use crate::fts::StemmingLanguage;
+use crate::scorer::ScorerConfig;
+use serde::{Deserialize, Serialize};
+
+/// How entries are chosen for eviction from the metadata cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    /// Evicts the least recently used entry.
+    #[default]
+    Lru,
+    /// Evicts the entry with the lowest frecency among the least recently used ones,
+    /// so that a burst of scans doesn't flush the frecent resources.
+    Frecency,
+}
+
+/// How a manager shares its database and store with the other managers using them,
+/// possibly from other processes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessMode {
+    /// Several managers can read and write, relying on SQLite locking: writes wait
+    /// for the other writers, and fail with `ResourceStoreError::Busy` after a timeout.
+    /// Fails with `ResourceStoreError::WriterLocked` if a single writer is running.
+    #[default]
+    Shared,
+    /// The only manager allowed to write. Fails with `ResourceStoreError::WriterLocked`
+    /// if another manager, except read only ones, is running.
+    SingleWriter,
+    /// Never writes, and can run alongside any other manager. Operations that need
+    /// to write fail with `ResourceStoreError::ReadOnly`. The database must exist
+    /// and be up to date.
+    ReadOnly,
+}
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub db_path: String,
     pub data_dir: String,
     pub metadata_cache_capacity: usize, // The number of items kept in the LRU cache.
+    #[serde(default)]
+    pub metadata_cache_policy: CachePolicy,
+    #[serde(default)]
+    pub scorer: ScorerConfig, // How the manager samples and weights visits.
+    #[serde(default)]
+    pub access_mode: AccessMode,
+    #[serde(default)]
+    pub stop_words: Vec<String>, // Left out of text searches, see `Fts::set_stop_words()`.
+    #[serde(default)]
+    pub stemming: Option<StemmingLanguage>, // Stems search terms, see `Fts::set_stemming()`.
 }
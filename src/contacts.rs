@@ -0,0 +1,176 @@
+/// Photos of contacts.
+///
+/// `ContactPhotoTransformer` extracts the photo embedded in contacts into a `photo`
+/// variant, and generates a small `avatar` thumbnail of it, so that contact lists don't
+/// have to parse the contacts to show them.
+/// Photos are read from the `photo` member of `application/x-contact+json` resources,
+/// either a data url or base64 content, or a list of them. vCards embed them in their
+/// `PHOTO` property. Photos only linked to with a url are skipped.
+use crate::common::{ResourceMetadata, ResourceStoreError, Variant, VariantMetadata};
+use crate::thumbnails::{image_error, jpeg_variant};
+use crate::transformers::Transformer;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+
+pub const CONTACT_MIME_TYPE: &str = "application/x-contact+json";
+pub const VCARD_MIME_TYPES: [&str; 2] = ["text/vcard", "text/x-vcard"];
+
+/// A decoded photo, with its mime type if known.
+struct Photo {
+    mime_type: Option<String>,
+    content: Vec<u8>,
+}
+
+/// Decodes a data url, or plain base64 content.
+fn decode_photo(value: &str) -> Option<Photo> {
+    let value = value.trim();
+    let (mime_type, data) = match value.strip_prefix("data:") {
+        Some(url) => {
+            let (header, data) = url.split_once(',')?;
+            let mime_type = header.strip_suffix(";base64")?;
+            (Some(mime_type.to_owned()).filter(|m| !m.is_empty()), data)
+        }
+        None if value.contains(':') => return None, // Not embedded.
+        None => (None, value),
+    };
+    let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+
+    STANDARD
+        .decode(data)
+        .ok()
+        .map(|content| Photo { mime_type, content })
+}
+
+fn json_photo(content: &[u8]) -> Result<Option<Photo>, ResourceStoreError> {
+    let contact: Value = serde_json::from_slice(content)?;
+    Ok(match contact.get("photo") {
+        Some(Value::String(photo)) => decode_photo(photo),
+        Some(Value::Array(photos)) => photos
+            .iter()
+            .filter_map(|photo| photo.as_str())
+            .find_map(decode_photo),
+        _ => None,
+    })
+}
+
+fn vcard_photo(content: &[u8]) -> Option<Photo> {
+    // Long lines are folded, continuing on lines starting with a space or a tab.
+    let text = String::from_utf8_lossy(content)
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let mut params = name.split(';');
+        // Properties may be prefixed by a group name, like `item1.PHOTO`.
+        let property = params.next()?.rsplit('.').next()?;
+        if !property.eq_ignore_ascii_case("PHOTO") {
+            return None;
+        }
+        let mut photo = decode_photo(value)?;
+        // vCard 2.1 and 3.0 give the image type as a parameter, like `TYPE=JPEG`.
+        if photo.mime_type.is_none() {
+            photo.mime_type = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("TYPE"))
+                .map(|(_, kind)| format!("image/{}", kind.to_lowercase()));
+        }
+        Some(photo)
+    })
+}
+
+/// Generates the `photo` and `avatar` variants of contacts with an embedded photo.
+pub struct ContactPhotoTransformer {
+    avatar_size: u32,
+}
+
+impl ContactPhotoTransformer {
+    /// Avatars are scaled to fit in `avatar_size`.
+    pub fn new(avatar_size: u32) -> Self {
+        Self { avatar_size }
+    }
+}
+
+impl Default for ContactPhotoTransformer {
+    fn default() -> Self {
+        Self::new(96)
+    }
+}
+
+#[async_trait(?Send)]
+impl Transformer for ContactPhotoTransformer {
+    fn name(&self) -> &str {
+        "contact-photo"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    async fn transform(
+        &self,
+        _meta: &ResourceMetadata,
+        source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        let mime_type = source.mime_type();
+        let photo = if mime_type == CONTACT_MIME_TYPE {
+            json_photo(content)?
+        } else if VCARD_MIME_TYPES.contains(&mime_type.as_str()) {
+            vcard_photo(content)
+        } else {
+            return Ok(vec![]);
+        };
+        let photo = match photo {
+            Some(photo) => photo,
+            None => return Ok(vec![]),
+        };
+
+        let image = image::load_from_memory(&photo.content).map_err(image_error)?;
+        let photo_mime_type = match photo.mime_type {
+            Some(mime_type) => mime_type,
+            None => image::guess_format(&photo.content)
+                .map_err(image_error)?
+                .to_mime_type()
+                .to_owned(),
+        };
+        let size = self.avatar_size;
+
+        Ok(vec![
+            Variant::new(
+                VariantMetadata::new("photo", &photo_mime_type, photo.content.len() as _),
+                Box::new(async_std::io::Cursor::new(photo.content)),
+            ),
+            jpeg_variant("avatar", &image.thumbnail(size, size))?,
+        ])
+    }
+}
+
+#[test]
+fn contact_photos() {
+    let photo = vcard_photo(
+        b"BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Jean Dupont\r\nitem1.PHOTO;ENCODING=b;TYPE=JPEG:SGVsbG8g\r\n V29ybGQ=\r\nEND:VCARD\r\n",
+    )
+    .unwrap();
+    assert_eq!(photo.content, b"Hello World");
+    assert_eq!(photo.mime_type.as_deref(), Some("image/jpeg"));
+
+    let photo =
+        vcard_photo(b"BEGIN:VCARD\nVERSION:4.0\nPHOTO:data:image/png;base64,SGVsbG8=\nEND:VCARD")
+            .unwrap();
+    assert_eq!(photo.content, b"Hello");
+    assert_eq!(photo.mime_type.as_deref(), Some("image/png"));
+
+    assert!(vcard_photo(b"BEGIN:VCARD\nPHOTO:https://example.com/me.jpg\nEND:VCARD").is_none());
+
+    let photo = json_photo(br#"{ "name": "Jean", "photo": ["SGVsbG8="] }"#)
+        .unwrap()
+        .unwrap();
+    assert_eq!(photo.content, b"Hello");
+    assert_eq!(photo.mime_type, None);
+    assert!(json_photo(br#"{ "name": "Jean" }"#).unwrap().is_none());
+}
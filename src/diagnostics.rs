@@ -0,0 +1,171 @@
+/// Diagnostics reports, meant to be attached to bug reports.
+///
+/// The report describes the state of a manager: its configuration, the
+/// result of a self-test of the database and the store, some counters and
+/// the last failed store operations.
+/// Reports written to files are redacted by default, so that they don't
+/// leak the local paths or the names of resources.
+use crate::common::ResourceId;
+use crate::config::{AccessMode, CachePolicy};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+static REDACTED: &str = "<redacted>";
+
+// The number of errors kept for diagnostics.
+const MAX_RECENT_ERRORS: usize = 20;
+
+/// A failed store operation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RecentError {
+    pub time: DateTime<Utc>,
+    pub operation: String,
+    pub id: String,
+    pub message: String,
+}
+
+/// Keeps the last failed store operations, dropping the oldest ones.
+#[derive(Default)]
+pub(crate) struct RecentErrors {
+    errors: VecDeque<RecentError>,
+}
+
+impl RecentErrors {
+    pub(crate) fn push(&mut self, operation: &str, id: &ResourceId, message: String) {
+        if self.errors.len() == MAX_RECENT_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(RecentError {
+            time: Utc::now(),
+            operation: operation.into(),
+            id: id.to_string(),
+            message,
+        });
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<RecentError> {
+        self.errors.iter().cloned().collect()
+    }
+}
+
+/// The configuration and settings of the manager.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiagnosticsConfig {
+    pub db_path: String,
+    pub data_dir: String,
+    pub metadata_cache_capacity: usize,
+    pub metadata_cache_policy: CachePolicy,
+    pub access_mode: AccessMode,
+    pub indexing_version: u32,
+    pub index_ancestor_names: bool,
+    pub inherit_tags: bool,
+    pub index_storage_names: bool,
+    pub derived_variants_budget: Option<u64>,
+}
+
+/// A snapshot of the state of a manager, see `Manager::diagnostics()`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostics {
+    pub schema_version: i64, // The version of the last applied migration.
+    pub config: DiagnosticsConfig,
+    pub problems: Vec<String>, // Failures of the self-test, empty when healthy.
+    pub unclean_shutdown: bool, // Whether the previous session ended without `Manager::shutdown()`.
+    pub resources: u32,
+    pub variants: u32,
+    pub tags: u32,
+    pub bytes: u64,                    // The total size of the variants.
+    pub pending_indexing: u32, // Resources not indexed yet, see `Manager::indexing_progress()`.
+    pub stale_variants: u32,   // Derived variants to regenerate.
+    pub rehydrations: u32, // Resources missing from the database and restored from the store, since startup.
+    pub repairs: u32, // Inconsistencies fixed by `Manager::check_consistency()`, since startup.
+    pub last_errors: Vec<RecentError>, // Oldest first.
+}
+
+impl Diagnostics {
+    /// Removes the local paths from the report, and the messages of the errors
+    /// since they can include resource names or content.
+    pub fn redacted(mut self) -> Self {
+        let paths = [self.config.db_path.clone(), self.config.data_dir.clone()];
+        let redact = |text: &str| {
+            paths
+                .iter()
+                .filter(|path| !path.is_empty())
+                .fold(text.to_owned(), |text, path| text.replace(path, REDACTED))
+        };
+
+        self.problems = self
+            .problems
+            .iter()
+            .map(|problem| redact(problem))
+            .collect();
+        for error in self.last_errors.iter_mut() {
+            // Only keep the kind of error, eg. "I/O Error" or "No Such Resource".
+            if let Some((kind, _)) = error.message.split_once(':') {
+                error.message = kind.to_owned();
+            }
+        }
+        self.config.db_path = REDACTED.into();
+        self.config.data_dir = REDACTED.into();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_errors() {
+        let mut errors = RecentErrors::default();
+        for i in 0..25 {
+            errors.push("create", &i.into(), format!("Error #{i}"));
+        }
+        let errors = errors.to_vec();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].message, "Error #5");
+        assert_eq!(errors[19].id, "id-24");
+    }
+
+    #[test]
+    fn redacted() {
+        let mut errors = RecentErrors::default();
+        errors.push("update", &1.into(), "Custom Error: secret.txt".into());
+        let diagnostics = Diagnostics {
+            schema_version: 15,
+            config: DiagnosticsConfig {
+                db_path: "/home/user/db.sqlite".into(),
+                data_dir: "/home/user/data".into(),
+                metadata_cache_capacity: 100,
+                metadata_cache_policy: CachePolicy::Lru,
+                access_mode: AccessMode::Shared,
+                indexing_version: 0,
+                index_ancestor_names: false,
+                inherit_tags: false,
+                index_storage_names: false,
+                derived_variants_budget: None,
+            },
+            problems: vec!["Can't read /home/user/data/root.meta".into()],
+            unclean_shutdown: false,
+            resources: 1,
+            variants: 1,
+            tags: 0,
+            bytes: 42,
+            pending_indexing: 0,
+            stale_variants: 0,
+            rehydrations: 0,
+            repairs: 0,
+            last_errors: errors.to_vec(),
+        }
+        .redacted();
+
+        assert_eq!(diagnostics.config.db_path, REDACTED);
+        assert_eq!(diagnostics.config.data_dir, REDACTED);
+        assert_eq!(
+            diagnostics.problems,
+            vec!["Can't read <redacted>/root.meta"]
+        );
+        assert_eq!(diagnostics.last_errors[0].message, "Custom Error");
+        assert_eq!(diagnostics.last_errors[0].id, "id-1");
+    }
+}
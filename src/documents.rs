@@ -0,0 +1,226 @@
+/// Previews and page counts of PDF and EPUB documents.
+///
+/// `DocumentTransformer` generates a `preview` variant with the first page of documents,
+/// and a `document-info` json variant with their page count: `{ "pageCount": 12 }`.
+/// The first page of EPUB documents is their cover image. This crate can't render PDF
+/// pages itself, so their preview needs a `PageRenderer` provided by the embedder.
+use crate::archive::read_zip_entries;
+use crate::common::{ResourceMetadata, ResourceStoreError, Variant, VariantMetadata};
+use crate::thumbnails::{image_error, jpeg_variant, ThumbnailerConfig};
+use crate::transformers::Transformer;
+use async_trait::async_trait;
+use serde_json::json;
+
+pub const PDF_MIME_TYPE: &str = "application/pdf";
+pub const EPUB_MIME_TYPE: &str = "application/epub+zip";
+
+#[async_trait(?Send)]
+pub trait PageRenderer {
+    /// Renders the first page of a document as an image, in one of the
+    /// `crate::thumbnails::IMAGE_MIME_TYPES`.
+    async fn render_first_page(
+        &self,
+        mime_type: &str,
+        content: &[u8],
+    ) -> Result<Vec<u8>, ResourceStoreError>;
+}
+
+fn invalid_document() -> ResourceStoreError {
+    ResourceStoreError::Custom("InvalidDocument".into())
+}
+
+/// Returns the start tags named `name` in this xml document, without their brackets.
+fn start_tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let prefix = format!("<{name}");
+    let mut tags = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&prefix) {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        // Don't mistake `<itemref` for `<item`.
+        let next = rest[name.len()..].chars().next();
+        if matches!(next, Some(c) if c.is_whitespace() || c == '/' || c == '>') {
+            tags.push(&rest[..end]);
+        }
+    }
+    tags
+}
+
+/// Returns the value of an attribute in a start tag.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(start) = rest.find(name) {
+        let preceded_by_space = rest[..start]
+            .chars()
+            .last()
+            .is_some_and(char::is_whitespace);
+        rest = &rest[start + name.len()..];
+        let value = rest.trim_start();
+        if !preceded_by_space || !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        return value[1..].split(quote).next();
+    }
+    None
+}
+
+/// An EPUB document: the number of sections in its reading order, and its cover image.
+/// The pages of EPUB documents depend on the layout, so sections stand for pages.
+struct Epub {
+    sections: usize,
+    cover: Option<Vec<u8>>,
+}
+
+impl Epub {
+    fn parse(content: &[u8]) -> Result<Self, ResourceStoreError> {
+        let mut entries = read_zip_entries(content)?;
+        let mut text = |path: &str| {
+            entries
+                .iter()
+                .position(|entry| entry.path == path)
+                .map(|index| {
+                    String::from_utf8_lossy(&entries.swap_remove(index).content).into_owned()
+                })
+        };
+
+        // The container file points to the package document, which lists the content.
+        let container = text("META-INF/container.xml").ok_or_else(invalid_document)?;
+        let package_path = start_tags(&container, "rootfile")
+            .iter()
+            .find_map(|tag| attribute(tag, "full-path"))
+            .ok_or_else(invalid_document)?
+            .to_owned();
+        let package = text(&package_path).ok_or_else(invalid_document)?;
+        let base = match package_path.rfind('/') {
+            Some(end) => &package_path[..=end],
+            None => "",
+        };
+
+        let sections = start_tags(&package, "itemref").len();
+
+        // EPUB 3 flags the cover image in the manifest, EPUB 2 names it in the metadata.
+        let items = start_tags(&package, "item");
+        let cover_id = start_tags(&package, "meta")
+            .iter()
+            .find(|tag| attribute(tag, "name") == Some("cover"))
+            .and_then(|tag| attribute(tag, "content"));
+        let cover_href = items
+            .iter()
+            .find(|tag| {
+                attribute(tag, "properties")
+                    .is_some_and(|properties| properties.split(' ').any(|p| p == "cover-image"))
+            })
+            .or_else(|| {
+                items
+                    .iter()
+                    .find(|tag| cover_id.is_some() && attribute(tag, "id") == cover_id)
+            })
+            .and_then(|tag| attribute(tag, "href"));
+        let cover = cover_href.and_then(|href| {
+            let path = format!("{base}{href}");
+            entries
+                .into_iter()
+                .find(|entry| entry.path == path)
+                .map(|entry| entry.content)
+        });
+
+        Ok(Self { sections, cover })
+    }
+}
+
+/// Generates the `preview` and `document-info` variants of PDF and EPUB documents.
+#[derive(Default)]
+pub struct DocumentTransformer {
+    config: ThumbnailerConfig,
+    renderer: Option<Box<dyn PageRenderer + Send + Sync>>,
+}
+
+impl DocumentTransformer {
+    /// Previews are scaled to fit in `config.size`.
+    pub fn new(config: ThumbnailerConfig) -> Self {
+        Self {
+            config,
+            renderer: None,
+        }
+    }
+
+    /// Sets the renderer of PDF pages. PDF documents only get a page count without one.
+    pub fn set_page_renderer(&mut self, renderer: Box<dyn PageRenderer + Send + Sync>) {
+        self.renderer = Some(renderer);
+    }
+}
+
+#[async_trait(?Send)]
+impl Transformer for DocumentTransformer {
+    fn name(&self) -> &str {
+        "document"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    async fn transform(
+        &self,
+        _meta: &ResourceMetadata,
+        source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        let mime_type = source.mime_type();
+        let (page_count, first_page) = match mime_type.as_str() {
+            PDF_MIME_TYPE => {
+                let document = lopdf::Document::load_mem(content)
+                    .map_err(|err| ResourceStoreError::Custom(format!("InvalidDocument: {err}")))?;
+                let first_page = match &self.renderer {
+                    Some(renderer) => Some(renderer.render_first_page(&mime_type, content).await?),
+                    None => None,
+                };
+                (document.get_pages().len(), first_page)
+            }
+            EPUB_MIME_TYPE => {
+                let epub = Epub::parse(content)?;
+                (epub.sections, epub.cover)
+            }
+            _ => return Ok(vec![]),
+        };
+
+        let mut variants = vec![];
+        if let Some(first_page) = first_page {
+            let size = self.config.size;
+            let image = image::load_from_memory(&first_page).map_err(image_error)?;
+            variants.push(jpeg_variant("preview", &image.thumbnail(size, size))?);
+        }
+        let info = serde_json::to_vec(&json!({ "pageCount": page_count }))?;
+        variants.push(Variant::new(
+            VariantMetadata::new("document-info", "application/json", info.len() as _),
+            Box::new(async_std::io::Cursor::new(info)),
+        ));
+
+        Ok(variants)
+    }
+}
+
+#[test]
+fn epub_package_parsing() {
+    let tags = start_tags(
+        r#"<spine><itemref idref="a"/><itemref idref='b' /></spine><item id="c">"#,
+        "itemref",
+    );
+    assert_eq!(tags, vec![r#"itemref idref="a"/"#, "itemref idref='b' /"]);
+    assert_eq!(attribute(tags[0], "idref"), Some("a"));
+    assert_eq!(attribute(tags[1], "idref"), Some("b"));
+    assert_eq!(
+        start_tags(r#"<item id="c">"#, "item"),
+        vec![r#"item id="c""#]
+    );
+    assert_eq!(attribute(r#"item xid="a" id="c""#, "id"), Some("c"));
+    assert_eq!(attribute(r#"item id="c""#, "href"), None);
+}
@@ -0,0 +1,78 @@
+/// End-to-end encryption of exported archives, see `Manager::export_encrypted_archive()`.
+///
+/// Payloads use the age format with X25519 keys, so they can transit untrusted relays
+/// and be decrypted by other age implementations. Recipients are given as age public
+/// keys (`age1...`), and identities as age secret keys (`AGE-SECRET-KEY-1...`).
+use crate::common::ResourceStoreError;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Parses age public keys.
+pub(crate) fn recipients(keys: &[&str]) -> Result<Vec<age::x25519::Recipient>, ResourceStoreError> {
+    keys.iter()
+        .map(|key| {
+            age::x25519::Recipient::from_str(key)
+                .map_err(|_| ResourceStoreError::Custom("InvalidRecipient".into()))
+        })
+        .collect()
+}
+
+/// Encrypts this content so that it can be decrypted by any of the recipients.
+pub(crate) fn encrypt(
+    content: &[u8],
+    recipients: &[age::x25519::Recipient],
+) -> Result<Vec<u8>, ResourceStoreError> {
+    let encryptor = age::Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )
+    .map_err(|_| ResourceStoreError::Custom("NoRecipients".into()))?;
+
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(content)?;
+    writer.finish()?;
+
+    Ok(encrypted)
+}
+
+/// Decrypts content encrypted to the public key of this identity.
+pub(crate) fn decrypt(content: &[u8], identity: &str) -> Result<Vec<u8>, ResourceStoreError> {
+    let identity = age::x25519::Identity::from_str(identity)
+        .map_err(|_| ResourceStoreError::Custom("InvalidIdentity".into()))?;
+    let decryptor = age::Decryptor::new(content)
+        .map_err(|_| ResourceStoreError::Custom("InvalidEncryptedArchive".into()))?;
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|_| ResourceStoreError::Custom("DecryptionFailed".into()))?;
+
+    let mut decrypted = vec![];
+    reader.read_to_end(&mut decrypted)?;
+
+    Ok(decrypted)
+}
+
+#[test]
+fn encryption_round_trip() {
+    use age::secrecy::ExposeSecret;
+
+    let identity = age::x25519::Identity::generate();
+    let other = age::x25519::Identity::generate();
+    let keys = recipients(&[&identity.to_public().to_string()]).unwrap();
+
+    let encrypted = encrypt(b"Hello World", &keys).unwrap();
+    assert!(!encrypted.windows(5).any(|window| window == b"Hello"));
+    assert_eq!(
+        decrypt(&encrypted, identity.to_string().expose_secret()).unwrap(),
+        b"Hello World"
+    );
+    assert_eq!(
+        decrypt(&encrypted, other.to_string().expose_secret()),
+        Err(ResourceStoreError::Custom("DecryptionFailed".into()))
+    );
+    assert_eq!(
+        recipients(&["not-a-key"]).err(),
+        Some(ResourceStoreError::Custom("InvalidRecipient".into()))
+    );
+}
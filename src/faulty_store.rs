@@ -0,0 +1,198 @@
+/// A resource store wrapper that can be scripted to fail or slow down
+/// some operations, to check rollback and retry behavior.
+use crate::common::{
+    BoxedReader, ResourceId, ResourceMetadata, ResourceStore, ResourceStoreError, Variant,
+};
+use async_std::path::PathBuf;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Default)]
+struct FaultState {
+    writes: usize,                        // Number of write operations seen so far.
+    failing_writes: HashSet<usize>,       // Write operations that will fail, starting at 1.
+    failing_deletes: HashSet<ResourceId>, // Resources that can't be deleted.
+    read_delay: Option<Duration>,         // Delay added before each read.
+}
+
+/// The set of faults to inject. It can be cloned and kept around to
+/// change the faults once the store is owned by a manager.
+#[derive(Clone, Default)]
+pub struct FaultPlan {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultPlan {
+    /// Makes the nth write operation fail, counting from 1 since the
+    /// store creation. Creating, updating and deleting are write operations.
+    pub fn fail_nth_write(&self, nth: usize) {
+        self.state.lock().failing_writes.insert(nth);
+    }
+
+    /// Makes every deletion of this resource fail.
+    pub fn fail_delete(&self, id: &ResourceId) {
+        self.state.lock().failing_deletes.insert(id.clone());
+    }
+
+    /// Delays all reads by this duration.
+    pub fn slow_reads(&self, delay: Duration) {
+        self.state.lock().read_delay = Some(delay);
+    }
+
+    /// Removes all the scripted faults. The write counter is preserved.
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.failing_writes.clear();
+        state.failing_deletes.clear();
+        state.read_delay = None;
+    }
+
+    /// Returns the number of write operations seen so far.
+    pub fn writes(&self) -> usize {
+        self.state.lock().writes
+    }
+
+    fn check_write(&self) -> Result<(), ResourceStoreError> {
+        let mut state = self.state.lock();
+        state.writes += 1;
+        if state.failing_writes.contains(&state.writes) {
+            return Err(ResourceStoreError::Custom(format!(
+                "Injected fault for write #{}",
+                state.writes
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_delete(&self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        if self.state.lock().failing_deletes.contains(id) {
+            return Err(ResourceStoreError::Custom(format!(
+                "Injected fault for deletion of {id}"
+            )));
+        }
+        self.check_write()
+    }
+
+    async fn delay_read(&self) {
+        let delay = self.state.lock().read_delay;
+        if let Some(delay) = delay {
+            async_std::task::sleep(delay).await;
+        }
+    }
+}
+
+pub struct FaultyStore {
+    inner: Box<dyn ResourceStore + Send + Sync>,
+    plan: FaultPlan,
+}
+
+impl FaultyStore {
+    pub fn new(inner: Box<dyn ResourceStore + Send + Sync>, plan: FaultPlan) -> Self {
+        Self { inner, plan }
+    }
+}
+
+#[async_trait(?Send)]
+impl ResourceStore for FaultyStore {
+    async fn create(
+        &self,
+        metadata: &ResourceMetadata,
+        variant: Option<Variant>,
+    ) -> Result<Option<String>, ResourceStoreError> {
+        self.plan.check_write()?;
+        self.inner.create(metadata, variant).await
+    }
+
+    async fn update(
+        &self,
+        metadata: &ResourceMetadata,
+        variant: Option<Variant>,
+    ) -> Result<Option<String>, ResourceStoreError> {
+        self.plan.check_write()?;
+        self.inner.update(metadata, variant).await
+    }
+
+    async fn update_default_variant_from_slice(
+        &self,
+        id: &ResourceId,
+        content: &[u8],
+    ) -> Result<(), ResourceStoreError> {
+        self.plan.check_write()?;
+        self.inner
+            .update_default_variant_from_slice(id, content)
+            .await
+    }
+
+    async fn append_to_variant(
+        &self,
+        metadata: &ResourceMetadata,
+        variant: &str,
+        content: &[u8],
+    ) -> Result<(), ResourceStoreError> {
+        self.plan.check_write()?;
+        self.inner
+            .append_to_variant(metadata, variant, content)
+            .await
+    }
+
+    async fn delete(&self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        self.plan.check_delete(id)?;
+        self.inner.delete(id).await
+    }
+
+    async fn delete_variant(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<(), ResourceStoreError> {
+        self.plan.check_write()?;
+        self.inner.delete_variant(id, variant).await
+    }
+
+    async fn get_metadata(&self, id: &ResourceId) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.plan.delay_read().await;
+        self.inner.get_metadata(id).await
+    }
+
+    async fn get_variant(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<BoxedReader, ResourceStoreError> {
+        self.plan.delay_read().await;
+        self.inner.get_variant(id, variant).await
+    }
+
+    async fn get_full(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<(ResourceMetadata, BoxedReader), ResourceStoreError> {
+        self.plan.delay_read().await;
+        self.inner.get_full(id, variant).await
+    }
+
+    async fn get_native_path(&self, id: &ResourceId, variant: &str) -> Option<PathBuf> {
+        self.inner.get_native_path(id, variant).await
+    }
+
+    async fn list_ids(&self) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        self.plan.delay_read().await;
+        self.inner.list_ids().await
+    }
+
+    async fn prefetch(&self, hints: &[(ResourceId, String)]) {
+        self.inner.prefetch(hints).await
+    }
+
+    fn storage_names(&self, metadata: &ResourceMetadata) -> Vec<(String, Option<String>)> {
+        self.inner.storage_names(metadata)
+    }
+
+    async fn content_url(&self, id: &ResourceId, variant: &str, ttl: Duration) -> Option<String> {
+        self.inner.content_url(id, variant, ttl).await
+    }
+}
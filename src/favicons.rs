@@ -0,0 +1,80 @@
+/// Favicons for "Places" resources.
+///
+/// This crate doesn't access the network itself: icons are fetched by an
+/// `IconFetcher` provided by the embedder, and stored as the `icon` variant of
+/// places. See `Manager::update_favicon()` and `Manager::refresh_favicons()`.
+use crate::common::ResourceStoreError;
+use async_trait::async_trait;
+use serde_json::Value;
+
+#[async_trait(?Send)]
+pub trait IconFetcher {
+    /// Fetches the icon at this url, returning its mime type and content.
+    async fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), ResourceStoreError>;
+}
+
+/// Returns the urls to try for the icon of a place: the one declared by
+/// the page if any, then the conventional "/favicon.ico" of its origin.
+pub fn icon_candidates(place: &Value) -> Vec<String> {
+    let url = match place.get("url") {
+        Some(Value::String(url)) if !url.is_empty() => url,
+        _ => return vec![],
+    };
+    let scheme = url
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_lowercase())
+        .unwrap_or_else(|| "https".into());
+    // Unlike canonical urls, keep the "www." prefix since the host may need it.
+    let host = url
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(url)
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if host.is_empty() {
+        return vec![];
+    }
+    let origin = format!("{scheme}://{host}");
+
+    let mut candidates = vec![];
+    if let Some(Value::String(icon)) = place.get("icon") {
+        if icon.contains("://") {
+            candidates.push(icon.clone());
+        } else if icon.starts_with('/') {
+            candidates.push(format!("{origin}{icon}"));
+        } else if !icon.is_empty() {
+            candidates.push(format!("{origin}/{icon}"));
+        }
+    }
+    let fallback = format!("{origin}/favicon.ico");
+    if !candidates.contains(&fallback) {
+        candidates.push(fallback);
+    }
+    candidates
+}
+
+#[test]
+fn favicon_candidates() {
+    use serde_json::json;
+
+    assert_eq!(
+        icon_candidates(&json!({ "url": "https://www.example.com/news", "icon": "/icon.png" })),
+        vec![
+            "https://www.example.com/icon.png",
+            "https://www.example.com/favicon.ico"
+        ]
+    );
+    assert_eq!(
+        icon_candidates(
+            &json!({ "url": "http://example.com", "icon": "http://example.com/favicon.ico" })
+        ),
+        vec!["http://example.com/favicon.ico"]
+    );
+    assert_eq!(
+        icon_candidates(&json!({ "url": "https://example.com/a/b", "icon": "" })),
+        vec!["https://example.com/favicon.ico"]
+    );
+    assert!(icon_candidates(&json!({ "title": "No url" })).is_empty());
+}
@@ -1,11 +1,21 @@
 /// A file based storage engine.
-/// Each object is stored in 2 files:
-/// ${object.id}.meta for the metadata serialized as Json.
-/// ${object.id}.content for the opaque content.
+/// Each object is stored in several files:
+/// ${object.id}.meta for the metadata serialized with speedy.
+/// ${object.id}.${variant}.content for the opaque content of each variant.
+/// Both the metadata and the content go through the transformer, so an
+/// encrypting transformer also protects names and tags at rest.
+/// The SHA-256 hash of the content of variants is computed while storing them,
+/// and recorded in the metadata.
+/// The content of variants with a hash is stored in a ${hash}.blob file instead, so
+/// that identical content is only stored once. The number of variants using each blob
+/// is kept in the refcounts file, and blobs are removed when no variant uses them anymore.
+/// Variants get a private copy of their blob before their file can be changed in place,
+/// when appending to them or when their native path is requested.
 use crate::common::{
     BoxedReader, ResourceId, ResourceKind, ResourceMetadata, ResourceNameProvider, ResourceStore,
-    ResourceStoreError, ResourceTransformer, Variant,
+    ResourceStoreError, ResourceTransformer, Variant, ROOT_ID,
 };
+use async_std::sync::Mutex;
 use async_std::{
     fs,
     fs::File,
@@ -16,6 +26,9 @@ use async_std::{
 use async_trait::async_trait;
 use log::error;
 use speedy::{Readable, Writable};
+use std::collections::HashMap;
+
+static REFCOUNTS_NAME: &str = "refcounts";
 
 macro_rules! custom_error {
     ($error:expr) => {
@@ -27,6 +40,8 @@ pub struct FileStore {
     root: PathBuf, // The root path of the storage.
     name_provider: Box<dyn ResourceNameProvider>,
     transformer: Box<dyn ResourceTransformer>,
+    content_base_url: Option<String>, // The url of the http server serving this store, if any.
+    refcounts: Mutex<Option<HashMap<String, u32>>>, // The number of variants using each blob.
 }
 
 impl FileStore {
@@ -49,21 +64,274 @@ impl FileStore {
             root,
             name_provider,
             transformer,
+            content_base_url: None,
+            refcounts: Mutex::new(None),
         })
     }
 
+    /// Sets the base url of the http server giving access to this store, like
+    /// `http://127.0.0.1:$port/cmgr/$access_key`, so that `content_url()` returns urls
+    /// for its resources. These urls remain valid as long as the access key is accepted
+    /// by the server, regardless of the requested ttl.
+    pub fn set_content_base_url(&mut self, base_url: Option<&str>) {
+        self.content_base_url = base_url.map(|url| url.trim_end_matches('/').to_owned());
+    }
+
     pub fn metadata_path(&self, id: &ResourceId) -> PathBuf {
         let mut metadata_path = self.root.clone();
-        metadata_path.push(&self.name_provider.metadata_name(id));
+        metadata_path.push(self.name_provider.metadata_name(id));
         metadata_path
     }
 
     pub fn variant_path(&self, id: &ResourceId, variant: &str) -> PathBuf {
         let mut content_path = self.root.clone();
-        content_path.push(&self.name_provider.variant_name(id, variant));
+        content_path.push(self.name_provider.variant_name(id, variant));
         content_path
     }
 
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        let mut blob_path = self.root.clone();
+        blob_path.push(self.name_provider.blob_name(hash));
+        blob_path
+    }
+
+    fn refcounts_path(&self) -> PathBuf {
+        let mut refcounts_path = self.root.clone();
+        refcounts_path.push(REFCOUNTS_NAME);
+        refcounts_path
+    }
+
+    // Reads the reference counts the first time they are needed.
+    async fn load_refcounts<'a>(
+        &self,
+        refcounts: &'a mut Option<HashMap<String, u32>>,
+    ) -> Result<&'a mut HashMap<String, u32>, ResourceStoreError> {
+        if refcounts.is_none() {
+            let loaded = match fs::read(self.refcounts_path()).await {
+                Ok(buffer) => {
+                    HashMap::read_from_buffer(&self.transformer.transform_array_from(&buffer))?
+                }
+                Err(_) => HashMap::new(),
+            };
+            *refcounts = Some(loaded);
+        }
+        Ok(refcounts.get_or_insert_with(HashMap::new))
+    }
+
+    async fn save_refcounts(
+        &self,
+        refcounts: &HashMap<String, u32>,
+    ) -> Result<(), ResourceStoreError> {
+        let path = self.refcounts_path();
+        let temp = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut file = Self::create_file(&temp).await?;
+        file.write_all(
+            &self
+                .transformer
+                .transform_array_to(&refcounts.write_to_vec()?),
+        )
+        .await?;
+        file.sync_all().await?;
+        fs::rename(&temp, &path).await?;
+        Ok(())
+    }
+
+    // Adds a reference to the blob with this hash, writing it from the content
+    // if no variant uses it yet.
+    async fn acquire_blob(&self, hash: &str, content: Variant) -> Result<(), ResourceStoreError> {
+        let mut guard = self.refcounts.lock().await;
+        let refcounts = self.load_refcounts(&mut guard).await?;
+        let count = refcounts.get(hash).copied().unwrap_or(0);
+        let path = self.blob_path(hash);
+        if count == 0 || !path.exists().await {
+            let mut file = Self::create_file(&path).await?;
+            file.set_len(content.metadata.size() as _).await?;
+            let writer = self.transformer.transform_to(content.reader);
+            futures::io::copy(writer, &mut file).await?;
+            file.sync_all().await?;
+        }
+        refcounts.insert(hash.into(), count + 1);
+        self.save_refcounts(refcounts).await
+    }
+
+    // Removes a reference to the blob with this hash, and the blob itself if no
+    // variant uses it anymore.
+    async fn release_blob(&self, hash: Option<String>) -> Result<(), ResourceStoreError> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        let mut guard = self.refcounts.lock().await;
+        let refcounts = self.load_refcounts(&mut guard).await?;
+        match refcounts.get(&hash).copied() {
+            Some(count) if count > 1 => {
+                refcounts.insert(hash, count - 1);
+            }
+            _ => {
+                refcounts.remove(&hash);
+                let path = self.blob_path(&hash);
+                if path.exists().await {
+                    fs::remove_file(&path).await?;
+                }
+            }
+        }
+        self.save_refcounts(refcounts).await
+    }
+
+    /// Returns the number of variants using the blob with this hash.
+    pub async fn blob_refcount(&self, hash: &str) -> Result<u32, ResourceStoreError> {
+        let mut guard = self.refcounts.lock().await;
+        let refcounts = self.load_refcounts(&mut guard).await?;
+        Ok(refcounts.get(hash).copied().unwrap_or(0))
+    }
+
+    // Returns the path of the file holding the content of a variant: its own file, or
+    // the blob it shares with other variants.
+    async fn content_path(&self, id: &ResourceId, variant: &str) -> Option<PathBuf> {
+        let path = self.variant_path(id, variant);
+        if path.exists().await {
+            return Some(path);
+        }
+        let path = self.blob_path(&self.stored_hash(id, variant).await?);
+        if path.exists().await {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    // Gives a variant a private copy of the blob it shares with other variants, so that its
+    // file can be changed in place, and forgets its hash.
+    async fn unshare_variant(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<(), ResourceStoreError> {
+        let hash = match self.stored_hash(id, variant).await {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+
+        let path = self.variant_path(id, variant);
+        if !path.exists().await {
+            let copy = PathBuf::from(format!("{}.unshare", path.display()));
+            fs::copy(&self.blob_path(&hash), &copy).await?;
+            fs::rename(&copy, &path).await?;
+        }
+
+        // Forget the hash so that the blob reference is only released once.
+        let mut metadata = self.get_metadata(id).await?;
+        let variants = metadata
+            .variants()
+            .iter()
+            .cloned()
+            .map(|mut item| {
+                if item.name() == variant {
+                    item.set_hash(None);
+                }
+                item
+            })
+            .collect();
+        metadata.set_variants(variants);
+        self.write_metadata(&metadata).await?;
+        self.release_blob(Some(hash)).await
+    }
+
+    // Returns the hash of a variant as recorded in the current metadata, if any.
+    async fn stored_hash(&self, id: &ResourceId, variant: &str) -> Option<String> {
+        let metadata = self.get_metadata(id).await.ok()?;
+        variant_hash(&metadata, variant)
+    }
+
+    async fn write_metadata(&self, metadata: &ResourceMetadata) -> Result<(), ResourceStoreError> {
+        let mut file = Self::create_file(&self.metadata_path(&metadata.id())).await?;
+        let meta = self
+            .transformer
+            .transform_array_to(&metadata.write_to_vec()?);
+        file.write_all(&meta).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Renames the files of all the resources reachable from the root, from the naming
+    /// scheme of `previous` to the current one. Each file is moved independently, so
+    /// running this again after an interruption resumes the migration.
+    /// Returns the number of files that were renamed.
+    pub async fn migrate_names(
+        &self,
+        previous: &dyn ResourceNameProvider,
+    ) -> Result<usize, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let mut renamed = 0;
+        let mut pending = vec![ROOT_ID.clone()];
+
+        while let Some(id) = pending.pop() {
+            let mut old_path = self.root.clone();
+            old_path.push(previous.metadata_name(&id));
+            if self
+                .migrate_file(&old_path, &self.metadata_path(&id))
+                .await?
+            {
+                renamed += 1;
+            }
+
+            let metadata = match self.get_metadata(&id).await {
+                Ok(metadata) => metadata,
+                Err(ResourceStoreError::NoSuchResource) => continue,
+                Err(err) => return Err(err),
+            };
+
+            // Blobs are shared, so they may have been moved already with another resource.
+            for hash in metadata.variants().iter().filter_map(|v| v.hash()) {
+                let mut old_path = self.root.clone();
+                old_path.push(previous.blob_name(&hash));
+                if self.migrate_file(&old_path, &self.blob_path(&hash)).await? {
+                    renamed += 1;
+                }
+            }
+
+            let mut variants: Vec<String> = metadata.variants().iter().map(|v| v.name()).collect();
+            if metadata.kind() == ResourceKind::Container {
+                variants.push("default".into());
+            }
+            for variant in variants {
+                let mut old_path = self.root.clone();
+                old_path.push(previous.variant_name(&id, &variant));
+                if self
+                    .migrate_file(&old_path, &self.variant_path(&id, &variant))
+                    .await?
+                {
+                    renamed += 1;
+                }
+            }
+
+            // Containers list their children in their default variant.
+            if metadata.kind() == ResourceKind::Container {
+                if let Ok(mut file) = self.get_variant(&id, "default").await {
+                    let mut buffer = vec![];
+                    file.read_to_end(&mut buffer).await?;
+                    let children = Vec::<ResourceId>::read_from_buffer(&buffer)?;
+                    pending.extend(children.into_iter().filter(|child| !child.is_root()));
+                }
+            }
+        }
+
+        Ok(renamed)
+    }
+
+    // Moves a file to its new path, unless it was already moved.
+    async fn migrate_file(&self, from: &Path, to: &Path) -> Result<bool, ResourceStoreError> {
+        if from == to || !from.exists().await || to.exists().await {
+            return Ok(false);
+        }
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(from, to).await?;
+        Ok(true)
+    }
+
     /// Creates a file and set permission to rw for the owner only.
     async fn create_file<P: AsRef<Path>>(path: P) -> Result<File, ResourceStoreError> {
         use std::os::unix::fs::PermissionsExt;
@@ -80,7 +348,7 @@ impl FileStore {
         metadata: &ResourceMetadata,
         content: Option<Variant>,
         create: bool,
-    ) -> Result<(), ResourceStoreError> {
+    ) -> Result<Option<String>, ResourceStoreError> {
         // 0. TODO: check if we have enough storage available.
 
         let id = metadata.id();
@@ -95,43 +363,116 @@ impl FileStore {
             }
         }
 
-        // 2. Store the metadata.
-        let mut file = Self::create_file(&meta_path).await?;
-        let meta = self
-            .transformer
-            .transform_array_to(&metadata.write_to_vec()?);
-        file.write_all(&meta).await?;
-        file.sync_all().await?;
+        // The hash of the content being replaced, to release its blob once done.
+        let stored = if create {
+            None
+        } else {
+            self.get_metadata(&id).await.ok()
+        };
+        let previous_hash = match (&content, &stored) {
+            (Some(content), Some(stored)) => variant_hash(stored, &content.metadata.name()),
+            _ => None,
+        };
+
+        // 2. Hash the content of leaf nodes, and of the variants of containers other than
+        // their list of children. Hashes are not taken from the caller since they decide
+        // which content gets shared.
+        let mut metadata = with_stored_hashes(metadata, stored.as_ref());
+        let content = match content {
+            Some(mut content)
+                if metadata.kind() == ResourceKind::Leaf
+                    || content.metadata.name() != "default" =>
+            {
+                let name = content.metadata.name();
+                if !metadata.has_variant(&name) {
+                    error!("Variant '{}' is not in metadata.", name);
+                    return Err(ResourceStoreError::InvalidVariant(name));
+                }
+                let hash = content.update_hash().await?;
+                let variants = metadata
+                    .variants()
+                    .iter()
+                    .cloned()
+                    .map(|mut variant| {
+                        if variant.name() == name {
+                            variant.set_hash(Some(&hash));
+                        }
+                        variant
+                    })
+                    .collect();
+                metadata.set_variants(variants);
+                Some(content)
+            }
+            _ => None,
+        };
 
-        // 3. Store the variants for leaf nodes.
-        if metadata.kind() != ResourceKind::Leaf {
-            return Ok(());
-        }
+        // 3. Store the metadata.
+        self.write_metadata(&metadata).await?;
 
+        // 4. Store the variant.
+        let mut hash = None;
         if let Some(content) = content {
+            hash = content.metadata.hash();
             let name = content.metadata.name();
-            if !metadata.has_variant(&name) {
-                error!("Variant '{}' is not in metadata.", name);
-                return Err(ResourceStoreError::InvalidVariant(name));
+            let variant_path = self.variant_path(&id, &name);
+            match content.metadata.hash() {
+                Some(hash) => {
+                    // The content lives in the blob only, shared with other variants.
+                    self.acquire_blob(&hash, content).await?;
+                    if variant_path.exists().await {
+                        fs::remove_file(&variant_path).await?;
+                    }
+                }
+                None => {
+                    let mut file = Self::create_file(&variant_path).await?;
+                    file.set_len(content.metadata.size() as _).await?;
+                    let writer = self.transformer.transform_to(content.reader);
+                    futures::io::copy(writer, &mut file).await?;
+                    file.sync_all().await?;
+                }
             }
-            let mut file = Self::create_file(&self.variant_path(&id, &name)).await?;
-            file.set_len(content.metadata.size() as _).await?;
-            let writer = self.transformer.transform_to(content.reader);
-            futures::io::copy(writer, &mut file).await?;
-            file.sync_all().await?;
+            self.release_blob(previous_hash).await?;
         }
 
-        Ok(())
+        Ok(hash)
     }
 }
 
+fn variant_hash(metadata: &ResourceMetadata, variant: &str) -> Option<String> {
+    metadata
+        .variants()
+        .iter()
+        .find(|v| v.name() == variant)
+        .and_then(|v| v.hash())
+}
+
+// Returns the metadata with the variant hashes recorded by the store, if any.
+fn with_stored_hashes(
+    metadata: &ResourceMetadata,
+    stored: Option<&ResourceMetadata>,
+) -> ResourceMetadata {
+    let mut metadata = metadata.clone();
+    let variants = metadata
+        .variants()
+        .iter()
+        .cloned()
+        .map(|mut variant| {
+            let hash = stored.and_then(|stored| variant_hash(stored, &variant.name()));
+            variant.set_hash(hash.as_deref());
+            variant
+        })
+        .collect();
+    metadata.set_variants(variants);
+    metadata
+}
+
 #[async_trait(?Send)]
 impl ResourceStore for FileStore {
     async fn create(
         &self,
         metadata: &ResourceMetadata,
         content: Option<Variant>,
-    ) -> Result<(), ResourceStoreError> {
+    ) -> Result<Option<String>, ResourceStoreError> {
         self.create_or_update(metadata, content, true).await
     }
 
@@ -139,7 +480,7 @@ impl ResourceStore for FileStore {
         &self,
         metadata: &ResourceMetadata,
         content: Option<Variant>,
-    ) -> Result<(), ResourceStoreError> {
+    ) -> Result<Option<String>, ResourceStoreError> {
         self.create_or_update(metadata, content, false).await
     }
 
@@ -160,6 +501,56 @@ impl ResourceStore for FileStore {
         Ok(())
     }
 
+    /// Appends to the variant file directly instead of rewriting it. The transformer
+    /// is applied to the appended content only, so it must not depend on the
+    /// position of the data in the file.
+    async fn append_to_variant(
+        &self,
+        metadata: &ResourceMetadata,
+        variant: &str,
+        content: &[u8],
+    ) -> Result<(), ResourceStoreError> {
+        use async_std::fs::OpenOptions;
+        use std::os::unix::fs::PermissionsExt;
+
+        if !metadata.has_variant(variant) {
+            error!("Variant '{}' is not in metadata.", variant);
+            return Err(ResourceStoreError::InvalidVariant(variant.into()));
+        }
+
+        // Make a private copy of content shared with other variants before changing it.
+        self.unshare_variant(&metadata.id(), variant).await?;
+        let path = self.variant_path(&metadata.id(), variant);
+        let stored = self.get_metadata(&metadata.id()).await.ok();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.set_permissions(async_std::fs::Permissions::from_mode(0o600))
+            .await?;
+        file.write_all(&self.transformer.transform_array_to(content))
+            .await?;
+        file.sync_all().await?;
+
+        // Store the updated metadata. The hash of the full content is not known anymore.
+        let mut metadata = with_stored_hashes(metadata, stored.as_ref());
+        let variants = metadata
+            .variants()
+            .iter()
+            .cloned()
+            .map(|mut item| {
+                if item.name() == variant {
+                    item.set_hash(None);
+                }
+                item
+            })
+            .collect();
+        metadata.set_variants(variants);
+        self.write_metadata(&metadata).await
+    }
+
     async fn delete(&self, id: &ResourceId) -> Result<(), ResourceStoreError> {
         // 1. get the metadata in order to know all the possible variants.
         let metadata = self.get_metadata(id).await?;
@@ -174,6 +565,7 @@ impl ResourceStore for FileStore {
             if Path::new(&path).exists().await {
                 fs::remove_file(&path).await?;
             }
+            self.release_blob(variant.hash()).await?;
         }
         Ok(())
     }
@@ -187,7 +579,26 @@ impl ResourceStore for FileStore {
         if Path::new(&path).exists().await {
             fs::remove_file(&path).await?;
         }
-        Ok(())
+
+        // Forget the hash so that the blob reference is only released once.
+        let hash = self.stored_hash(id, variant).await;
+        if hash.is_some() {
+            let mut metadata = self.get_metadata(id).await?;
+            let variants = metadata
+                .variants()
+                .iter()
+                .cloned()
+                .map(|mut item| {
+                    if item.name() == variant {
+                        item.set_hash(None);
+                    }
+                    item
+                })
+                .collect();
+            metadata.set_variants(variants);
+            self.write_metadata(&metadata).await?;
+        }
+        self.release_blob(hash).await
     }
 
     async fn get_metadata(&self, id: &ResourceId) -> Result<ResourceMetadata, ResourceStoreError> {
@@ -223,7 +634,10 @@ impl ResourceStore for FileStore {
         let metadata: ResourceMetadata =
             ResourceMetadata::read_from_buffer(&self.transformer.transform_array_from(&buffer))?;
 
-        let content_path = self.variant_path(id, name);
+        let content_path = self
+            .content_path(id, name)
+            .await
+            .ok_or(ResourceStoreError::NoSuchResource)?;
         let file = File::open(&content_path)
             .await
             .map_err(|_| ResourceStoreError::NoSuchResource)?;
@@ -240,7 +654,10 @@ impl ResourceStore for FileStore {
         id: &ResourceId,
         name: &str,
     ) -> Result<BoxedReader, ResourceStoreError> {
-        let content_path = self.variant_path(id, name);
+        let content_path = self
+            .content_path(id, name)
+            .await
+            .ok_or(ResourceStoreError::NoSuchResource)?;
 
         let file = File::open(&content_path)
             .await
@@ -252,11 +669,38 @@ impl ResourceStore for FileStore {
     }
 
     async fn get_native_path(&self, id: &ResourceId, variant: &str) -> Option<PathBuf> {
-        let path = self.variant_path(id, variant);
-        if path.exists().await {
-            Some(path)
-        } else {
-            None
+        // Callers can change the file, which must not change the variants sharing its blob.
+        if let Err(err) = self.unshare_variant(id, variant).await {
+            error!("Failed to unshare variant '{}' of {}: {}", variant, id, err);
+            return None;
+        }
+        self.content_path(id, variant).await
+    }
+
+    async fn content_url(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+        _ttl: std::time::Duration,
+    ) -> Option<String> {
+        let base_url = self.content_base_url.as_ref()?;
+        self.content_path(id, variant).await?;
+        Some(format!("{base_url}/{id}/{variant}"))
+    }
+
+    fn storage_names(&self, metadata: &ResourceMetadata) -> Vec<(String, Option<String>)> {
+        let id = metadata.id();
+        let mut names = vec![(self.name_provider.metadata_name(&id), None)];
+        for variant in metadata.variants() {
+            let name = variant.name();
+            names.push((
+                self.name_provider.variant_name(&id, &name),
+                Some(name.clone()),
+            ));
+            if let Some(hash) = variant.hash() {
+                names.push((self.name_provider.blob_name(&hash), Some(name)));
+            }
         }
+        names
     }
 }
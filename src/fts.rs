@@ -1,24 +1,348 @@
-/// A naive implementation of Full Text Search.
-/// The goal is to provide substring matching to object names and tags.
+/// Full Text Search, providing substring matching to object names, tags and content.
 ///
-/// Using a simple SQlite table (ResourceId, ngram) which makes it easy to
-/// manage object removal at the expense of disk space usage and query performance.
-/// TODO: switch to a Key Value store (eg. Sled) instead, or a fts engine like Sonic.
-use crate::common::{IdFrec, ResourceId, ResourceStoreError, TransactionResult};
+/// The text is stored in SQLite FTS5 tables (ResourceId, variant, content) which makes
+/// it easy to manage object removal. Substrings are matched with `LIKE` on the trigram
+/// table, which uses its index for terms of at least 3 characters, while the word index
+/// of the fts table backs the vocabulary queries.
+/// Chinese, Japanese and Korean don't separate words with spaces: their text is split in
+/// overlapping bigrams in the word index and in the search terms.
+/// Stop words are left out of both indexes. When stemming is enabled, search terms also
+/// match the text containing their stem, so that "running" finds "run".
+use crate::common::{
+    IdFrec, Page, Paged, ResourceId, ResourceStoreError, TransactionResult, Visibility,
+};
 use crate::timer::Timer;
-use sqlx::{Sqlite, SqlitePool, Transaction};
+use rust_stemmers::Stemmer;
+use sqlx::query::QueryAs;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::collections::HashSet;
+use std::ops::Range;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 
+pub use rust_stemmers::Algorithm as StemmingLanguage;
+
+/// Sql condition restricting `resources.id` to the descendants of a container,
+/// or not restricting it at all if the container parameter is NULL.
+/// The container id parameter needs to be bound twice.
+pub(crate) const WITHIN_SUBTREE: &str = r#"(? IS NULL OR resources.id IN (
+    WITH RECURSIVE subtree(id) AS (
+        SELECT id FROM resources AS r WHERE r.parent = ? AND r.id != r.parent
+        UNION ALL
+        SELECT r.id FROM resources AS r JOIN subtree ON r.parent = subtree.id
+    )
+    SELECT id FROM subtree))"#;
+
+/// Sql condition matching resources with a tag, either directly or through
+/// one of their ancestors when tags are inherited. The tag parameter needs to be bound once.
+pub(crate) const HAS_TAG: &str =
+    "EXISTS (SELECT 1 FROM tags WHERE tags.id = resources.id AND tags.tag = ?)";
+pub(crate) const HAS_INHERITED_TAG: &str = r#"resources.id IN (
+    WITH RECURSIVE tagged(id) AS (
+        SELECT id FROM tags WHERE tag = ?
+        UNION
+        SELECT r.id FROM resources AS r JOIN tagged ON r.parent = tagged.id WHERE r.id != r.parent
+    )
+    SELECT id FROM tagged)"#;
+
+/// Sql condition selecting the resources included by a `Visibility`, depending on whether
+/// their name starts with a dot. The visibility needs to be bound once.
+pub(crate) const VISIBLE: &str = "(CASE ? WHEN 0 THEN substr(resources.name, 1, 1) != '.'
+    WHEN 1 THEN substr(resources.name, 1, 1) = '.' ELSE 1 END)";
+
+/// Resources with this tag, and all the descendants of containers with it, are left
+/// out of text searches, indexing and transformer processing.
+pub const NO_INDEX_TAG: &str = "no-index";
+
+/// Sql condition excluding resources opted out of indexing with `NO_INDEX_TAG`,
+/// directly or through one of their ancestors.
+pub(crate) const INDEXABLE: &str = r#"resources.id NOT IN (
+    WITH RECURSIVE excluded(id) AS (
+        SELECT id FROM tags WHERE tag = 'no-index'
+        UNION
+        SELECT r.id FROM resources AS r JOIN excluded ON r.parent = excluded.id WHERE r.id != r.parent
+    )
+    SELECT id FROM excluded)"#;
+
+/// Weights of the fields matching a search, used to rank results before
+/// their frecency, unless frecency is blended in with `Fts::set_frecency_weight()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldBoosts {
+    pub name: u32,      // The resource name.
+    pub tags: u32,      // Any of the resource tags.
+    pub content: u32,   // Text extracted from variants by the indexers.
+    pub ancestors: u32, // Names of the ancestor containers, when they are indexed.
+}
+
+impl Default for FieldBoosts {
+    fn default() -> Self {
+        Self {
+            name: 3,
+            tags: 4,
+            content: 2,
+            ancestors: 1,
+        }
+    }
+}
+
+/// A parsed text query.
+/// Words separated by spaces are matched independently, while "quoted phrases"
+/// need to be found as is in the indexed text.
+/// Words and phrases prefixed by `-` exclude the resources they match, and `-tag:name`
+/// excludes resources with this tag.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Query {
+    terms: Vec<String>,         // Words and phrases, all required to match.
+    stems: Vec<Option<String>>, // The stems of the unquoted words, also matching the text.
+    excluded: Vec<String>,      // Words and phrases that must not match.
+    excluded_tags: Vec<String>, // Tags the resources must not have.
+}
+
+impl Query {
+    #[cfg(test)]
+    fn parse(text: &str) -> Self {
+        Self::parse_with(text, &HashSet::new(), None)
+    }
+
+    // Stop words are left out, unless the query only has stop words. Unquoted words are
+    // stemmed when there is a stemmer.
+    fn parse_with(text: &str, stop_words: &HashSet<String>, stemmer: Option<&Stemmer>) -> Self {
+        let mut query = Query::default();
+        let mut stopped = vec![];
+        let mut chars = text.chars().peekable();
+
+        while let Some(mut c) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+
+            let negative = c == '-';
+            if negative {
+                match chars.next_if(|c| !c.is_whitespace()) {
+                    Some(next) => c = next,
+                    None => continue,
+                }
+            }
+
+            let mut term = String::new();
+            let quoted = c == '"';
+            if quoted {
+                // Read until the closing quote, or the end of the text.
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    term.push(c);
+                }
+            } else {
+                term.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    term.push(c);
+                }
+            }
+
+            if negative && !quoted {
+                if let Some(tag) = term.strip_prefix("tag:") {
+                    if !tag.is_empty() {
+                        query.excluded_tags.push(tag.to_owned());
+                    }
+                    continue;
+                }
+            }
+
+            let term = normalize(term.trim());
+            if term.is_empty() {
+                continue;
+            }
+            // Phrases are matched without their stop words, like the indexed text.
+            let indexed = without_stop_words(&term, stop_words).trim().to_owned();
+            if negative {
+                query
+                    .excluded
+                    .push(if indexed.is_empty() { term } else { indexed });
+            } else if quoted {
+                if indexed.is_empty() {
+                    stopped.push(term);
+                } else {
+                    query.terms.push(indexed);
+                    query.stems.push(None);
+                }
+            } else {
+                let (stop, terms): (Vec<_>, Vec<_>) = tokenize(&term)
+                    .into_iter()
+                    .partition(|term| stop_words.contains(term));
+                for term in terms {
+                    query
+                        .stems
+                        .push(stemmer.and_then(|stemmer| stem(stemmer, &term)));
+                    query.terms.push(term);
+                }
+                stopped.extend(stop);
+            }
+        }
+        if query.terms.is_empty() {
+            query.stems = vec![None; stopped.len()];
+            query.terms = stopped;
+        }
+
+        query
+    }
+
+    // Returns the `LIKE` patterns matching each term in the indexed text: the term
+    // itself, and its stem if it has one.
+    fn text_patterns(&self) -> Vec<Vec<String>> {
+        self.terms
+            .iter()
+            .zip(&self.stems)
+            .map(|(term, stem)| {
+                std::iter::once(term)
+                    .chain(stem)
+                    .map(|term| format!("%{term}%"))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Search results in a given container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerMatches {
+    pub container: ResourceId,
+    pub count: u32,       // The total number of matches in this container.
+    pub top: Vec<IdFrec>, // The best matches.
+}
+
+/// The size of the text index, see `Fts::stats()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FtsStats {
+    pub rows: u32,              // The indexed text entries.
+    pub distinct_words: u32,    // The distinct words of the word index.
+    pub distinct_trigrams: u32, // The distinct trigrams of the substring index.
+    pub bytes: u64,             // An estimation of the space used by the text and the indexes.
+}
+
+/// The fields of a resource that can match a text search.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchedField {
+    Name,
+    Tag(String),
+    Content(String), // The variant the text was extracted from.
+    Ancestors,       // The names of the ancestor containers.
+}
+
+/// Where the terms of a text search are found in a field, see `Fts::highlights()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Highlight {
+    pub field: MatchedField,
+    pub snippet: String, // The text of the field, shortened around the first match.
+    pub ranges: Vec<Range<usize>>, // Offsets of the matched terms in the snippet, in characters.
+}
+
+/// A text search result, with the parts of the resource that matched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchHit {
+    pub id: ResourceId,
+    pub frecency: u32,
+    pub highlights: Vec<Highlight>,
+}
+
+/// A text search result standing for all the matching resources with the same
+/// content, see `Fts::search_collapsed()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CollapsedMatch {
+    pub id: ResourceId,
+    pub frecency: u32,
+    pub duplicates: u32, // The number of other matching resources with the same content.
+}
+
+const SNIPPET_LENGTH: usize = 120; // The maximum length of snippets, in characters.
+const SNIPPET_CONTEXT: usize = 30; // The number of characters kept before the first match.
+
+#[derive(Clone)]
 pub struct Fts {
     db_pool: SqlitePool,
+    boosts: FieldBoosts,
+    inherit_tags: bool, // Whether the tags of containers apply to their descendants.
+    visibility: Visibility, // Whether hidden resources are part of the results.
+    fuzzy_threshold: Option<u32>, // The minimum similarity of fuzzy matches, in percent.
+    frecency_weight: u32, // The weight of 100 points of frecency, when ranking results.
+    stop_words: HashSet<String>, // Normalized words left out of the indexes and queries.
+    stemming: Option<StemmingLanguage>, // The language used to stem the search terms.
+}
+
+/// A word from the indexed content, with its number of occurrences.
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, Eq)]
+pub struct WordFrequency {
+    pub word: String,
+    pub frequency: u32,
 }
 
 impl Fts {
     pub fn new(pool: &SqlitePool) -> Self {
         Self {
             db_pool: pool.clone(),
+            boosts: FieldBoosts::default(),
+            inherit_tags: false,
+            visibility: Visibility::Visible,
+            fuzzy_threshold: None,
+            frecency_weight: 0,
+            stop_words: HashSet::new(),
+            stemming: None,
         }
     }
 
+    pub fn set_boosts(&mut self, boosts: FieldBoosts) {
+        self.boosts = boosts;
+    }
+
+    pub fn set_inherit_tags(&mut self, enabled: bool) {
+        self.inherit_tags = enabled;
+    }
+
+    pub fn set_visibility(&mut self, visibility: Visibility) {
+        self.visibility = visibility;
+    }
+
+    /// Enables typo tolerant matching when set: resources whose text contains at least
+    /// `threshold` (between 0 and 1) of the trigrams of each term also match, ranked after
+    /// the exact matches. For instance "contat" matches "contact" with a threshold of 0.75.
+    pub fn set_fuzzy_threshold(&mut self, threshold: Option<f32>) {
+        self.fuzzy_threshold =
+            threshold.map(|threshold| (threshold.clamp(0.0, 1.0) * 100.0).round() as u32);
+    }
+
+    /// Blends frecency into the ranking of text search results: each 100 points of
+    /// frecency add `weight` to the weight of the best matching field, so that frequently
+    /// used resources can rank above better matching ones. With the default weight of 0,
+    /// frecency only breaks ties. Fuzzy matches always rank after the exact ones.
+    pub fn set_frecency_weight(&mut self, weight: u32) {
+        self.frecency_weight = weight;
+    }
+
+    /// Sets the words that are too common to be useful in searches, like "the" or "of".
+    /// They are left out of the indexed text, and of queries having other terms. Text
+    /// that is already indexed keeps them until it is reindexed.
+    pub fn set_stop_words(&mut self, words: &[String]) {
+        self.stop_words = words
+            .iter()
+            .map(|word| normalize(word.trim()))
+            .filter(|word| !word.is_empty())
+            .collect();
+    }
+
+    /// Enables stemming of the unquoted search terms in this language: they also match
+    /// the text containing their stem, eg. "running" matches "run" and "happy" matches
+    /// "happiness". The indexed text is not stemmed, so that substrings still match.
+    pub fn set_stemming(&mut self, language: Option<StemmingLanguage>) {
+        self.stemming = language;
+    }
+
+    // Parses a text query with the stop words and the stemming of this index.
+    fn parse_query(&self, text: &str) -> Query {
+        let stemmer = self.stemming.map(Stemmer::create);
+        Query::parse_with(text, &self.stop_words, stemmer.as_ref())
+    }
+
     pub async fn remove_text<'c>(
         &self,
         id: &ResourceId,
@@ -29,10 +353,20 @@ impl Fts {
             sqlx::query!("DELETE FROM fts WHERE id = ? and variant = ?", id, v)
                 .execute(&mut *tx)
                 .await?;
+            sqlx::query!(
+                "DELETE FROM fts_trigrams WHERE id = ? and variant = ?",
+                id,
+                v
+            )
+            .execute(&mut *tx)
+            .await?;
         } else {
             sqlx::query!("DELETE FROM fts WHERE id = ?", id)
                 .execute(&mut *tx)
                 .await?;
+            sqlx::query!("DELETE FROM fts_trigrams WHERE id = ?", id)
+                .execute(&mut *tx)
+                .await?;
         }
 
         Ok(tx)
@@ -46,12 +380,23 @@ impl Fts {
         mut tx: Transaction<'c, Sqlite>,
     ) -> TransactionResult<'c> {
         // Remove diacritics since the trigram tokenizer of SQlite doesn't have this option.
-        let content = secular::lower_lay_string(text);
+        let content = normalize(text);
 
+        // The word index gets CJK text split in bigrams, the trigram index keeps it as is.
+        let content = without_stop_words(&content, &self.stop_words);
+        let words = segment(&content);
         sqlx::query!(
             "INSERT INTO fts ( id, variant, content ) VALUES ( ?, ?, ? )",
             id,
             variant,
+            words
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO fts_trigrams ( id, variant, content ) VALUES ( ?, ?, ? )",
+            id,
+            variant,
             content
         )
         .execute(&mut *tx)
@@ -60,52 +405,957 @@ impl Fts {
         Ok(tx)
     }
 
+    /// Removes the text of resources that don't exist anymore, eg. left over by an
+    /// interrupted deletion. Returns the number of removed entries.
+    pub async fn remove_orphans(&self) -> Result<u64, ResourceStoreError> {
+        let mut tx = self.db_pool.begin().await?;
+        let removed = sqlx::query!("DELETE FROM fts WHERE id NOT IN (SELECT id FROM resources)")
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query!("DELETE FROM fts_trigrams WHERE id NOT IN (SELECT id FROM resources)")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+
+    /// Returns the size of the text index.
+    pub async fn stats(&self) -> Result<FtsStats, ResourceStoreError> {
+        let mut tx = self.db_pool.begin().await?;
+        let rows = sqlx::query_scalar!(r#"SELECT count(*) AS "count: u32" FROM fts"#)
+            .fetch_one(&mut *tx)
+            .await?;
+        let distinct_words =
+            sqlx::query_scalar!(r#"SELECT count(*) AS "count: u32" FROM fts_vocab"#)
+                .fetch_one(&mut *tx)
+                .await?;
+        let distinct_trigrams =
+            sqlx::query_scalar!(r#"SELECT count(*) AS "count: u32" FROM fts_trigrams_vocab"#)
+                .fetch_one(&mut *tx)
+                .await?;
+        // The text is stored once per table, next to the index segments.
+        let bytes: i64 = sqlx::query_scalar(
+            r#"SELECT
+            (SELECT coalesce(sum(length(c0) + length(c1) + length(c2)), 0) FROM fts_content)
+            + (SELECT coalesce(sum(length(c0) + length(c1) + length(c2)), 0) FROM fts_trigrams_content)
+            + (SELECT coalesce(sum(length(block)), 0) FROM fts_data)
+            + (SELECT coalesce(sum(length(block)), 0) FROM fts_trigrams_data)"#,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(FtsStats {
+            rows,
+            distinct_words,
+            distinct_trigrams,
+            bytes: bytes as _,
+        })
+    }
+
+    /// Removes the duplicated text entries of resources, and merges the index segments
+    /// accumulated by the updates. Returns the number of removed entries.
+    pub async fn compact(&self) -> Result<u64, ResourceStoreError> {
+        let mut tx = self.db_pool.begin().await?;
+        let removed = sqlx::query(
+            r#"DELETE FROM fts WHERE rowid NOT IN
+            (SELECT min(rowid) FROM fts GROUP BY id, variant, content)"#,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        sqlx::query(
+            r#"DELETE FROM fts_trigrams WHERE rowid NOT IN
+            (SELECT min(rowid) FROM fts_trigrams GROUP BY id, variant, content)"#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("INSERT INTO fts ( fts ) VALUES ( 'optimize' )")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO fts_trigrams ( fts_trigrams ) VALUES ( 'optimize' )")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+
     pub async fn search(
         &self,
         text: &str,
         tag: Option<String>,
-    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
-        let _timer = Timer::start(&format!("Fts::search {text} {tag:?}"));
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<IdFrec>, ResourceStoreError> {
+        let mut tx = self.db_pool.begin().await?;
+        self.search_in(&mut tx, text, tag, within, page).await
+    }
+
+    /// Searches like `search`, using this connection. Runs against the snapshot of the
+    /// database seen by the connection when it is in a transaction.
+    pub(crate) async fn search_in(
+        &self,
+        conn: &mut SqliteConnection,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<IdFrec>, ResourceStoreError> {
+        let _timer = Timer::start(&format!("Fts::search {text} {tag:?} {page:?}"));
+
+        let query = self.parse_query(text);
+        if query.terms.is_empty() {
+            return Ok(Paged {
+                items: vec![],
+                total: 0,
+            });
+        }
+
+        let matching = self.matching_sql(&query);
+
+        let ranking = self.ranking_sql();
+        let sql = format!("SELECT * FROM ({matching}) ORDER BY {ranking} LIMIT ? OFFSET ?");
+        let items: Vec<IdFrec> = self
+            .bind_search(sqlx::query_as(&sql), &query, &tag, within)
+            .bind(page.limit)
+            .bind(page.offset)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        // A partial page is the last one, so the total is known without matching again.
+        let len = items.len() as u32;
+        if len < page.limit && (len > 0 || page.offset == 0) {
+            return Ok(Paged {
+                items,
+                total: page.offset.saturating_add(len),
+            });
+        }
+
+        let sql = format!("SELECT count(*) FROM ({matching})");
+        let (total,): (u32,) = self
+            .bind_search(sqlx::query_as(&sql), &query, &tag, within)
+            .fetch_one(&mut *conn)
+            .await?;
+
+        Ok(Paged { items, total })
+    }
+
+    /// Searches like `search`, collapsing the results that point at identical content:
+    /// resources whose default variants have the same hash are represented by the most
+    /// frecent one, along with the number of duplicates.
+    pub async fn search_collapsed(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<CollapsedMatch>, ResourceStoreError> {
+        let _timer = Timer::start(&format!("Fts::search_collapsed {text} {tag:?} {page:?}"));
+
+        let query = self.parse_query(text);
+        if query.terms.is_empty() {
+            return Ok(Paged {
+                items: vec![],
+                total: 0,
+            });
+        }
+
+        // Resources without a content hash are only identical to themselves.
+        let matching = self.matching_sql(&query);
+        let content = "coalesce(variants.hash, matching.id)";
+        let keyed = format!(
+            r#"WITH matching AS ({matching})
+            SELECT matching.*, {content} AS content FROM matching
+            LEFT JOIN variants ON variants.id = matching.id AND variants.name = 'default'"#
+        );
+
+        let ranking = self.ranking_sql();
+        let sql = format!(
+            r#"WITH ranked AS (
+                SELECT id, frecency, weight,
+                row_number() OVER (PARTITION BY content ORDER BY frecency DESC, weight DESC) AS rank,
+                count(*) OVER (PARTITION BY content) AS copies
+                FROM ({keyed})
+            )
+            SELECT id, frecency, copies - 1 FROM ranked WHERE rank = 1
+            ORDER BY {ranking} LIMIT ? OFFSET ?"#
+        );
+        let rows: Vec<(ResourceId, u32, u32)> = self
+            .bind_search(sqlx::query_as(&sql), &query, &tag, within)
+            .bind(page.limit)
+            .bind(page.offset)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let sql = format!("SELECT count(DISTINCT content) FROM ({keyed})");
+        let (total,): (u32,) = self
+            .bind_search(sqlx::query_as(&sql), &query, &tag, within)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        let items = rows
+            .into_iter()
+            .map(|(id, frecency, duplicates)| CollapsedMatch {
+                id,
+                frecency,
+                duplicates,
+            })
+            .collect();
+        Ok(Paged { items, total })
+    }
+
+    /// Returns a page of the resources with this tag, optionally restricted
+    /// to the descendants of the `within` container, using this connection.
+    pub(crate) async fn by_tag_in(
+        &self,
+        conn: &mut SqliteConnection,
+        tag: &str,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<ResourceId>, ResourceStoreError> {
+        let within = within.map(|id| id.to_string());
+        let has_tag = if self.inherit_tags {
+            HAS_INHERITED_TAG
+        } else {
+            HAS_TAG
+        };
+        let items: Vec<ResourceId> = sqlx::query_as(&format!(
+            r#"SELECT resources.id FROM resources
+            WHERE {has_tag} AND {WITHIN_SUBTREE} AND {VISIBLE}
+            ORDER BY frecency(resources.scorer) DESC LIMIT ? OFFSET ?"#
+        ))
+        .bind(tag)
+        .bind(within.clone())
+        .bind(within.clone())
+        .bind(self.visibility)
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let total = sqlx::query_scalar(&format!(
+            r#"SELECT count(*) FROM resources
+            WHERE {has_tag} AND {WITHIN_SUBTREE} AND {VISIBLE}"#
+        ))
+        .bind(tag)
+        .bind(within.clone())
+        .bind(within)
+        .bind(self.visibility)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(Paged { items, total })
+    }
+
+    /// Searches like `search`, grouping the results by parent container.
+    /// Containers are sorted by number of matches, and each one holds
+    /// up to `top` of its best matches.
+    pub async fn search_grouped(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        top: u32,
+    ) -> Result<Vec<ContainerMatches>, ResourceStoreError> {
+        let _timer = Timer::start(&format!("Fts::search_grouped {text} {tag:?} {top}"));
+
+        let query = self.parse_query(text);
+        if query.terms.is_empty() {
+            return Ok(vec![]);
+        }
 
+        let matching = self.matching_sql(&query);
+        let ranking = self.ranking_sql();
+        let sql = format!(
+            r#"WITH matching AS ({matching}),
+            ranked AS (
+                SELECT id, parent, frecency,
+                row_number() OVER (PARTITION BY parent ORDER BY {ranking}) AS rank,
+                count(*) OVER (PARTITION BY parent) AS total
+                FROM matching
+            )
+            SELECT parent, total, id, frecency FROM ranked WHERE rank <= ?
+            ORDER BY total DESC, parent, rank"#
+        );
+
+        let rows: Vec<(ResourceId, u32, ResourceId, u32)> = self
+            .bind_search(sqlx::query_as(&sql), &query, &tag, within)
+            .bind(top)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let mut results: Vec<ContainerMatches> = vec![];
+        for (container, count, id, frecency) in rows {
+            let item = IdFrec { id, frecency };
+            match results.last_mut() {
+                Some(last) if last.container == container => last.top.push(item),
+                _ => results.push(ContainerMatches {
+                    container,
+                    count,
+                    top: vec![item],
+                }),
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Returns up to `limit` keywords characterizing a resource, ranked by TF-IDF:
+    /// words frequent in this resource but rare in the rest of the index come first.
+    /// Words in `exclude` are never returned.
+    pub async fn keywords(
+        &self,
+        id: &ResourceId,
+        limit: usize,
+        exclude: &[String],
+    ) -> Result<Vec<String>, ResourceStoreError> {
         let mut tx = self.db_pool.begin().await?;
 
-        let search = format!("%{}%", secular::lower_lay_string(text));
+        let (documents,): (u32,) = sqlx::query_as("SELECT count(*) FROM fts")
+            .fetch_one(&mut *tx)
+            .await?;
+
+        // Ancestor names describe the containers rather than this resource.
+        let terms: Vec<(String, u32, u32)> = sqlx::query_as(
+            r#"SELECT fts_instances.term, count(*) AS frequency, fts_vocab.doc
+            FROM fts_instances JOIN fts_vocab ON fts_vocab.term = fts_instances.term
+            WHERE fts_instances.doc IN
+                (SELECT rowid FROM fts WHERE id = ? AND variant != '<ancestors>')
+            GROUP BY fts_instances.term"#,
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut scored: Vec<(f64, String)> = terms
+            .into_iter()
+            .filter(|(term, _, _)| {
+                term.chars().count() > 2
+                    && !term.chars().all(|c| c.is_numeric())
+                    && !exclude.contains(term)
+            })
+            .map(|(term, frequency, in_documents)| {
+                let idf = (documents as f64 / in_documents.max(1) as f64).ln();
+                (frequency as f64 * idf, term)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        Ok(scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, term)| term)
+            .collect())
+    }
+
+    // Returns the sql ordering of the rows selected by `matching_sql`.
+    fn ranking_sql(&self) -> String {
+        format!(
+            "weight < 0, weight + frecency * {} / 100.0 DESC, frecency DESC",
+            self.frecency_weight
+        )
+    }
 
-        let records: Vec<IdFrec> =
-            match tag {
-                None => sqlx::query_as(
-                    r#"SELECT resources.id, frecency(resources.scorer) AS frecency FROM resources
-                        JOIN fts
-                        WHERE fts.id = resources.id
-                        AND fts.content LIKE ?
-                        ORDER BY frecency DESC LIMIT 100"#,
+    // Returns the sql statement selecting the resources matching a query, with the weight
+    // of their best matching field. Fuzzy matches come after the exact ones, with a weight
+    // of -1. Parameters are bound by `bind_search`.
+    fn matching_sql(&self, query: &Query) -> String {
+        let exact = self.exact_matching_sql(query);
+        if self.fuzzy_threshold.is_none() {
+            return exact;
+        }
+
+        let fuzzy_terms = query
+            .terms
+            .iter()
+            .map(|term| {
+                let trigrams = trigrams(term).len();
+                let hits = vec!["(instr(content, ?) > 0)"; trigrams].join(" + ");
+                let tag_hits = vec!["(instr(lower(tag), ?) > 0)"; trigrams].join(" + ");
+                format!(
+                    r#"AND resources.id IN (
+                    SELECT id FROM fts_trigrams WHERE ({hits}) * 100 >= ?
+                    UNION
+                    SELECT id FROM tags WHERE ({tag_hits}) * 100 >= ?)"#
                 )
-                .bind(&search)
-                .fetch_all(&mut *tx)
-                .await?,
-                Some(ref tag) => sqlx::query_as(
-                    r#"SELECT resources.id, frecency(resources.scorer) AS frecency FROM resources
-                        JOIN fts, tags
-                        WHERE tags.tag = ?
-                        AND fts.id = resources.id AND tags.id = resources.id
-                        AND fts.content LIKE ?
-                        ORDER BY frecency DESC LIMIT 100"#,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (has_tag, exclusions) = self.filters_sql(query);
+        format!(
+            r#"WITH exact AS ({exact})
+                SELECT * FROM exact
+                UNION ALL
+                SELECT resources.id, resources.parent, frecency(resources.scorer) AS frecency, -1 AS weight
+                FROM resources
+                WHERE resources.id NOT IN (SELECT id FROM exact)
+                {fuzzy_terms}
+                AND (? IS NULL OR {has_tag})
+                AND {WITHIN_SUBTREE}
+                AND {VISIBLE}
+                AND {INDEXABLE}
+                {exclusions}"#
+        )
+    }
+
+    // Returns the sql condition restricting results to a tag, and the sql conditions
+    // leaving out excluded terms and tags.
+    fn filters_sql(&self, query: &Query) -> (&'static str, String) {
+        let no_excluded = vec![
+            r#"AND NOT EXISTS (SELECT 1 FROM fts_trigrams WHERE fts_trigrams.id = resources.id AND fts_trigrams.content LIKE ?)
+                AND NOT EXISTS (SELECT 1 FROM tags WHERE tags.id = resources.id AND tags.tag LIKE ?)"#;
+            query.excluded.len()
+        ]
+        .join("\n");
+        let no_excluded_tags = vec![
+            "AND NOT EXISTS (SELECT 1 FROM tags WHERE tags.id = resources.id AND tags.tag = ?)";
+            query.excluded_tags.len()
+        ]
+        .join("\n");
+        let has_tag = if self.inherit_tags {
+            HAS_INHERITED_TAG
+        } else {
+            HAS_TAG
+        };
+        (has_tag, format!("{no_excluded}\n{no_excluded_tags}"))
+    }
+
+    fn exact_matching_sql(&self, query: &Query) -> String {
+        // Each field matching any of the terms contributes its weight, and the best one
+        // is used to rank the resource. All the terms need to match the resource, either in
+        // the same field or in different ones.
+        let patterns = query.text_patterns();
+        let any_term = vec!["content LIKE ?"; patterns.iter().map(Vec::len).sum()].join(" OR ");
+        let any_tag = vec!["tag LIKE ?"; query.terms.len()].join(" OR ");
+        let all_terms = patterns
+            .iter()
+            .map(|patterns| {
+                let any_pattern = vec!["fts_trigrams.content LIKE ?"; patterns.len()].join(" OR ");
+                format!(
+                    r#"AND (EXISTS (SELECT 1 FROM fts_trigrams WHERE fts_trigrams.id = resources.id AND ({any_pattern}))
+                OR EXISTS (SELECT 1 FROM tags WHERE tags.id = resources.id AND tags.tag LIKE ?))"#
                 )
-                .bind(tag)
-                .bind(&search)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (has_tag, exclusions) = self.filters_sql(query);
+        format!(
+            r#"SELECT resources.id, resources.parent, frecency(resources.scorer) AS frecency, max(matches.weight) AS weight
+                FROM (
+                    SELECT id, CASE variant WHEN '<name>' THEN ? WHEN '<ancestors>' THEN ? ELSE ? END AS weight
+                    FROM fts_trigrams WHERE {any_term}
+                    UNION ALL
+                    SELECT id, ? AS weight FROM tags WHERE {any_tag}
+                ) AS matches
+                JOIN resources ON resources.id = matches.id
+                WHERE (? IS NULL OR {has_tag})
+                AND {WITHIN_SUBTREE}
+                AND {VISIBLE}
+                AND {INDEXABLE}
+                {all_terms}
+                {exclusions}
+                GROUP BY resources.id"#
+        )
+    }
+
+    // Binds the parameters of a search query, in the order they appear in the sql statement.
+    fn bind_search<'q, O>(
+        &self,
+        statement: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+        query: &Query,
+        tag: &Option<String>,
+        within: Option<&ResourceId>,
+    ) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        let mut statement = self.bind_exact_search(statement, query, tag, within);
+        let threshold = match self.fuzzy_threshold {
+            Some(threshold) => threshold,
+            None => return statement,
+        };
+
+        for term in &query.terms {
+            let trigrams = trigrams(term);
+            let minimum = threshold * trigrams.len() as u32;
+            for _field in 0..2 {
+                for trigram in &trigrams {
+                    statement = statement.bind(trigram.clone());
+                }
+                statement = statement.bind(minimum);
+            }
+        }
+        self.bind_filters(statement, query, tag, within)
+    }
+
+    fn bind_exact_search<'q, O>(
+        &self,
+        mut statement: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+        query: &Query,
+        tag: &Option<String>,
+        within: Option<&ResourceId>,
+    ) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        let patterns: Vec<String> = query.terms.iter().map(|term| format!("%{term}%")).collect();
+        let text_patterns = query.text_patterns();
+
+        statement = statement
+            .bind(self.boosts.name)
+            .bind(self.boosts.ancestors)
+            .bind(self.boosts.content);
+        for pattern in text_patterns.iter().flatten() {
+            statement = statement.bind(pattern.clone());
+        }
+        statement = statement.bind(self.boosts.tags);
+        for pattern in &patterns {
+            statement = statement.bind(pattern.clone());
+        }
+        statement = statement.bind(tag.clone()).bind(tag.clone());
+        let within_id = within.map(|id| id.to_string());
+        statement = statement
+            .bind(within_id.clone())
+            .bind(within_id)
+            .bind(self.visibility);
+        for (text_patterns, pattern) in text_patterns.into_iter().zip(patterns) {
+            for text_pattern in text_patterns {
+                statement = statement.bind(text_pattern);
+            }
+            statement = statement.bind(pattern);
+        }
+        self.bind_exclusions(statement, query)
+    }
+
+    // Binds the parameters of the conditions shared by exact and fuzzy matches.
+    fn bind_filters<'q, O>(
+        &self,
+        statement: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+        query: &Query,
+        tag: &Option<String>,
+        within: Option<&ResourceId>,
+    ) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        let within = within.map(|id| id.to_string());
+        let statement = statement
+            .bind(tag.clone())
+            .bind(tag.clone())
+            .bind(within.clone())
+            .bind(within)
+            .bind(self.visibility);
+        self.bind_exclusions(statement, query)
+    }
+
+    fn bind_exclusions<'q, O>(
+        &self,
+        mut statement: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+        query: &Query,
+    ) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        for pattern in query.excluded.iter().map(|term| format!("%{term}%")) {
+            statement = statement.bind(pattern.clone()).bind(pattern);
+        }
+        for tag in &query.excluded_tags {
+            statement = statement.bind(tag.clone());
+        }
+
+        statement
+    }
+
+    /// Returns the fields of a resource where the terms of a text query are found, with
+    /// snippets of their text. Fuzzy matches have no highlights. Indexed content is lower
+    /// cased and without diacritics nor stop words, so are its snippets.
+    pub async fn highlights(
+        &self,
+        id: &ResourceId,
+        text: &str,
+    ) -> Result<Vec<Highlight>, ResourceStoreError> {
+        let query = self.parse_query(text);
+        if query.terms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self.db_pool.begin().await?;
+        let name: Option<String> = sqlx::query_scalar("SELECT name FROM resources WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT variant, content FROM fts_trigrams WHERE id = ? ORDER BY rowid")
+                .bind(id)
                 .fetch_all(&mut *tx)
-                .await?,
-            };
-
-        // Filter out duplicates.
-        let mut seen = std::collections::HashSet::new();
-        let mut results = vec![];
-        for rec in records {
-            if !seen.contains(&rec.id) {
-                results.push(rec.clone());
-                seen.insert(rec.id);
+                .await?;
+        let tags: Vec<String> = sqlx::query_scalar("SELECT tag FROM tags WHERE id = ?")
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let terms: Vec<String> = query
+            .terms
+            .into_iter()
+            .chain(query.stems.into_iter().flatten())
+            .collect();
+        let mut fields = vec![];
+        for (variant, content) in rows {
+            match variant.as_str() {
+                // The indexed name is normalized, show the original one.
+                "<name>" => fields.push((MatchedField::Name, name.clone().unwrap_or(content))),
+                "<ancestors>" => fields.push((MatchedField::Ancestors, content)),
+                _ => fields.push((MatchedField::Content(variant), content)),
             }
         }
+        fields.extend(
+            tags.into_iter()
+                .map(|tag| (MatchedField::Tag(tag.clone()), tag)),
+        );
+
+        Ok(fields
+            .into_iter()
+            .filter_map(|(field, text)| {
+                highlight(&text, &terms).map(|(snippet, ranges)| Highlight {
+                    field,
+                    snippet,
+                    ranges,
+                })
+            })
+            .collect())
+    }
+
+    /// Returns the `count` most frequent words of the index.
+    /// Words are lower cased and without diacritics, like the indexed content.
+    pub async fn top_words(&self, count: u32) -> Result<Vec<WordFrequency>, ResourceStoreError> {
+        let results: Vec<WordFrequency> = sqlx::query_as(
+            r#"SELECT term AS word, cnt AS frequency FROM fts_vocab
+            WHERE length(term) > 1
+            ORDER BY cnt DESC, term ASC LIMIT ?"#,
+        )
+        .bind(count)
+        .fetch_all(&self.db_pool)
+        .await?;
+
         Ok(results)
     }
 }
+
+/// Normalizes text for indexing and searching: lower cases it and removes diacritics, using
+/// the compatibility decomposition of characters. This way "Łódź", "Việt" or "Ελληνικά" match
+/// their spelling without diacritics.
+/// Latin-1 text is normalized by `secular` first, like it used to be, so that it doesn't
+/// need to be indexed again. Text in other scripts indexed before has to be indexed again
+/// with `Manager::reindex()` to be found without its diacritics.
+pub fn normalize(text: &str) -> String {
+    secular::lower_lay_string(text)
+        .chars()
+        .flat_map(char::to_lowercase)
+        .nfkd()
+        // The voicing marks of kana change the sound rather than accentuate it.
+        .filter(|c| !is_combining_mark(*c) || matches!(c, '\u{3099}' | '\u{309a}'))
+        .map(|c| match c {
+            // Letters with a stroke or a special form have no decomposition.
+            'ł' => 'l',
+            'đ' => 'd',
+            'ħ' => 'h',
+            'ı' => 'i',
+            'ς' => 'σ',
+            _ => c,
+        })
+        // Recompose what is left, like the Hangul syllables and the voiced kana.
+        .nfc()
+        .collect()
+}
+
+// Whether this character is written in Chinese, Japanese or Korean, which don't separate
+// words with spaces.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11ff}' // Hangul Jamo
+        | '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3130}'..='\u{318f}' // Hangul Compatibility Jamo
+        | '\u{31f0}'..='\u{31ff}' // Katakana Phonetic Extensions
+        | '\u{3400}'..='\u{4dbf}' // CJK Unified Ideographs Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul Syllables
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{2fa1f}' // Supplementary Ideographic Plane
+    )
+}
+
+// Splits text in runs of CJK and of other characters, with a flag telling which.
+fn script_runs(chars: &[char]) -> Vec<(bool, &[char])> {
+    let mut runs = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        let cjk = is_cjk(chars[start]);
+        let end = chars[start..]
+            .iter()
+            .position(|c| is_cjk(*c) != cjk)
+            .map_or(chars.len(), |len| start + len);
+        runs.push((cjk, &chars[start..end]));
+        start = end;
+    }
+    runs
+}
+
+// Returns the overlapping bigrams of a run of CJK characters, or the run itself if it is shorter.
+fn bigrams(run: &[char]) -> Vec<String> {
+    if run.len() < 3 {
+        return vec![run.iter().collect()];
+    }
+    run.windows(2)
+        .map(|bigram| bigram.iter().collect())
+        .collect()
+}
+
+// Splits a search term in the tokens to match: runs of CJK characters become overlapping
+// bigrams, since words are not delimited there, and the other runs are kept as words.
+// For instance "東京タワー" matches text containing "東京" and "京タ" and "タワ" and "ワー".
+fn tokenize(term: &str) -> Vec<String> {
+    let chars: Vec<char> = term.chars().collect();
+    let mut tokens = vec![];
+    for (cjk, run) in script_runs(&chars) {
+        if cjk {
+            tokens.extend(bigrams(run));
+        } else {
+            let word: String = run.iter().collect();
+            if !word.trim().is_empty() {
+                tokens.push(word.trim().to_owned());
+            }
+        }
+    }
+    tokens
+}
+
+// Separates the CJK bigrams of a text with spaces, so that the word index has meaningful
+// words for these scripts too.
+fn segment(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    script_runs(&chars)
+        .into_iter()
+        .map(|(cjk, run)| {
+            if cjk {
+                format!(" {} ", bigrams(run).join(" "))
+            } else {
+                run.iter().collect()
+            }
+        })
+        .collect()
+}
+
+// Removes the stop words of a normalized text, keeping the characters around them so
+// that phrases without their stop words still match.
+fn without_stop_words(text: &str, stop_words: &HashSet<String>) -> String {
+    if stop_words.is_empty() {
+        return text.to_owned();
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word.push(c);
+            continue;
+        }
+        if !stop_words.contains(&word) {
+            result.push_str(&word);
+        }
+        word.clear();
+        result.push(c);
+    }
+    if !stop_words.contains(&word) {
+        result.push_str(&word);
+    }
+    result
+}
+
+// Returns the stem of a normalized word, if it is not the word itself.
+fn stem(stemmer: &Stemmer, word: &str) -> Option<String> {
+    if !word.chars().all(char::is_alphanumeric) {
+        return None;
+    }
+    let stem = stemmer.stem(word);
+    (!stem.is_empty() && stem != word).then(|| stem.into_owned())
+}
+
+// Finds the terms in a text, ignoring case and diacritics like the searches. Returns
+// the text shortened around the first match, with the character ranges of the matches.
+fn highlight(text: &str, terms: &[String]) -> Option<(String, Vec<Range<usize>>)> {
+    let normalized: Vec<char> = normalize(text).chars().collect();
+    let original: Vec<char> = text.chars().collect();
+    // Offsets only map to the original text if the normalization kept its length.
+    let display = if original.len() == normalized.len() {
+        original
+    } else {
+        normalized.clone()
+    };
+
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for term in terms {
+        let term: Vec<char> = term.chars().collect();
+        if term.is_empty() || term.len() > normalized.len() {
+            continue;
+        }
+        for start in 0..=normalized.len() - term.len() {
+            if normalized[start..start + term.len()] == term[..] {
+                ranges.push(start..start + term.len());
+            }
+        }
+    }
+    if ranges.is_empty() {
+        return None;
+    }
+
+    // Merge the overlapping matches.
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    let end = (merged[0].start.saturating_sub(SNIPPET_CONTEXT) + SNIPPET_LENGTH).min(display.len());
+    let start = end.saturating_sub(SNIPPET_LENGTH);
+    let ranges = merged
+        .into_iter()
+        .filter(|range| range.start >= start && range.end <= end)
+        .map(|range| range.start - start..range.end - start)
+        .collect();
+
+    Some((display[start..end].iter().collect(), ranges))
+}
+
+// Splits a term in overlapping sequences of 3 characters, used to measure how similar
+// some text is. Shorter terms are kept whole.
+fn trigrams(term: &str) -> Vec<String> {
+    let chars: Vec<char> = term.chars().collect();
+    if chars.len() <= 3 {
+        return vec![term.to_owned()];
+    }
+    chars
+        .windows(3)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexable_condition() {
+        assert!(INDEXABLE.contains(&format!("tag = '{}'", NO_INDEX_TAG)));
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn highlighted_terms() {
+        assert_eq!(highlight("Holidays", &["beach".into()]), None);
+        assert_eq!(
+            highlight("Été à la plage", &["ete".into(), "plage".into()]),
+            Some(("Été à la plage".into(), vec![0..3, 9..14]))
+        );
+        // Overlapping matches are merged.
+        assert_eq!(
+            highlight("contact", &["cont".into(), "tact".into()]),
+            Some(("contact".into(), vec![0..7]))
+        );
+
+        let text = format!("{} needle {}", "a".repeat(200), "b".repeat(200));
+        let (snippet, ranges) = highlight(&text, &["needle".into()]).unwrap();
+        assert_eq!(snippet.chars().count(), SNIPPET_LENGTH);
+        assert_eq!(ranges, vec![SNIPPET_CONTEXT..SNIPPET_CONTEXT + 6]);
+        assert_eq!(&snippet[ranges[0].clone()], "needle");
+    }
+
+    #[test]
+    fn normalized_text() {
+        assert_eq!(normalize("Crème Brûlée"), "creme brulee");
+        assert_eq!(normalize("Łódź Źdźbło"), "lodz zdzblo");
+        assert_eq!(normalize("Tiếng Việt Đà Nẵng"), "tieng viet da nang");
+        assert_eq!(normalize("ΕΛΛΗΝΙΚΌΣ ελληνικός"), "ελληνικοσ ελληνικοσ");
+        assert_eq!(normalize("ﬁle ｆｕｌｌ"), "file full");
+        assert_eq!(normalize("한국어 ガイド ｶﾞｲﾄﾞ"), "한국어 ガイド ガイド");
+        // Latin-1 text is normalized like before.
+        for text in ["Straße", "Ærø Øresund", "Ça ïs ñ"] {
+            assert_eq!(normalize(text), secular::lower_lay_string(text));
+        }
+    }
+
+    #[test]
+    fn term_trigrams() {
+        assert_eq!(trigrams("contat"), vec!["con", "ont", "nta", "tat"]);
+        assert_eq!(trigrams("été"), vec!["été"]);
+        assert_eq!(trigrams("a b"), vec!["a b"]);
+    }
+
+    #[test]
+    fn parse_query() {
+        assert_eq!(Query::parse("  ").terms, Vec::<String>::new());
+        assert_eq!(Query::parse("Café  plan").terms, vec!["cafe", "plan"]);
+        assert_eq!(
+            Query::parse(r#"the "Project Plan" draft"#).terms,
+            vec!["the", "project plan", "draft"]
+        );
+        // Unbalanced quotes extend to the end of the text.
+        assert_eq!(Query::parse(r#"a "b c"#).terms, vec!["a", "b c"]);
+        assert_eq!(Query::parse(r#""" x"#).terms, vec!["x"]);
+
+        let query = Query::parse(r#"plan -Draft -"old plan" -tag:Archive - -tag: x-y"#);
+        assert_eq!(query.terms, vec!["plan", "x-y"]);
+        assert_eq!(query.excluded, vec!["draft", "old plan"]);
+        assert_eq!(query.excluded_tags, vec!["Archive"]);
+
+        // Runs of CJK characters are split in bigrams, unless quoted.
+        assert_eq!(
+            Query::parse("東京タワー").terms,
+            vec!["東京", "京タ", "タワ", "ワー"]
+        );
+        assert_eq!(
+            Query::parse(r#"iPhone手机 "手机壳""#).terms,
+            vec!["iphone", "手机", "手机壳"]
+        );
+        assert_eq!(Query::parse("한국어 책").terms, vec!["한국", "국어", "책"]);
+
+        let stop_words: HashSet<String> = ["the".into(), "of".into()].into();
+        assert_eq!(
+            Query::parse_with(r#"The end of "the story" -the"#, &stop_words, None),
+            Query {
+                terms: vec!["end".into(), "story".into()],
+                stems: vec![None, None],
+                excluded: vec!["the".into()],
+                excluded_tags: vec![],
+            }
+        );
+        assert_eq!(
+            Query::parse_with(r#"the "of""#, &stop_words, None).terms,
+            vec!["the", "of"]
+        );
+        assert_eq!(
+            Query::parse_with(r#""end of the story""#, &stop_words, None).terms,
+            vec!["end   story"]
+        );
+        assert_eq!(
+            without_stop_words("the end, of the story", &stop_words),
+            " end,   story"
+        );
+
+        // Only unquoted words are stemmed.
+        let stemmer = Stemmer::create(StemmingLanguage::English);
+        let query = Query::parse_with(r#"running "happy days" run"#, &stop_words, Some(&stemmer));
+        assert_eq!(query.terms, vec!["running", "happy days", "run"]);
+        assert_eq!(query.stems, vec![Some("run".into()), None, None]);
+        assert_eq!(
+            query.text_patterns(),
+            vec![
+                vec!["%running%", "%run%"],
+                vec!["%happy days%"],
+                vec!["%run%"]
+            ]
+        );
+    }
+
+    #[test]
+    fn segmented_text() {
+        assert_eq!(segment("plain text"), "plain text");
+        assert_eq!(
+            segment("東京タワーの夜景"),
+            " 東京 京タ タワ ワー ーの の夜 夜景 "
+        );
+        assert_eq!(segment("iphone手机壳"), "iphone 手机 机壳 ");
+    }
+}
@@ -0,0 +1,66 @@
+/// A resource name provider deriving file names from a keyed HMAC-SHA256, so that
+/// neither the resource ids nor the variant names can be discovered from the
+/// file system without the key.
+use crate::common::{ResourceId, ResourceNameProvider};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub struct HmacNameProvider {
+    key: Vec<u8>,
+}
+
+impl HmacNameProvider {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    // Returns the hex encoded HMAC of the parts, separated by a nul byte.
+    fn hash(&self, parts: &[&str]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC can take keys of any size");
+        for (index, part) in parts.iter().enumerate() {
+            if index > 0 {
+                mac.update(&[0]);
+            }
+            mac.update(part.as_bytes());
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl ResourceNameProvider for HmacNameProvider {
+    fn metadata_name(&self, id: &ResourceId) -> String {
+        self.hash(&["meta", &id.to_string()])
+    }
+
+    fn variant_name(&self, id: &ResourceId, variant: &str) -> String {
+        self.hash(&["variant", &id.to_string(), variant])
+    }
+
+    fn blob_name(&self, hash: &str) -> String {
+        self.hash(&["blob", hash])
+    }
+}
+
+#[test]
+fn hmac_names() {
+    let provider = HmacNameProvider::new(b"secret");
+    let id: ResourceId = "resource".to_owned().into();
+
+    let meta = provider.metadata_name(&id);
+    assert_eq!(meta.len(), 64);
+    assert_eq!(meta, provider.metadata_name(&id));
+    assert!(!meta.contains("resource"));
+
+    // Names don't clash between metadata and variants, and depend on the key.
+    assert_ne!(meta, provider.variant_name(&id, "default"));
+    assert_ne!(
+        provider.variant_name(&id, "default"),
+        provider.variant_name(&id, "thumbnail")
+    );
+    assert_ne!(meta, HmacNameProvider::new(b"other").metadata_name(&id));
+}
@@ -8,6 +8,7 @@ use crate::common::{
     BoxedReader, ResourceId, ResourceKind, ResourceMetadata, ResourceStore, ResourceStoreError,
 };
 use crate::file_store::FileStore;
+use crate::rate_limiter::RateLimiter;
 use actix_web::http::header;
 use actix_web::web::{self, Bytes};
 use actix_web::{HttpResponse, Responder};
@@ -64,6 +65,7 @@ impl Stream for ChunkedReader {
 pub struct HttpData {
     pub store: FileStore,
     pub keys: Arc<Mutex<HashSet<String>>>,
+    pub rate_limiter: Option<RateLimiter>, // Rate limiting applied per access key.
 }
 
 #[derive(Deserialize)]
@@ -79,6 +81,7 @@ struct MetaSummary {
     name: String,
     container: bool,
     tags: Vec<String>,
+    frecency: u32,
 }
 
 impl From<ResourceMetadata> for MetaSummary {
@@ -88,6 +91,7 @@ impl From<ResourceMetadata> for MetaSummary {
             name: meta.name(),
             container: meta.kind() == ResourceKind::Container,
             tags: meta.tags().clone(),
+            frecency: meta.frecency(),
         }
     }
 }
@@ -101,6 +105,12 @@ pub async fn resource_handler(data: web::Data<HttpData>, info: web::Path<Info>)
         }
     }
 
+    if let Some(rate_limiter) = &data.rate_limiter {
+        if rate_limiter.check_operation(&info.access_key).is_err() {
+            return HttpResponse::TooManyRequests().finish();
+        }
+    }
+
     let store = &data.store;
 
     match store
@@ -114,6 +124,15 @@ pub async fn resource_handler(data: web::Data<HttpData>, info: web::Path<Info>)
             // Find mime type and size from the metadata.
             for variant in meta.variants() {
                 if variant.name() == info.variant {
+                    if let Some(rate_limiter) = &data.rate_limiter {
+                        if rate_limiter
+                            .check_bytes(&info.access_key, variant.size() as _)
+                            .is_err()
+                        {
+                            return HttpResponse::TooManyRequests().finish();
+                        }
+                    }
+
                     let mime_type = variant.mime_type();
 
                     // Disable compression by setting ContentEncoding::Identity (see https://docs.rs/actix-web/4.0.0-beta.19/actix_web/middleware/struct.Compress.html)
@@ -128,6 +147,9 @@ pub async fn resource_handler(data: web::Data<HttpData>, info: web::Path<Info>)
 
                     response.insert_header((header::CONTENT_TYPE, mime_type));
                     response.insert_header((header::CONTENT_LENGTH, variant.size().to_string()));
+                    if let Some(hash) = variant.hash() {
+                        response.insert_header((header::ETAG, format!("\"{hash}\"")));
+                    }
 
                     break;
                 }
@@ -252,6 +274,7 @@ mod test {
             .await
             .unwrap(),
             keys: Arc::new(Mutex::new(HashSet::new())),
+            rate_limiter: None,
         }
     }
 
@@ -321,7 +344,8 @@ mod test {
             .to_request();
 
         let result = test::call_and_read_body(&app, req).await;
-        assert_eq!(result, Bytes::from_static(b"#!/bin/bash\n\nset -x -e\n\nrm build.sqlite\nsqlite3 build.sqlite < db/migrations/00001_main.sql\n\n"));
+        let expected = fs::read("./create_db.sh").await.unwrap();
+        assert_eq!(result, Bytes::from(expected));
     }
 
     #[actix_rt::test]
@@ -402,6 +426,50 @@ mod test {
         assert_eq!(headers.get(header::CONTENT_LENGTH).unwrap(), "42");
     }
 
+    #[actix_rt::test]
+    async fn http_rate_limited() {
+        use crate::rate_limiter::RateLimits;
+
+        let mut data = get_data("./http-test-content/7").await;
+        data.rate_limiter = Some(RateLimiter::new(RateLimits {
+            operations_per_second: Some(2),
+            bytes_per_second: None,
+        }));
+
+        {
+            {
+                let mut keys = data.keys.lock();
+                keys.insert("somekey".into());
+                keys.insert("otherkey".into());
+            }
+
+            add_root(&data.store).await;
+        }
+
+        let app = create_app!(data);
+
+        for _ in 0..2 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/cmgr/somekey/{ROOT_ID_STR}/default"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/cmgr/somekey/{ROOT_ID_STR}/default"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Other keys are not affected.
+        let req = test::TestRequest::get()
+            .uri(&format!("/cmgr/otherkey/{ROOT_ID_STR}/default"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
     #[actix_rt::test]
     async fn directory_content() {
         let data = get_data("./http-test-content/6").await;
@@ -111,8 +111,84 @@ impl Indexer for FlatJsonIndexer {
 // Indexer for the content of a "Places" object.
 // This is a json value with the following format:
 // { url: "...", title: "...", icon: "..." }
-pub fn create_places_indexer() -> FlatJsonIndexer {
-    FlatJsonIndexer::new("application/x-places+json", &["url", "title"], None)
+// Besides the url and title text, the canonical url and its origin are
+// stored in the places table, see `Manager::places_by_origin()`.
+pub struct PlacesIndexer {
+    inner: FlatJsonIndexer,
+}
+
+#[async_trait(?Send)]
+impl Indexer for PlacesIndexer {
+    async fn index<'c>(
+        &self,
+        meta: &ResourceMetadata,
+        variant: &mut Variant,
+        fts: &Fts,
+        tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let mut tx = self.inner.index(meta, variant, fts, tx).await?;
+        if self.inner.mime_type != variant.metadata.mime_type() {
+            return Ok(tx);
+        }
+
+        let content = &mut variant.reader;
+        let mut buffer = vec![];
+        content.read_to_end(&mut buffer).await?;
+        content.seek(SeekFrom::Start(0)).await?;
+        let v: Value = serde_json::from_slice(&buffer)?;
+
+        if let Some(Value::String(url)) = v.get("url") {
+            let id = meta.id();
+            let url = canonical_url(url);
+            let origin = url_origin(&url);
+            sqlx::query!(
+                "INSERT OR REPLACE INTO places ( id, url, origin ) VALUES ( ?, ?, ? )",
+                id,
+                url,
+                origin
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        Ok(tx)
+    }
+}
+
+pub fn create_places_indexer() -> PlacesIndexer {
+    PlacesIndexer {
+        inner: FlatJsonIndexer::new("application/x-places+json", &["url", "title"], None),
+    }
+}
+
+/// Returns a canonical form of the url: without fragment, trailing slash nor "www."
+/// prefix, and with a lower case scheme and host.
+pub fn canonical_url(url: &str) -> String {
+    let url = url.trim();
+    let url = url.split('#').next().unwrap_or_default();
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => (String::new(), url),
+    };
+    let (host, path) = match rest.find(['/', '?']) {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, ""),
+    };
+    let host = host.to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let path = path.trim_end_matches('/');
+
+    if scheme.is_empty() {
+        format!("{host}{path}")
+    } else {
+        format!("{scheme}://{host}{path}")
+    }
+}
+
+/// Returns the origin of a canonical url, eg. "example.com" for "https://example.com/news".
+pub fn url_origin(url: &str) -> String {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    rest.split(['/', '?']).next().unwrap_or_default().to_owned()
 }
 
 // Indexer for the content of a "Contacts" object.
@@ -156,3 +232,18 @@ pub fn create_media_indexer() -> FlatJsonIndexer {
         None,
     )
 }
+
+#[test]
+fn canonical_urls() {
+    assert_eq!(
+        canonical_url("HTTPS://WWW.Example.com/Path/#top"),
+        "https://example.com/Path"
+    );
+    assert_eq!(canonical_url("https://example.com"), "https://example.com");
+    assert_eq!(
+        canonical_url("https://example.com/?q=1"),
+        "https://example.com/?q=1"
+    );
+    assert_eq!(url_origin("https://example.com/?q=1"), "example.com");
+    assert_eq!(url_origin("example.com/news"), "example.com");
+}
@@ -4,14 +4,40 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod archive;
 pub mod array;
+pub mod capability;
+#[cfg(feature = "codecs")]
+pub mod codecs;
 pub mod common;
 pub mod config;
+#[cfg(feature = "transformers")]
+pub mod contacts;
+pub mod diagnostics;
+#[cfg(feature = "transformers")]
+pub mod documents;
+#[cfg(feature = "encryption")]
+mod encryption;
+pub mod faulty_store;
+pub mod favicons;
 pub mod file_store;
 pub mod fts;
+pub mod hmac_names;
 pub mod http;
 pub mod indexer;
 pub mod manager;
+mod metadata_cache;
+pub mod rate_limiter;
+pub mod read_filter;
 pub mod scorer;
+#[cfg(feature = "semantic")]
+pub mod semantic;
+#[cfg(feature = "transformers")]
+pub mod share_codes;
+pub mod snapshot;
+pub mod testing;
+#[cfg(feature = "transformers")]
+pub mod thumbnails;
 mod timer;
+pub mod transformers;
 pub mod xor_store;
@@ -15,35 +15,59 @@
 ///
 /// Any failure of the remote side leads to a rollback of the database transaction
 /// to preserve the consistency between both sides.
+use crate::archive::{invalid_archive, path_component, read_entries, ArchivedMetadata, TarWriter};
+use crate::capability::{Capability, Operations};
+#[cfg(feature = "codecs")]
+use crate::codecs::{duplicate_groups, Deduplicate, ResourceCodec};
 use crate::common::{
-    BoxedReader, IdFrec, ResourceId, ResourceKind, ResourceMetadata, ResourceStore,
-    ResourceStoreError, TransactionResult, Variant, VariantMetadata, ROOT_ID,
+    BoxedReader, ChildrenPage, DerivedVariant, IdFrec, IndexingProgress, IndexingStatus, Page,
+    Paged, ProgressSink, ResourceId, ResourceKind, ResourceMetadata, ResourceStore,
+    ResourceStoreError, SortKey, SortOrder, TransactionResult, TransformationFilter,
+    TransformerFailure, Variant, VariantMetadata, Visibility, ROOT_ID,
+};
+use crate::config::{AccessMode, Config};
+use crate::diagnostics::{Diagnostics, DiagnosticsConfig, RecentErrors};
+#[cfg(feature = "encryption")]
+use crate::encryption;
+use crate::favicons::{icon_candidates, IconFetcher};
+use crate::fts::{
+    normalize, CollapsedMatch, ContainerMatches, FieldBoosts, Fts, FtsStats, SearchHit,
+    WordFrequency, INDEXABLE, VISIBLE, WITHIN_SUBTREE,
 };
-use crate::config::Config;
-use crate::fts::Fts;
 use crate::indexer::Indexer;
-use crate::scorer::sqlite_frecency;
-use crate::scorer::VisitEntry;
+use crate::metadata_cache::MetadataCache;
+use crate::rate_limiter::RateLimiter;
+use crate::read_filter::ReadFilter;
+use crate::scorer::{release_scoring, sqlite_frecency};
+use crate::scorer::{ScorerConfig, Scoring, ScoringPolicy, VisitEntry};
+#[cfg(feature = "semantic")]
+use crate::semantic::{cosine_similarity, from_blob, to_blob, Embedder};
+use crate::snapshot::SearchSnapshot;
+#[cfg(feature = "transformers")]
+use crate::thumbnails::{compose_collage, COLLAGE_SIZE};
 use crate::timer::Timer;
+use crate::transformers::Transformer;
 use async_std::path::{Path, PathBuf};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use libsqlite3_sys::{
-    sqlite3_create_function, SQLITE_DETERMINISTIC, SQLITE_DIRECTONLY, SQLITE_INNOCUOUS, SQLITE_UTF8,
+    sqlite3_create_function_v2, SQLITE_DETERMINISTIC, SQLITE_DIRECTONLY, SQLITE_INNOCUOUS,
+    SQLITE_UTF8,
 };
 use log::{debug, error};
-use lru::LruCache;
+use parking_lot::Mutex;
 use speedy::{Readable, Writable};
-use sqlx::ConnectOptions;
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
-    Sqlite, SqlitePool, Transaction,
+    sqlite::{SqliteConnectOptions, SqliteLockingMode, SqlitePoolOptions},
+    Sqlite, SqliteConnection, SqlitePool, Transaction,
 };
-use std::collections::{HashMap, HashSet};
+use sqlx::{Acquire, ConnectOptions, Connection};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::CString;
-use std::num::NonZeroUsize;
+use std::os::raw::c_void;
 use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ParentChild {
     pub parent: ResourceId,
     pub child: ResourceId,
@@ -58,7 +82,157 @@ impl ParentChild {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceVariant {
+    pub id: ResourceId,
+    pub variant: String,
+}
+
+impl ResourceVariant {
+    fn new(id: &ResourceId, variant: &str) -> Self {
+        Self {
+            id: id.clone(),
+            variant: variant.into(),
+        }
+    }
+}
+
+/// Variants to drop from leaves when they get a tag, eg. to save space by
+/// removing the thumbnails of archived resources.
+#[derive(Clone, Debug)]
+pub struct TagPolicy {
+    pub tag: String,
+    pub drop_variants: Vec<String>,
+}
+
+impl TagPolicy {
+    pub fn new(tag: &str, drop_variants: &[&str]) -> Self {
+        Self {
+            tag: tag.into(),
+            drop_variants: drop_variants.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+/// A discrepancy between the database and the store, found by `Manager::check_consistency()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The resource is in the database but the store has no metadata for it.
+    MissingFromStore(ResourceId),
+    /// The variant is in the database but its content is missing from the store.
+    MissingVariant(ResourceId, String),
+    /// The variant is in the store metadata but not in the database.
+    OrphanVariant(ResourceId, String),
+    /// The children list stored for this container doesn't match the database.
+    ChildrenMismatch(ResourceId),
+    /// The store has an older revision of the metadata than the database.
+    StaleMetadata(ResourceId),
+    /// The parents of this resource don't lead back to the root, because of a cycle
+    /// or of a missing parent.
+    Unreachable(ResourceId),
+}
+
+/// What `Manager::maintain()` did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub stale_text: u64,     // Text entries of resources that don't exist anymore.
+    pub duplicate_text: u64, // Text entries indexed several times.
+    pub fts: FtsStats,       // The size of the text index after the maintenance.
+}
+
+/// A variant whose content doesn't match its recorded hash, found by `Manager::verify()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptedVariant {
+    pub id: ResourceId,
+    pub variant: String,
+    pub expected: String,
+    /// The hash of the stored content, or None if it could not be read.
+    pub actual: Option<String>,
+}
+
+/// A resource file of the store, as found by `Manager::whois()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageOwner {
+    pub id: ResourceId,
+    /// The variant held by this file, or None for the metadata file.
+    pub variant: Option<String>,
+}
+
+/// A leaf missing some of the variants required by its container,
+/// see `Manager::non_conforming_children()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonConformingChild {
+    pub id: ResourceId,
+    pub missing: Vec<String>,
+}
+
+/// How a sidecar variant is accounted for, see `Manager::set_sidecar()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SidecarOptions {
+    pub exclude_from_quota: bool, // Not counted by `Manager::quota_usage()`.
+    pub exclude_from_sync: bool, // Left out of the archives written by `Manager::export_archive()`.
+}
+
+/// How archives are written, see `Manager::export_archive_with_options()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub mtime: Option<DateTime<Utc>>, // The modification time of all the entries, instead of the resources ones.
+}
+
+/// Well-known containers that applications share, see `Manager::system_container()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SystemContainer {
+    Downloads,
+    Screenshots,
+    Camera,
+}
+
+impl SystemContainer {
+    /// The stable alias recorded in the database.
+    pub fn alias(&self) -> &'static str {
+        match self {
+            Self::Downloads => "downloads",
+            Self::Screenshots => "screenshots",
+            Self::Camera => "camera",
+        }
+    }
+
+    /// The name of the container when it is created.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Downloads => "Downloads",
+            Self::Screenshots => "Screenshots",
+            Self::Camera => "Camera",
+        }
+    }
+}
+
+/// The duration of the buckets used by `Manager::visits_timeline()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBucket {
+    Day,
+    /// Weeks start on Monday.
+    Week,
+}
+
+/// What a destructive operation would change, see `Manager::delete_dry_run()` and
+/// `Manager::move_resource_dry_run()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DryRunReport {
+    /// The affected resources, parents before their children.
+    pub resources: Vec<ResourceId>,
+    /// The total size of their variants.
+    pub bytes: u64,
+}
+
+/// The number of visits during the day or week starting at `start`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VisitsBucket {
+    pub start: DateTime<Utc>,
+    pub visits: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ResourceModification {
     Created(ResourceId),
     Modified(ResourceId),
@@ -66,6 +240,46 @@ pub enum ResourceModification {
     ChildCreated(ParentChild),
     ChildModified(ParentChild),
     ChildDeleted(ParentChild),
+    VariantChanged(ResourceVariant), // A variant was updated or deleted.
+    Visited(ResourceId),
+    Rehydrated(ResourceId), // Missing from the database, the metadata was restored from the store.
+    Repaired(Inconsistency), // Fixed by `Manager::check_consistency()`.
+}
+
+/// How the events of a batch are delivered to an observer, see `Manager::begin_batch()`.
+/// Outside of batches, observers get each event right away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coalescing {
+    /// Every event is delivered right away, even during batches.
+    #[default]
+    None,
+    /// Events are delivered at the end of the batch, once each.
+    Deduplicate,
+    /// Like `Deduplicate`, and the events about the children of a container are
+    /// replaced by a single `Modified` event for this container.
+    PerContainer,
+}
+
+impl Coalescing {
+    // Merges a batch of events, keeping the order of their first occurrence.
+    fn coalesce(&self, events: Vec<ResourceModification>) -> Vec<ResourceModification> {
+        let mut result: Vec<ResourceModification> = Vec::with_capacity(events.len());
+        for event in events {
+            let event = match (self, event) {
+                (
+                    Self::PerContainer,
+                    ResourceModification::ChildCreated(ParentChild { parent, .. })
+                    | ResourceModification::ChildModified(ParentChild { parent, .. })
+                    | ResourceModification::ChildDeleted(ParentChild { parent, .. }),
+                ) => ResourceModification::Modified(parent),
+                (_, event) => event,
+            };
+            if !result.contains(&event) {
+                result.push(event);
+            }
+        }
+        result
+    }
 }
 
 pub trait ModificationObserver {
@@ -75,14 +289,195 @@ pub trait ModificationObserver {
     fn get_inner(&mut self) -> &mut Self::Inner;
 }
 
+/// Returns the options needed by database pools used by a manager, which register the
+/// SQL functions used in queries on each connection. The `frecency` function computes
+/// scores with `scoring`, which has to be given to the manager too. See `Manager::with_pool()`.
+pub fn pool_options(scoring: &Arc<Scoring>) -> SqlitePoolOptions {
+    // Register our custom function to evaluate frecency based on the scorer serialized representation.
+    let scoring = scoring.clone();
+    SqlitePoolOptions::new().after_connect(move |conn, _meta| {
+        let scoring = scoring.clone();
+        Box::pin(async move {
+            match conn.lock_handle().await {
+                Ok(mut handle) => {
+                    let name = CString::new("frecency").unwrap();
+                    unsafe {
+                        // Each connection holds a reference, released by SQLite when it closes.
+                        sqlite3_create_function_v2(
+                            handle.as_raw_handle().as_ptr(),
+                            name.as_ptr(),
+                            1, // Argument count.
+                            SQLITE_UTF8
+                                | SQLITE_DETERMINISTIC
+                                | SQLITE_INNOCUOUS
+                                | SQLITE_DIRECTONLY,
+                            Arc::into_raw(scoring) as *mut c_void,
+                            Some(sqlite_frecency),
+                            None,
+                            None,
+                            Some(release_scoring),
+                        );
+                    }
+                }
+                Err(err) => error!("Failed to acquire SQLite handle: {}", err),
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Reads the metadata of a resource from the database, without going through the cache.
+pub(crate) async fn read_db_metadata(
+    conn: &mut SqliteConnection,
+    id: &ResourceId,
+) -> Result<ResourceMetadata, ResourceStoreError> {
+    let record = sqlx::query!(
+        r#"
+SELECT id, parent, kind, name, created, modified, scorer, rev FROM resources
+WHERE id = ?"#,
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let mut meta = ResourceMetadata::new(
+        &record.id.into(),
+        &record.parent.into(),
+        record.kind.into(),
+        &record.name,
+        vec![],
+        vec![],
+    );
+
+    // Get the tags if any.
+    let tags: Vec<String> = sqlx::query!("SELECT tag FROM tags WHERE id = ?", id)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|r| r.tag.clone())
+        .collect();
+
+    if !tags.is_empty() {
+        meta.set_tags(tags);
+    }
+
+    // Get the variants if any.
+    let variants: Vec<VariantMetadata> = sqlx::query!(
+        "SELECT name, mimeType, size, hash FROM variants WHERE id = ?",
+        id
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .map(|r| {
+        let mut variant = VariantMetadata::new(&r.name, &r.mimeType, r.size as _);
+        variant.set_hash(r.hash.as_deref());
+        variant
+    })
+    .collect();
+
+    if !variants.is_empty() {
+        meta.set_variants(variants);
+    }
+
+    meta.set_created(DateTime::<Utc>::from_naive_utc_and_offset(record.created, Utc).into());
+    meta.set_modified(DateTime::<Utc>::from_naive_utc_and_offset(record.modified, Utc).into());
+    meta.set_scorer_from_db(&record.scorer);
+    meta.set_rev(record.rev as _);
+
+    Ok(meta)
+}
+
+/// Returns `true` if `id` is `ancestor` or one of its descendants.
+pub(crate) async fn resource_is_within<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+    executor: E,
+    id: &ResourceId,
+    ancestor: &ResourceId,
+) -> Result<bool, ResourceStoreError> {
+    let count = sqlx::query_scalar!(
+        r#"WITH RECURSIVE ancestors(id) AS (
+            SELECT ?
+            UNION
+            SELECT resources.parent FROM resources JOIN ancestors ON resources.id = ancestors.id
+        )
+        SELECT count(*) FROM ancestors WHERE id = ?"#,
+        id,
+        ancestor
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(count == 1)
+}
+
+/// Drops the items that the read filter hides from this caller, reading their
+/// metadata with `conn`. Items whose metadata can't be read are dropped too.
+pub(crate) async fn readable_items<R>(
+    conn: &mut SqliteConnection,
+    filter: &(dyn ReadFilter + Send + Sync),
+    caller: Option<&str>,
+    items: Vec<R>,
+    id: fn(&R) -> &ResourceId,
+) -> Result<Vec<R>, ResourceStoreError> {
+    let mut readable = Vec::with_capacity(items.len());
+    for item in items {
+        match read_db_metadata(conn, id(&item)).await {
+            Ok(metadata) if filter.allows(caller, &metadata) => readable.push(item),
+            Ok(_) | Err(ResourceStoreError::NoSuchResource) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(readable)
+}
+
+/// Returns this page of the readable results of a query, along with their total.
+pub(crate) fn readable_page_of<R>(readable: Vec<R>, page: Page) -> Paged<R> {
+    Paged {
+        total: readable.len() as u32,
+        items: readable
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit as usize)
+            .collect(),
+    }
+}
+
 pub struct Manager<T> {
     db_pool: SqlitePool,
     store: Box<dyn ResourceStore + Send + Sync>,
     fts: Fts,
     indexers: Vec<Box<dyn Indexer + Send + Sync>>, // The list of indexers available.
-    cache: LruCache<ResourceId, ResourceMetadata>, // Cache frequently accessed metadata.
+    transformers: Vec<Box<dyn Transformer + Send + Sync>>, // Generate the derived variants.
+    cache: MetadataCache,                          // Cache frequently accessed metadata.
     observers: HashMap<usize, Box<dyn ModificationObserver<Inner = T>>>,
     current_observer: usize,
+    coalescing: HashMap<usize, Coalescing>, // Observers that don't get events right away in batches.
+    pending_events: HashMap<usize, Vec<ResourceModification>>, // Events of the current batch.
+    batch_depth: u32,                       // The number of nested batches.
+    capability_token: Option<String>,       // The token used to validate calls, if any.
+    rate_limiter: Option<RateLimiter>,      // Rate limiting applied per capability token.
+    read_filter: Option<Arc<dyn ReadFilter + Send + Sync>>, // Hook consulted when delivering content.
+    indexing_version: u32,        // The version of the current set of indexers.
+    index_ancestor_names: bool,   // Whether ancestor names are indexed with each resource.
+    tag_policies: Vec<TagPolicy>, // Applied when resources get a tag.
+    inherit_tags: bool,           // Whether the tags of containers apply to their descendants.
+    visibility: Visibility,       // Whether hidden resources are listed and searchable.
+    icon_fetcher: Option<Box<dyn IconFetcher>>, // Fetches the favicons of places.
+    idempotency_ttl: chrono::Duration, // How long the results of idempotent operations are kept.
+    index_storage_names: bool,    // Whether the store file names are recorded for `whois()`.
+    derived_variants_budget: Option<u64>, // The maximum total size of derived variants.
+    scoring: Arc<Scoring>,        // The scorer settings, shared with the `frecency` SQL function.
+    config: Config,
+    recent_errors: Mutex<RecentErrors>, // The last failed store operations, for diagnostics.
+    unclean_shutdown: bool,             // Whether the previous session ended without `shutdown()`.
+    access_lock: Option<sqlx::SqliteConnection>, // Held while the manager runs, see `AccessMode`.
+    owns_pool: bool,   // False when the database pool is provided by the application.
+    rehydrations: u32, // The resources restored from the store by `get_metadata()`.
+    repairs: u32,      // The inconsistencies fixed by `check_consistency()`.
+    #[cfg(feature = "semantic")]
+    embedder: Option<Box<dyn Embedder>>, // Computes embeddings for semantic search.
+    #[cfg(feature = "codecs")]
+    codec_indexers: HashSet<&'static str>, // Mime types of the codecs with a registered indexer.
 }
 
 impl<T> Manager<T> {
@@ -90,62 +485,220 @@ impl<T> Manager<T> {
         config: Config,
         store: Box<dyn ResourceStore + Send + Sync>,
     ) -> Result<Self, ResourceStoreError> {
+        let read_only = config.access_mode == AccessMode::ReadOnly;
+        let access_lock = Self::lock_access(&config).await?;
+
         let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", config.db_path))?
-            .create_if_missing(true)
+            .create_if_missing(!read_only)
+            .read_only(read_only)
             .auto_vacuum(sqlx::sqlite::SqliteAutoVacuum::Incremental)
+            // Readers don't block writers in WAL mode, which lets search snapshots stay open.
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
             .log_statements(log::LevelFilter::Trace)
             .log_slow_statements(
                 log::LevelFilter::Error,
                 std::time::Duration::from_millis(100),
             );
 
-        // Register our custom function to evaluate frecency based on the scorer serialized representation.
-        let pool_options = SqlitePoolOptions::new().after_connect(|conn, _meta| {
-            Box::pin(async move {
-                match conn.lock_handle().await {
-                    Ok(mut handle) => {
-                        let name = CString::new("frecency").unwrap();
-                        unsafe {
-                            sqlite3_create_function(
-                                handle.as_raw_handle().as_ptr(),
-                                name.as_ptr(),
-                                1, // Argument count.
-                                SQLITE_UTF8
-                                    | SQLITE_DETERMINISTIC
-                                    | SQLITE_INNOCUOUS
-                                    | SQLITE_DIRECTONLY,
-                                std::ptr::null_mut(),
-                                Some(sqlite_frecency),
-                                None,
-                                None,
-                            );
-                        }
-                    }
-                    Err(err) => error!("Failed to acquire SQLite handle: {}", err),
-                }
-                Ok(())
-            })
-        });
+        let scoring = Arc::new(Scoring::new(config.scorer.clone()));
+        let db_pool = pool_options(&scoring).connect_with(options).await?;
+        Self::from_pool(config, store, db_pool, scoring, access_lock, true).await
+    }
 
-        let db_pool = pool_options.connect_with(options).await?;
-        sqlx::migrate!("db/migrations")
-            .run(&db_pool)
-            .await
-            .map_err(|err| ResourceStoreError::Custom(format!("Failed to run migration: {err}")))?;
+    /// Creates a manager using a database pool managed by the application, for instance to
+    /// keep resources in the application database instead of a separate file. The pool needs
+    /// to be created with `pool_options()` and the same `scoring`, whose settings are replaced
+    /// by `config.scorer`, and in WAL mode for search snapshots to not block writers.
+    /// The manager tables are created with the sqlx migrations, so the application can't
+    /// use sqlx migrations with the same version numbers on this database.
+    /// `config.db_path` is still used for the access lock file, and `shutdown()` leaves
+    /// the pool open.
+    pub async fn with_pool(
+        config: Config,
+        store: Box<dyn ResourceStore + Send + Sync>,
+        db_pool: SqlitePool,
+        scoring: Arc<Scoring>,
+    ) -> Result<Self, ResourceStoreError> {
+        let access_lock = Self::lock_access(&config).await?;
+        Self::from_pool(config, store, db_pool, scoring, access_lock, false).await
+    }
+
+    async fn from_pool(
+        config: Config,
+        store: Box<dyn ResourceStore + Send + Sync>,
+        db_pool: SqlitePool,
+        scoring: Arc<Scoring>,
+        access_lock: Option<sqlx::SqliteConnection>,
+        owns_pool: bool,
+    ) -> Result<Self, ResourceStoreError> {
+        let read_only = config.access_mode == AccessMode::ReadOnly;
+        scoring.set_config(config.scorer.clone());
+        let mut migrator = sqlx::migrate!("db/migrations");
+        // Shared databases may have been migrated by the application too.
+        migrator.set_ignore_missing(!owns_pool);
+        let latest = migrator
+            .iter()
+            .map(|migration| migration.version)
+            .max()
+            .unwrap_or_default();
+        let has_migrations: bool = sqlx::query_scalar(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_one(&db_pool)
+        .await?;
+        let applied: i64 = if has_migrations {
+            sqlx::query_scalar(
+                "SELECT COALESCE(max(version), 0) FROM _sqlx_migrations WHERE success = 1",
+            )
+            .fetch_one(&db_pool)
+            .await?
+        } else {
+            0
+        };
+        // Read only managers can't run the migrations, so they need to be already applied.
+        // Databases migrated by a newer version can't be used either, unless they are
+        // shared with the application which may have its own migrations.
+        if (read_only && applied < latest) || (owns_pool && applied > latest) {
+            return Err(ResourceStoreError::IncompatibleDatabase {
+                found: applied,
+                expected: latest,
+            });
+        }
+        if !read_only {
+            migrator.run(&db_pool).await.map_err(|err| {
+                ResourceStoreError::Custom(format!("Failed to run migration: {err}"))
+            })?;
+        }
+
+        // Mark the session as running until `shutdown()` is called.
+        let clean = sqlx::query_scalar!("SELECT clean FROM shutdown_state")
+            .fetch_optional(&db_pool)
+            .await?;
+        let unclean_shutdown = clean == Some(0) && !read_only;
+        if unclean_shutdown {
+            error!("The previous session was not shut down properly.");
+        }
+        if !read_only {
+            sqlx::query!("INSERT OR REPLACE INTO shutdown_state ( id, clean ) VALUES ( 0, 0 )")
+                .execute(&db_pool)
+                .await?;
+        }
 
-        let fts = Fts::new(&db_pool);
-        Ok(Manager {
+        // Stored frecencies computed before the settings were recorded used the default ones.
+        let stored_scorer_config = sqlx::query_scalar!("SELECT config FROM scorer_config")
+            .fetch_optional(&db_pool)
+            .await?
+            .map(|config| serde_json::from_str::<ScorerConfig>(&config))
+            .transpose()?
+            .unwrap_or_default();
+        let scorer_config_changed = stored_scorer_config != config.scorer;
+
+        let mut fts = Fts::new(&db_pool);
+        fts.set_stop_words(&config.stop_words);
+        fts.set_stemming(config.stemming);
+        let manager = Manager {
             db_pool,
             store,
             fts,
             indexers: Vec::new(),
-            cache: LruCache::new(
-                NonZeroUsize::new(config.metadata_cache_capacity)
-                    .unwrap_or(unsafe { NonZeroUsize::new_unchecked(128) }),
-            ),
+            transformers: Vec::new(),
+            cache: MetadataCache::new(config.metadata_cache_capacity, config.metadata_cache_policy),
             observers: HashMap::new(),
             current_observer: 0,
-        })
+            coalescing: HashMap::new(),
+            pending_events: HashMap::new(),
+            batch_depth: 0,
+            capability_token: None,
+            rate_limiter: None,
+            read_filter: None,
+            indexing_version: 0,
+            index_ancestor_names: false,
+            tag_policies: vec![],
+            inherit_tags: false,
+            visibility: Visibility::Visible,
+            icon_fetcher: None,
+            idempotency_ttl: chrono::Duration::days(1),
+            index_storage_names: false,
+            derived_variants_budget: None,
+            scoring,
+            config,
+            recent_errors: Mutex::new(RecentErrors::default()),
+            unclean_shutdown,
+            access_lock,
+            owns_pool,
+            rehydrations: 0,
+            repairs: 0,
+            #[cfg(feature = "semantic")]
+            embedder: None,
+            #[cfg(feature = "codecs")]
+            codec_indexers: HashSet::new(),
+        };
+
+        for problem in manager.self_test().await {
+            error!("Self-test failed: {}", problem);
+        }
+
+        // Stored frecencies were computed with other settings.
+        if scorer_config_changed && !read_only {
+            manager.update_frecencies().await?;
+            let scorer_config = serde_json::to_string(&manager.config.scorer)?;
+            sqlx::query!(
+                "INSERT OR REPLACE INTO scorer_config ( id, config ) VALUES ( 0, ? )",
+                scorer_config
+            )
+            .execute(&manager.db_pool)
+            .await?;
+        }
+
+        Ok(manager)
+    }
+
+    // Takes the lock matching the access mode, on a lock file next to the database. Writers
+    // hold an exclusive lock and the other managers a shared one, so that a single writer
+    // excludes them. SQLite file locks are released by the OS if the process dies.
+    async fn lock_access(
+        config: &Config,
+    ) -> Result<Option<sqlx::SqliteConnection>, ResourceStoreError> {
+        if config.access_mode == AccessMode::ReadOnly {
+            return Ok(None);
+        }
+
+        let single_writer = config.access_mode == AccessMode::SingleWriter;
+        let mut conn =
+            SqliteConnectOptions::from_str(&format!("sqlite://{}.lock", config.db_path))?
+                .create_if_missing(true)
+                .busy_timeout(std::time::Duration::ZERO)
+                .locking_mode(if single_writer {
+                    SqliteLockingMode::Exclusive
+                } else {
+                    SqliteLockingMode::Normal
+                })
+                .connect()
+                .await?;
+
+        let locked = if single_writer {
+            // In exclusive locking mode, the lock taken by the first write is kept.
+            sqlx::query("CREATE TABLE IF NOT EXISTS writer ( id INTEGER PRIMARY KEY )")
+                .execute(&mut conn)
+                .await
+                .and(
+                    sqlx::query("INSERT OR REPLACE INTO writer ( id ) VALUES ( 0 )")
+                        .execute(&mut conn)
+                        .await,
+                )
+        } else {
+            // Keep a read transaction open.
+            sqlx::query("BEGIN").execute(&mut conn).await.and(
+                sqlx::query("SELECT count(*) FROM sqlite_master")
+                    .execute(&mut conn)
+                    .await,
+            )
+        };
+        match locked.map_err(ResourceStoreError::from) {
+            Ok(_) => Ok(Some(conn)),
+            Err(ResourceStoreError::Busy) => Err(ResourceStoreError::WriterLocked),
+            Err(err) => Err(err),
+        }
     }
 
     pub fn add_observer(&mut self, observer: Box<dyn ModificationObserver<Inner = T>>) -> usize {
@@ -154,8 +707,47 @@ impl<T> Manager<T> {
         self.current_observer
     }
 
+    /// Adds an observer whose events are coalesced during batches.
+    pub fn add_observer_with_coalescing(
+        &mut self,
+        observer: Box<dyn ModificationObserver<Inner = T>>,
+        coalescing: Coalescing,
+    ) -> usize {
+        let id = self.add_observer(observer);
+        if coalescing != Coalescing::None {
+            self.coalescing.insert(id, coalescing);
+        }
+        id
+    }
+
     pub fn remove_observer(&mut self, observer_id: usize) {
         let _ = self.observers.remove(&observer_id);
+        let _ = self.coalescing.remove(&observer_id);
+        let _ = self.pending_events.remove(&observer_id);
+    }
+
+    /// Starts a batch of modifications, eg. a bulk import. Until the matching `end_batch()`,
+    /// the events of observers added with `add_observer_with_coalescing()` are queued.
+    /// Batches can be nested, events being delivered at the end of the outermost one.
+    pub fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// Ends a batch, delivering the coalesced events once the outermost batch ends.
+    pub fn end_batch(&mut self) {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        if self.batch_depth > 0 {
+            return;
+        }
+
+        for (id, events) in self.pending_events.drain() {
+            let coalescing = self.coalescing.get(&id).copied().unwrap_or_default();
+            if let Some(observer) = self.observers.get_mut(&id) {
+                for event in coalescing.coalesce(events) {
+                    observer.modified(&event);
+                }
+            }
+        }
     }
 
     pub fn with_observer(
@@ -175,557 +767,4063 @@ impl<T> Manager<T> {
     }
 
     fn notify_observers(&mut self, modification: &ResourceModification) {
-        for observer in self.observers.values_mut() {
-            observer.modified(modification);
+        for (id, observer) in self.observers.iter_mut() {
+            if self.batch_depth > 0 && self.coalescing.contains_key(id) {
+                self.pending_events
+                    .entry(*id)
+                    .or_default()
+                    .push(modification.clone());
+            } else {
+                observer.modified(modification);
+            }
         }
     }
 
-    fn evict_from_cache(&mut self, id: &ResourceId) {
-        self.cache.pop(id);
+    /// Sets the capability token used to validate subsequent calls.
+    /// When no token is set, all operations are allowed.
+    pub fn set_capability_context(&mut self, token: Option<&str>) {
+        self.capability_token = token.map(|token| token.to_owned());
     }
 
-    fn update_cache(&mut self, metadata: &ResourceMetadata) {
-        self.cache.put(metadata.id(), (*metadata).clone());
+    /// Sets the rate limiter applied to calls made with a capability token context.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
     }
 
-    /// Update the frecency for that resource.
-    pub async fn visit(
+    /// Sets the hook that can veto or transform content delivery in the get paths.
+    pub fn set_read_filter(&mut self, read_filter: Option<Box<dyn ReadFilter + Send + Sync>>) {
+        self.read_filter = read_filter.map(Arc::from);
+    }
+
+    pub fn set_idempotency_ttl(&mut self, ttl: chrono::Duration) {
+        self.idempotency_ttl = ttl;
+    }
+
+    /// Runs a mutating operation only once for this idempotency key: when called again
+    /// with the same key, the result of the first call is returned without running
+    /// `operation`. This lets embedders retry calls safely, eg. `create()` after a timeout.
+    /// Failed operations are not recorded, so they run again when retried.
+    pub async fn idempotent<R, F>(
         &mut self,
-        id: &ResourceId,
-        visit: &VisitEntry,
-    ) -> Result<(), ResourceStoreError> {
-        let mut metadata = self.get_metadata(id).await?;
-        metadata.modify_now();
+        key: &str,
+        operation: F,
+    ) -> Result<R, ResourceStoreError>
+    where
+        R: for<'a> Readable<'a, speedy::LittleEndian> + Writable<speedy::LittleEndian>,
+        F: for<'a> FnOnce(
+            &'a mut Self,
+        )
+            -> futures::future::LocalBoxFuture<'a, Result<R, ResourceStoreError>>,
+    {
+        let expired = Utc::now() - self.idempotency_ttl;
+        sqlx::query!("DELETE FROM idempotency_keys WHERE created < ?", expired)
+            .execute(&self.db_pool)
+            .await?;
 
-        self.evict_from_cache(id);
-        metadata.update_scorer(visit);
+        if let Some(record) = sqlx::query!("SELECT result FROM idempotency_keys WHERE key = ?", key)
+            .fetch_optional(&self.db_pool)
+            .await?
+        {
+            return Ok(R::read_from_buffer(&record.result)?);
+        }
 
-        let scorer = metadata.db_scorer();
-        let modified = *metadata.modified();
-        // We only need to update the scorer, so not doing a full update here.
+        let result = operation(self).await?;
+        let encoded = result.write_to_vec()?;
+        let created = Utc::now();
         sqlx::query!(
-            "UPDATE OR REPLACE resources SET scorer = ?, modified = ? WHERE id = ?",
-            scorer,
-            modified,
-            id
+            "INSERT OR REPLACE INTO idempotency_keys ( key, result, created ) VALUES ( ?, ?, ? )",
+            key,
+            encoded,
+            created
         )
         .execute(&self.db_pool)
         .await?;
 
-        // Update the metadata in the store.
-        self.store.update(&metadata, None).await?;
-
-        self.update_cache(&metadata);
+        Ok(result)
+    }
 
-        Ok(())
+    /// Returns `true` if the read filter lets the current caller see this resource.
+    fn is_readable(&self, metadata: &ResourceMetadata) -> bool {
+        match &self.read_filter {
+            Some(filter) => filter.allows(self.capability_token.as_deref(), metadata),
+            None => true,
+        }
     }
 
-    /// Add a tag to a resource.
-    pub async fn add_tag(
-        &mut self,
-        id: &ResourceId,
-        tag: &str,
-    ) -> Result<ResourceMetadata, ResourceStoreError> {
-        let mut metadata = self.get_metadata(id).await?;
+    /// Drops the results that the read filter hides from the current caller.
+    async fn readable_results<R>(
+        &self,
+        items: Vec<R>,
+        id: fn(&R) -> &ResourceId,
+    ) -> Result<Vec<R>, ResourceStoreError> {
+        let filter = match &self.read_filter {
+            Some(filter) => filter,
+            None => return Ok(items),
+        };
 
-        if metadata.add_tag(tag) {
-            sqlx::query!("INSERT INTO tags ( id, tag ) VALUES ( ?1, ?2 )", id, tag)
-                .execute(&self.db_pool)
-                .await?;
-            self.store.update(&metadata, None).await?;
-            self.update_cache(&metadata);
-            self.notify_observers(&ResourceModification::Modified(id.clone()));
+        let mut conn = self.db_pool.acquire().await?;
+        readable_items(
+            &mut conn,
+            filter.as_ref(),
+            self.capability_token.as_deref(),
+            items,
+            id,
+        )
+        .await
+    }
 
-            let parent = metadata.parent();
-            if metadata.kind() == ResourceKind::Leaf && *id != parent {
-                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
-                    &parent, id,
-                )));
-            }
+    /// Returns the page to query for results going through `readable_page()`. The read
+    /// filter can't run in sql, so all the results are queried when one is set.
+    fn query_page(&self, page: Page) -> Page {
+        if self.read_filter.is_some() {
+            Page::all()
+        } else {
+            page
         }
-
-        Ok(metadata)
     }
 
-    /// Remove a tag from a resource.
-    pub async fn remove_tag(
-        &mut self,
-        id: &ResourceId,
-        tag: &str,
-    ) -> Result<ResourceMetadata, ResourceStoreError> {
-        let mut metadata = self.get_metadata(id).await?;
-
-        if metadata.remove_tag(tag) {
-            sqlx::query!("DELETE FROM tags where id = ? and tag = ?", id, tag)
-                .execute(&self.db_pool)
-                .await?;
-            self.store.update(&metadata, None).await?;
-            self.update_cache(&metadata);
-            self.notify_observers(&ResourceModification::Modified(id.clone()));
-
-            let parent = metadata.parent();
-            if metadata.kind() == ResourceKind::Leaf && *id != parent {
-                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
-                    &parent, id,
-                )));
-            }
+    /// Same as `readable_results()` for results queried with `query_page(page)`,
+    /// returning this page of the readable ones and their total.
+    async fn readable_page<R>(
+        &self,
+        results: Paged<R>,
+        page: Page,
+        id: fn(&R) -> &ResourceId,
+    ) -> Result<Paged<R>, ResourceStoreError> {
+        if self.read_filter.is_none() {
+            return Ok(results);
         }
+        let readable = self.readable_results(results.items, id).await?;
+        Ok(readable_page_of(readable, page))
+    }
 
-        Ok(metadata)
+    /// Returns `true` if this resource is part of container listings.
+    fn is_listed(&self, metadata: &ResourceMetadata) -> bool {
+        self.visibility.includes(metadata) && self.is_readable(metadata)
     }
 
-    /// Use a existing transation to run the sql commands needed to create a metadata record.
-    async fn create_metadata<'c>(
-        &mut self,
-        metadata: &ResourceMetadata,
-        mut tx: Transaction<'c, Sqlite>,
-    ) -> TransactionResult<'c> {
-        let _timer = Timer::start("create_metadata");
-        let id = metadata.id();
-        let parent = metadata.parent();
-        let kind = metadata.kind();
-        let name = metadata.name();
-        let created = *metadata.created();
-        let modified = *metadata.modified();
-        let scorer = metadata.db_scorer();
+    /// Issues a new capability for the subtree rooted at `scope`.
+    /// When a token context is set, the new capability can't grant more than the current one.
+    pub async fn issue_capability(
+        &self,
+        scope: &ResourceId,
+        operations: Operations,
+        expiry: Option<DateTime<Utc>>,
+    ) -> Result<Capability, ResourceStoreError> {
+        if !self.has_object(scope).await? {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        self.check_access(scope, operations).await?;
+
+        let capability = Capability::new(scope, operations, expiry);
+        let bits = operations.bits();
         sqlx::query!(
-            r#"
-    INSERT INTO resources ( id, parent, kind, name, created, modified, scorer )
-    VALUES ( ?, ?, ?, ?, ?, ?, ? )
-            "#,
-            id,
-            parent,
-            kind,
-            name,
-            created,
-            modified,
-            scorer,
+            "INSERT INTO capabilities ( token, scope, operations, expiry ) VALUES ( ?, ?, ?, ? )",
+            capability.token,
+            capability.scope,
+            bits,
+            capability.expiry,
         )
-        .execute(&mut *tx)
+        .execute(&self.db_pool)
         .await?;
 
-        // Insert the tags.
-        for tag in metadata.tags() {
-            sqlx::query!("INSERT INTO tags ( id, tag ) VALUES ( ?1, ?2 )", id, tag)
-                .execute(&mut *tx)
-                .await?;
-        }
-
-        // Insert variants
-        for variant in metadata.variants() {
-            let name = variant.name();
-            let mime_type = variant.mime_type();
-            let size = variant.size();
-            sqlx::query!(
-                "INSERT INTO variants ( id, name, mimeType, size ) VALUES ( ?1, ?2, ?3, ?4 )",
-                id,
-                name,
-                mime_type,
-                size
-            )
-            .execute(&mut *tx)
-            .await?;
-        }
-
-        // Insert the full text search data.
-        let tx2 = self.fts.add_text(&id, "<name>", &name, tx).await?;
-
-        self.update_cache(metadata);
-
-        Ok(tx2)
-    }
-
-    /// Returns `true` if this object id is in the local index.
-    pub async fn has_object(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
-        let count = sqlx::query_scalar!("SELECT count(*) FROM resources WHERE id = ?", id)
-            .fetch_one(&self.db_pool)
-            .await?;
-
-        Ok(count == 1)
+        Ok(capability)
     }
 
-    /// Returns the number of resources in the local index.
-    pub async fn resource_count(&self) -> Result<i32, ResourceStoreError> {
-        let count = sqlx::query_scalar!("SELECT count(*) FROM resources")
-            .fetch_one(&self.db_pool)
+    /// Revokes a capability. Revoking an unknown token is not an error.
+    pub async fn revoke_capability(&self, token: &str) -> Result<(), ResourceStoreError> {
+        sqlx::query!("DELETE FROM capabilities WHERE token = ?", token)
+            .execute(&self.db_pool)
             .await?;
 
-        Ok(count)
+        Ok(())
     }
 
-    /// Returns `true` if this object id is in the local index and is a container.
-    pub async fn is_container(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
-        let count = sqlx::query_scalar!(
-            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
-            id,
-            ResourceKind::Container
+    /// Returns the capability matching this token.
+    pub async fn get_capability(&self, token: &str) -> Result<Capability, ResourceStoreError> {
+        let record = sqlx::query!(
+            "SELECT token, scope, operations, expiry FROM capabilities WHERE token = ?",
+            token
         )
-        .fetch_one(&self.db_pool)
+        .fetch_optional(&self.db_pool)
         .await?;
 
-        Ok(count == 1)
+        match record {
+            Some(record) => Ok(Capability {
+                token: record.token,
+                scope: record.scope.into(),
+                operations: Operations::from_bits(record.operations as _),
+                expiry: record
+                    .expiry
+                    .map(|expiry| DateTime::<Utc>::from_naive_utc_and_offset(expiry, Utc)),
+            }),
+            None => Err(ResourceStoreError::PermissionDenied),
+        }
     }
 
-    /// Returns `true` if this object id is in the local index and is a container.
-    pub async fn is_container_in_tx<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+    /// Returns `true` if `id` is `ancestor` or one of its descendants.
+    pub async fn is_within(
         &self,
         id: &ResourceId,
-        executor: E,
+        ancestor: &ResourceId,
     ) -> Result<bool, ResourceStoreError> {
-        let count = sqlx::query_scalar!(
-            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
-            id,
-            ResourceKind::Container
-        )
-        .fetch_one(executor)
-        .await?;
-
-        Ok(count == 1)
+        resource_is_within(&self.db_pool, id, ancestor).await
     }
 
-    /// Returns `true` if this object id is in the local index and is a leaf.
-    pub async fn is_leaf(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
-        let count = sqlx::query_scalar!(
-            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
-            id,
-            ResourceKind::Leaf
-        )
-        .fetch_one(&self.db_pool)
-        .await?;
-
-        Ok(count == 1)
+    /// Checks that the current token context grants these operations on this resource.
+    async fn check_access(
+        &self,
+        id: &ResourceId,
+        operations: Operations,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access_all(std::slice::from_ref(id), operations)
+            .await
     }
 
-    /// Check container <-> leaf constraints
-    // container == leaf is only valid for the root (container == 0)
-    pub async fn check_container_leaf(
+    /// Same as `check_access()` for several resources, counted as a single operation
+    /// by the rate limiter.
+    async fn check_access_all(
         &self,
-        id: &ResourceId,
-        parent: &ResourceId,
+        ids: &[ResourceId],
+        operations: Operations,
     ) -> Result<(), ResourceStoreError> {
-        if parent == id && !parent.is_root() {
-            error!("Only the root can be its own container.");
-            return Err(ResourceStoreError::InvalidContainerId);
+        let token = match &self.capability_token {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        let capability = self.get_capability(token).await?;
+        for id in ids {
+            if !capability.allows(operations) || !self.is_within(id, &capability.scope).await? {
+                error!("Capability {} doesn't allow access to #{}", token, id);
+                return Err(ResourceStoreError::PermissionDenied);
+            }
         }
-        // Check that the parent is a known container, except when we create the root.
-        if !id.is_root() && !self.is_container(parent).await? {
-            error!("Resource #{} is not a container", parent);
-            return Err(ResourceStoreError::InvalidContainerId);
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.check_operation(token)?;
         }
 
         Ok(())
     }
 
-    pub async fn children_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+    /// Returns the container search results are restricted to with the current token
+    /// context, after checking that it grants these operations. Results are not
+    /// restricted without a token context, or when the capability covers the root.
+    async fn search_scope(
         &self,
-        parent: &ResourceId,
-        executor: E,
-    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
-        let children: Vec<ResourceId> = sqlx::query!(
-            "SELECT id FROM resources WHERE parent = ? AND parent != id",
-            parent
-        )
-        .fetch_all(executor)
-        .await?
-        .iter()
-        .map(|r| r.id.clone().into())
-        .collect();
+        operations: Operations,
+    ) -> Result<Option<ResourceId>, ResourceStoreError> {
+        let token = match &self.capability_token {
+            Some(token) => token,
+            None => return Ok(None),
+        };
 
-        Ok(children)
+        let scope = self.get_capability(token).await?.scope;
+        self.check_access(&scope, operations).await?;
+        if scope.is_root() {
+            Ok(None)
+        } else {
+            Ok(Some(scope))
+        }
     }
 
-    pub async fn serialize_children_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+    /// Returns the container a search asked to be restricted to `within` is run in,
+    /// taking the current token context into account.
+    async fn search_within(
         &self,
-        parent: &ResourceId,
-        executor: E,
-    ) -> Result<Vec<u8>, ResourceStoreError> {
-        let children = self.children_of(parent, executor).await?;
-        let res = children.write_to_vec()?;
+        within: Option<&ResourceId>,
+    ) -> Result<Option<ResourceId>, ResourceStoreError> {
+        match within {
+            Some(within) => {
+                self.check_access(within, Operations::LIST).await?;
+                Ok(Some(within.clone()))
+            }
+            None => self.search_scope(Operations::LIST).await,
+        }
+    }
 
-        Ok(res)
+    /// Accounts for bytes transferred with the current token context.
+    fn check_bytes(&self, bytes: u32) -> Result<(), ResourceStoreError> {
+        match (&self.capability_token, &self.rate_limiter) {
+            (Some(token), Some(rate_limiter)) => rate_limiter.check_bytes(token, bytes as _),
+            _ => Ok(()),
+        }
     }
 
-    pub async fn update_container_content<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
-        &self,
-        parent: &ResourceId,
-        executor: E,
-    ) -> Result<(), ResourceStoreError> {
-        let children = self.serialize_children_of(parent, executor).await?;
-        self.store
-            .update_default_variant_from_slice(parent, &children)
-            .await?;
+    fn evict_from_cache(&mut self, id: &ResourceId) {
+        self.cache.pop(id);
+    }
 
-        Ok(())
+    fn update_cache(&mut self, metadata: &ResourceMetadata) {
+        self.cache.put(metadata);
     }
 
-    pub async fn parent_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
-        &self,
+    /// Update the frecency for that resource.
+    pub async fn visit(
+        &mut self,
         id: &ResourceId,
-        executor: E,
-    ) -> Result<ResourceId, ResourceStoreError> {
-        let maybe_parent = sqlx::query!("SELECT parent FROM resources WHERE id = ?", id)
-            .fetch_optional(executor)
-            .await?;
+        visit: &VisitEntry,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let mut metadata = self.load_metadata(id).await?;
+        metadata.modify_now();
 
-        if let Some(record) = maybe_parent {
-            return Ok(record.parent.into());
-        }
-        Err(ResourceStoreError::NoSuchResource)
-    }
+        self.evict_from_cache(id);
+        let mut scorer = metadata.scorer().clone();
+        self.scoring.add(&mut scorer, visit);
+        metadata.set_scorer(&scorer);
 
-    pub async fn clear(&mut self) -> Result<(), ResourceStoreError> {
+        let scorer = metadata.db_scorer();
+        let frecency = self.scoring.frecency(metadata.scorer());
+        let modified = *metadata.modified();
         let mut tx = self.db_pool.begin().await?;
-        sqlx::query!("DELETE FROM resources")
-            .execute(&mut *tx)
-            .await?;
-        sqlx::query!("DELETE FROM tags").execute(&mut *tx).await?;
-        sqlx::query!("DELETE FROM variants")
-            .execute(&mut *tx)
+        // We only need to update the scorer, so not doing a full update here.
+        sqlx::query!(
+            "UPDATE OR REPLACE resources SET scorer = ?, frecency = ?, modified = ? WHERE id = ?",
+            scorer,
+            frecency,
+            modified,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let tx = self
+            .record_visits(id, std::slice::from_ref(visit), tx)
             .await?;
-        sqlx::query!("DELETE FROM fts").execute(&mut *tx).await?;
         tx.commit().await?;
 
-        self.notify_observers(&ResourceModification::Deleted(ROOT_ID.clone()));
-        self.cache.clear();
-        Ok(())
-    }
+        // Update the metadata in the store.
+        self.store.update(&metadata, None).await?;
 
-    pub async fn create_root(&mut self) -> Result<(), ResourceStoreError> {
-        let mut root = ResourceMetadata::new(
-            &ROOT_ID,
-            &ROOT_ID,
-            ResourceKind::Container,
-            "/",
-            vec![],
-            vec![VariantMetadata::new("default", "inode/directory", 0)],
-        );
-        self.create(&mut root, None).await
-    }
+        self.update_cache(&metadata);
 
-    pub async fn get_root(
-        &mut self,
-    ) -> Result<(ResourceMetadata, Vec<ResourceMetadata>), ResourceStoreError> {
-        self.get_container(&ROOT_ID).await
+        self.notify_observers(&ResourceModification::Visited(id.clone()));
+
+        Ok(())
     }
 
-    // Returns the whole set of object metadata from the root to the given object.
-    // Will fail if a cycle is detected or if any parent id fails to return metadata.
-    pub async fn get_full_path(
+    /// Replays historical visits, for instance from a migrated browser history, in a single
+    /// transaction with multi-row inserts. Unlike `visit()`, the modification date is left
+    /// unchanged.
+    pub async fn import_visits(
         &mut self,
         id: &ResourceId,
-    ) -> Result<Vec<ResourceMetadata>, ResourceStoreError> {
-        let mut res = vec![];
-        let mut current = id.clone();
-        let mut visited = HashSet::new();
-
-        loop {
-            if visited.contains(&current) {
-                return Err(ResourceStoreError::ResourceCycle);
-            }
-            let meta = self.get_metadata(&current).await?;
-            visited.insert(current.clone());
-            let next = meta.parent();
-            res.push(meta);
-            if current.is_root() {
-                break;
-            }
-            current = next;
+        entries: &[VisitEntry],
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        if entries.is_empty() {
+            return Ok(());
         }
 
-        // Make sure we order elements from root -> target node.
-        res.reverse();
-        Ok(res)
-    }
+        let mut metadata = self.load_metadata(id).await?;
+        self.evict_from_cache(id);
 
-    // Retrieve the list of objects matching the given name, optionnaly restricted to a given tag.
-    // TODO: pagination
-    pub async fn by_name(
-        &self,
-        name: &str,
-        tag: Option<&str>,
-    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
-        if name.trim().is_empty() {
-            return Err(ResourceStoreError::Custom("EmptyNameQuery".into()));
+        // Replay the visits in chronological order, so that the most recent ones are sampled.
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|entry| entry.timestamp);
+        let mut scorer = metadata.scorer().clone();
+        for entry in &entries {
+            self.scoring.add(&mut scorer, entry);
         }
+        metadata.set_scorer(&scorer);
 
-        let results: Vec<ResourceId> = if let Some(tag) = tag {
-            sqlx::query_as(
-                "SELECT resources.id FROM resources JOIN tags
-                WHERE tags.tag = ? AND name = ? AND tags.id = resources.id ORDER BY frecency(resources.scorer) DESC",
-            ).bind(name).bind(tag)
-            .fetch_all(&self.db_pool)
-            .await?
-        } else {
-            sqlx::query_as("SELECT id FROM resources WHERE name = ? ORDER BY frecency(scorer) DESC")
-                .bind(name)
-                .fetch_all(&self.db_pool)
-                .await?
-        };
+        let scorer = metadata.db_scorer();
+        let frecency = self.scoring.frecency(metadata.scorer());
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!(
+            "UPDATE OR REPLACE resources SET scorer = ?, frecency = ? WHERE id = ?",
+            scorer,
+            frecency,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let tx = self.record_visits(id, &entries, tx).await?;
+        tx.commit().await?;
 
-        Ok(results)
+        self.store.update(&metadata, None).await?;
+
+        self.update_cache(&metadata);
+
+        self.notify_observers(&ResourceModification::Visited(id.clone()));
+
+        Ok(())
     }
 
-    // Retrieve the object with a given name and parent.
+    async fn record_visits<'c>(
+        &self,
+        id: &ResourceId,
+        entries: &[VisitEntry],
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        // Stay well below the maximum number of sql parameters.
+        for chunk in entries.chunks(500) {
+            let placeholders = vec!["( ?, ? )"; chunk.len()].join(", ");
+            let sql = format!("INSERT OR IGNORE INTO visits ( id, visited ) VALUES {placeholders}");
+            let mut query = sqlx::query(&sql);
+            for entry in chunk {
+                query = query.bind(id).bind(entry.when());
+            }
+            query.execute(&mut *tx).await?;
+        }
+        if let Some(last) = entries.iter().map(|entry| entry.when()).max() {
+            sqlx::query!(
+                r#"UPDATE resources SET last_visited = ?
+                WHERE id = ? AND (last_visited IS NULL OR last_visited < ?)"#,
+                last,
+                id,
+                last
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(tx)
+    }
+
+    /// Returns the number of visits per day or week in this time range, oldest first,
+    /// optionally restricted to the resources with this tag. Buckets without visits
+    /// are omitted.
+    pub async fn visits_timeline(
+        &self,
+        range: std::ops::Range<DateTime<Utc>>,
+        bucket: TimeBucket,
+        tag: Option<&str>,
+    ) -> Result<Vec<VisitsBucket>, ResourceStoreError> {
+        let weekly = bucket == TimeBucket::Week;
+        let (start, end) = (range.start, range.end);
+        let scope = self.search_scope(Operations::LIST).await?;
+        let results: Vec<(String, i64)> = sqlx::query_as(&format!(
+            r#"SELECT
+            CASE WHEN ? THEN date(visited, 'weekday 0', '-6 days') ELSE date(visited) END,
+            COUNT(*)
+            FROM visits JOIN resources ON resources.id = visits.id
+            WHERE visited >= ? AND visited < ?
+            AND (? IS NULL OR visits.id IN (SELECT id FROM tags WHERE tag = ?))
+            AND {WITHIN_SUBTREE}
+            GROUP BY 1 ORDER BY 1"#,
+        ))
+        .bind(weekly)
+        .bind(start)
+        .bind(end)
+        .bind(tag)
+        .bind(tag)
+        .bind(&scope)
+        .bind(&scope)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        results
+            .into_iter()
+            .map(|(bucket, visits)| {
+                let day = chrono::NaiveDate::parse_from_str(&bucket, "%Y-%m-%d")
+                    .map_err(|_| ResourceStoreError::Custom("InvalidVisitDate".into()))?;
+                Ok(VisitsBucket {
+                    start: DateTime::from_naive_utc_and_offset(
+                        day.and_hms_opt(0, 0, 0).unwrap(),
+                        Utc,
+                    ),
+                    visits: visits as _,
+                })
+            })
+            .collect()
+    }
+
+    /// Merge visits recorded on another device into the frecency of that resource.
+    /// Visits already known locally are ignored, so merging is idempotent.
+    /// Returns the number of visits that were added.
+    pub async fn merge_visits(
+        &mut self,
+        id: &ResourceId,
+        entries: &[VisitEntry],
+    ) -> Result<usize, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let mut metadata = self.load_metadata(id).await?;
+        let mut scorer = metadata.scorer().clone();
+        let added = self.scoring.merge(&mut scorer, entries);
+        if added == 0 {
+            return Ok(0);
+        }
+
+        self.evict_from_cache(id);
+        metadata.set_scorer(&scorer);
+
+        let scorer = metadata.db_scorer();
+        let frecency = self.scoring.frecency(metadata.scorer());
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!(
+            "UPDATE OR REPLACE resources SET scorer = ?, frecency = ? WHERE id = ?",
+            scorer,
+            frecency,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let tx = self.record_visits(id, entries, tx).await?;
+        tx.commit().await?;
+
+        self.store.update(&metadata, None).await?;
+
+        self.update_cache(&metadata);
+
+        self.notify_observers(&ResourceModification::Visited(id.clone()));
+
+        Ok(added)
+    }
+
+    /// Add a tag to a resource.
+    pub async fn add_tag(
+        &mut self,
+        id: &ResourceId,
+        tag: &str,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let mut metadata = self.load_metadata(id).await?;
+
+        if metadata.add_tag(tag) {
+            // Only the tags and the revision are modified, and rolled back if the store update fails.
+            let mut tx = self.db_pool.begin().await?;
+            sqlx::query!("INSERT INTO tags ( id, tag ) VALUES ( ?1, ?2 )", id, tag)
+                .execute(&mut *tx)
+                .await?;
+            self.bump_rev(&mut metadata, &mut tx).await?;
+            self.store.update(&metadata, None).await?;
+            tx.commit().await?;
+            self.update_cache(&metadata);
+            self.notify_observers(&ResourceModification::Modified(id.clone()));
+
+            let parent = metadata.parent();
+            if metadata.kind() == ResourceKind::Leaf && *id != parent {
+                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+                    &parent, id,
+                )));
+            }
+
+            metadata = self.apply_tag_policies(metadata, tag).await?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Renames a tag on all the resources, merging it with the new tag on resources
+    /// that already have both. Returns the number of modified resources.
+    pub async fn rename_tag(&mut self, old: &str, new: &str) -> Result<usize, ResourceStoreError> {
+        if old == new {
+            return Ok(0);
+        }
+        self.retag(old, Some(new)).await
+    }
+
+    /// Removes a tag from all the resources. Returns the number of modified resources.
+    pub async fn delete_tag(&mut self, tag: &str) -> Result<usize, ResourceStoreError> {
+        self.retag(tag, None).await
+    }
+
+    // The store is updated once the database changes are committed, so that slow store
+    // writes don't hold the database lock. Metadata that failed to be written is left
+    // stale in the store until `check_consistency()` repairs it.
+    async fn retag(&mut self, old: &str, new: Option<&str>) -> Result<usize, ResourceStoreError> {
+        // Tags are shared by all the resources.
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let ids: Vec<ResourceId> = sqlx::query_as("SELECT DISTINCT id FROM tags WHERE tag = ?")
+            .bind(old)
+            .fetch_all(&self.db_pool)
+            .await?;
+        let mut updated = Vec::with_capacity(ids.len());
+        for id in &ids {
+            let mut metadata = self.load_metadata(id).await?;
+            metadata.remove_tag(old);
+            if let Some(new) = new {
+                metadata.add_tag(new);
+            }
+            metadata.bump_rev();
+            updated.push(metadata);
+        }
+
+        let mut tx = self.db_pool.begin().await?;
+
+        // Like any other update, this gives a new revision to the modified resources.
+        sqlx::query!(
+            "UPDATE resources SET rev = rev + 1 WHERE id IN (SELECT id FROM tags WHERE tag = ?)",
+            old
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        match new {
+            Some(new) => {
+                sqlx::query!(
+                    "DELETE FROM tags WHERE tag = ? AND id IN (SELECT id FROM tags WHERE tag = ?)",
+                    old,
+                    new
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!("UPDATE tags SET tag = ? WHERE tag = ?", new, old)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM tags WHERE tag = ?", old)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        // Keep the metadata in the store up to date, and report the first failure
+        // once all the resources were processed.
+        let mut failure = None;
+        for metadata in &updated {
+            let id = metadata.id();
+            match self.store.update(metadata, None).await {
+                Ok(_) => self.update_cache(metadata),
+                Err(err) => {
+                    self.evict_from_cache(&id);
+                    let err = self.record_error("retag", &id, err);
+                    failure.get_or_insert(err);
+                }
+            }
+            self.notify_observers(&ResourceModification::Modified(id));
+        }
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(updated.len()),
+        }
+    }
+
+    /// When enabled, the tags of containers also apply to all their descendants
+    /// when searching by tag, so tagging a container once covers everything inside.
+    pub fn set_inherit_tags(&mut self, enabled: bool) {
+        self.inherit_tags = enabled;
+        self.fts.set_inherit_tags(enabled);
+    }
+
+    /// Sets whether hidden resources (see `ResourceMetadata::is_hidden()`) are part of
+    /// container listings, search results and frecency tops. They are left out by default.
+    pub fn set_visibility(&mut self, visibility: Visibility) {
+        self.visibility = visibility;
+        self.fts.set_visibility(visibility);
+    }
+
+    /// Returns the tags of a resource, including the ones inherited from its
+    /// ancestors when tag inheritance is enabled.
+    pub async fn effective_tags(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let mut tags = self.load_metadata(id).await?.tags().clone();
+        if !self.inherit_tags {
+            return Ok(tags);
+        }
+
+        let inherited: Vec<String> = sqlx::query_scalar(
+            r#"WITH RECURSIVE ancestors(id) AS (
+                SELECT parent FROM resources WHERE id = ? AND parent != id
+                UNION
+                SELECT r.parent FROM resources AS r JOIN ancestors ON r.id = ancestors.id
+                WHERE r.parent != r.id
+            )
+            SELECT DISTINCT tag FROM tags WHERE id IN (SELECT id FROM ancestors) ORDER BY tag"#,
+        )
+        .bind(id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        for tag in inherited {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        Ok(tags)
+    }
+
+    /// Declares the variants expected on the leaf children of a container, eg. `default` and
+    /// `thumbnail` for a photo album. An empty list removes the requirement.
+    /// Required variants can't be deleted, nor dropped by tag policies or evicted to fit the
+    /// derived variants budget. Leaves missing some can't be created, moved or copied into
+    /// the container. Existing children are not checked: use
+    /// `non_conforming_children()` to find the incomplete ones.
+    pub async fn set_required_variants(
+        &mut self,
+        container: &ResourceId,
+        variants: &[&str],
+    ) -> Result<(), ResourceStoreError> {
+        if !self.is_container(container).await? {
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+        self.check_access(container, Operations::WRITE).await?;
+
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!("DELETE FROM required_variants WHERE id = ?", container)
+            .execute(&mut *tx)
+            .await?;
+        for variant in variants {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO required_variants ( id, variant ) VALUES ( ?, ? )",
+                container,
+                variant
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Returns the variants required on the leaf children of this container.
+    pub async fn required_variants(
+        &self,
+        container: &ResourceId,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        self.check_access(container, Operations::READ).await?;
+        self.load_required_variants(container).await
+    }
+
+    // Same as `required_variants()`, without checking the token context.
+    async fn load_required_variants(
+        &self,
+        container: &ResourceId,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        let variants = sqlx::query_scalar!(
+            "SELECT variant FROM required_variants WHERE id = ? ORDER BY variant",
+            container
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(variants)
+    }
+
+    /// Returns the leaf children of this container missing some of its required variants.
+    pub async fn non_conforming_children(
+        &self,
+        container: &ResourceId,
+    ) -> Result<Vec<NonConformingChild>, ResourceStoreError> {
+        self.check_access(container, Operations::LIST).await?;
+        let leaf = ResourceKind::Leaf;
+        let records = sqlx::query!(
+            r#"SELECT resources.id, required_variants.variant FROM resources
+            JOIN required_variants ON required_variants.id = resources.parent
+            WHERE resources.parent = ? AND resources.kind = ?
+            AND NOT EXISTS (SELECT 1 FROM variants
+                            WHERE variants.id = resources.id AND variants.name = required_variants.variant)
+            ORDER BY resources.id, required_variants.variant"#,
+            container,
+            leaf
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut children: Vec<NonConformingChild> = vec![];
+        for record in records {
+            let id: ResourceId = record.id.into();
+            match children.last_mut() {
+                Some(child) if child.id == id => child.missing.push(record.variant),
+                _ => children.push(NonConformingChild {
+                    id,
+                    missing: vec![record.variant],
+                }),
+            }
+        }
+
+        Ok(children)
+    }
+
+    // Fails if a leaf doesn't have all the variants required in this container.
+    async fn check_required_variants(
+        &self,
+        container: &ResourceId,
+        metadata: &ResourceMetadata,
+    ) -> Result<(), ResourceStoreError> {
+        if metadata.kind() != ResourceKind::Leaf {
+            return Ok(());
+        }
+        let missing: Vec<String> = self
+            .load_required_variants(container)
+            .await?
+            .into_iter()
+            .filter(|variant| !metadata.has_variant(variant))
+            .collect();
+        if !missing.is_empty() {
+            return Err(ResourceStoreError::MissingVariants(missing));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a policy applied to leaves when they get its tag.
+    pub fn add_tag_policy(&mut self, policy: TagPolicy) {
+        self.tag_policies.push(policy);
+    }
+
+    async fn apply_tag_policies(
+        &mut self,
+        metadata: ResourceMetadata,
+        tag: &str,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        if metadata.kind() != ResourceKind::Leaf {
+            return Ok(metadata);
+        }
+
+        let id = metadata.id();
+        // Variants required in the container are kept.
+        let required = self.load_required_variants(&metadata.parent()).await?;
+        let to_drop: HashSet<String> = self
+            .tag_policies
+            .iter()
+            .filter(|policy| policy.tag == tag)
+            .flat_map(|policy| policy.drop_variants.iter())
+            .filter(|variant| metadata.has_variant(variant) && !required.contains(variant))
+            .cloned()
+            .collect();
+        if to_drop.is_empty() {
+            return Ok(metadata);
+        }
+
+        for variant in to_drop {
+            self.delete_variant(&id, &variant).await?;
+        }
+        self.load_metadata(&id).await
+    }
+
+    /// Remove a tag from a resource.
+    pub async fn remove_tag(
+        &mut self,
+        id: &ResourceId,
+        tag: &str,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let mut metadata = self.load_metadata(id).await?;
+
+        if metadata.remove_tag(tag) {
+            let mut tx = self.db_pool.begin().await?;
+            sqlx::query!("DELETE FROM tags where id = ? and tag = ?", id, tag)
+                .execute(&mut *tx)
+                .await?;
+            self.bump_rev(&mut metadata, &mut tx).await?;
+            self.store.update(&metadata, None).await?;
+            tx.commit().await?;
+            self.update_cache(&metadata);
+            self.notify_observers(&ResourceModification::Modified(id.clone()));
+
+            let parent = metadata.parent();
+            if metadata.kind() == ResourceKind::Leaf && *id != parent {
+                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+                    &parent, id,
+                )));
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Use a existing transation to run the sql commands needed to create a metadata record.
+    async fn create_metadata<'c>(
+        &mut self,
+        metadata: &ResourceMetadata,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let _timer = Timer::start("create_metadata");
+        let id = metadata.id();
+        let parent = metadata.parent();
+        let kind = metadata.kind();
+        let name = metadata.name();
+        let created = *metadata.created();
+        let modified = *metadata.modified();
+        let scorer = metadata.db_scorer();
+        let frecency = self.scoring.frecency(metadata.scorer());
+        let rev = metadata.rev() as i64;
+        sqlx::query!(
+            r#"
+    INSERT INTO resources ( id, parent, kind, name, created, modified, scorer, frecency, rev )
+    VALUES ( ?, ?, ?, ?, ?, ?, ?, ?, ? )
+            "#,
+            id,
+            parent,
+            kind,
+            name,
+            created,
+            modified,
+            scorer,
+            frecency,
+            rev,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        self.insert_metadata_details(metadata, tx).await
+    }
+
+    // Updates the resources row of an existing resource, keeping its creation date and
+    // scorer, and replaces its tags, variants and name index.
+    async fn update_metadata<'c>(
+        &mut self,
+        metadata: &ResourceMetadata,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let _timer = Timer::start("update_metadata");
+        let id = metadata.id();
+        let parent = metadata.parent();
+        let kind = metadata.kind();
+        let name = metadata.name();
+        let modified = *metadata.modified();
+        let rev = metadata.rev() as i64;
+        sqlx::query!(
+            "UPDATE resources SET parent = ?, kind = ?, name = ?, modified = ?, rev = ? WHERE id = ?",
+            parent,
+            kind,
+            name,
+            modified,
+            rev,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM tags WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM variants WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+
+        self.insert_metadata_details(metadata, tx).await
+    }
+
+    // Inserts the tags, variants and indexed names of a resource.
+    async fn insert_metadata_details<'c>(
+        &mut self,
+        metadata: &ResourceMetadata,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let id = metadata.id();
+        let name = metadata.name();
+
+        // Insert the tags.
+        for tag in metadata.tags() {
+            sqlx::query!("INSERT INTO tags ( id, tag ) VALUES ( ?1, ?2 )", id, tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Insert variants
+        for variant in metadata.variants() {
+            let name = variant.name();
+            let mime_type = variant.mime_type();
+            let size = variant.size();
+            let hash = variant.hash();
+            sqlx::query!(
+                "INSERT INTO variants ( id, name, mimeType, size, hash ) VALUES ( ?1, ?2, ?3, ?4, ?5 )",
+                id,
+                name,
+                mime_type,
+                size,
+                hash
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if self.index_storage_names {
+            self.update_storage_names(metadata, &mut tx).await?;
+        }
+
+        // Insert the full text search data.
+        let tx2 = self.fts.add_text(&id, "<name>", &name, tx).await?;
+        let tx3 = self.index_ancestor_names(&id, tx2).await?;
+
+        Ok(tx3)
+    }
+
+    /// Records the store file names of resources, so that `whois()` can find them.
+    /// Resources created before this is enabled can be added with `rebuild_storage_names()`.
+    pub fn set_index_storage_names(&mut self, enabled: bool) {
+        self.index_storage_names = enabled;
+    }
+
+    async fn update_storage_names(
+        &self,
+        metadata: &ResourceMetadata,
+        conn: &mut sqlx::SqliteConnection,
+    ) -> Result<(), ResourceStoreError> {
+        let id = metadata.id();
+        sqlx::query!("DELETE FROM storage_names WHERE id = ?", id)
+            .execute(&mut *conn)
+            .await?;
+        for (name, variant) in self.store.storage_names(metadata) {
+            sqlx::query!(
+                "INSERT INTO storage_names ( name, id, variant ) VALUES ( ?, ?, ? )",
+                name,
+                id,
+                variant
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Records the store file names of all the resources, see `set_index_storage_names()`.
+    /// Returns the number of resources.
+    pub async fn rebuild_storage_names(&mut self) -> Result<usize, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let ids: Vec<ResourceId> = sqlx::query!("SELECT id FROM resources")
+            .fetch_all(&self.db_pool)
+            .await?
+            .into_iter()
+            .map(|r| r.id.into())
+            .collect();
+
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!("DELETE FROM storage_names")
+            .execute(&mut *tx)
+            .await?;
+        for id in &ids {
+            let metadata = self.load_metadata(id).await?;
+            self.update_storage_names(&metadata, &mut tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(ids.len())
+    }
+
+    /// Returns the resources stored in this file, which can be a file name or a path in
+    /// the store directory. This helps external tools scanning the store, like backup
+    /// software, to map files back to resources.
+    /// Only file names recorded with `set_index_storage_names()` are found. Files holding
+    /// content shared by several resources have several owners.
+    pub async fn whois(&self, storage_name: &str) -> Result<Vec<StorageOwner>, ResourceStoreError> {
+        let name = Path::new(storage_name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let scope = self.search_scope(Operations::LIST).await?;
+        let owners = sqlx::query_as::<_, (ResourceId, Option<String>)>(&format!(
+            r#"SELECT storage_names.id, variant FROM storage_names
+            JOIN resources ON resources.id = storage_names.id
+            WHERE storage_names.name = ? AND {WITHIN_SUBTREE}
+            ORDER BY storage_names.id, variant"#,
+        ))
+        .bind(name)
+        .bind(&scope)
+        .bind(&scope)
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|(id, variant)| StorageOwner { id, variant })
+        .collect();
+
+        Ok(owners)
+    }
+
+    /// When enabled, the names of the ancestor containers of each resource are indexed
+    /// as lower weight search terms, so that searching "taxes 2023" finds resources
+    /// in `/documents/taxes/2023/`.
+    /// Call `reindex_ancestor_names()` to update the existing resources.
+    pub fn set_index_ancestor_names(&mut self, enabled: bool) {
+        self.index_ancestor_names = enabled;
+    }
+
+    /// Rebuilds the text index of this resource from its name, ancestors and variants,
+    /// replacing all its indexed text. This recovers from indexing that was interrupted,
+    /// and applies the current indexers to an existing resource.
+    pub async fn reindex(&mut self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let metadata = self.load_metadata(id).await?;
+
+        let mut tx = self.db_pool.begin().await?;
+        // Filled again by the places indexer if needed.
+        sqlx::query!("DELETE FROM places WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        let tx = self.fts.remove_text(id, None, tx).await?;
+        let tx = self
+            .fts
+            .add_text(id, "<name>", &metadata.name(), tx)
+            .await?;
+        let mut tx = self.index_ancestor_names(id, tx).await?;
+
+        if metadata.kind() == ResourceKind::Leaf {
+            for variant in metadata.variants() {
+                let content = self.store.get_variant(id, &variant.name()).await?;
+                tx = self
+                    .update_text_index(&metadata, &mut Variant::new(variant.clone(), content), tx)
+                    .await?;
+            }
+            self.mark_indexed(id, &mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Removes the indexed text of resources that don't exist anymore, and returns
+    /// the number of removed entries.
+    pub async fn remove_stale_text(&self) -> Result<u64, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        self.fts.remove_orphans().await
+    }
+
+    /// Returns the size of the text index.
+    pub async fn fts_stats(&self) -> Result<FtsStats, ResourceStoreError> {
+        self.fts.stats().await
+    }
+
+    /// Compacts the text index and reclaims the free pages of the database. This is
+    /// meant to be called periodically, for instance when the device is idle.
+    pub async fn maintain(&self) -> Result<MaintenanceReport, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let stale_text = self.fts.remove_orphans().await?;
+        let duplicate_text = self.fts.compact().await?;
+        sqlx::query("PRAGMA incremental_vacuum")
+            .execute(&self.db_pool)
+            .await?;
+        sqlx::query("PRAGMA optimize")
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(MaintenanceReport {
+            stale_text,
+            duplicate_text,
+            fts: self.fts.stats().await?,
+        })
+    }
+
+    /// Updates the indexed ancestor names of all the resources.
+    pub async fn reindex_ancestor_names(&self) -> Result<(), ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let tx = self.db_pool.begin().await?;
+        let tx = self.index_descendants_ancestor_names(&ROOT_ID, tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Replaces the indexed ancestor names of this resource.
+    async fn index_ancestor_names<'c>(
+        &self,
+        id: &ResourceId,
+        tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let mut tx = self.fts.remove_text(id, Some("<ancestors>"), tx).await?;
+        if !self.index_ancestor_names {
+            return Ok(tx);
+        }
+
+        // Walk up the hierarchy, leaving out the root.
+        let names: Vec<String> = sqlx::query_scalar(
+            r#"WITH RECURSIVE ancestors(id, parent, name, depth) AS (
+                SELECT id, parent, name, 0 FROM resources
+                WHERE id = (SELECT parent FROM resources WHERE id = ?)
+                UNION ALL
+                SELECT resources.id, resources.parent, resources.name, ancestors.depth + 1
+                FROM resources JOIN ancestors ON resources.id = ancestors.parent
+                WHERE ancestors.id != ancestors.parent
+            )
+            SELECT name FROM ancestors WHERE id != parent ORDER BY depth DESC"#,
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if names.is_empty() {
+            return Ok(tx);
+        }
+        self.fts
+            .add_text(id, "<ancestors>", &names.join(" "), tx)
+            .await
+    }
+
+    // Replaces the indexed ancestor names of all the descendants of this resource.
+    async fn index_descendants_ancestor_names<'c>(
+        &self,
+        id: &ResourceId,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        let descendants: Vec<ResourceId> = sqlx::query_scalar(
+            r#"WITH RECURSIVE descendants(id) AS (
+                SELECT id FROM resources WHERE parent = ? AND id != parent
+                UNION
+                SELECT resources.id FROM resources
+                JOIN descendants ON resources.parent = descendants.id
+            )
+            SELECT id FROM descendants"#,
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for descendant in descendants {
+            tx = self.index_ancestor_names(&descendant, tx).await?;
+        }
+
+        Ok(tx)
+    }
+
+    /// Returns `true` if this object id is in the local index.
+    pub async fn has_object(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
+        let count = sqlx::query_scalar!("SELECT count(*) FROM resources WHERE id = ?", id)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        Ok(count == 1)
+    }
+
+    /// Returns the number of resources in the local index.
+    pub async fn resource_count(&self) -> Result<i32, ResourceStoreError> {
+        let count = sqlx::query_scalar!("SELECT count(*) FROM resources")
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Returns the ids of all the resources in the local index.
+    pub async fn all_ids(&self) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        let ids: Vec<ResourceId> = sqlx::query_as("SELECT id FROM resources ORDER BY id")
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        Ok(ids)
+    }
+
+    pub(crate) fn store(&self) -> &(dyn ResourceStore + Send + Sync) {
+        self.store.as_ref()
+    }
+
+    /// Returns `true` if this object id is in the local index and is a container.
+    pub async fn is_container(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
+        let count = sqlx::query_scalar!(
+            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
+            id,
+            ResourceKind::Container
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(count == 1)
+    }
+
+    /// Returns `true` if this object id is in the local index and is a container.
+    pub async fn is_container_in_tx<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        id: &ResourceId,
+        executor: E,
+    ) -> Result<bool, ResourceStoreError> {
+        let count = sqlx::query_scalar!(
+            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
+            id,
+            ResourceKind::Container
+        )
+        .fetch_one(executor)
+        .await?;
+
+        Ok(count == 1)
+    }
+
+    /// Returns `true` if this object id is in the local index and is a leaf.
+    pub async fn is_leaf(&self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
+        let count = sqlx::query_scalar!(
+            "SELECT count(*) FROM resources WHERE id = ? and kind = ?",
+            id,
+            ResourceKind::Leaf
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(count == 1)
+    }
+
+    /// Check container <-> leaf constraints
+    // container == leaf is only valid for the root (container == 0)
+    pub async fn check_container_leaf(
+        &self,
+        id: &ResourceId,
+        parent: &ResourceId,
+    ) -> Result<(), ResourceStoreError> {
+        if parent == id && !parent.is_root() {
+            error!("Only the root can be its own container.");
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+        // Check that the parent is a known container, except when we create the root.
+        if !id.is_root() && !self.is_container(parent).await? {
+            error!("Resource #{} is not a container", parent);
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+
+        Ok(())
+    }
+
+    pub async fn children_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        parent: &ResourceId,
+        executor: E,
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        let children: Vec<ResourceId> = sqlx::query!(
+            "SELECT id FROM resources WHERE parent = ? AND parent != id",
+            parent
+        )
+        .fetch_all(executor)
+        .await?
+        .iter()
+        .map(|r| r.id.clone().into())
+        .collect();
+
+        Ok(children)
+    }
+
+    pub async fn serialize_children_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        parent: &ResourceId,
+        executor: E,
+    ) -> Result<Vec<u8>, ResourceStoreError> {
+        let children = self.children_of(parent, executor).await?;
+        let res = children.write_to_vec()?;
+
+        Ok(res)
+    }
+
+    pub async fn update_container_content<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        parent: &ResourceId,
+        executor: E,
+    ) -> Result<(), ResourceStoreError> {
+        let children = self.serialize_children_of(parent, executor).await?;
+        self.store
+            .update_default_variant_from_slice(parent, &children)
+            .await
+            .map_err(|err| self.record_error("update_container", parent, err))?;
+
+        Ok(())
+    }
+
+    pub async fn parent_of<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        id: &ResourceId,
+        executor: E,
+    ) -> Result<ResourceId, ResourceStoreError> {
+        let maybe_parent = sqlx::query!("SELECT parent FROM resources WHERE id = ?", id)
+            .fetch_optional(executor)
+            .await?;
+
+        if let Some(record) = maybe_parent {
+            return Ok(record.parent.into());
+        }
+        Err(ResourceStoreError::NoSuchResource)
+    }
+
+    pub async fn clear(&mut self) -> Result<(), ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!("DELETE FROM resources")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM tags").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM variants")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM fts").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM fts_trigrams")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM indexing_status")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM embeddings")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM places").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM favicons")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM visits").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM storage_names")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM required_variants")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM system_containers")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM collages")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM transformer_failures")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM sidecar_variants")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        self.notify_observers(&ResourceModification::Deleted(ROOT_ID.clone()));
+        self.cache.clear();
+        Ok(())
+    }
+
+    /// Re-reads the variants of this resource from the store and checks that their
+    /// content matches the recorded hashes. Variants without a hash are not checked.
+    pub async fn verify(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<Vec<CorruptedVariant>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let metadata = self.load_metadata(id).await?;
+        let mut corrupted = vec![];
+        for variant in metadata.variants() {
+            if let Some(expected) = variant.hash() {
+                if let Some(item) = self.verify_variant(id, &variant.name(), expected).await {
+                    corrupted.push(item);
+                }
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Same as `verify()`, for all the resources.
+    pub async fn verify_all(
+        &mut self,
+        mut progress: Option<&mut dyn ProgressSink>,
+    ) -> Result<Vec<CorruptedVariant>, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let variants = sqlx::query!(
+            r#"SELECT id, name, hash AS "hash!" FROM variants WHERE hash IS NOT NULL ORDER BY id"#
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let total = variants.len();
+        let mut corrupted = vec![];
+        for (done, record) in variants.into_iter().enumerate() {
+            let id: ResourceId = record.id.into();
+            if let Some(item) = self.verify_variant(&id, &record.name, record.hash).await {
+                corrupted.push(item);
+            }
+            if let Some(sink) = progress.as_mut() {
+                sink.progress(done + 1, total, &id);
+            }
+        }
+        Ok(corrupted)
+    }
+
+    async fn verify_variant(
+        &self,
+        id: &ResourceId,
+        name: &str,
+        expected: String,
+    ) -> Option<CorruptedVariant> {
+        let actual = match self.store.get_variant(id, name).await {
+            Ok(reader) => {
+                let mut content = Variant::new(VariantMetadata::new(name, "", 0), reader);
+                content.update_hash().await.ok()
+            }
+            Err(_) => None,
+        };
+        if actual.as_ref() == Some(&expected) {
+            return None;
+        }
+        Some(CorruptedVariant {
+            id: id.clone(),
+            variant: name.into(),
+            expected,
+            actual,
+        })
+    }
+
+    /// Runs quick checks of the database and the store, returning the problems found.
+    /// This is run when creating the manager, see `check_consistency()` for a full check.
+    pub async fn self_test(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        let check = if self.unclean_shutdown {
+            "PRAGMA integrity_check"
+        } else {
+            "PRAGMA quick_check"
+        };
+        match sqlx::query_scalar::<_, String>(check)
+            .fetch_all(&self.db_pool)
+            .await
+        {
+            Ok(results) if results == ["ok"] => {}
+            Ok(results) => problems.push(format!("Database check failed: {}", results.join(", "))),
+            Err(err) => problems.push(format!("Database check failed: {err}")),
+        }
+
+        match sqlx::query_scalar!("SELECT count(*) FROM resources WHERE id = ?", *ROOT_ID)
+            .fetch_one(&self.db_pool)
+            .await
+        {
+            Ok(0) => {}
+            Ok(_) => {
+                if let Err(err) = self.store.get_metadata(&ROOT_ID).await {
+                    problems.push(format!("Failed to read the root from the store: {err}"));
+                }
+            }
+            Err(err) => problems.push(format!("Failed to read the root from the database: {err}")),
+        }
+
+        problems
+    }
+
+    /// Returns a report of the state of the manager, to help with bug reports.
+    /// This covers all the resources, so it needs write access to the root.
+    pub async fn diagnostics(&self) -> Result<Diagnostics, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let schema_version: i64 =
+            sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM _sqlx_migrations")
+                .fetch_one(&self.db_pool)
+                .await?;
+        let variants = sqlx::query!(
+            r#"SELECT count(*) AS "count!: u32", COALESCE(SUM(size), 0) AS "bytes!: i64" FROM variants"#
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+        let tags = sqlx::query_scalar!(r#"SELECT count(DISTINCT tag) AS "count!: u32" FROM tags"#)
+            .fetch_one(&self.db_pool)
+            .await?;
+        let progress = self.indexing_progress().await?;
+
+        Ok(Diagnostics {
+            schema_version,
+            config: DiagnosticsConfig {
+                db_path: self.config.db_path.clone(),
+                data_dir: self.config.data_dir.clone(),
+                metadata_cache_capacity: self.config.metadata_cache_capacity,
+                metadata_cache_policy: self.config.metadata_cache_policy,
+                access_mode: self.config.access_mode,
+                indexing_version: self.indexing_version,
+                index_ancestor_names: self.index_ancestor_names,
+                inherit_tags: self.inherit_tags,
+                index_storage_names: self.index_storage_names,
+                derived_variants_budget: self.derived_variants_budget,
+            },
+            problems: self.self_test().await,
+            unclean_shutdown: self.unclean_shutdown,
+            resources: self.resource_count().await? as _,
+            variants: variants.count,
+            tags,
+            bytes: variants.bytes as _,
+            pending_indexing: progress.total - progress.indexed,
+            stale_variants: self.find_stale_variants(&None).await?.len() as _,
+            rehydrations: self.rehydrations,
+            repairs: self.repairs,
+            last_errors: self.recent_errors.lock().to_vec(),
+        })
+    }
+
+    /// Writes the diagnostics report as JSON to a file, redacting the local paths and
+    /// the error messages when `redact` is true. Returns the written report.
+    pub async fn write_diagnostics<P: AsRef<Path>>(
+        &self,
+        path: P,
+        redact: bool,
+    ) -> Result<Diagnostics, ResourceStoreError> {
+        let mut diagnostics = self.diagnostics().await?;
+        if redact {
+            diagnostics = diagnostics.redacted();
+        }
+        async_std::fs::write(path, serde_json::to_vec_pretty(&diagnostics)?).await?;
+
+        Ok(diagnostics)
+    }
+
+    // Keeps track of a failed store operation for diagnostics.
+    fn record_error(
+        &self,
+        operation: &str,
+        id: &ResourceId,
+        err: ResourceStoreError,
+    ) -> ResourceStoreError {
+        self.recent_errors
+            .lock()
+            .push(operation, id, err.to_string());
+        err
+    }
+
+    /// Checks that the database and the store agree with each other.
+    /// When `repair` is true, inconsistencies are fixed as they are found: resources missing
+    /// from the store are removed from the database, missing variants are forgotten, orphan
+    /// variants are deleted from the store, and stale metadata and children lists are rewritten.
+    /// Unreachable resources are only reported since there is no obvious place to move them to.
+    pub async fn check_consistency(
+        &mut self,
+        repair: bool,
+    ) -> Result<Vec<Inconsistency>, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let mut issues = vec![];
+
+        // 1. Check the metadata and variants of each resource.
+        for id in self.all_ids().await? {
+            let mut metadata = self.load_metadata(&id).await?;
+            let stored = match self.store.get_metadata(&id).await {
+                Ok(stored) => stored,
+                Err(ResourceStoreError::NoSuchResource) => {
+                    let issue = Inconsistency::MissingFromStore(id.clone());
+                    if repair {
+                        self.remove_from_index(&id).await?;
+                        self.repaired(&issue);
+                    }
+                    issues.push(issue);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            let known: HashSet<String> = metadata.variants().iter().map(|v| v.name()).collect();
+            let mut changed = false;
+            if stored.rev() < metadata.rev() {
+                let issue = Inconsistency::StaleMetadata(id.clone());
+                if repair {
+                    changed = true;
+                    self.repaired(&issue);
+                }
+                issues.push(issue);
+            }
+            // The default variant of containers is checked with the children lists.
+            if metadata.kind() == ResourceKind::Leaf {
+                for variant in metadata.variants().clone() {
+                    let name = variant.name();
+                    if self.store.get_variant(&id, &name).await.is_ok() {
+                        continue;
+                    }
+                    let issue = Inconsistency::MissingVariant(id.clone(), name.clone());
+                    if repair {
+                        sqlx::query!("DELETE FROM variants WHERE id = ? AND name = ?", id, name)
+                            .execute(&self.db_pool)
+                            .await?;
+                        let tx = self.db_pool.begin().await?;
+                        let tx = self.fts.remove_text(&id, Some(&name), tx).await?;
+                        tx.commit().await?;
+                        metadata.delete_variant(&name);
+                        changed = true;
+                        self.repaired(&issue);
+                    }
+                    issues.push(issue);
+                }
+            }
+
+            for variant in stored.variants() {
+                let name = variant.name();
+                if known.contains(&name) {
+                    continue;
+                }
+                let issue = Inconsistency::OrphanVariant(id.clone(), name.clone());
+                if repair {
+                    self.store.delete_variant(&id, &name).await?;
+                    changed = true;
+                    self.repaired(&issue);
+                }
+                issues.push(issue);
+            }
+
+            if changed {
+                self.store.update(&metadata, None).await?;
+                self.update_cache(&metadata);
+            }
+        }
+
+        // 2. Check that all resources are reachable from the root.
+        let parents: HashMap<ResourceId, ResourceId> =
+            sqlx::query!("SELECT id, parent FROM resources")
+                .fetch_all(&self.db_pool)
+                .await?
+                .into_iter()
+                .map(|r| (r.id.into(), r.parent.into()))
+                .collect();
+        let mut ids: Vec<&ResourceId> = parents.keys().collect();
+        ids.sort_by_key(|id| id.to_string());
+        for id in ids {
+            let mut current = id;
+            let mut visited = HashSet::new();
+            while !current.is_root() {
+                match parents.get(current) {
+                    Some(parent) if visited.insert(current) => current = parent,
+                    _ => {
+                        issues.push(Inconsistency::Unreachable(id.clone()));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // 3. Check the children lists of containers.
+        let containers: Vec<ResourceId> = sqlx::query!(
+            "SELECT id FROM resources WHERE kind = ? ORDER BY id",
+            ResourceKind::Container
+        )
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|r| r.id.into())
+        .collect();
+        for id in containers {
+            let mut expected = self.children_of(&id, &self.db_pool).await?;
+            let mut listed = match self.store.get_variant(&id, "default").await {
+                Ok(mut file) => {
+                    let mut buffer = vec![];
+                    file.read_to_end(&mut buffer).await?;
+                    Vec::<ResourceId>::read_from_buffer(&buffer).ok()
+                }
+                // Empty containers may have no content at all.
+                Err(_) => Some(vec![]),
+            };
+            expected.sort_by_key(|id| id.to_string());
+            if let Some(listed) = listed.as_mut() {
+                listed.sort_by_key(|id| id.to_string());
+            }
+            if listed.as_ref() == Some(&expected) {
+                continue;
+            }
+            let issue = Inconsistency::ChildrenMismatch(id.clone());
+            if repair {
+                self.update_container_content(&id, &self.db_pool).await?;
+                self.repaired(&issue);
+            }
+            issues.push(issue);
+        }
+
+        Ok(issues)
+    }
+
+    fn repaired(&mut self, issue: &Inconsistency) {
+        self.repairs += 1;
+        self.notify_observers(&ResourceModification::Repaired(issue.clone()));
+    }
+
+    /// Rebuilds the database entries of all the resources listed by the store, including
+    /// their tags, variants and full text index. Entries of resources that are not in the
+    /// store anymore are left untouched, use `check_consistency()` to remove them.
+    /// Progress is reported after each resource.
+    /// Returns the number of resources that were rehydrated.
+    pub async fn rehydrate_all(
+        &mut self,
+        mut progress: Option<&mut dyn ProgressSink>,
+    ) -> Result<usize, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let ids = self.store.list_ids().await?;
+        let total = ids.len();
+
+        for (done, id) in ids.iter().enumerate() {
+            let metadata = self.store.get_metadata(id).await?;
+
+            let mut tx = self.db_pool.begin().await?;
+            sqlx::query!("DELETE FROM resources WHERE id = ?", id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM places WHERE id = ?", id)
+                .execute(&mut *tx)
+                .await?;
+            let tx = self.fts.remove_text(id, None, tx).await?;
+            let tx = self.create_metadata(&metadata, tx).await?;
+            // Only the visits kept by the scorer can be recovered.
+            let mut tx = self
+                .record_visits(id, metadata.scorer().entries(), tx)
+                .await?;
+
+            if metadata.kind() == ResourceKind::Leaf {
+                for variant in metadata.variants() {
+                    let reader = self.store.get_variant(id, &variant.name()).await?;
+                    let mut content = Variant::new(variant.clone(), reader);
+                    tx = self.update_text_index(&metadata, &mut content, tx).await?;
+                }
+            }
+            tx.commit().await?;
+
+            self.update_cache(&metadata);
+            if let Some(sink) = progress.as_mut() {
+                sink.progress(done + 1, total, id);
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Deletes all the database entries of a resource, including its text index.
+    /// The tags are removed by the delete cascade sql rule.
+    async fn delete_index_entries<'c>(
+        &self,
+        id: &ResourceId,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        sqlx::query!("DELETE FROM resources WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM derived_variants WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM indexing_status WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM embeddings WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM places WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM favicons WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM visits WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM storage_names WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM required_variants WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM system_containers WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM collages WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM transformer_failures WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM sidecar_variants WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        self.fts.remove_text(id, None, tx).await
+    }
+
+    /// Removes a resource from the database, without touching the store.
+    async fn remove_from_index(&mut self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        let tx = self.db_pool.begin().await?;
+        let tx = self.delete_index_entries(id, tx).await?;
+        tx.commit().await?;
+
+        self.evict_from_cache(id);
+        self.notify_observers(&ResourceModification::Deleted(id.clone()));
+        Ok(())
+    }
+
+    pub async fn create_root(&mut self) -> Result<(), ResourceStoreError> {
+        let mut root = ResourceMetadata::new(
+            &ROOT_ID,
+            &ROOT_ID,
+            ResourceKind::Container,
+            "/",
+            vec![],
+            vec![VariantMetadata::new("default", "inode/directory", 0)],
+        );
+        self.create(&mut root, None).await
+    }
+
+    /// Returns the id of a system container, creating it in the root container if needed.
+    /// An existing container of the root with the expected name is adopted, so that
+    /// applications converge on the same folders. The container can be renamed or moved
+    /// afterwards, and is recreated if deleted.
+    pub async fn system_container(
+        &mut self,
+        kind: SystemContainer,
+    ) -> Result<ResourceId, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        self.ensure_system_container(kind).await
+    }
+
+    // Same as `system_container()`, without checking access.
+    async fn ensure_system_container(
+        &mut self,
+        kind: SystemContainer,
+    ) -> Result<ResourceId, ResourceStoreError> {
+        let alias = kind.alias();
+        let id = sqlx::query_scalar!("SELECT id FROM system_containers WHERE kind = ?", alias)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        if let Some(id) = id {
+            let id: ResourceId = id.into();
+            match self.load_metadata(&id).await {
+                Ok(meta) if meta.kind() == ResourceKind::Container => return Ok(id),
+                Ok(_) | Err(ResourceStoreError::NoSuchResource) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let id = match self.find_child(&ROOT_ID, kind.name()).await {
+            Ok(meta) if meta.kind() == ResourceKind::Container => meta.id(),
+            Ok(_) => return Err(ResourceStoreError::ResourceAlreadyExists),
+            Err(ResourceStoreError::NoSuchResource) => {
+                let mut container = ResourceMetadata::new(
+                    &ResourceId::new(),
+                    &ROOT_ID,
+                    ResourceKind::Container,
+                    kind.name(),
+                    vec![],
+                    vec![VariantMetadata::new("default", "inode/directory", 0)],
+                );
+                self.create(&mut container, None).await?;
+                container.id()
+            }
+            Err(err) => return Err(err),
+        };
+
+        sqlx::query!(
+            "INSERT OR REPLACE INTO system_containers ( kind, id ) VALUES ( ?, ? )",
+            alias,
+            id
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Makes sure that these system containers exist, typically on first run.
+    /// Returns their ids in the same order.
+    pub async fn declare_system_containers(
+        &mut self,
+        kinds: &[SystemContainer],
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        let mut ids = Vec::with_capacity(kinds.len());
+        for kind in kinds {
+            ids.push(self.ensure_system_container(*kind).await?);
+        }
+        Ok(ids)
+    }
+
+    pub async fn get_root(
+        &mut self,
+    ) -> Result<(ResourceMetadata, Vec<ResourceMetadata>), ResourceStoreError> {
+        self.get_container(&ROOT_ID).await
+    }
+
+    // Returns the whole set of object metadata from the root to the given object.
+    // Will fail if a cycle is detected or if any parent id fails to return metadata.
+    pub async fn get_full_path(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<Vec<ResourceMetadata>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let mut res = vec![];
+        let mut current = id.clone();
+        let mut visited = HashSet::new();
+
+        loop {
+            if visited.contains(&current) {
+                return Err(ResourceStoreError::ResourceCycle);
+            }
+            let meta = self.load_metadata(&current).await?;
+            if !self.is_readable(&meta) {
+                return Err(ResourceStoreError::NoSuchResource);
+            }
+            visited.insert(current.clone());
+            let next = meta.parent();
+            res.push(meta);
+            if current.is_root() {
+                break;
+            }
+            current = next;
+        }
+
+        // Make sure we order elements from root -> target node.
+        res.reverse();
+        Ok(res)
+    }
+
+    // Retrieve the list of objects matching the given name, optionnaly restricted to a given tag.
+    pub async fn by_name(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        Ok(self.by_name_paged(name, tag, Page::all()).await?.items)
+    }
+
+    pub async fn by_name_paged(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+        page: Page,
+    ) -> Result<Paged<ResourceId>, ResourceStoreError> {
+        if name.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyNameQuery".into()));
+        }
+        let scope = self.search_scope(Operations::LIST).await?;
+        let query = self.query_page(page);
+
+        let (items, total): (Vec<ResourceId>, u32) = if let Some(tag) = tag {
+            let items = sqlx::query_as(&format!(
+                r#"SELECT resources.id FROM resources JOIN tags
+                WHERE tags.tag = ? AND name = ? AND tags.id = resources.id AND {VISIBLE}
+                AND {WITHIN_SUBTREE}
+                ORDER BY frecency(resources.scorer) DESC LIMIT ? OFFSET ?"#
+            ))
+            .bind(tag)
+            .bind(name)
+            .bind(self.visibility)
+            .bind(&scope)
+            .bind(&scope)
+            .bind(query.limit)
+            .bind(query.offset)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let total = sqlx::query_scalar(&format!(
+                r#"SELECT count(*) FROM resources JOIN tags
+                WHERE tags.tag = ? AND name = ? AND tags.id = resources.id AND {VISIBLE}
+                AND {WITHIN_SUBTREE}"#
+            ))
+            .bind(tag)
+            .bind(name)
+            .bind(self.visibility)
+            .bind(&scope)
+            .bind(&scope)
+            .fetch_one(&self.db_pool)
+            .await?;
+            (items, total)
+        } else {
+            let items = sqlx::query_as(&format!(
+                "SELECT id FROM resources WHERE name = ? AND {VISIBLE} AND {WITHIN_SUBTREE} ORDER BY frecency(scorer) DESC LIMIT ? OFFSET ?",
+            ))
+            .bind(name)
+            .bind(self.visibility)
+            .bind(&scope)
+            .bind(&scope)
+            .bind(query.limit)
+            .bind(query.offset)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let total = sqlx::query_scalar(&format!(
+                "SELECT count(*) FROM resources WHERE name = ? AND {VISIBLE} AND {WITHIN_SUBTREE}"
+            ))
+            .bind(name)
+            .bind(self.visibility)
+            .bind(&scope)
+            .bind(&scope)
+            .fetch_one(&self.db_pool)
+            .await?;
+            (items, total)
+        };
+
+        self.readable_page(Paged { items, total }, page, |id| id)
+            .await
+    }
+
+    // Retrieve the object with a given name and parent.
     pub async fn child_by_name(
         &mut self,
-        parent: &ResourceId,
+        parent: &ResourceId,
+        name: &str,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.check_access(parent, Operations::LIST).await?;
+        let child = self.find_child(parent, name).await?;
+        if !self.is_readable(&child) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        Ok(child)
+    }
+
+    // Same as `child_by_name()`, without checking the token context.
+    async fn find_child(
+        &mut self,
+        parent: &ResourceId,
+        name: &str,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        if name.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyNameQuery".into()));
+        }
+
+        let record = sqlx::query!(
+            "SELECT id FROM resources WHERE parent = ? AND name = ?",
+            parent,
+            name,
+        )
+        .fetch_optional(&self.db_pool)
+        .await?;
+
+        match record {
+            Some(child) => self.load_metadata(&child.id.into()).await,
+            None => Err(ResourceStoreError::NoSuchResource),
+        }
+    }
+
+    // Retrieve the list of objects matching the given tag.
+    pub async fn by_tag(&self, tag: &str) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        Ok(self.by_tag_paged(tag, None, Page::all()).await?.items)
+    }
+
+    /// Returns a page of the resources with this tag, optionally restricted
+    /// to the descendants of the `within` container.
+    pub async fn by_tag_paged(
+        &self,
+        tag: &str,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<ResourceId>, ResourceStoreError> {
+        if tag.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTagQuery".into()));
+        }
+        let within = self.search_within(within).await?;
+
+        let mut conn = self.db_pool.acquire().await?;
+        let results = self
+            .fts
+            .by_tag_in(&mut conn, tag, within.as_ref(), self.query_page(page))
+            .await?;
+        self.readable_page(results, page, |id| id).await
+    }
+
+    pub async fn by_text(
+        &self,
+        text: &str,
+        tag: Option<String>,
+    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        Ok(self
+            .by_text_paged(text, tag, None, Page::new(0, 100))
+            .await?
+            .items)
+    }
+
+    /// Returns a page of the resources matching this text, optionally restricted
+    /// to the descendants of the `within` container.
+    pub async fn by_text_paged(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<IdFrec>, ResourceStoreError> {
+        if text.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTextQuery".into()));
+        }
+        let within = self.search_within(within).await?;
+
+        let results = self
+            .fts
+            .search(text, tag, within.as_ref(), self.query_page(page))
+            .await?;
+        self.readable_page(results, page, |item| &item.id).await
+    }
+
+    /// Same as `by_text_paged()`, with the fields of each result matching the text and
+    /// snippets of their content, see `Fts::highlights()`.
+    pub async fn by_text_highlighted(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<SearchHit>, ResourceStoreError> {
+        let results = self.by_text_paged(text, tag, within, page).await?;
+        let mut items = Vec::with_capacity(results.items.len());
+        for item in results.items {
+            items.push(SearchHit {
+                highlights: self.fts.highlights(&item.id, text).await?,
+                id: item.id,
+                frecency: item.frecency,
+            });
+        }
+
+        Ok(Paged {
+            items,
+            total: results.total,
+        })
+    }
+
+    /// Same as `by_text_paged()`, collapsing the results that point at identical content,
+    /// for instance copies of the same file. See `Fts::search_collapsed()`.
+    pub async fn by_text_collapsed(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<CollapsedMatch>, ResourceStoreError> {
+        if text.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTextQuery".into()));
+        }
+        let within = self.search_within(within).await?;
+
+        let results = self
+            .fts
+            .search_collapsed(text, tag, within.as_ref(), self.query_page(page))
+            .await?;
+        self.readable_page(results, page, |item| &item.id).await
+    }
+
+    /// Returns a snapshot of the database to run searches against, so that paging
+    /// through results is not disturbed by later changes. See `SearchSnapshot`.
+    /// The capability scope and the read filter of the current caller apply to the
+    /// snapshot searches as well.
+    pub async fn snapshot(&self) -> Result<SearchSnapshot, ResourceStoreError> {
+        let scope = self.search_scope(Operations::LIST).await?;
+        SearchSnapshot::new(
+            &self.db_pool,
+            self.fts.clone(),
+            scope,
+            self.read_filter.clone(),
+            self.capability_token.clone(),
+        )
+        .await
+    }
+
+    /// Text search with results grouped by parent container, eg. to display
+    /// "results in Documents (12), in Photos (3)".
+    pub async fn by_text_grouped(
+        &self,
+        text: &str,
+        tag: Option<String>,
+        top: u32,
+    ) -> Result<Vec<ContainerMatches>, ResourceStoreError> {
+        if text.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTextQuery".into()));
+        }
+        let within = self.search_scope(Operations::LIST).await?;
+
+        // The read filter can't run in sql, so all the matches are queried when one is set
+        // to count the readable ones.
+        let query_top = if self.read_filter.is_some() {
+            u32::MAX
+        } else {
+            top
+        };
+        let mut groups = vec![];
+        for mut group in self
+            .fts
+            .search_grouped(text, tag, within.as_ref(), query_top)
+            .await?
+        {
+            if self.read_filter.is_some() {
+                group.top = self.readable_results(group.top, |item| &item.id).await?;
+                group.count = group.top.len() as u32;
+                group.top.truncate(top as usize);
+            }
+            if !group.top.is_empty() {
+                groups.push(group);
+            }
+        }
+        // Keep the most matches first once the counts are filtered.
+        groups.sort_by_key(|group| std::cmp::Reverse(group.count));
+        Ok(groups)
+    }
+
+    /// Enables typo tolerant text searches, see `Fts::set_fuzzy_threshold()`.
+    pub fn set_fuzzy_threshold(&mut self, threshold: Option<f32>) {
+        self.fts.set_fuzzy_threshold(threshold);
+    }
+
+    /// Configures how matches on each field are weighted when ranking text search results.
+    pub fn set_field_boosts(&mut self, boosts: FieldBoosts) {
+        self.fts.set_boosts(boosts);
+    }
+
+    /// Blends frecency into the ranking of text search results, see `Fts::set_frecency_weight()`.
+    pub fn set_frecency_weight(&mut self, weight: u32) {
+        self.fts.set_frecency_weight(weight);
+    }
+
+    /// Suggests tags for a resource, based on the words of its indexed content.
+    pub async fn suggest_tags(
+        &mut self,
+        id: &ResourceId,
+        limit: usize,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let metadata = self.load_metadata(id).await?;
+        let existing: Vec<String> = metadata.tags().iter().map(|tag| normalize(tag)).collect();
+
+        self.fts.keywords(id, limit, &existing).await
+    }
+
+    #[cfg(feature = "semantic")]
+    pub fn set_embedder(&mut self, embedder: Box<dyn Embedder>) {
+        self.embedder = Some(embedder);
+    }
+
+    /// Computes and stores the embedding of a resource from its indexed text.
+    /// This is not done automatically since embedders can be slow.
+    #[cfg(feature = "semantic")]
+    pub async fn update_embedding(&mut self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let embedder = self
+            .embedder
+            .as_ref()
+            .ok_or_else(|| ResourceStoreError::Custom("NoEmbedder".into()))?;
+
+        let text: Vec<String> = sqlx::query_scalar(
+            "SELECT content FROM fts_trigrams WHERE id = ? AND variant != '<ancestors>' ORDER BY rowid",
+        )
+        .bind(id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        if text.is_empty() {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+
+        let vector = to_blob(&embedder.embed(&text.join("\n")).await?);
+        sqlx::query("INSERT OR REPLACE INTO embeddings ( id, vector ) VALUES ( ?, ? )")
+            .bind(id)
+            .bind(vector)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the `k` resources with the embeddings most similar to this one,
+    /// with their cosine similarity.
+    #[cfg(feature = "semantic")]
+    pub async fn semantic_search(
+        &self,
+        query_embedding: &[f32],
+        k: usize,
+    ) -> Result<Vec<(ResourceId, f32)>, ResourceStoreError> {
+        let scope = self.search_scope(Operations::LIST).await?;
+        let rows: Vec<(ResourceId, Vec<u8>)> = sqlx::query_as(&format!(
+            r#"SELECT embeddings.id, vector FROM embeddings
+            JOIN resources ON resources.id = embeddings.id
+            WHERE {WITHIN_SUBTREE} AND {VISIBLE}"#,
+        ))
+        .bind(&scope)
+        .bind(&scope)
+        .bind(self.visibility)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut results: Vec<(ResourceId, f32)> = rows
+            .into_iter()
+            .map(|(id, vector)| {
+                let similarity = cosine_similarity(query_embedding, &from_blob(&vector));
+                (id, similarity)
+            })
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut results = self.readable_results(results, |item| &item.0).await?;
+        results.truncate(k);
+
+        Ok(results)
+    }
+
+    /// Returns up to `limit` resources related to this one, for "you might also want" suggestions.
+    /// Resources of the same kind are scored by shared tags, then by how close in time they
+    /// were visited or modified, and then by being in the same container. With semantic search
+    /// enabled, the similarity of embeddings is also accounted for.
+    pub async fn related(
+        &mut self,
+        id: &ResourceId,
+        limit: u32,
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        // Make sure the resource is in the index.
+        let _ = self.load_metadata(id).await?;
+        let scope = self.search_scope(Operations::LIST).await?;
+
+        let candidates: Vec<(ResourceId, f64)> = sqlx::query_as(&format!(
+            r#"SELECT id, score FROM (
+                SELECT resources.id, frecency(resources.scorer) AS frecency,
+                3.0 * (SELECT count(*) FROM tags AS source_tags JOIN tags ON source_tags.tag = tags.tag
+                       WHERE source_tags.id = source.id AND tags.id = resources.id)
+                + 2.0 * (abs(julianday(resources.modified) - julianday(source.modified)) < 1.0 / 24)
+                + 1.0 * (resources.parent = source.parent) AS score
+                FROM resources, (SELECT id, parent, kind, modified FROM resources WHERE id = ?) AS source
+                WHERE resources.id != source.id AND resources.id != resources.parent
+                AND resources.kind = source.kind AND {WITHIN_SUBTREE} AND {VISIBLE}
+            )
+            WHERE score > 0
+            ORDER BY score DESC, frecency DESC"#,
+        ))
+        .bind(id)
+        .bind(&scope)
+        .bind(&scope)
+        .bind(self.visibility)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        #[cfg(feature = "semantic")]
+        let candidates = {
+            let mut candidates = candidates;
+            let source: Option<Vec<u8>> =
+                sqlx::query_scalar("SELECT vector FROM embeddings WHERE id = ?")
+                    .bind(id)
+                    .fetch_optional(&self.db_pool)
+                    .await?;
+            if let Some(source) = source {
+                let source = from_blob(&source);
+                let similar = self.semantic_search(&source, limit as usize + 1).await?;
+                for (similar_id, similarity) in similar {
+                    if similar_id == *id || similarity <= 0.0 {
+                        continue;
+                    }
+                    let bonus = 3.0 * similarity as f64;
+                    match candidates.iter_mut().find(|(id, _)| *id == similar_id) {
+                        Some(candidate) => candidate.1 += bonus,
+                        None => candidates.push((similar_id, bonus)),
+                    }
+                }
+                // Stable sort, keeping the frecency order for equal scores.
+                candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+            }
+            candidates
+        };
+
+        let candidates = self.readable_results(candidates, |item| &item.0).await?;
+        Ok(candidates
+            .into_iter()
+            .take(limit as usize)
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    /// Returns up to `limit` resources likely to be accessed after this one: siblings of the
+    /// same kind that are the closest in name order (eg. the next and previous photos of an
+    /// album), the most frecent first when at the same distance.
+    pub async fn prefetch_candidates(
+        &self,
+        current: &ResourceId,
+        limit: u32,
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        self.check_access(current, Operations::READ).await?;
+        let scope = self.search_scope(Operations::LIST).await?;
+        // The read filter can't run in sql, so all the candidates are queried when one is set.
+        let query_limit: i64 = if self.read_filter.is_some() {
+            -1
+        } else {
+            limit.into()
+        };
+        let candidates: Vec<ResourceId> = sqlx::query_scalar(&format!(
+            r#"WITH target AS (SELECT id, parent FROM resources WHERE id = ?),
+            siblings AS (
+                SELECT id, kind, frecency(scorer) AS frecency,
+                row_number() OVER (ORDER BY name) AS position
+                FROM resources
+                WHERE parent = (SELECT parent FROM target) AND id != parent
+                AND {WITHIN_SUBTREE} AND ({VISIBLE} OR id = (SELECT id FROM target))
+            ),
+            current AS (SELECT id, kind, position FROM siblings WHERE id = (SELECT id FROM target))
+            SELECT siblings.id FROM siblings, current
+            WHERE siblings.id != current.id AND siblings.kind = current.kind
+            ORDER BY abs(siblings.position - current.position),
+            siblings.frecency DESC, siblings.position > current.position DESC
+            LIMIT ?"#,
+        ))
+        .bind(current)
+        .bind(&scope)
+        .bind(&scope)
+        .bind(self.visibility)
+        .bind(query_limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut candidates = self.readable_results(candidates, |id| id).await?;
+        candidates.truncate(limit as usize);
+        Ok(candidates)
+    }
+
+    /// Hints the store to prefetch this variant for the likely next accessed resources.
+    /// Returns the resources that were hinted.
+    pub async fn prefetch(
+        &self,
+        current: &ResourceId,
+        variant: &str,
+        limit: u32,
+    ) -> Result<Vec<ResourceId>, ResourceStoreError> {
+        let candidates = self.prefetch_candidates(current, limit).await?;
+        let hints: Vec<(ResourceId, String)> = candidates
+            .iter()
+            .map(|id| (id.clone(), variant.to_owned()))
+            .collect();
+        self.store.prefetch(&hints).await;
+
+        Ok(candidates)
+    }
+
+    /// Returns the most frequent indexed words, eg. to let a keyboard learn the user vocabulary.
+    /// The vocabulary covers all the resources, so capabilities restricted to a container
+    /// can't read it.
+    pub async fn top_words(&self, count: u32) -> Result<Vec<WordFrequency>, ResourceStoreError> {
+        if self.search_scope(Operations::LIST).await?.is_some() {
+            return Err(ResourceStoreError::PermissionDenied);
+        }
+        self.fts.top_words(count).await
+    }
+
+    pub async fn top_by_frecency(
+        &self,
+        tag: Option<String>,
+        count: u32,
+    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        if count == 0 {
+            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        }
+
+        Ok(self
+            .top_by_frecency_paged(tag, Page::new(0, count))
+            .await?
+            .items)
+    }
+
+    /// Re-evaluates the frecency of all the resources, so that the decay of older visits
+    /// is taken into account when sorting by frecency. This is meant to be called
+    /// periodically, for instance once a day. Returns the number of updated resources.
+    pub async fn recompute_frecencies(&mut self) -> Result<u64, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        self.update_frecencies().await
+    }
+
+    // Same as `recompute_frecencies()`, without checking the token context.
+    async fn update_frecencies(&self) -> Result<u64, ResourceStoreError> {
+        let result = sqlx::query(
+            "UPDATE resources SET frecency = frecency(scorer) WHERE frecency != frecency(scorer)",
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Replaces the algorithm computing the frecency of resources, and recomputes the stored
+    /// frecencies. Managers using other `Scoring` settings are not affected.
+    /// Returns the number of updated resources.
+    pub async fn set_scoring_policy(
+        &mut self,
+        policy: Arc<dyn ScoringPolicy>,
+    ) -> Result<u64, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        self.scoring.set_policy(policy);
+        self.update_frecencies().await
+    }
+
+    /// Returns the up to date frecency of these resources, in the same order.
+    /// Unlike the scores computed by earlier queries, these values account for
+    /// the time elapsed since the last visits.
+    pub async fn refresh_scores(
+        &mut self,
+        ids: &[ResourceId],
+    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        Ok(self
+            .get_metadata_batch(ids)
+            .await?
+            .iter()
+            .map(|meta| IdFrec::new(&meta.id(), self.scoring.frecency(meta.scorer())))
+            .collect())
+    }
+
+    /// Returns the resources sorted by frecency. The frecency of resources is updated when
+    /// they are visited, and `recompute_frecencies()` needs to be called from time to time
+    /// to account for the decay of older visits.
+    pub async fn top_by_frecency_paged(
+        &self,
+        tag: Option<String>,
+        page: Page,
+    ) -> Result<Paged<IdFrec>, ResourceStoreError> {
+        if page.limit == 0 {
+            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        }
+
+        let scope = self.search_scope(Operations::LIST).await?;
+        let query = self.query_page(page);
+
+        let (items, total): (Vec<IdFrec>, u32) = match tag {
+            None => {
+                let items = sqlx::query_as(&format!(
+                    "SELECT id, frecency FROM resources WHERE {WITHIN_SUBTREE} AND {VISIBLE} ORDER BY frecency DESC LIMIT ? OFFSET ?",
+                ))
+                .bind(&scope)
+                .bind(&scope)
+                .bind(self.visibility)
+                .bind(query.limit)
+                .bind(query.offset)
+                .fetch_all(&self.db_pool)
+                .await?;
+                let total = sqlx::query_scalar(&format!(
+                    "SELECT count(*) FROM resources WHERE {WITHIN_SUBTREE} AND {VISIBLE}"
+                ))
+                .bind(&scope)
+                .bind(&scope)
+                .bind(self.visibility)
+                .fetch_one(&self.db_pool)
+                .await?;
+                (items, total)
+            }
+            Some(tag) => {
+                let items = sqlx::query_as(&format!(
+                    r#"SELECT resources.id, resources.frecency FROM resources
+                    JOIN tags
+                    WHERE tags.tag = ?
+                    AND tags.id = resources.id
+                    AND {WITHIN_SUBTREE}
+                    AND {VISIBLE}
+                    ORDER BY resources.frecency DESC LIMIT ? OFFSET ?"#,
+                ))
+                .bind(&tag)
+                .bind(&scope)
+                .bind(&scope)
+                .bind(self.visibility)
+                .bind(query.limit)
+                .bind(query.offset)
+                .fetch_all(&self.db_pool)
+                .await?;
+                let total = sqlx::query_scalar(&format!(
+                    r#"SELECT count(*) FROM resources JOIN tags
+                    WHERE tags.tag = ? AND tags.id = resources.id AND {WITHIN_SUBTREE} AND {VISIBLE}"#
+                ))
+                .bind(&tag)
+                .bind(&scope)
+                .bind(&scope)
+                .bind(self.visibility)
+                .fetch_one(&self.db_pool)
+                .await?;
+                (items, total)
+            }
+        };
+
+        self.readable_page(Paged { items, total }, page, |item| &item.id)
+            .await
+    }
+
+    pub fn set_icon_fetcher(&mut self, fetcher: Box<dyn IconFetcher>) {
+        self.icon_fetcher = Some(fetcher);
+    }
+
+    /// Fetches the favicon of a place and stores it as its `icon` variant.
+    /// Returns whether an icon was found. The attempt is recorded either way, so that
+    /// `refresh_favicons()` doesn't retry before the refresh delay.
+    pub async fn update_favicon(&mut self, id: &ResourceId) -> Result<bool, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        self.check_access(id, Operations::WRITE).await?;
+        let (meta, mut reader) = self.get_leaf(id, "default").await?;
+        if meta.mime_type_for_variant("default").as_deref() != Some("application/x-places+json") {
+            return Err(ResourceStoreError::InvalidVariant("default".into()));
+        }
+        let mut buffer = vec![];
+        reader.read_to_end(&mut buffer).await?;
+        let place: serde_json::Value = serde_json::from_slice(&buffer)?;
+
+        let fetcher = self
+            .icon_fetcher
+            .as_ref()
+            .ok_or_else(|| ResourceStoreError::Custom("NoIconFetcher".into()))?;
+        let mut found = None;
+        for url in icon_candidates(&place) {
+            match fetcher.fetch(&url).await {
+                Ok((mime_type, content)) => {
+                    found = Some((url, mime_type, content));
+                    break;
+                }
+                Err(err) => debug!("Failed to fetch icon at {}: {}", url, err),
+            }
+        }
+
+        let url = match found {
+            Some((url, mime_type, content)) => {
+                let variant = VariantMetadata::new("icon", &mime_type, content.len() as _);
+                self.update_variant(
+                    id,
+                    Variant::new(variant, Box::new(async_std::io::Cursor::new(content))),
+                )
+                .await?;
+                url
+            }
+            None => String::new(),
+        };
+
+        let fetched = Utc::now();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO favicons ( id, url, fetched ) VALUES ( ?, ?, ? )",
+            id,
+            url,
+            fetched
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(!url.is_empty())
+    }
+
+    /// Updates the favicons of the places that were never fetched, or fetched more
+    /// than `max_age` ago. This is meant to be called periodically by the embedder.
+    /// Returns the number of icons that were found.
+    pub async fn refresh_favicons(
+        &mut self,
+        max_age: chrono::Duration,
+    ) -> Result<usize, ResourceStoreError> {
+        let before = Utc::now() - max_age;
+        let ids: Vec<ResourceId> = sqlx::query!(
+            r#"SELECT variants.id FROM variants
+            LEFT JOIN favicons ON variants.id = favicons.id
+            WHERE variants.name = 'default' AND variants.mimeType = 'application/x-places+json'
+            AND (favicons.fetched IS NULL OR favicons.fetched < ?)
+            ORDER BY variants.id"#,
+            before
+        )
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|r| r.id.into())
+        .collect();
+
+        let mut found = 0;
+        for id in ids {
+            if self.update_favicon(&id).await? {
+                found += 1;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Returns the places whose origin starts with `origin`, most frecent first.
+    /// Origins are lower case hosts without the "www." prefix, so that "exa" matches
+    /// places on "www.example.com".
+    /// This relies on the places indexer, see `crate::indexer::create_places_indexer()`.
+    pub async fn places_by_origin(
+        &self,
+        origin: &str,
+        count: u32,
+    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        if count == 0 {
+            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        }
+
+        let origin = origin.trim().to_lowercase();
+        let origin = origin.strip_prefix("www.").unwrap_or(&origin);
+        // Range query on the prefix, to use the origin index.
+        let upper = format!("{origin}\u{10FFFF}");
+        let scope = self.search_scope(Operations::LIST).await?;
+        let results: Vec<IdFrec> = sqlx::query_as(&format!(
+            r#"SELECT places.id, frecency(resources.scorer) AS frecency FROM places
+            JOIN resources ON places.id = resources.id
+            WHERE places.origin >= ? AND places.origin < ? AND {WITHIN_SUBTREE} AND {VISIBLE}
+            ORDER BY frecency DESC LIMIT ?"#,
+        ))
+        .bind(origin)
+        .bind(upper)
+        .bind(&scope)
+        .bind(&scope)
+        .bind(self.visibility)
+        .bind(count)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        self.readable_results(results, |item| &item.id).await
+    }
+
+    pub async fn last_modified(
+        &self,
+        tag: Option<String>,
+        count: u32,
+    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        if count == 0 {
+            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        }
+
+        let scope = self.search_scope(Operations::LIST).await?;
+        let results: Vec<IdFrec> = match tag {
+            None => sqlx::query_as(&format!(
+                "SELECT id, frecency(scorer) AS frecency FROM resources WHERE {WITHIN_SUBTREE} AND {VISIBLE} ORDER BY modified DESC LIMIT ?",
+            ))
+            .bind(&scope)
+            .bind(&scope)
+            .bind(self.visibility)
+            .bind(count)
+            .fetch_all(&self.db_pool)
+            .await?,
+            Some(tag) => sqlx::query_as(&format!(
+                r#"SELECT resources.id, frecency(scorer) AS frecency FROM resources
+                JOIN tags
+                WHERE tags.tag = ?
+                AND tags.id = resources.id
+                AND {WITHIN_SUBTREE}
+                AND {VISIBLE}
+                ORDER BY modified DESC LIMIT ?"#,
+            ))
+            .bind(tag)
+            .bind(&scope)
+            .bind(&scope)
+            .bind(self.visibility)
+            .bind(count)
+            .fetch_all(&self.db_pool)
+            .await?,
+        };
+
+        log::info!("last_modified({}): {:?}", count, results);
+        self.readable_results(results, |item| &item.id).await
+    }
+
+    /// Returns the most recently visited resources, last visited first.
+    /// Unlike `top_by_frecency()`, the number of visits doesn't matter, and unlike
+    /// `last_modified()` other modifications are ignored.
+    pub async fn recently_visited(&self, count: u32) -> Result<Vec<IdFrec>, ResourceStoreError> {
+        if count == 0 {
+            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        }
+
+        let scope = self.search_scope(Operations::LIST).await?;
+        let results: Vec<IdFrec> = sqlx::query_as(&format!(
+            r#"SELECT id, frecency(scorer) AS frecency FROM resources
+            WHERE last_visited IS NOT NULL AND {WITHIN_SUBTREE} AND {VISIBLE}
+            ORDER BY last_visited DESC LIMIT ?"#,
+        ))
+        .bind(&scope)
+        .bind(&scope)
+        .bind(self.visibility)
+        .bind(count)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        self.readable_results(results, |item| &item.id).await
+    }
+
+    /// Returns the last visits of a resource, oldest first. Only the most recent
+    /// ones are kept, see `Scorer::all_time_visits()` for the total count.
+    pub async fn visits(&mut self, id: &ResourceId) -> Result<Vec<VisitEntry>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let metadata = self.load_metadata(id).await?;
+        Ok(metadata.scorer().entries().to_vec())
+    }
+
+    pub async fn update_text_index<'c>(
+        &'c self,
+        metadata: &'c ResourceMetadata,
+        content: &mut Variant,
+        mut tx: Transaction<'c, Sqlite>,
+    ) -> TransactionResult<'c> {
+        if metadata.kind() == ResourceKind::Container {
+            return Ok(tx);
+        }
+
+        // Resources opted out of indexing count as indexed, with nothing to index.
+        if self.is_indexable(&metadata.id(), &mut *tx).await? {
+            for indexer in &self.indexers {
+                tx = indexer.index(metadata, content, &self.fts, tx).await?
+            }
+        }
+
+        self.mark_indexed(&metadata.id(), &mut *tx).await?;
+
+        Ok(tx)
+    }
+
+    /// Returns false if this resource, or one of its ancestors, is tagged with `NO_INDEX_TAG`.
+    /// Tagging a resource hides it from text searches right away, but the text already
+    /// indexed for its content is only dropped by `reindex()`.
+    pub async fn is_indexable<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        id: &ResourceId,
+        executor: E,
+    ) -> Result<bool, ResourceStoreError> {
+        let excluded: i64 = sqlx::query_scalar(&format!(
+            "SELECT count(*) FROM resources WHERE resources.id = ? AND NOT {INDEXABLE}"
+        ))
+        .bind(id)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(excluded == 0)
+    }
+
+    pub fn add_indexer(&mut self, indexer: Box<dyn Indexer + Send + Sync>) {
+        self.indexers.push(indexer);
+    }
+
+    /// Registers a transformer, run by `run_transformers()` and `update_stale_variants()`.
+    /// When its version is bumped, `set_transformer_version()` marks the variants it
+    /// generated as stale.
+    pub fn add_transformer(&mut self, transformer: Box<dyn Transformer + Send + Sync>) {
+        self.transformers.push(transformer);
+    }
+
+    /// Creates a leaf holding this typed content in its default variant.
+    /// The indexer for this kind of content is registered on first use, so it
+    /// should not also be added with `add_indexer()`.
+    #[cfg(feature = "codecs")]
+    pub async fn create_typed<C: ResourceCodec>(
+        &mut self,
+        parent: &ResourceId,
+        value: &C,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        if self.codec_indexers.insert(C::MIME_TYPE) {
+            self.add_indexer(C::indexer());
+        }
+
+        let content = value.encode()?;
+        let variant = VariantMetadata::new("default", C::MIME_TYPE, content.len() as _);
+        let mut metadata = ResourceMetadata::new(
+            &ResourceId::new(),
+            parent,
+            ResourceKind::Leaf,
+            &value.resource_name(),
+            vec![],
+            vec![],
+        );
+        self.create(
+            &mut metadata,
+            Some(Variant::new(
+                variant,
+                Box::new(async_std::io::Cursor::new(content)),
+            )),
+        )
+        .await?;
+
+        Ok(metadata)
+    }
+
+    /// Returns the typed content of a leaf. Fails with `InvalidVariant` if the
+    /// default variant doesn't hold this kind of content.
+    #[cfg(feature = "codecs")]
+    pub async fn get_typed<C: ResourceCodec>(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<C, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let (metadata, mut reader) = self.get_leaf(id, "default").await?;
+        if !metadata
+            .variants()
+            .iter()
+            .any(|v| v.name() == "default" && v.mime_type() == C::MIME_TYPE)
+        {
+            return Err(ResourceStoreError::InvalidVariant("default".into()));
+        }
+
+        let mut buffer = vec![];
+        reader.read_to_end(&mut buffer).await?;
+        C::decode(&buffer)
+    }
+
+    /// Returns groups of resources holding duplicate content of this type, eg. contacts
+    /// sharing a phone number or an email address, or places with the same canonical url.
+    /// Each group is a set of merge candidates.
+    #[cfg(feature = "codecs")]
+    pub async fn find_duplicates<C: Deduplicate>(
+        &mut self,
+    ) -> Result<Vec<Vec<ResourceId>>, ResourceStoreError> {
+        let scope = self.search_scope(Operations::LIST).await?;
+        let ids: Vec<ResourceId> = sqlx::query_scalar(&format!(
+            r#"SELECT variants.id FROM variants JOIN resources ON resources.id = variants.id
+            WHERE variants.name = 'default' AND mimeType = ? AND {WITHIN_SUBTREE}
+            ORDER BY variants.id"#,
+        ))
+        .bind(C::MIME_TYPE)
+        .bind(&scope)
+        .bind(&scope)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut items = Vec::with_capacity(ids.len());
+        for id in ids {
+            let value = self.get_typed::<C>(&id).await?;
+            items.push((id, value));
+        }
+
+        Ok(duplicate_groups(&items))
+    }
+
+    /// Declares the version of the current set of indexers. Resources indexed
+    /// with an older version are reported as pending until they are indexed again.
+    pub fn set_indexing_version(&mut self, version: u32) {
+        self.indexing_version = version;
+    }
+
+    async fn mark_indexed<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        id: &ResourceId,
+        executor: E,
+    ) -> Result<(), ResourceStoreError> {
+        let version = self.indexing_version;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO indexing_status ( id, version ) VALUES ( ?, ? )",
+            id,
+            version
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether this resource has been processed by the current indexers
+    /// and transformers. Containers and leaves without variants have nothing to index.
+    pub async fn indexing_status(
+        &self,
+        id: &ResourceId,
+    ) -> Result<IndexingStatus, ResourceStoreError> {
+        if !self.has_object(id).await? {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        self.check_access(id, Operations::READ).await?;
+
+        let leaf = ResourceKind::Leaf;
+        let version = self.indexing_version;
+        let pending = sqlx::query_scalar!(
+            r#"SELECT count(*) FROM resources
+            LEFT JOIN indexing_status ON indexing_status.id = resources.id
+            WHERE resources.id = ? AND resources.kind = ?
+            AND EXISTS (SELECT 1 FROM variants WHERE variants.id = resources.id)
+            AND (indexing_status.version IS NULL OR indexing_status.version < ?
+                 OR EXISTS (SELECT 1 FROM derived_variants
+                            WHERE derived_variants.id = resources.id AND derived_variants.stale = 1))"#,
+            id,
+            leaf,
+            version
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(if pending == 0 {
+            IndexingStatus::Indexed
+        } else {
+            IndexingStatus::Pending
+        })
+    }
+
+    /// Returns the indexing progress over all the leaves that have content.
+    pub async fn indexing_progress(&self) -> Result<IndexingProgress, ResourceStoreError> {
+        let leaf = ResourceKind::Leaf;
+        let version = self.indexing_version;
+        let record = sqlx::query!(
+            r#"SELECT count(*) AS "total!: u32",
+            coalesce(sum(indexing_status.version IS NOT NULL AND indexing_status.version >= ?
+                AND NOT EXISTS (SELECT 1 FROM derived_variants
+                                WHERE derived_variants.id = resources.id AND derived_variants.stale = 1
+                                AND derived_variants.evicted = 0)), 0)
+                AS "indexed!: u32"
+            FROM resources
+            LEFT JOIN indexing_status ON indexing_status.id = resources.id
+            WHERE resources.kind = ?
+            AND EXISTS (SELECT 1 FROM variants WHERE variants.id = resources.id)"#,
+            version,
+            leaf
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(IndexingProgress {
+            indexed: record.indexed,
+            total: record.total,
+        })
+    }
+
+    /// Records that a variant was generated from another one by a given transformer.
+    /// This also clears the stale state of the derived variant, so it should be
+    /// called each time the derived variant is (re)generated.
+    /// Other derived variants are evicted if this one exceeds the size budget.
+    pub async fn record_derived_variant(
+        &mut self,
+        derived: &DerivedVariant,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(&derived.id, Operations::WRITE).await?;
+        self.save_derived_variant(derived).await
+    }
+
+    // Same as `record_derived_variant()`, without checking access.
+    async fn save_derived_variant(
+        &mut self,
+        derived: &DerivedVariant,
+    ) -> Result<(), ResourceStoreError> {
+        sqlx::query!(
+            r#"INSERT OR REPLACE INTO derived_variants ( id, variant, source, transformer, version, stale, evicted )
+            VALUES ( ?, ?, ?, ?, ?, 0, 0 )"#,
+            derived.id,
+            derived.variant,
+            derived.source,
+            derived.transformer,
+            derived.version,
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        self.evict_derived_variants(Some((&derived.id, &derived.variant)))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Attaches application private state to a resource, as a sidecar variant named after
+    /// the application id and the name of `content`, see `VariantMetadata::sidecar_name()`.
+    /// Sidecars are regular variants otherwise, replaced when set again.
+    pub async fn set_sidecar(
+        &mut self,
+        id: &ResourceId,
+        app_id: &str,
+        mut content: Variant,
+        options: SidecarOptions,
+    ) -> Result<(), ResourceStoreError> {
+        let variant = VariantMetadata::sidecar_name(app_id, &content.metadata.name())?;
+        content.metadata.set_name(&variant);
+        self.check_access(id, Operations::WRITE).await?;
+        self.check_bytes(content.metadata.size())?;
+        let metadata = self.load_metadata(id).await?;
+
+        // Rolled back with the variant update if the store update fails.
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query!(
+            r#"INSERT OR REPLACE INTO sidecar_variants ( id, variant, app, no_quota, no_sync )
+            VALUES ( ?, ?, ?, ?, ? )"#,
+            id,
+            variant,
+            app_id,
+            options.exclude_from_quota,
+            options.exclude_from_sync,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        self.update_variant_in(metadata, content, None, tx).await
+    }
+
+    /// Returns the content of the sidecar `name` of this application.
+    pub async fn get_sidecar(
+        &mut self,
+        id: &ResourceId,
+        app_id: &str,
+        name: &str,
+    ) -> Result<(ResourceMetadata, BoxedReader), ResourceStoreError> {
+        let variant = VariantMetadata::sidecar_name(app_id, name)?;
+        self.get_leaf(id, &variant).await
+    }
+
+    /// Returns the names of the sidecars of this application, without their prefix.
+    pub async fn sidecars(
+        &mut self,
+        id: &ResourceId,
+        app_id: &str,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let prefix = VariantMetadata::sidecar_name(app_id, "_")?;
+        let prefix = prefix.trim_end_matches('_');
+        let metadata = self.load_metadata(id).await?;
+
+        Ok(metadata
+            .variants()
+            .iter()
+            .filter_map(|variant| {
+                variant
+                    .name()
+                    .strip_prefix(prefix)
+                    .map(|name| name.to_owned())
+            })
+            .collect())
+    }
+
+    pub async fn delete_sidecar(
+        &mut self,
+        id: &ResourceId,
+        app_id: &str,
         name: &str,
-    ) -> Result<ResourceMetadata, ResourceStoreError> {
-        if name.trim().is_empty() {
-            return Err(ResourceStoreError::Custom("EmptyNameQuery".into()));
+    ) -> Result<(), ResourceStoreError> {
+        let variant = VariantMetadata::sidecar_name(app_id, name)?;
+        self.delete_variant(id, &variant).await
+    }
+
+    /// Returns the total size of the variants counted against storage quotas, which
+    /// leaves out the sidecars excluded with `SidecarOptions::exclude_from_quota`.
+    /// This covers all the resources, so it is denied to capabilities restricted to a container.
+    pub async fn quota_usage(&self) -> Result<u64, ResourceStoreError> {
+        if self.search_scope(Operations::READ).await?.is_some() {
+            return Err(ResourceStoreError::PermissionDenied);
         }
+        let record = sqlx::query!(
+            r#"SELECT COALESCE(SUM(size), 0) AS "size!: i64" FROM variants
+            WHERE NOT EXISTS (SELECT 1 FROM sidecar_variants
+                              WHERE sidecar_variants.id = variants.id
+                              AND sidecar_variants.variant = variants.name
+                              AND sidecar_variants.no_quota = 1)"#
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
 
+        Ok(record.size as _)
+    }
+
+    /// Sets the maximum total size of the derived variants, or None for no limit.
+    /// Derived variants are treated as a cache: when over budget, the ones of the least
+    /// frecent resources are removed. They keep their derivation record, so that they
+    /// can be regenerated on demand, see `evicted_variant()`.
+    /// Returns the variants evicted to fit in the new budget.
+    pub async fn set_derived_variants_budget(
+        &mut self,
+        budget: Option<u64>,
+    ) -> Result<Vec<(ResourceId, String)>, ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        self.derived_variants_budget = budget;
+        self.evict_derived_variants(None).await
+    }
+
+    /// Returns the total size of the derived variants.
+    pub async fn derived_variants_size(&self) -> Result<u64, ResourceStoreError> {
         let record = sqlx::query!(
-            "SELECT id FROM resources WHERE parent = ? AND name = ?",
-            parent,
-            name,
+            r#"SELECT COALESCE(SUM(variants.size), 0) AS "size!: i64" FROM derived_variants
+            JOIN variants ON variants.id = derived_variants.id AND variants.name = derived_variants.variant
+            WHERE derived_variants.evicted = 0"#
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(record.size as _)
+    }
+
+    // Evicts derived variants until their total size is within budget, except `keep`.
+    // Returns the evicted variants.
+    async fn evict_derived_variants(
+        &mut self,
+        keep: Option<(&ResourceId, &String)>,
+    ) -> Result<Vec<(ResourceId, String)>, ResourceStoreError> {
+        let budget = match self.derived_variants_budget {
+            Some(budget) => budget,
+            None => return Ok(vec![]),
+        };
+        let mut size = self.derived_variants_size().await?;
+        if size <= budget {
+            return Ok(vec![]);
+        }
+
+        let candidates: Vec<(ResourceId, String, String, String, u32, i64)> = sqlx::query_as(
+            r#"SELECT derived_variants.id, variant, source, transformer, version, variants.size
+            FROM derived_variants
+            JOIN variants ON variants.id = derived_variants.id AND variants.name = derived_variants.variant
+            JOIN resources ON resources.id = derived_variants.id
+            WHERE derived_variants.evicted = 0
+            AND NOT EXISTS (SELECT 1 FROM required_variants
+                            WHERE required_variants.id = resources.parent
+                            AND required_variants.variant = derived_variants.variant)
+            ORDER BY frecency(resources.scorer) ASC, resources.modified ASC"#,
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut evicted = vec![];
+        for (id, variant, source, transformer, version, variant_size) in candidates {
+            if size <= budget {
+                break;
+            }
+            let derived = DerivedVariant {
+                id,
+                variant,
+                source,
+                transformer,
+                version,
+            };
+            if keep == Some((&derived.id, &derived.variant)) {
+                continue;
+            }
+
+            // Deleting the variant also forgets its derivation, so record it again.
+            self.remove_variant(&derived.id, &derived.variant).await?;
+            sqlx::query!(
+                r#"INSERT OR REPLACE INTO derived_variants ( id, variant, source, transformer, version, stale, evicted )
+                VALUES ( ?, ?, ?, ?, ?, 0, 1 )"#,
+                derived.id,
+                derived.variant,
+                derived.source,
+                derived.transformer,
+                derived.version,
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            size = size.saturating_sub(variant_size as u64);
+            evicted.push((derived.id, derived.variant));
+        }
+
+        Ok(evicted)
+    }
+
+    /// Returns how to regenerate this variant if it was evicted to stay within the size
+    /// budget of derived variants. Reading an evicted variant fails with
+    /// `ResourceStoreError::EvictedVariant` until it is regenerated.
+    pub async fn evicted_variant(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<Option<DerivedVariant>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        self.find_evicted_variant(id, variant).await
+    }
+
+    // Same as `evicted_variant()`, without checking access.
+    async fn find_evicted_variant(
+        &self,
+        id: &ResourceId,
+        variant: &str,
+    ) -> Result<Option<DerivedVariant>, ResourceStoreError> {
+        let result: Option<DerivedVariant> = sqlx::query_as(
+            r#"SELECT id, variant, source, transformer, version FROM derived_variants
+            WHERE id = ? AND variant = ? AND evicted = 1"#,
         )
+        .bind(id)
+        .bind(variant)
         .fetch_optional(&self.db_pool)
         .await?;
 
-        match record {
-            Some(child) => self.get_metadata(&child.id.into()).await,
-            None => Err(ResourceStoreError::NoSuchResource),
+        Ok(result)
+    }
+
+    /// Declares the current version of a transformer. Variants generated by
+    /// an older version are marked as stale.
+    pub async fn set_transformer_version(
+        &self,
+        transformer: &str,
+        version: u32,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(&ROOT_ID, Operations::WRITE).await?;
+        sqlx::query!(
+            "UPDATE derived_variants SET stale = 1 WHERE transformer = ? AND version < ?",
+            transformer,
+            version
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the derived variants that need to be regenerated, either because their
+    /// source changed or because their transformer version was bumped.
+    /// Resources opted out of indexing with `NO_INDEX_TAG` are left out.
+    pub async fn stale_variants(&self) -> Result<Vec<DerivedVariant>, ResourceStoreError> {
+        let scope = self.search_scope(Operations::READ).await?;
+        self.find_stale_variants(&scope).await
+    }
+
+    // Same as `stale_variants()`, restricted to the descendants of `scope` if any.
+    async fn find_stale_variants(
+        &self,
+        scope: &Option<ResourceId>,
+    ) -> Result<Vec<DerivedVariant>, ResourceStoreError> {
+        let results: Vec<DerivedVariant> = sqlx::query_as(&format!(
+            r#"SELECT id, variant, source, transformer, version FROM derived_variants
+            WHERE stale = 1 AND evicted = 0
+            AND id IN (SELECT resources.id FROM resources WHERE {INDEXABLE} AND {WITHIN_SUBTREE})"#
+        ))
+        .bind(scope)
+        .bind(scope)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(results)
+    }
+
+    /// Runs the registered transformers on a leaf, storing the variants they generate and
+    /// recording their derivation. Returns the names of the generated variants.
+    /// Transformers that fail don't prevent the other ones from running, their failure
+    /// is recorded instead, see `failed_transformations()`.
+    pub async fn run_transformers(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        let mut generated = vec![];
+        for index in 0..self.transformers.len() {
+            generated.extend(self.run_transformer(id, index).await?);
         }
+
+        Ok(generated)
     }
 
-    // Retrieve the list of objects matching the given tag.
-    // TODO: pagination
-    pub async fn by_tag(&self, tag: &str) -> Result<Vec<ResourceId>, ResourceStoreError> {
-        if tag.trim().is_empty() {
-            return Err(ResourceStoreError::Custom("EmptyTagQuery".into()));
+    /// Generates the stale derived variants again with the registered transformers.
+    /// Variants of transformers that are not registered are left as is.
+    /// Returns the number of generated variants. Like `refresh_favicons()`, this is meant
+    /// to be called periodically by the embedder.
+    pub async fn update_stale_variants(&mut self) -> Result<usize, ResourceStoreError> {
+        // A transformer generating several variants only runs once per resource.
+        let mut pending = BTreeSet::new();
+        for derived in self.stale_variants().await? {
+            if let Some(index) = self
+                .transformers
+                .iter()
+                .position(|transformer| transformer.name() == derived.transformer)
+            {
+                pending.insert((derived.id.to_string(), index));
+            }
         }
 
-        let results: Vec<ResourceId> = sqlx::query_as(
-            r#"SELECT resources.id FROM resources
-            JOIN tags
-            WHERE tags.tag = ? and tags.id = resources.id
-            ORDER BY frecency(resources.scorer) DESC"#,
+        let mut generated = 0;
+        for (id, index) in pending {
+            let id = id.into();
+            self.check_access(&id, Operations::WRITE).await?;
+            generated += self.run_transformer(&id, index).await?.len();
+        }
+
+        Ok(generated)
+    }
+
+    // Runs the transformer at this index on a leaf, if it has the source variant.
+    async fn run_transformer(
+        &mut self,
+        id: &ResourceId,
+        index: usize,
+    ) -> Result<Vec<String>, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let meta = self.load_metadata(id).await?;
+        let transformer = &self.transformers[index];
+        let source = match meta
+            .variants()
+            .iter()
+            .find(|v| v.name() == transformer.source())
+        {
+            Some(source) if meta.kind() == ResourceKind::Leaf => source.clone(),
+            _ => return Ok(vec![]),
+        };
+        self.check_bytes(source.size())?;
+        let mut content = vec![];
+        self.store
+            .get_variant(id, &source.name())
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        let transformer_name = transformer.name().to_owned();
+        let version = transformer.version();
+        let variants = match transformer.transform(&meta, &source, &content).await {
+            Ok(variants) => variants,
+            Err(err) => {
+                error!("Transformer {} failed on {}: {}", transformer_name, id, err);
+                self.record_transformer_failure(id, &transformer_name, &err)
+                    .await?;
+                return Ok(vec![]);
+            }
+        };
+        sqlx::query!(
+            "DELETE FROM transformer_failures WHERE id = ? AND transformer = ?",
+            id,
+            transformer_name
         )
-        .bind(tag)
+        .execute(&self.db_pool)
+        .await?;
+        let mut generated = vec![];
+        for variant in variants {
+            let derived = DerivedVariant {
+                id: id.clone(),
+                variant: variant.metadata.name(),
+                source: source.name(),
+                transformer: transformer_name.clone(),
+                version,
+            };
+            self.update_variant(id, variant).await?;
+            self.save_derived_variant(&derived).await?;
+            generated.push(derived.variant);
+        }
+
+        Ok(generated)
+    }
+
+    async fn record_transformer_failure(
+        &self,
+        id: &ResourceId,
+        transformer: &str,
+        err: &ResourceStoreError,
+    ) -> Result<(), ResourceStoreError> {
+        let error = err.to_string();
+        let now = Utc::now();
+        sqlx::query!(
+            r#"INSERT INTO transformer_failures ( id, transformer, error, attempts, last_attempt )
+            VALUES ( ?, ?, ?, 1, ? )
+            ON CONFLICT(id, transformer) DO UPDATE
+            SET error = excluded.error, attempts = attempts + 1, last_attempt = excluded.last_attempt"#,
+            id,
+            transformer,
+            error,
+            now
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the transformers that failed on resources, most recent attempt first.
+    /// Failures are forgotten when the transformer succeeds on the resource again.
+    pub async fn failed_transformations(
+        &self,
+    ) -> Result<Vec<TransformerFailure>, ResourceStoreError> {
+        let scope = self.search_scope(Operations::READ).await?;
+        let results: Vec<TransformerFailure> = sqlx::query_as(&format!(
+            r#"SELECT id, transformer, error, attempts, last_attempt FROM transformer_failures
+            WHERE id IN (SELECT resources.id FROM resources WHERE {WITHIN_SUBTREE})
+            ORDER BY last_attempt DESC, id, transformer"#
+        ))
+        .bind(&scope)
+        .bind(&scope)
         .fetch_all(&self.db_pool)
         .await?;
 
         Ok(results)
     }
 
-    pub async fn by_text(
+    /// Runs the failed transformations selected by `filter` again, eg. after fixing the
+    /// content or upgrading a transformer. Failures of transformers that are not
+    /// registered are left as is. Returns the number of generated variants.
+    pub async fn retry_transformations(
+        &mut self,
+        filter: &TransformationFilter,
+    ) -> Result<usize, ResourceStoreError> {
+        let mut generated = 0;
+        for failure in self.failed_transformations().await? {
+            if filter.id.as_ref().is_some_and(|id| *id != failure.id)
+                || filter
+                    .transformer
+                    .as_ref()
+                    .is_some_and(|name| *name != failure.transformer)
+            {
+                continue;
+            }
+            if let Some(index) = self
+                .transformers
+                .iter()
+                .position(|transformer| transformer.name() == failure.transformer)
+            {
+                self.check_access(&failure.id, Operations::WRITE).await?;
+                generated += self.run_transformer(&failure.id, index).await?.len();
+            }
+        }
+
+        Ok(generated)
+    }
+
+    /// Returns a collage of `size` pixels of the thumbnails of the first children of a
+    /// container by name, so that folder tiles don't need to read each thumbnail.
+    /// The collage is kept as the `collage` variant of the container, and composed again
+    /// when these children or their thumbnails change. Returns None when no child has
+    /// a thumbnail.
+    #[cfg(feature = "transformers")]
+    pub async fn container_collage(
+        &mut self,
+        id: &ResourceId,
+        size: u32,
+    ) -> Result<Option<Variant>, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        self.check_access(id, Operations::READ).await?;
+        let mut meta = self.load_metadata(id).await?;
+        if meta.kind() != ResourceKind::Container || !self.is_readable(&meta) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+
+        let children: Vec<ResourceId> = sqlx::query_as(&format!(
+            r#"SELECT resources.id FROM resources
+            JOIN variants ON variants.id = resources.id AND variants.name = 'thumbnail'
+            WHERE parent = ? AND parent != resources.id AND {VISIBLE}
+            ORDER BY resources.name"#
+        ))
+        .bind(id)
+        .bind(Visibility::Visible)
+        .fetch_all(&self.db_pool)
+        .await?;
+        let children: Vec<ResourceMetadata> = self
+            .load_metadata_batch(&children)
+            .await?
+            .into_iter()
+            .filter(|child| self.is_readable(child))
+            .take(COLLAGE_SIZE)
+            .collect();
+        if children.is_empty() {
+            return Ok(None);
+        }
+
+        // The collage is composed again when any of its sources is modified.
+        let mut sources = size.to_string();
+        for child in &children {
+            sources.push_str(&format!(
+                " {}@{}",
+                child.id(),
+                child.modified().timestamp_micros()
+            ));
+        }
+        let composed = sqlx::query_scalar!("SELECT sources FROM collages WHERE id = ?", id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        let collage = meta
+            .variants()
+            .iter()
+            .find(|v| v.name() == "collage")
+            .cloned();
+        // The collage of the children readable through the read filter is not kept.
+        if let (Some(collage), Some(composed), None) = (collage, composed, &self.read_filter) {
+            if composed == sources {
+                let content = self.store.get_variant(id, "collage").await?;
+                return Ok(Some(Variant::new(collage, content)));
+            }
+        }
+
+        let mut thumbnails = vec![];
+        for child in &children {
+            let mut content = vec![];
+            self.store
+                .get_variant(&child.id(), "thumbnail")
+                .await?
+                .read_to_end(&mut content)
+                .await?;
+            thumbnails.push(content);
+        }
+        let content = compose_collage(&thumbnails, size)?;
+        let collage = VariantMetadata::new("collage", "image/jpeg", content.len() as _);
+        if self.read_filter.is_some() {
+            return Ok(Some(Variant::new(
+                collage,
+                Box::new(async_std::io::Cursor::new(content)),
+            )));
+        }
+
+        // Not a modification of the container, so its revision and date are kept.
+        meta.add_or_update_variant(collage.clone());
+        let tx = self.db_pool.begin().await?;
+        let mut tx = self.update_metadata(&meta, tx).await?;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO collages ( id, sources ) VALUES ( ?, ? )",
+            id,
+            sources
+        )
+        .execute(&mut *tx)
+        .await?;
+        let hash = self
+            .store
+            .update(
+                &meta,
+                Some(Variant::new(
+                    collage.clone(),
+                    Box::new(async_std::io::Cursor::new(content.clone())),
+                )),
+            )
+            .await
+            .map_err(|err| self.record_error("update", id, err))?;
+        let variants = self
+            .record_hashes(&meta, &[("collage".into(), hash)], &mut tx)
+            .await?;
+        tx.commit().await?;
+        meta.set_variants(variants);
+        self.update_cache(&meta);
+        self.save_derived_variant(&DerivedVariant {
+            id: id.clone(),
+            variant: "collage".into(),
+            source: "default".into(),
+            transformer: "collage".into(),
+            version: 1,
+        })
+        .await?;
+        self.notify_observers(&ResourceModification::VariantChanged(ResourceVariant::new(
+            id, "collage",
+        )));
+
+        Ok(Some(Variant::new(
+            collage,
+            Box::new(async_std::io::Cursor::new(content)),
+        )))
+    }
+
+    pub async fn close(&self) {
+        self.db_pool.close().await
+    }
+
+    /// Shuts the manager down: pending database work is flushed to disk, the metadata cache
+    /// is emptied and the session is marked as cleanly closed before closing the database.
+    /// Unlike `close()`, the next startup won't run the recovery checks.
+    /// The manager can't be used afterwards.
+    pub async fn shutdown(&mut self) -> Result<(), ResourceStoreError> {
+        if self.config.access_mode == AccessMode::ReadOnly {
+            self.cache.clear();
+            if self.owns_pool {
+                self.db_pool.close().await;
+            }
+            return Ok(());
+        }
+
+        // Drop the results of idempotent operations that can't be replayed anymore.
+        let expired = Utc::now() - self.idempotency_ttl;
+        sqlx::query!("DELETE FROM idempotency_keys WHERE created < ?", expired)
+            .execute(&self.db_pool)
+            .await?;
+
+        self.cache.clear();
+
+        sqlx::query!("UPDATE shutdown_state SET clean = 1")
+            .execute(&self.db_pool)
+            .await?;
+        sqlx::query("PRAGMA optimize")
+            .execute(&self.db_pool)
+            .await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.db_pool)
+            .await?;
+
+        if self.owns_pool {
+            self.db_pool.close().await;
+        }
+        if let Some(lock) = self.access_lock.take() {
+            lock.close().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the previous session ended without calling `shutdown()`, for
+    /// instance after a crash. In that case the startup self-test runs a full integrity check
+    /// of the database.
+    pub fn unclean_shutdown(&self) -> bool {
+        self.unclean_shutdown
+    }
+
+    pub async fn create(
+        &mut self,
+        metadata: &mut ResourceMetadata,
+        content: Option<Variant>,
+    ) -> Result<(), ResourceStoreError> {
+        self.create_with_variants(metadata, content.into_iter().collect())
+            .await
+    }
+
+    /// Creates a resource with several variants at once, for instance the default variant
+    /// and a thumbnail already made by the client. Either all the variants are stored or
+    /// the resource is not created.
+    pub async fn create_with_variants(
+        &mut self,
+        metadata: &mut ResourceMetadata,
+        mut variants: Vec<Variant>,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_container_leaf(&metadata.id(), &metadata.parent())
+            .await?;
+        self.check_access(&metadata.parent(), Operations::WRITE)
+            .await?;
+
+        let mut names = HashSet::new();
+        for content in variants.iter_mut() {
+            if !names.insert(content.metadata.name()) {
+                return Err(ResourceStoreError::InvalidVariant(content.metadata.name()));
+            }
+            // The store computes the hash when writing the content.
+            content.metadata.set_hash(None);
+            metadata.add_or_update_variant(content.metadata.clone());
+        }
+        self.check_required_variants(&metadata.parent(), metadata)
+            .await?;
+        metadata.bump_rev();
+        let has_content = !variants.is_empty();
+
+        // Start a transaction to store the new metadata.
+        let tx = self.db_pool.begin().await?;
+        let mut tx2 = self.create_metadata(metadata, tx).await?;
+
+        // Update the children content of the parent if this is not creating the root.
+        if !metadata.id().is_root() {
+            self.update_container_content(&metadata.parent(), &mut *tx2)
+                .await?;
+        }
+
+        // If there is content run the text indexer for this mime type.
+        if variants.is_empty() && metadata.kind() == ResourceKind::Leaf {
+            self.mark_indexed(&metadata.id(), &mut *tx2).await?;
+        }
+        let mut tx3 = tx2;
+        for content in variants.iter_mut() {
+            tx3 = self.update_text_index(metadata, content, tx3).await?;
+        }
+
+        // Create the store entry, and commit the SQlite transaction in case of success.
+        match self.store_new_resource(metadata, variants).await {
+            Ok(hashes) => {
+                let variants = if has_content {
+                    Some(self.record_hashes(metadata, &hashes, &mut tx3).await?)
+                } else {
+                    None
+                };
+                tx3.commit().await?;
+                if let Some(variants) = variants {
+                    metadata.set_variants(variants);
+                }
+                self.update_cache(metadata);
+                // Trigger observers once we have committed all changes.
+                let id = metadata.id();
+                let parent = metadata.parent();
+                self.notify_observers(&ResourceModification::Created(id.clone()));
+                if !id.is_root() {
+                    self.notify_observers(&ResourceModification::Modified(parent.clone()));
+                    self.notify_observers(&ResourceModification::ChildCreated(ParentChild::new(
+                        &parent, &id,
+                    )));
+                }
+                Ok(())
+            }
+            Err(err) => {
+                // Roll back the database changes, and restore the children list of
+                // the parent in the store.
+                drop(tx3);
+                let parent = metadata.parent();
+                if !metadata.id().is_root() {
+                    if let Err(restore_err) =
+                        self.update_container_content(&parent, &self.db_pool).await
+                    {
+                        error!(
+                            "Failed to restore the children of #{}: {}",
+                            parent, restore_err
+                        );
+                    }
+                }
+                Err(self.record_error("create", &metadata.id(), err))
+            }
+        }
+    }
+
+    // Records the content hashes returned by the store when writing the variants, as
+    // (variant name, hash) pairs. Returns the variants with their hashes.
+    async fn record_hashes(
         &self,
-        text: &str,
-        tag: Option<String>,
-    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
-        if text.trim().is_empty() {
-            return Err(ResourceStoreError::Custom("EmptyTextQuery".into()));
+        metadata: &ResourceMetadata,
+        hashes: &[(String, Option<String>)],
+        conn: &mut sqlx::SqliteConnection,
+    ) -> Result<Vec<VariantMetadata>, ResourceStoreError> {
+        let id = metadata.id();
+        let mut variants = metadata.variants().clone();
+        for (name, hash) in hashes {
+            let variant = match variants.iter_mut().find(|v| v.name() == *name) {
+                Some(variant) if variant.hash() != *hash => variant,
+                _ => continue,
+            };
+            sqlx::query!(
+                "UPDATE variants SET hash = ? WHERE id = ? AND name = ?",
+                hash,
+                id,
+                name
+            )
+            .execute(&mut *conn)
+            .await?;
+            variant.set_hash(hash.as_deref());
         }
 
-        self.fts.search(text, tag).await
+        if self.index_storage_names {
+            let mut metadata = metadata.clone();
+            metadata.set_variants(variants.clone());
+            self.update_storage_names(&metadata, conn).await?;
+        }
+        Ok(variants)
     }
 
-    pub async fn top_by_frecency(
+    // Creates the store entry with its first variant and adds the other ones, removing
+    // the entry if one of them fails. Returns the hashes of the variants, see
+    // `record_hashes()`.
+    async fn store_new_resource(
         &self,
-        tag: Option<String>,
-        count: u32,
-    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
-        if count == 0 {
-            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+        metadata: &ResourceMetadata,
+        variants: Vec<Variant>,
+    ) -> Result<Vec<(String, Option<String>)>, ResourceStoreError> {
+        let mut hashes = Vec::with_capacity(variants.len());
+        let mut variants = variants.into_iter();
+        let first = variants.next();
+        let first_name = first.as_ref().map(|content| content.metadata.name());
+        let hash = self.store.create(metadata, first).await?;
+        hashes.extend(first_name.map(|name| (name, hash)));
+        for content in variants {
+            let name = content.metadata.name();
+            match self.store.update(metadata, Some(content)).await {
+                Ok(hash) => hashes.push((name, hash)),
+                Err(err) => {
+                    if let Err(delete_err) = self.store.delete(&metadata.id()).await {
+                        error!(
+                            "Failed to remove #{} after a failed creation: {}",
+                            metadata.id(),
+                            delete_err
+                        );
+                    }
+                    return Err(err);
+                }
+            }
         }
 
-        let results: Vec<IdFrec> = match tag {
-            None => sqlx::query_as(
-                "SELECT id, frecency(scorer) AS frecency FROM resources ORDER BY frecency DESC LIMIT ?",
-            )
-            .bind(count)
-            .fetch_all(&self.db_pool)
-            .await?,
-            Some(tag) => sqlx::query_as(
-                r#"SELECT resources.id, frecency(scorer) AS frecency FROM resources
-                JOIN tags
-                WHERE tags.tag = ?
-                AND tags.id = resources.id
-                ORDER BY frecency DESC LIMIT ?"#,
-            )
-            .bind(tag)
-            .bind(count)
-            .fetch_all(&self.db_pool)
-            .await?,
-        };
+        Ok(hashes)
+    }
 
-        Ok(results)
+    /// Creates many resources at once, sharing a single transaction and updating
+    /// the parent containers content only once at the end.
+    /// Parents need to be listed before their children. Each item gets its own result,
+    /// and a failing item doesn't prevent the others from being created.
+    /// Observers get the events of the batch coalesced, see `begin_batch()`.
+    pub async fn create_batch(
+        &mut self,
+        items: Vec<(ResourceMetadata, Option<Variant>)>,
+    ) -> Result<Vec<Result<(), ResourceStoreError>>, ResourceStoreError> {
+        self.begin_batch();
+        let results = self.create_batch_items(items).await;
+        self.end_batch();
+        results
     }
 
-    pub async fn last_modified(
-        &self,
-        tag: Option<String>,
-        count: u32,
-    ) -> Result<Vec<IdFrec>, ResourceStoreError> {
-        if count == 0 {
-            return Err(ResourceStoreError::Custom("ZeroCountQuery".into()));
+    async fn create_batch_items(
+        &mut self,
+        items: Vec<(ResourceMetadata, Option<Variant>)>,
+    ) -> Result<Vec<Result<(), ResourceStoreError>>, ResourceStoreError> {
+        let mut results = Vec::with_capacity(items.len());
+        let mut created: Vec<ResourceMetadata> = vec![];
+        let mut created_ids: HashSet<ResourceId> = HashSet::new();
+
+        let mut tx = self.db_pool.begin().await?;
+
+        for (mut metadata, content) in items {
+            // Access to containers created in this batch was checked with their own parent.
+            if !created_ids.contains(&metadata.parent()) {
+                if let Err(err) = self
+                    .check_access(&metadata.parent(), Operations::WRITE)
+                    .await
+                {
+                    results.push(Err(err));
+                    continue;
+                }
+            }
+
+            match self.create_in_batch(&mut metadata, content, &mut tx).await {
+                Ok(()) => {
+                    created_ids.insert(metadata.id());
+                    created.push(metadata);
+                    results.push(Ok(()));
+                }
+                Err(err) => results.push(Err(err)),
+            }
         }
 
-        let results: Vec<IdFrec> = match tag {
-            None => sqlx::query_as(
-                "SELECT id, frecency(scorer) AS frecency FROM resources ORDER BY modified DESC LIMIT ?",
-            )
-            .bind(count)
-            .fetch_all(&self.db_pool)
-            .await?,
-            Some(tag) => sqlx::query_as(
-                r#"SELECT resources.id, frecency(scorer) AS frecency FROM resources
-                JOIN tags
-                WHERE tags.tag = ?
-                AND tags.id = resources.id
-                ORDER BY modified DESC LIMIT ?"#,
-            )
-            .bind(tag)
-            .bind(count)
-            .fetch_all(&self.db_pool)
-            .await?,
-        };
+        // Update the children content of all the modified parents.
+        let parents: HashSet<ResourceId> = created
+            .iter()
+            .filter(|metadata| !metadata.id().is_root())
+            .map(|metadata| metadata.parent())
+            .collect();
+        for parent in &parents {
+            self.update_container_content(parent, &mut *tx).await?;
+        }
+
+        tx.commit().await?;
+
+        // Trigger observers once we have committed all changes.
+        for metadata in created {
+            self.update_cache(&metadata);
+            let id = metadata.id();
+            let parent = metadata.parent();
+            self.notify_observers(&ResourceModification::Created(id.clone()));
+            if !id.is_root() {
+                self.notify_observers(&ResourceModification::ChildCreated(ParentChild::new(
+                    &parent, &id,
+                )));
+            }
+        }
+        for parent in parents {
+            self.notify_observers(&ResourceModification::Modified(parent));
+        }
 
-        log::info!("last_modified({}): {:?}", count, results);
         Ok(results)
     }
 
-    pub async fn update_text_index<'c>(
-        &'c self,
-        metadata: &'c ResourceMetadata,
-        content: &mut Variant,
-        mut tx: Transaction<'c, Sqlite>,
-    ) -> TransactionResult<'c> {
-        if metadata.kind() == ResourceKind::Container {
-            return Ok(tx);
+    // Creates a single resource of a batch, in a savepoint of the batch transaction.
+    async fn create_in_batch(
+        &mut self,
+        metadata: &mut ResourceMetadata,
+        mut content: Option<Variant>,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<(), ResourceStoreError> {
+        let id = metadata.id();
+        let parent = metadata.parent();
+        if parent == id && !parent.is_root() {
+            error!("Only the root can be its own container.");
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+        // Check that the parent is a known container, except when we create the root.
+        if !id.is_root() && !self.is_container_in_tx(&parent, &mut **tx).await? {
+            error!("Resource #{} is not a container", parent);
+            return Err(ResourceStoreError::InvalidContainerId);
         }
 
-        for indexer in &self.indexers {
-            tx = indexer.index(metadata, content, &self.fts, tx).await?
+        if let Some(content) = &mut content {
+            content.metadata.set_hash(None);
+            metadata.add_or_update_variant(content.metadata.clone());
         }
+        self.check_required_variants(&parent, metadata).await?;
+        metadata.bump_rev();
 
-        Ok(tx)
+        let savepoint = tx.begin().await?;
+        let mut savepoint = self.create_metadata(metadata, savepoint).await?;
+
+        let savepoint = if let Some(ref mut content) = content {
+            self.update_text_index(metadata, content, savepoint).await?
+        } else {
+            if metadata.kind() == ResourceKind::Leaf {
+                self.mark_indexed(&id, &mut *savepoint).await?;
+            }
+            savepoint
+        };
+
+        // Dropping the savepoint rolls back this item if the store fails.
+        let name = content.as_ref().map(|content| content.metadata.name());
+        let hash = self.store.create(metadata, content).await?;
+        let mut savepoint = savepoint;
+        let variants = match name {
+            Some(name) => Some(
+                self.record_hashes(metadata, &[(name, hash)], &mut savepoint)
+                    .await?,
+            ),
+            None => None,
+        };
+        savepoint.commit().await?;
+        if let Some(variants) = variants {
+            metadata.set_variants(variants);
+        }
+
+        Ok(())
     }
 
-    pub fn add_indexer(&mut self, indexer: Box<dyn Indexer + Send + Sync>) {
-        self.indexers.push(indexer);
+    // Add or replace a variant for this resource.
+    pub async fn update_variant(
+        &mut self,
+        id: &ResourceId,
+        content: Variant,
+    ) -> Result<(), ResourceStoreError> {
+        self.update_variant_checked(id, content, None).await
     }
 
-    pub async fn close(&self) {
-        self.db_pool.close().await
+    /// Same as `update_variant`, but fails with a `Conflict` error if the resource
+    /// revision is not the expected one because someone else updated it.
+    pub async fn update_variant_at_rev(
+        &mut self,
+        id: &ResourceId,
+        content: Variant,
+        expected_rev: u64,
+    ) -> Result<(), ResourceStoreError> {
+        self.update_variant_checked(id, content, Some(expected_rev))
+            .await
     }
 
-    pub async fn create(
+    async fn update_variant_checked(
         &mut self,
-        metadata: &mut ResourceMetadata,
-        mut content: Option<Variant>,
+        id: &ResourceId,
+        content: Variant,
+        expected_rev: Option<u64>,
     ) -> Result<(), ResourceStoreError> {
-        self.check_container_leaf(&metadata.id(), &metadata.parent())
+        self.check_access(id, Operations::WRITE).await?;
+        self.check_bytes(content.metadata.size())?;
+        let metadata = self.load_metadata(id).await?;
+
+        let tx = self.db_pool.begin().await?;
+        self.update_variant_in(metadata, content, expected_rev, tx)
+            .await
+    }
+
+    // Same as `update_variant_checked()` once the metadata is loaded, committing `tx` only
+    // once the store is updated so that callers can make other database changes along
+    // with the update.
+    async fn update_variant_in(
+        &mut self,
+        mut metadata: ResourceMetadata,
+        mut content: Variant,
+        expected_rev: Option<u64>,
+        mut tx: Transaction<'static, Sqlite>,
+    ) -> Result<(), ResourceStoreError> {
+        let id = &metadata.id();
+        content.metadata.set_hash(None);
+        metadata.add_or_update_variant(content.metadata.clone());
+        metadata.modify_now();
+
+        let rev = self.check_rev(id, expected_rev, &mut *tx).await?;
+        metadata.set_rev(rev + 1);
+
+        // Filled again by the places indexer if needed.
+        sqlx::query!("DELETE FROM places WHERE id = ?", id)
+            .execute(&mut *tx)
             .await?;
 
-        if let Some(content) = &content {
-            metadata.add_or_update_variant(content.metadata.clone());
-        }
+        let tx1 = self.fts.remove_text(id, None, tx).await?;
 
-        // Start a transaction to store the new metadata.
-        let tx = self.db_pool.begin().await?;
-        let mut tx2 = self.create_metadata(metadata, tx).await?;
+        let mut tx2 = self.update_metadata(&metadata, tx1).await?;
 
         // Update the children content of the parent if this is not creating the root.
         if !metadata.id().is_root() {
@@ -733,108 +4831,300 @@ impl<T> Manager<T> {
                 .await?;
         }
 
-        // If there is content run the text indexer for this mime type.
-        let tx3 = if let Some(ref mut content) = content {
-            self.update_text_index(metadata, content, tx2).await?
-        } else {
-            tx2
-        };
+        // Variants derived from this one need to be regenerated.
+        let variant_name = content.metadata.name();
+        sqlx::query!(
+            "UPDATE derived_variants SET stale = 1 WHERE id = ? AND source = ?",
+            id,
+            variant_name
+        )
+        .execute(&mut *tx2)
+        .await?;
 
-        // Create the store entry, and commit the SQlite transaction in case of success.
-        match self.store.create(metadata, content).await {
-            Ok(_) => {
+        match self.store.update(&metadata, Some(content)).await {
+            Ok(hash) => {
+                log::info!("Updating fts for {:?}", metadata);
+                let mut tx3 = tx2;
+                // Re-index all variants since the `DELETE` sql triggers full deletion of the ftx index.
+                for variant in metadata.variants() {
+                    let content = self
+                        .store
+                        .get_variant(&metadata.id(), &variant.name())
+                        .await?;
+                    tx3 = self
+                        .update_text_index(
+                            &metadata,
+                            &mut Variant::new(variant.clone(), content),
+                            tx3,
+                        )
+                        .await?;
+                }
+                let variants = self
+                    .record_hashes(&metadata, &[(variant_name.clone(), hash)], &mut tx3)
+                    .await?;
                 tx3.commit().await?;
-                // Trigger observers once we have committed all changes.
+                metadata.set_variants(variants);
+                self.update_cache(&metadata);
+
                 let id = metadata.id();
                 let parent = metadata.parent();
-                self.notify_observers(&ResourceModification::Created(id.clone()));
                 if !id.is_root() {
                     self.notify_observers(&ResourceModification::Modified(parent.clone()));
-                    self.notify_observers(&ResourceModification::ChildCreated(ParentChild::new(
-                        &parent, &id,
-                    )));
                 }
+                self.notify_observers(&ResourceModification::Modified(id.clone()));
+                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+                    &parent, &id,
+                )));
+                self.notify_observers(&ResourceModification::VariantChanged(ResourceVariant::new(
+                    &id,
+                    &variant_name,
+                )));
+
                 Ok(())
             }
-            Err(err) => Err(err),
+            Err(err) => Err(self.record_error("update", &metadata.id(), err)),
         }
     }
 
-    // Add or replace a variant for this resource.
-    pub async fn update_variant(
+    /// Gives a new revision to a resource, so that updates based on the previous one conflict.
+    async fn bump_rev(
+        &self,
+        metadata: &mut ResourceMetadata,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<(), ResourceStoreError> {
+        let id = metadata.id();
+        let rev = self.check_rev(&id, None, &mut **tx).await? + 1;
+        metadata.set_rev(rev);
+        let db_rev = rev as i64;
+        sqlx::query!("UPDATE resources SET rev = ? WHERE id = ?", db_rev, id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the current revision of a resource, checking that it matches the
+    /// expected one if any.
+    async fn check_rev<'c, E: sqlx::Executor<'c, Database = Sqlite>>(
+        &self,
+        id: &ResourceId,
+        expected_rev: Option<u64>,
+        executor: E,
+    ) -> Result<u64, ResourceStoreError> {
+        let rev = sqlx::query_scalar!("SELECT rev FROM resources WHERE id = ?", id)
+            .fetch_optional(executor)
+            .await?
+            .ok_or(ResourceStoreError::NoSuchResource)? as u64;
+
+        match expected_rev {
+            Some(expected) if expected != rev => Err(ResourceStoreError::Conflict),
+            _ => Ok(rev),
+        }
+    }
+
+    /// Appends content at the end of an existing variant, which is cheaper than
+    /// updating it for logs or journals that keep growing.
+    pub async fn append_to_variant(
         &mut self,
         id: &ResourceId,
-        content: Variant,
+        variant_name: &str,
+        content: &[u8],
     ) -> Result<(), ResourceStoreError> {
-        let mut metadata = self.get_metadata(id).await?;
+        self.check_access(id, Operations::WRITE).await?;
+        self.check_bytes(content.len() as _)?;
+        let mut metadata = self.load_metadata(id).await?;
 
-        metadata.add_or_update_variant(content.metadata.clone());
+        if metadata.kind() != ResourceKind::Leaf {
+            return Err(ResourceStoreError::InvalidResourceId);
+        }
+
+        let mut variant = match metadata
+            .variants()
+            .iter()
+            .find(|v| v.name() == variant_name)
+        {
+            Some(variant) => variant.clone(),
+            None => {
+                error!("Variant '{}' is not in metadata.", variant_name);
+                return Err(ResourceStoreError::InvalidVariant(variant_name.into()));
+            }
+        };
+        variant.set_size(variant.size() + content.len() as u32);
+        // The hash of the full content is not known anymore.
+        variant.set_hash(None);
+        metadata.add_or_update_variant(variant.clone());
         metadata.modify_now();
 
-        let mut tx = self.db_pool.begin().await?;
-        sqlx::query!("DELETE FROM resources WHERE id = ?", id)
-            .execute(&mut *tx)
+        let mut tx = self.db_pool.begin().await?;
+        let size = variant.size();
+        sqlx::query!(
+            "UPDATE variants SET size = ?, hash = NULL WHERE id = ? AND name = ?",
+            size,
+            id,
+            variant_name
+        )
+        .execute(&mut *tx)
+        .await?;
+        let rev = self.check_rev(id, None, &mut *tx).await? + 1;
+        metadata.set_rev(rev);
+        let modified = metadata.modified();
+        let db_rev = rev as i64;
+        sqlx::query!(
+            "UPDATE resources SET modified = ?, rev = ? WHERE id = ?",
+            *modified,
+            db_rev,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE derived_variants SET stale = 1 WHERE id = ? AND source = ?",
+            id,
+            variant_name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if self.index_storage_names {
+            self.update_storage_names(&metadata, &mut tx).await?;
+        }
+
+        self.store
+            .append_to_variant(&metadata, variant_name, content)
+            .await?;
+
+        // Re-index the whole variant, since indexers may not work on partial content.
+        let tx = self.fts.remove_text(id, Some(variant_name), tx).await?;
+        let reader = self.store.get_variant(id, variant_name).await?;
+        let tx = self
+            .update_text_index(&metadata, &mut Variant::new(variant, reader), tx)
             .await?;
+        tx.commit().await?;
 
-        let tx1 = self.fts.remove_text(id, None, tx).await?;
+        self.update_cache(&metadata);
 
-        let mut tx2 = self.create_metadata(&metadata, tx1).await?;
+        let parent = metadata.parent();
+        self.notify_observers(&ResourceModification::Modified(id.clone()));
+        self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+            &parent, id,
+        )));
+        self.notify_observers(&ResourceModification::VariantChanged(ResourceVariant::new(
+            id,
+            variant_name,
+        )));
 
-        // Update the children content of the parent if this is not creating the root.
-        if !metadata.id().is_root() {
-            self.update_container_content(&metadata.parent(), &mut *tx2)
-                .await?;
+        Ok(())
+    }
+
+    /// Updates the mime type and size of an existing variant without sending its content
+    /// again. Only the database and the stored metadata are modified, so the content hash
+    /// is kept, but the resource gets a new revision like with other updates.
+    pub async fn update_variant_metadata(
+        &mut self,
+        id: &ResourceId,
+        variant: VariantMetadata,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+
+        let mut metadata = self.load_metadata(id).await?;
+        let variant_name = variant.name();
+        if !metadata.has_variant(&variant_name) {
+            error!("Variant '{}' is not in metadata.", variant_name);
+            return Err(ResourceStoreError::InvalidVariant(variant_name));
         }
 
-        match self.store.update(&metadata, Some(content)).await {
-            Ok(_) => {
-                log::info!("Updating fts for {:?}", metadata);
-                let mut tx3 = tx2;
-                // Re-index all variants since the `DELETE` sql triggers full deletion of the ftx index.
-                for variant in metadata.variants() {
-                    let content = self
-                        .store
-                        .get_variant(&metadata.id(), &variant.name())
-                        .await?;
-                    tx3 = self
-                        .update_text_index(
-                            &metadata,
-                            &mut Variant::new(variant.clone(), content),
-                            tx3,
-                        )
-                        .await?;
+        // Patch the variant in place to keep the variants order.
+        let variants = metadata
+            .variants()
+            .iter()
+            .map(|current| {
+                if current.name() != variant_name {
+                    return current.clone();
                 }
-                tx3.commit().await?;
+                let mut patched = current.clone();
+                patched.set_mime_type(&variant.mime_type());
+                patched.set_size(variant.size());
+                patched
+            })
+            .collect();
+        metadata.set_variants(variants);
+        metadata.modify_now();
 
-                let id = metadata.id();
-                let parent = metadata.parent();
-                if !id.is_root() {
-                    self.notify_observers(&ResourceModification::Modified(parent.clone()));
-                }
-                self.notify_observers(&ResourceModification::Modified(id.clone()));
-                self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
-                    &parent, &id,
-                )));
+        // Only the variants table, the revision and the modification date are modified,
+        // and rolled back if the store update fails.
+        let mut tx = self.db_pool.begin().await?;
+        let mime_type = variant.mime_type();
+        let size = variant.size();
+        sqlx::query!(
+            "UPDATE variants SET mimeType = ?, size = ? WHERE id = ? AND name = ?",
+            mime_type,
+            size,
+            id,
+            variant_name
+        )
+        .execute(&mut *tx)
+        .await?;
+        let rev = self.check_rev(id, None, &mut *tx).await? + 1;
+        metadata.set_rev(rev);
+        let modified = metadata.modified();
+        let db_rev = rev as i64;
+        sqlx::query!(
+            "UPDATE resources SET modified = ?, rev = ? WHERE id = ?",
+            *modified,
+            db_rev,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        self.store.update(&metadata, None).await?;
+        tx.commit().await?;
 
-                Ok(())
-            }
-            Err(err) => Err(err),
-        }
+        self.update_cache(&metadata);
+        let parent = metadata.parent();
+        self.notify_observers(&ResourceModification::Modified(id.clone()));
+        self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+            &parent, id,
+        )));
+        self.notify_observers(&ResourceModification::VariantChanged(ResourceVariant::new(
+            id,
+            &variant_name,
+        )));
+
+        Ok(metadata)
     }
 
     pub async fn delete_variant(
         &mut self,
         id: &ResourceId,
         variant_name: &str,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        self.remove_variant(id, variant_name).await
+    }
+
+    // Same as `delete_variant()`, without checking access.
+    async fn remove_variant(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
     ) -> Result<(), ResourceStoreError> {
         // 1. Get the metadata for this id.
-        let mut metadata = self.get_metadata(id).await?;
+        let mut metadata = self.load_metadata(id).await?;
 
         // 2. Check variant validity
         if !metadata.has_variant(variant_name) {
             error!("Variant '{}' is not in metadata.", variant_name);
             return Err(ResourceStoreError::InvalidVariant(variant_name.into()));
         }
+        if metadata.kind() == ResourceKind::Leaf
+            && self
+                .load_required_variants(&metadata.parent())
+                .await?
+                .iter()
+                .any(|variant| variant == variant_name)
+        {
+            return Err(ResourceStoreError::RequiredVariant(variant_name.into()));
+        }
 
         // 3. remove variant from database and store
         sqlx::query!(
@@ -844,41 +5134,116 @@ impl<T> Manager<T> {
         )
         .execute(&self.db_pool)
         .await?;
+        sqlx::query!(
+            "DELETE FROM storage_names WHERE id = ? AND variant = ?",
+            id,
+            variant_name
+        )
+        .execute(&self.db_pool)
+        .await?;
+        // Forget about this variant derivation, and mark the ones derived from it as stale.
+        sqlx::query!(
+            "DELETE FROM derived_variants WHERE id = ? AND variant = ?",
+            id,
+            variant_name
+        )
+        .execute(&self.db_pool)
+        .await?;
+        sqlx::query!(
+            "UPDATE derived_variants SET stale = 1 WHERE id = ? AND source = ?",
+            id,
+            variant_name
+        )
+        .execute(&self.db_pool)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM sidecar_variants WHERE id = ? AND variant = ?",
+            id,
+            variant_name
+        )
+        .execute(&self.db_pool)
+        .await?;
         metadata.delete_variant(variant_name);
         self.store.delete_variant(id, variant_name).await?;
 
         // 4. Remove the fts index for this variant.
         let tx = self.db_pool.begin().await?;
-        let _ = self.fts.remove_text(id, Some(variant_name), tx).await?;
+        let tx = self.fts.remove_text(id, Some(variant_name), tx).await?;
+        tx.commit().await?;
 
         // 5. Perform an update with no variant to keep the metadata up to date.
         self.store.update(&metadata, None).await?;
+        self.update_cache(&metadata);
         let id = metadata.id();
         let parent = metadata.parent();
         self.notify_observers(&ResourceModification::Modified(id.clone()));
         self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
             &parent, &id,
         )));
+        self.notify_observers(&ResourceModification::VariantChanged(ResourceVariant::new(
+            &id,
+            variant_name,
+        )));
         Ok(())
     }
 
     pub async fn delete(&mut self, id: &ResourceId) -> Result<(), ResourceStoreError> {
+        self.delete_with_progress(id, None).await
+    }
+
+    /// Returns the resources that `delete()` would remove, without changing anything.
+    pub async fn delete_dry_run(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<DryRunReport, ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
+        self.subtree_report(id).await
+    }
+
+    // Collects a resource and all its descendants, with the size of their variants.
+    // Hidden and filtered out descendants are included, since they are deleted too.
+    async fn subtree_report(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<DryRunReport, ResourceStoreError> {
+        let mut report = DryRunReport::default();
+        let mut pending = std::collections::VecDeque::new();
+        pending.push_back(self.load_metadata(id).await?);
+
+        while let Some(meta) = pending.pop_front() {
+            report.resources.push(meta.id());
+            if meta.kind() == ResourceKind::Container {
+                let children = self.children_of(&meta.id(), &self.db_pool).await?;
+                pending.extend(self.load_metadata_batch(&children).await?);
+            } else {
+                report.bytes += meta.variants().iter().map(|v| v.size() as u64).sum::<u64>();
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Deletes a resource, and all its descendants for containers, reporting progress
+    /// after each deleted resource.
+    pub async fn delete_with_progress(
+        &mut self,
+        id: &ResourceId,
+        mut progress: Option<&mut dyn ProgressSink>,
+    ) -> Result<(), ResourceStoreError> {
+        self.check_access(id, Operations::WRITE).await?;
         let mut tx = self.db_pool.begin().await?;
         let is_container = self.is_container(id).await?;
 
         let parent_id = self.parent_of(id, &mut *tx).await?;
 
         // Delete the object itself.
-        // The tags will be removed by the delete cascade sql rule.
-        sqlx::query!("DELETE FROM resources WHERE id = ?", id)
-            .execute(&mut *tx)
-            .await?;
-
-        // Remove fts for all variants
-        let mut tx1 = self.fts.remove_text(id, None, tx).await?;
+        let mut tx1 = self.delete_index_entries(id, tx).await?;
 
         if !is_container {
-            self.store.delete(id).await?;
+            self.store
+                .delete(id)
+                .await
+                .map_err(|err| self.record_error("delete", id, err))?;
 
             self.update_container_content(&parent_id, &mut *tx1).await?;
             tx1.commit().await?;
@@ -889,6 +5254,9 @@ impl<T> Manager<T> {
             )));
 
             self.evict_from_cache(id);
+            if let Some(sink) = progress.as_mut() {
+                sink.progress(1, 1, id);
+            }
             return Ok(());
         }
 
@@ -925,19 +5293,25 @@ impl<T> Manager<T> {
             containers = new_obj;
         }
 
-        for child in to_delete {
+        let total = to_delete.len() + 1;
+        for (done, child) in to_delete.into_iter().enumerate() {
             // Delete the child.
-            // The tags will be removed by the delete cascade sql rule.
-            sqlx::query!("DELETE FROM resources WHERE id = ?", child)
-                .execute(&mut *tx1)
-                .await?;
-            self.store.delete(&child).await?;
-            tx1 = self.fts.remove_text(&child, None, tx1).await?;
+            tx1 = self.delete_index_entries(&child, tx1).await?;
+            self.store
+                .delete(&child)
+                .await
+                .map_err(|err| self.record_error("delete", &child, err))?;
             self.notify_observers(&ResourceModification::Deleted(child.clone()));
             self.evict_from_cache(&child);
+            if let Some(sink) = progress.as_mut() {
+                sink.progress(done + 1, total, &child);
+            }
         }
 
-        self.store.delete(id).await?;
+        self.store
+            .delete(id)
+            .await
+            .map_err(|err| self.record_error("delete", id, err))?;
         self.update_container_content(&parent_id, &mut *tx1).await?;
         tx1.commit().await?;
         self.notify_observers(&ResourceModification::Deleted(id.clone()));
@@ -947,12 +5321,28 @@ impl<T> Manager<T> {
         )));
 
         self.evict_from_cache(id);
+        if let Some(sink) = progress.as_mut() {
+            sink.progress(total, total, id);
+        }
         Ok(())
     }
 
     pub async fn get_metadata(
         &mut self,
         id: &ResourceId,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let metadata = self.load_metadata(id).await?;
+        if !self.is_readable(&metadata) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        Ok(metadata)
+    }
+
+    // Same as `get_metadata()`, without checking the token context.
+    async fn load_metadata(
+        &mut self,
+        id: &ResourceId,
     ) -> Result<ResourceMetadata, ResourceStoreError> {
         // Check if we have this metadata in the LRU cache.
         if let Some(meta) = self.cache.get(id) {
@@ -960,54 +5350,8 @@ impl<T> Manager<T> {
         }
 
         // Metadata can be retrieved fully from the SQL database.
-        match sqlx::query!(
-            r#"
-    SELECT id, parent, kind, name, created, modified, scorer FROM resources
-    WHERE id = ?"#,
-            id
-        )
-        .fetch_one(&self.db_pool)
-        .await
-        {
-            Ok(record) => {
-                let mut meta = ResourceMetadata::new(
-                    &record.id.into(),
-                    &record.parent.into(),
-                    record.kind.into(),
-                    &record.name,
-                    vec![],
-                    vec![],
-                );
-
-                // Get the tags if any.
-                let tags: Vec<String> = sqlx::query!("SELECT tag FROM tags WHERE id = ?", id)
-                    .fetch_all(&self.db_pool)
-                    .await?
-                    .iter()
-                    .map(|r| r.tag.clone())
-                    .collect();
-
-                if !tags.is_empty() {
-                    meta.set_tags(tags);
-                }
-
-                // Get the variants if any.
-                let variants: Vec<VariantMetadata> =
-                    sqlx::query!("SELECT name, mimeType, size FROM variants WHERE id = ?", id)
-                        .fetch_all(&self.db_pool)
-                        .await?
-                        .iter()
-                        .map(|r| VariantMetadata::new(&r.name, &r.mimeType, r.size as _))
-                        .collect();
-
-                if !variants.is_empty() {
-                    meta.set_variants(variants);
-                }
-
-                meta.set_created(DateTime::<Utc>::from_utc(record.created, Utc).into());
-                meta.set_modified(DateTime::<Utc>::from_utc(record.modified, Utc).into());
-                meta.set_scorer_from_db(&record.scorer);
-
+        match self.db_metadata(id).await {
+            Ok(meta) => {
                 self.update_cache(&meta);
                 Ok(meta)
             }
@@ -1024,55 +5368,362 @@ impl<T> Manager<T> {
                 tx2.commit().await?;
 
                 self.update_cache(&metadata);
+                self.rehydrations += 1;
+                self.notify_observers(&ResourceModification::Rehydrated(id.clone()));
                 Ok(metadata)
             }
         }
     }
 
+    // Builds the metadata of a resource from the database only.
+    async fn db_metadata(&self, id: &ResourceId) -> Result<ResourceMetadata, ResourceStoreError> {
+        let mut conn = self.db_pool.acquire().await?;
+        read_db_metadata(&mut conn, id).await
+    }
+
+    /// Fetches the metadata of several resources, using set based queries for the
+    /// ones that are not in the cache. Results are in the same order as `ids`.
+    pub async fn get_metadata_batch(
+        &mut self,
+        ids: &[ResourceId],
+    ) -> Result<Vec<ResourceMetadata>, ResourceStoreError> {
+        self.check_access_all(ids, Operations::READ).await?;
+        let batch = self.load_metadata_batch(ids).await?;
+        if !batch.iter().all(|metadata| self.is_readable(metadata)) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        Ok(batch)
+    }
+
+    // Same as `get_metadata_batch()`, without checking the token context.
+    async fn load_metadata_batch(
+        &mut self,
+        ids: &[ResourceId],
+    ) -> Result<Vec<ResourceMetadata>, ResourceStoreError> {
+        let mut found: HashMap<ResourceId, ResourceMetadata> = HashMap::new();
+        let mut missing: Vec<ResourceId> = vec![];
+        for id in ids {
+            if let Some(meta) = self.cache.get(id) {
+                found.insert(id.clone(), meta.clone());
+            } else if !missing.contains(id) {
+                missing.push(id.clone());
+            }
+        }
+
+        // Stay well below the maximum number of sql parameters.
+        for chunk in missing.chunks(500) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+
+            let sql = format!(
+                "SELECT id, parent, kind, name, created, modified, scorer, rev FROM resources WHERE id IN ({placeholders})"
+            );
+            let mut query = sqlx::query_as::<
+                _,
+                (
+                    ResourceId,
+                    ResourceId,
+                    i64,
+                    String,
+                    NaiveDateTime,
+                    NaiveDateTime,
+                    Vec<u8>,
+                    i64,
+                ),
+            >(&sql);
+            for id in chunk {
+                query = query.bind(id);
+            }
+            let resources = query.fetch_all(&self.db_pool).await?;
+
+            let sql =
+                format!("SELECT id, tag FROM tags WHERE id IN ({placeholders}) ORDER BY rowid");
+            let mut query = sqlx::query_as::<_, (ResourceId, String)>(&sql);
+            for id in chunk {
+                query = query.bind(id);
+            }
+            let mut tags: HashMap<ResourceId, Vec<String>> = HashMap::new();
+            for (id, tag) in query.fetch_all(&self.db_pool).await? {
+                tags.entry(id).or_default().push(tag);
+            }
+
+            let sql = format!(
+                "SELECT id, name, mimeType, size, hash FROM variants WHERE id IN ({placeholders}) ORDER BY rowid"
+            );
+            let mut query =
+                sqlx::query_as::<_, (ResourceId, String, String, u32, Option<String>)>(&sql);
+            for id in chunk {
+                query = query.bind(id);
+            }
+            let mut variants: HashMap<ResourceId, Vec<VariantMetadata>> = HashMap::new();
+            for (id, name, mime_type, size, hash) in query.fetch_all(&self.db_pool).await? {
+                let mut variant = VariantMetadata::new(&name, &mime_type, size);
+                variant.set_hash(hash.as_deref());
+                variants.entry(id).or_default().push(variant);
+            }
+
+            for (id, parent, kind, name, created, modified, scorer, rev) in resources {
+                let mut meta = ResourceMetadata::new(
+                    &id,
+                    &parent,
+                    kind.into(),
+                    &name,
+                    tags.remove(&id).unwrap_or_default(),
+                    variants.remove(&id).unwrap_or_default(),
+                );
+                meta.set_created(DateTime::<Utc>::from_naive_utc_and_offset(created, Utc).into());
+                meta.set_modified(DateTime::<Utc>::from_naive_utc_and_offset(modified, Utc).into());
+                meta.set_scorer_from_db(&scorer);
+                meta.set_rev(rev as _);
+
+                self.update_cache(&meta);
+                found.insert(id, meta);
+            }
+        }
+
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            match found.get(id) {
+                Some(meta) => results.push(meta.clone()),
+                // Not in the index, try to rehydrate it from the store.
+                None => {
+                    let meta = self.load_metadata(id).await?;
+                    found.insert(id.clone(), meta.clone());
+                    results.push(meta);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
     pub async fn get_leaf(
         &mut self,
         id: &ResourceId,
-        variant_name: &str,
-    ) -> Result<(ResourceMetadata, BoxedReader), ResourceStoreError> {
-        let meta = self.get_metadata(id).await?;
+        variant_name: &str,
+    ) -> Result<(ResourceMetadata, BoxedReader), ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let meta = self.load_metadata(id).await?;
+
+        if meta.kind() != ResourceKind::Leaf || !self.is_readable(&meta) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+
+        if let Some(variant) = meta.variants().iter().find(|v| v.name() == variant_name) {
+            self.check_bytes(variant.size())?;
+        } else if self.find_evicted_variant(id, variant_name).await?.is_some() {
+            return Err(ResourceStoreError::EvictedVariant(variant_name.into()));
+        }
+
+        let content = self.filtered_content(&meta, variant_name).await?;
+
+        Ok((meta, content))
+    }
+
+    // Returns the content of a variant, transformed by the read filter if any.
+    async fn filtered_content(
+        &self,
+        meta: &ResourceMetadata,
+        variant_name: &str,
+    ) -> Result<BoxedReader, ResourceStoreError> {
+        // Just relay content from the underlying store since we don't keep the content in the index.
+        let content = self.store.get_variant(&meta.id(), variant_name).await?;
+        Ok(match &self.read_filter {
+            Some(filter) => filter.transform(
+                self.capability_token.as_deref(),
+                meta,
+                variant_name,
+                content,
+            ),
+            None => content,
+        })
+    }
+
+    /// Returns the first available variant in `preferences`, for instance
+    /// `["preview", "thumbnail", "default"]` when derived variants may not be generated yet.
+    /// The name of the returned variant is the first item of the result.
+    pub async fn get_best_variant(
+        &mut self,
+        id: &ResourceId,
+        preferences: &[&str],
+    ) -> Result<(String, ResourceMetadata, BoxedReader), ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let meta = self.load_metadata(id).await?;
+
+        let variant_name = preferences
+            .iter()
+            .find(|name| meta.has_variant(name))
+            .ok_or_else(|| ResourceStoreError::InvalidVariant(preferences.join(", ")))?;
+        let (meta, content) = self.get_leaf(id, variant_name).await?;
+
+        Ok((variant_name.to_string(), meta, content))
+    }
+
+    pub async fn get_container(
+        &mut self,
+        id: &ResourceId,
+    ) -> Result<(ResourceMetadata, Vec<ResourceMetadata>), ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        self.check_access(id, Operations::LIST).await?;
+        let meta = self.load_metadata(id).await?;
+
+        if meta.kind() != ResourceKind::Container || !self.is_readable(&meta) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+
+        // Read the list of children from the container content.
+        if let Ok(mut file) = self.store.get_variant(id, "default").await {
+            let mut buffer = vec![];
+            file.read_to_end(&mut buffer).await?;
+            let children = Vec::<ResourceId>::read_from_buffer(&buffer)?;
+
+            // Get the metadata for all the children.
+            let res = self
+                .load_metadata_batch(&children)
+                .await?
+                .into_iter()
+                .filter(|child_meta| self.is_listed(child_meta))
+                .collect();
+
+            Ok((meta, res))
+        } else {
+            // No children for this container.
+            Ok((meta, vec![]))
+        }
+    }
+
+    /// Returns a page of the children of a container, sorted in the database.
+    pub async fn get_container_sorted(
+        &mut self,
+        id: &ResourceId,
+        order: SortOrder,
+        page: Page,
+    ) -> Result<(ResourceMetadata, Vec<ResourceMetadata>), ResourceStoreError> {
+        self.check_access(id, Operations::LIST).await?;
+        let meta = self.load_metadata(id).await?;
 
-        if meta.kind() != ResourceKind::Leaf {
+        if meta.kind() != ResourceKind::Container || !self.is_readable(&meta) {
             return Err(ResourceStoreError::NoSuchResource);
         }
 
-        // Just relay content from the underlying store since we don't keep the content in the index.
-        Ok((meta, self.store.get_variant(id, variant_name).await?))
+        let key = match order.key {
+            SortKey::Name => "name",
+            SortKey::Created => "created",
+            SortKey::Modified => "modified",
+            SortKey::Size => {
+                "(SELECT COALESCE(SUM(size), 0) FROM variants WHERE variants.id = resources.id)"
+            }
+            SortKey::Frecency => "frecency(scorer)",
+        };
+        let sql = format!(
+            "SELECT id FROM resources WHERE parent = ? AND parent != id AND {VISIBLE} ORDER BY {}{} {}, name LIMIT ? OFFSET ?",
+            if order.containers_first { "kind, " } else { "" },
+            key,
+            if order.descending { "DESC" } else { "ASC" }
+        );
+        let children: Vec<ResourceId> = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(self.visibility)
+            .bind(page.limit)
+            .bind(page.offset)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let res = self
+            .load_metadata_batch(&children)
+            .await?
+            .into_iter()
+            .filter(|child_meta| self.is_listed(child_meta))
+            .collect();
+
+        Ok((meta, res))
     }
 
-    pub async fn get_container(
+    /// Returns up to `limit` children of a container, sorted by name, with a single query.
+    /// The returned cursor can be used to fetch the next page, and is `None` once all the
+    /// children have been listed.
+    pub async fn children_page(
         &mut self,
         id: &ResourceId,
-    ) -> Result<(ResourceMetadata, Vec<ResourceMetadata>), ResourceStoreError> {
-        use async_std::io::ReadExt;
-
-        let meta = self.get_metadata(id).await?;
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<ChildrenPage, ResourceStoreError> {
+        self.check_access(id, Operations::LIST).await?;
+        let meta = self.load_metadata(id).await?;
 
-        if meta.kind() != ResourceKind::Container {
+        if meta.kind() != ResourceKind::Container || !self.is_readable(&meta) {
             return Err(ResourceStoreError::NoSuchResource);
         }
 
-        // Read the list of children from the container content.
-        if let Ok(mut file) = self.store.get_variant(id, "default").await {
-            let mut buffer = vec![];
-            file.read_to_end(&mut buffer).await?;
-            let children = Vec::<ResourceId>::read_from_buffer(&buffer)?;
+        #[derive(sqlx::FromRow)]
+        struct ChildRow {
+            id: String,
+            parent: String,
+            kind: i64,
+            name: String,
+            created: NaiveDateTime,
+            modified: NaiveDateTime,
+            scorer: Vec<u8>,
+            rev: i64,
+            tags: String,     // Json array of tags.
+            variants: String, // Json array of [name, mime type, size, hash] arrays.
+        }
 
-            // Get the metadata for each child.
-            let mut res = vec![];
-            for child in children {
-                res.push(self.get_metadata(&child).await?);
-            }
+        let rows: Vec<ChildRow> = sqlx::query_as(&format!(
+            r#"SELECT id, parent, kind, name, created, modified, scorer, rev,
+            (SELECT json_group_array(tag) FROM tags WHERE tags.id = resources.id) AS tags,
+            (SELECT json_group_array(json_array(name, mimeType, size, hash)) FROM variants
+             WHERE variants.id = resources.id) AS variants
+            FROM resources
+            WHERE parent = ? AND parent != id AND name > ? AND {VISIBLE}
+            ORDER BY name LIMIT ?"#
+        ))
+        .bind(id)
+        .bind(cursor.unwrap_or_default())
+        .bind(self.visibility)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
 
-            Ok((meta, res))
+        let cursor = if rows.len() as u32 == limit {
+            rows.last().map(|row| row.name.clone())
         } else {
-            // No children for this container.
-            Ok((meta, vec![]))
+            None
+        };
+
+        let mut children = Vec::with_capacity(rows.len());
+        for row in rows {
+            let tags: Vec<String> = serde_json::from_str(&row.tags)?;
+            #[allow(clippy::type_complexity)]
+            let variants: Vec<(String, String, u32, Option<String>)> =
+                serde_json::from_str(&row.variants)?;
+            let mut child = ResourceMetadata::new(
+                &row.id.into(),
+                &row.parent.into(),
+                row.kind.into(),
+                &row.name,
+                tags,
+                variants
+                    .iter()
+                    .map(|(name, mime_type, size, hash)| {
+                        let mut variant = VariantMetadata::new(name, mime_type, *size);
+                        variant.set_hash(hash.as_deref());
+                        variant
+                    })
+                    .collect(),
+            );
+            child.set_created(DateTime::<Utc>::from_naive_utc_and_offset(row.created, Utc).into());
+            child
+                .set_modified(DateTime::<Utc>::from_naive_utc_and_offset(row.modified, Utc).into());
+            child.set_scorer_from_db(&row.scorer);
+            child.set_rev(row.rev as _);
+
+            if self.is_readable(&child) {
+                children.push(child);
+            }
         }
+
+        Ok(ChildrenPage { children, cursor })
     }
 
     /// Imports an existing file from a given path, storing it as the default variant for this resource.
@@ -1081,6 +5732,18 @@ impl<T> Manager<T> {
         parent: &ResourceId,
         path: P,
         delete_file: bool,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.import_from_path_with_progress(parent, path, delete_file, None)
+            .await
+    }
+
+    /// Same as `import_from_path()`, reporting progress once the file is imported.
+    pub async fn import_from_path_with_progress<P: AsRef<Path>>(
+        &mut self,
+        parent: &ResourceId,
+        path: P,
+        delete_file: bool,
+        progress: Option<&mut dyn ProgressSink>,
     ) -> Result<ResourceMetadata, ResourceStoreError> {
         use async_std::fs::File;
 
@@ -1097,35 +5760,7 @@ impl<T> Manager<T> {
         if let Some(name) = path.as_ref().file_name() {
             let name = name.to_string_lossy();
 
-            // Create a unique name, adding `(N)` if needed.
-            let mut suffix = 0;
-            let mut final_name = name.clone();
-            loop {
-                if let Err(ResourceStoreError::NoSuchResource) =
-                    self.child_by_name(parent, &final_name).await
-                {
-                    // Target name is not used, this is our choice.
-                    break;
-                }
-
-                let aname = format!("{name}");
-                let ppath = Path::new(&aname);
-                suffix += 1;
-                let ext = match ppath.extension() {
-                    Some(ext) => format!(".{}", ext.to_string_lossy()),
-                    None => String::new(),
-                };
-                let new_name = format!(
-                    "{}({}){}",
-                    ppath
-                        .file_stem()
-                        .unwrap_or_else(|| std::ffi::OsStr::new("_"))
-                        .to_string_lossy(),
-                    suffix,
-                    ext
-                );
-                final_name = std::borrow::Cow::from(new_name);
-            }
+            let final_name = self.unique_name(parent, &name).await;
 
             let variant = VariantMetadata::new("default", &mime_type, fs_meta.len() as _);
             let mut meta = ResourceMetadata::new(
@@ -1144,12 +5779,370 @@ impl<T> Manager<T> {
                 async_std::fs::remove_file(path).await?;
             }
 
+            if let Some(sink) = progress {
+                sink.progress(1, 1, &meta.id());
+            }
             return Ok(meta);
         }
 
         Err(ResourceStoreError::Custom("InvalidFileName".to_owned()))
     }
 
+    /// Writes a tar archive of this resource and all its descendants, with their metadata
+    /// and variants. See `crate::archive` for the layout of the archive.
+    /// Hidden descendants are exported too, so that the archive is a complete backup.
+    /// Descendants rejected by the read filter are left out, and the content of variants
+    /// goes through the read filter like with `get_leaf()`.
+    /// Returns the number of exported resources.
+    pub async fn export_archive<W: async_std::io::Write + Unpin>(
+        &mut self,
+        id: &ResourceId,
+        writer: &mut W,
+    ) -> Result<usize, ResourceStoreError> {
+        self.export_archive_with_options(id, writer, ExportOptions::default())
+            .await
+    }
+
+    /// Same as `export_archive()`, with options.
+    /// Children are written sorted by name, and variants and tags are sorted too, so the same
+    /// resources always produce the same archive. With a fixed `mtime` the archive doesn't
+    /// depend on the modification dates of the entries either, which lets backup systems
+    /// deduplicate archives by hash.
+    pub async fn export_archive_with_options<W: async_std::io::Write + Unpin>(
+        &mut self,
+        id: &ResourceId,
+        writer: &mut W,
+        options: ExportOptions,
+    ) -> Result<usize, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        self.check_access(id, Operations::READ).await?;
+        let meta = self.load_metadata(id).await?;
+        if !self.is_readable(&meta) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        let path = if id.is_root() {
+            "root".into()
+        } else {
+            path_component(&meta.name())
+        };
+
+        let mut tar = TarWriter::new(writer);
+        let mut count = 0;
+        let mut pending = vec![(meta, path)];
+        while let Some((mut meta, path)) = pending.pop() {
+            let mtime = options
+                .mtime
+                .map(|mtime| mtime.timestamp())
+                .unwrap_or_else(|| meta.modified().timestamp());
+
+            // Leave out the sidecars that must not be synced.
+            let id = meta.id();
+            let private = sqlx::query_scalar!(
+                "SELECT variant FROM sidecar_variants WHERE id = ? AND no_sync = 1",
+                id
+            )
+            .fetch_all(&self.db_pool)
+            .await?;
+            for variant in &private {
+                meta.delete_variant(variant);
+            }
+            let json = serde_json::to_vec_pretty(&ArchivedMetadata::from(&meta))?;
+
+            if meta.kind() == ResourceKind::Container {
+                tar.append_dir(&path, mtime).await?;
+                tar.append_file(&format!("{path}/.metadata.json"), &json, mtime)
+                    .await?;
+                let children = self.children_of(&meta.id(), &self.db_pool).await?;
+                let mut children = self.load_metadata_batch(&children).await?;
+                children.sort_by(|a, b| {
+                    a.name()
+                        .cmp(&b.name())
+                        .then_with(|| a.id().to_string().cmp(&b.id().to_string()))
+                });
+                for child in children.into_iter().rev() {
+                    if !self.is_readable(&child) {
+                        continue;
+                    }
+                    let child_path = format!("{path}/{}", path_component(&child.name()));
+                    pending.push((child, child_path));
+                }
+            } else {
+                tar.append_file(&format!("{path}.metadata.json"), &json, mtime)
+                    .await?;
+                let mut variants = meta.variants().clone();
+                variants.sort_by_key(|variant| variant.name());
+                for variant in variants {
+                    self.check_bytes(variant.size())?;
+                    let mut reader = self.filtered_content(&meta, &variant.name()).await?;
+                    let mut content = vec![];
+                    reader.read_to_end(&mut content).await?;
+                    let variant_path = if variant.name() == "default" {
+                        path.clone()
+                    } else {
+                        format!("{path}.variants/{}", path_component(&variant.name()))
+                    };
+                    tar.append_file(&variant_path, &content, mtime).await?;
+                }
+            }
+            count += 1;
+        }
+        tar.finish().await?;
+
+        Ok(count)
+    }
+
+    /// Same as `export_archive_with_options()`, with the archive encrypted to these age
+    /// public keys so that it can transit untrusted relays. Any of the recipients can
+    /// import it with `import_encrypted_archive()`.
+    /// The archive is built in memory before being encrypted.
+    #[cfg(feature = "encryption")]
+    pub async fn export_encrypted_archive<W: async_std::io::Write + Unpin>(
+        &mut self,
+        id: &ResourceId,
+        writer: &mut W,
+        recipients: &[&str],
+        options: ExportOptions,
+    ) -> Result<usize, ResourceStoreError> {
+        use async_std::io::WriteExt;
+
+        let recipients = encryption::recipients(recipients)?;
+        let mut archive = vec![];
+        let count = self
+            .export_archive_with_options(id, &mut archive, options)
+            .await?;
+        writer
+            .write_all(&encryption::encrypt(&archive, &recipients)?)
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Imports a tar or zip archive in this container, recreating its containers and leaves.
+    /// Archives produced by `export_archive()` keep their names, tags, dates and
+    /// variants; other archives are imported as plain files and directories.
+    /// Imported resources get new ids, and are renamed with a `(N)` suffix when their
+    /// name is already used.
+    /// Returns the number of imported resources.
+    /// Observers get the events of the import coalesced, see `begin_batch()`.
+    pub async fn import_archive<R: async_std::io::Read + Unpin>(
+        &mut self,
+        parent: &ResourceId,
+        reader: &mut R,
+    ) -> Result<usize, ResourceStoreError> {
+        self.begin_batch();
+        let count = self.import_archive_entries(parent, reader).await;
+        self.end_batch();
+        count
+    }
+
+    /// Imports an archive produced by `export_encrypted_archive()`, decrypting it with the
+    /// age secret key of one of its recipients. See `import_archive()`.
+    #[cfg(feature = "encryption")]
+    pub async fn import_encrypted_archive<R: async_std::io::Read + Unpin>(
+        &mut self,
+        parent: &ResourceId,
+        reader: &mut R,
+        identity: &str,
+    ) -> Result<usize, ResourceStoreError> {
+        use async_std::io::ReadExt;
+
+        let mut encrypted = vec![];
+        reader.read_to_end(&mut encrypted).await?;
+        let archive = encryption::decrypt(&encrypted, identity)?;
+        self.import_archive(parent, &mut async_std::io::Cursor::new(archive))
+            .await
+    }
+
+    async fn import_archive_entries<R: async_std::io::Read + Unpin>(
+        &mut self,
+        parent: &ResourceId,
+        reader: &mut R,
+    ) -> Result<usize, ResourceStoreError> {
+        if !self.is_container(parent).await? {
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+
+        // Sort the archive entries by path, turning them into directories and files.
+        let mut dirs = BTreeSet::new();
+        let mut files = BTreeMap::new();
+        for entry in read_entries(reader).await? {
+            let components: Vec<&str> = entry
+                .path
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+                .collect();
+            if components.is_empty() {
+                continue;
+            }
+            for i in 1..components.len() {
+                dirs.insert(components[..i].join("/"));
+            }
+            let path = components.join("/");
+            if entry.is_dir {
+                dirs.insert(path);
+            } else {
+                files.insert(path, entry.content);
+            }
+        }
+
+        let mut dir_meta = HashMap::new();
+        let mut leaf_meta = HashMap::new();
+        let mut variants = HashMap::new();
+        let mut leaves = BTreeSet::new();
+        for (path, content) in &files {
+            if let Some(dir) = path.strip_suffix("/.metadata.json") {
+                if let Ok(meta) = serde_json::from_slice::<ArchivedMetadata>(content) {
+                    dir_meta.insert(dir.to_owned(), meta);
+                    continue;
+                }
+            } else if let Some(leaf) = path.strip_suffix(".metadata.json") {
+                if !dirs.contains(leaf) {
+                    if let Ok(meta) = serde_json::from_slice::<ArchivedMetadata>(content) {
+                        leaf_meta.insert(leaf.to_owned(), meta);
+                        leaves.insert(leaf.to_owned());
+                        continue;
+                    }
+                }
+            }
+            leaves.insert(path.clone());
+        }
+        // Extra variants are only recognized for leaves with metadata.
+        for leaf in leaf_meta.keys() {
+            let prefix = format!("{leaf}.variants/");
+            for (path, content) in files.range(prefix.clone()..) {
+                match path.strip_prefix(&prefix) {
+                    Some(variant) => {
+                        leaves.remove(path);
+                        variants.insert((leaf.clone(), variant.to_owned()), content);
+                    }
+                    None => break,
+                }
+            }
+            dirs.retain(|dir| !format!("{dir}/").starts_with(&prefix));
+        }
+
+        // Files can't be under a directory that was not created, like the variants
+        // directory of another leaf.
+        let parent_of = |path: &str, ids: &HashMap<String, ResourceId>| match path.rsplit_once('/')
+        {
+            Some((dir, _)) => ids.get(dir).cloned().ok_or_else(invalid_archive),
+            None => Ok(parent.clone()),
+        };
+        let file_name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_owned();
+
+        // Containers come before their children in lexicographic order.
+        let mut ids = HashMap::new();
+        for path in &dirs {
+            let parent_id = parent_of(path, &ids)?;
+            let archived = dir_meta.get(path);
+            let name = archived
+                .map(|m| m.name.clone())
+                .unwrap_or_else(|| file_name(path));
+            let name = self.unique_name(&parent_id, &name).await;
+            let mut meta = ResourceMetadata::new(
+                &ResourceId::new(),
+                &parent_id,
+                ResourceKind::Container,
+                &name,
+                archived.map(|m| m.tags.clone()).unwrap_or_default(),
+                vec![],
+            );
+            if let Some(archived) = archived {
+                meta.set_created(archived.created.into());
+                meta.set_modified(archived.modified.into());
+            }
+            self.create(&mut meta, None).await?;
+            ids.insert(path.clone(), meta.id());
+        }
+
+        for path in &leaves {
+            let parent_id = parent_of(path, &ids)?;
+            let archived = leaf_meta.get(path);
+
+            let mut contents = vec![];
+            if let Some(archived) = archived {
+                for variant in &archived.variants {
+                    let content = if variant.name == "default" {
+                        files.get(path)
+                    } else {
+                        variants
+                            .get(&(path.clone(), path_component(&variant.name)))
+                            .copied()
+                    };
+                    if let Some(content) = content {
+                        contents.push((variant.name.clone(), variant.mime_type.clone(), content));
+                    }
+                }
+            } else {
+                let mime_type = new_mime_guess::from_path(path)
+                    .first_or_octet_stream()
+                    .essence_str()
+                    .to_owned();
+                contents.push(("default".into(), mime_type, &files[path]));
+            }
+
+            let name = archived
+                .map(|m| m.name.clone())
+                .unwrap_or_else(|| file_name(path));
+            let name = self.unique_name(&parent_id, &name).await;
+            let mut meta = ResourceMetadata::new(
+                &ResourceId::new(),
+                &parent_id,
+                ResourceKind::Leaf,
+                &name,
+                archived.map(|m| m.tags.clone()).unwrap_or_default(),
+                vec![],
+            );
+            if let Some(archived) = archived {
+                meta.set_created(archived.created.into());
+                meta.set_modified(archived.modified.into());
+            }
+
+            let contents = contents
+                .into_iter()
+                .map(|(name, mime_type, content)| {
+                    Variant::new(
+                        VariantMetadata::new(&name, &mime_type, content.len() as _),
+                        Box::new(async_std::io::Cursor::new(content.clone())),
+                    )
+                })
+                .collect();
+            self.create_with_variants(&mut meta, contents).await?;
+        }
+
+        Ok(dirs.len() + leaves.len())
+    }
+
+    /// Returns `name`, or `name(N)` with the first N that makes it unique in this container.
+    async fn unique_name(&mut self, parent: &ResourceId, name: &str) -> String {
+        let mut suffix = 0;
+        let mut final_name = name.to_owned();
+        loop {
+            if let Err(ResourceStoreError::NoSuchResource) =
+                self.find_child(parent, &final_name).await
+            {
+                // Target name is not used, this is our choice.
+                return final_name;
+            }
+
+            let path = Path::new(name);
+            suffix += 1;
+            let ext = match path.extension() {
+                Some(ext) => format!(".{}", ext.to_string_lossy()),
+                None => String::new(),
+            };
+            final_name = format!(
+                "{}({}){}",
+                path.file_stem()
+                    .unwrap_or_else(|| std::ffi::OsStr::new("_"))
+                    .to_string_lossy(),
+                suffix,
+                ext
+            );
+        }
+    }
+
     /// Returns the size of all the resources attached to this container.
     pub async fn container_size(&mut self, id: &ResourceId) -> Result<usize, ResourceStoreError> {
         let mut current_size = 0;
@@ -1179,44 +6172,43 @@ impl<T> Manager<T> {
         Ok(current_size)
     }
 
-    /// Move a resource to a target container
+    /// Move a resource to a target container.
+    /// The resource keeps its id, tags, creation date and scorer.
     pub async fn move_resource(
         &mut self,
         source: &ResourceId,
         target: &ResourceId,
     ) -> Result<ResourceMetadata, ResourceStoreError> {
-        // Check that the target exists and is a container.
-        if !self.is_container(target).await? {
-            return Err(ResourceStoreError::InvalidContainerId);
-        }
-
-        if !self.is_leaf(source).await? {
-            return Err(ResourceStoreError::InvalidResourceId);
-        }
-
-        let source_meta = self.get_metadata(source).await?;
+        let source_meta = self.check_move(source, target).await?;
 
         if source_meta.parent() == *target {
             // Nothing to do, but not an error either.
             return Ok(source_meta);
         }
 
-        self.evict_from_cache(source);
-
         // Update the source metadata with the new parent id.
         let old_parent = source_meta.parent();
-        let new_meta = source_meta.reparent(target);
+        let mut new_meta = source_meta;
+        new_meta.set_parent(target);
+        new_meta.modify_now();
 
         let mut tx = self.db_pool.begin().await?;
 
+        let modified = *new_meta.modified();
         sqlx::query!(
-            "UPDATE OR REPLACE resources SET parent = ? WHERE id = ?",
+            "UPDATE resources SET parent = ?, modified = ? WHERE id = ?",
             target,
+            modified,
             source
         )
         .execute(&mut *tx)
         .await?;
 
+        if self.index_ancestor_names {
+            tx = self.index_ancestor_names(source, tx).await?;
+            tx = self.index_descendants_ancestor_names(source, tx).await?;
+        }
+
         self.store.update(&new_meta, None).await?;
 
         // Update old parent's child list.
@@ -1243,11 +6235,75 @@ impl<T> Manager<T> {
         Ok(new_meta)
     }
 
+    /// Returns the resources that `move_resource()` would move, without changing anything.
+    /// This fails in the same cases as `move_resource()`.
+    pub async fn move_resource_dry_run(
+        &mut self,
+        source: &ResourceId,
+        target: &ResourceId,
+    ) -> Result<DryRunReport, ResourceStoreError> {
+        let source_meta = self.check_move(source, target).await?;
+        if source_meta.parent() == *target {
+            return Ok(DryRunReport::default());
+        }
+        self.subtree_report(source).await
+    }
+
+    // Checks that a resource can be moved to the target, and returns its metadata.
+    async fn check_move(
+        &mut self,
+        source: &ResourceId,
+        target: &ResourceId,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        // Check that the target exists and is a container.
+        if !self.is_container(target).await? {
+            return Err(ResourceStoreError::InvalidContainerId);
+        }
+
+        // The root can't be moved anywhere.
+        if source.is_root() || !self.has_object(source).await? {
+            return Err(ResourceStoreError::InvalidResourceId);
+        }
+
+        self.check_access(source, Operations::WRITE).await?;
+        self.check_access(target, Operations::WRITE).await?;
+
+        let source_meta = self.load_metadata(source).await?;
+
+        if source_meta.parent() == *target {
+            return Ok(source_meta);
+        }
+
+        // A container can't be moved into itself or one of its descendants.
+        if self.is_within(target, source).await? {
+            return Err(ResourceStoreError::ResourceCycle);
+        }
+
+        // Don't clobber a resource with the same name in the target container.
+        if self.find_child(target, &source_meta.name()).await.is_ok() {
+            return Err(ResourceStoreError::ResourceAlreadyExists);
+        }
+
+        self.check_required_variants(target, &source_meta).await?;
+
+        Ok(source_meta)
+    }
+
     /// Copy a resource to a target container with all its variants.
     pub async fn copy_resource(
         &mut self,
         source: &ResourceId,
         target: &ResourceId,
+    ) -> Result<ResourceMetadata, ResourceStoreError> {
+        self.copy_resource_with_progress(source, target, None).await
+    }
+
+    /// Same as `copy_resource()`, reporting progress after each copied variant.
+    pub async fn copy_resource_with_progress(
+        &mut self,
+        source: &ResourceId,
+        target: &ResourceId,
+        mut progress: Option<&mut dyn ProgressSink>,
     ) -> Result<ResourceMetadata, ResourceStoreError> {
         // Copying containers is not supported yet.
         if self.is_container(source).await? {
@@ -1261,15 +6317,15 @@ impl<T> Manager<T> {
             return Err(ResourceStoreError::InvalidContainerId);
         }
 
+        self.check_access(source, Operations::READ).await?;
+        self.check_access(target, Operations::WRITE).await?;
+
         // Fail if a child with the same name already exists in the target container.
-        let source_meta = self.get_metadata(source).await?;
-        if self
-            .child_by_name(target, &source_meta.name())
-            .await
-            .is_ok()
-        {
+        let source_meta = self.load_metadata(source).await?;
+        if self.find_child(target, &source_meta.name()).await.is_ok() {
             return Err(ResourceStoreError::ResourceAlreadyExists);
         }
+        self.check_required_variants(target, &source_meta).await?;
 
         // Get a new metadata representation with the new parent.
         let mut new_meta = source_meta.reparent(target);
@@ -1278,7 +6334,8 @@ impl<T> Manager<T> {
         self.create(&mut new_meta, None).await?;
 
         // For each variant, perform an update_variant
-        for variant in source_meta.variants() {
+        let total = source_meta.variants().len();
+        for (done, variant) in source_meta.variants().iter().enumerate() {
             let item = self
                 .store
                 .get_variant(&source_meta.id(), &variant.name())
@@ -1286,13 +6343,67 @@ impl<T> Manager<T> {
 
             let content = Variant::new(variant.clone(), item);
             self.update_variant(&new_meta.id(), content).await?;
+            if let Some(sink) = progress.as_mut() {
+                sink.progress(done + 1, total, &new_meta.id());
+            }
         }
 
         Ok(new_meta)
     }
 
-    /// Returns the native path of a resource variant.
+    /// Returns a URL serving the content of a variant directly from the store, valid for
+    /// at least `ttl`, so that large content doesn't need to go through the manager.
+    /// Returns None when the store can't provide one, or when a read filter is set since
+    /// the content would bypass it.
+    pub async fn content_url(
+        &mut self,
+        id: &ResourceId,
+        variant: &str,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, ResourceStoreError> {
+        self.check_access(id, Operations::READ).await?;
+        let meta = self.load_metadata(id).await?;
+        if meta.kind() != ResourceKind::Leaf || !self.is_readable(&meta) {
+            return Err(ResourceStoreError::NoSuchResource);
+        }
+        if !meta.has_variant(variant) {
+            return Err(ResourceStoreError::InvalidVariant(variant.into()));
+        }
+        if self.read_filter.is_some() {
+            return Ok(None);
+        }
+
+        Ok(self.store.content_url(id, variant, ttl).await)
+    }
+
+    /// Returns a QR code encoding the content url of a resource variant, as an
+    /// `image/svg+xml` variant named `qr-code`. It expires with the url, and is not stored.
+    /// Returns None when there is no content url for this variant, see `content_url()`.
+    #[cfg(feature = "transformers")]
+    pub async fn share_qr_code(
+        &mut self,
+        id: &ResourceId,
+        variant: &str,
+        ttl: std::time::Duration,
+    ) -> Result<Option<Variant>, ResourceStoreError> {
+        match self.content_url(id, variant, ttl).await? {
+            Some(url) => Ok(Some(crate::share_codes::qr_code_variant(&url)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the native path of a resource variant, or None if the token context
+    /// or the read filter don't allow reading it.
     pub async fn get_native_path(&self, id: &ResourceId, variant: &str) -> Option<PathBuf> {
+        if self.check_access(id, Operations::READ).await.is_err() {
+            return None;
+        }
+        if self.read_filter.is_some() {
+            match self.db_metadata(id).await {
+                Ok(metadata) if self.is_readable(&metadata) => {}
+                _ => return None,
+            }
+        }
         self.store.get_native_path(id, variant).await
     }
 
@@ -1303,43 +6414,58 @@ impl<T> Manager<T> {
         id: &ResourceId,
         name: &str,
     ) -> Result<ResourceMetadata, ResourceStoreError> {
-        let mut current = self.get_metadata(id).await?;
+        self.check_access(id, Operations::WRITE).await?;
+        let mut current = self.load_metadata(id).await?;
 
-        if let Err(ResourceStoreError::NoSuchResource) =
-            self.child_by_name(&current.parent(), name).await
-        {
-            current.set_name(name);
-            current.modify_now();
+        if current.name() == name {
+            // Nothing to do, but not an error either.
+            return Ok(current);
+        }
 
-            self.evict_from_cache(id);
+        match self.find_child(&current.parent(), name).await {
+            Err(ResourceStoreError::NoSuchResource) => {}
+            Err(err) => return Err(err),
+            Ok(_) => return Err(ResourceStoreError::ResourceAlreadyExists),
+        }
 
-            let modified = *current.modified();
-            // We only need to update the name and modified date, so not doing a full update here.
-            sqlx::query!(
-                "UPDATE OR REPLACE resources SET name = ?, modified = ? WHERE id = ?",
-                name,
-                modified,
-                id
-            )
-            .execute(&self.db_pool)
-            .await?;
+        current.set_name(name);
+        current.modify_now();
+
+        let mut tx = self.db_pool.begin().await?;
+
+        let modified = *current.modified();
+        // We only need to update the name and modified date, so not doing a full update here.
+        sqlx::query!(
+            "UPDATE resources SET name = ?, modified = ? WHERE id = ?",
+            name,
+            modified,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
 
-            // Update the metadata in the store.
-            self.store.update(&current, None).await?;
+        // Replace the indexed name.
+        let tx = self.fts.remove_text(id, Some("<name>"), tx).await?;
+        let mut tx = self.fts.add_text(id, "<name>", name, tx).await?;
+        if self.index_ancestor_names && current.kind() == ResourceKind::Container {
+            tx = self.index_descendants_ancestor_names(id, tx).await?;
+        }
 
-            self.update_cache(&current);
+        // Update the metadata in the store.
+        self.store.update(&current, None).await?;
 
-            // Trigger modification observers for the resource and its parent.
-            self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
-                &current.parent(),
-                id,
-            )));
+        tx.commit().await?;
 
-            self.notify_observers(&ResourceModification::Modified(id.clone()));
+        self.update_cache(&current);
 
-            Ok(current)
-        } else {
-            Err(ResourceStoreError::ResourceAlreadyExists)
-        }
+        // Trigger modification observers for the resource and its parent.
+        self.notify_observers(&ResourceModification::ChildModified(ParentChild::new(
+            &current.parent(),
+            id,
+        )));
+
+        self.notify_observers(&ResourceModification::Modified(id.clone()));
+
+        Ok(current)
     }
 }
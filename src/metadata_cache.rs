@@ -0,0 +1,113 @@
+/// The metadata cache, with a configurable replacement policy.
+use crate::common::{ResourceId, ResourceMetadata};
+use crate::config::CachePolicy;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+// The number of least recently used entries considered for eviction
+// with the frecency policy.
+const EVICTION_CANDIDATES: usize = 8;
+
+pub(crate) struct MetadataCache {
+    entries: LruCache<ResourceId, ResourceMetadata>,
+    policy: CachePolicy,
+}
+
+impl MetadataCache {
+    pub(crate) fn new(capacity: usize, policy: CachePolicy) -> Self {
+        Self {
+            entries: LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(unsafe { NonZeroUsize::new_unchecked(128) }),
+            ),
+            policy,
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: &ResourceId) -> Option<&ResourceMetadata> {
+        self.entries.get(id)
+    }
+
+    pub(crate) fn put(&mut self, metadata: &ResourceMetadata) {
+        let id = metadata.id();
+        if self.policy == CachePolicy::Frecency
+            && self.entries.len() == self.entries.cap().get()
+            && !self.entries.contains(&id)
+        {
+            // Make room by hand, since the lru cache would drop its oldest entry.
+            let victim = self
+                .entries
+                .iter()
+                .rev()
+                .take(EVICTION_CANDIDATES)
+                .min_by_key(|(_, meta)| meta.frecency())
+                .map(|(id, _)| id.clone());
+            if let Some(victim) = victim {
+                self.entries.pop(&victim);
+            }
+        }
+        self.entries.put(id, metadata.clone());
+    }
+
+    pub(crate) fn pop(&mut self, id: &ResourceId) {
+        self.entries.pop(id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{ResourceKind, ROOT_ID};
+    use crate::scorer::{VisitEntry, VisitPriority};
+
+    fn meta(id: i32, visits: usize) -> ResourceMetadata {
+        let mut meta = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            &format!("leaf #{id}"),
+            vec![],
+            vec![],
+        );
+        for _ in 0..visits {
+            meta.update_scorer(&VisitEntry::now(VisitPriority::High));
+        }
+        meta
+    }
+
+    fn scan(cache: &mut MetadataCache) {
+        for id in 100..120 {
+            cache.put(&meta(id, 0));
+        }
+    }
+
+    #[test]
+    fn lru_policy() {
+        let mut cache = MetadataCache::new(10, CachePolicy::Lru);
+        cache.put(&meta(1, 5));
+        scan(&mut cache);
+        assert!(cache.get(&1.into()).is_none());
+        assert!(cache.get(&119.into()).is_some());
+    }
+
+    #[test]
+    fn frecency_policy() {
+        let mut cache = MetadataCache::new(10, CachePolicy::Frecency);
+        cache.put(&meta(1, 5));
+        cache.put(&meta(2, 1));
+        scan(&mut cache);
+        // Frecent resources survive the scan.
+        assert!(cache.get(&1.into()).is_some());
+        assert!(cache.get(&2.into()).is_some());
+        assert!(cache.get(&119.into()).is_some());
+        assert!(cache.get(&100.into()).is_none());
+
+        // Updating an entry doesn't evict anything.
+        cache.put(&meta(119, 0));
+        assert!(cache.get(&1.into()).is_some());
+        assert_eq!(cache.entries.len(), 10);
+    }
+}
@@ -0,0 +1,158 @@
+/// Per principal rate limiting, using token buckets.
+///
+/// A principal is any string identifying a caller: a capability token
+/// or an HTTP access key for instance.
+/// Each principal gets its own budget of operations and bytes per second,
+/// so that one misbehaving caller can't starve the others.
+use crate::common::ResourceStoreError;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub operations_per_second: Option<u32>,
+    pub bytes_per_second: Option<u64>,
+}
+
+struct Bucket {
+    rate: f64, // Tokens added per second, which is also the bucket capacity.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    // Takes `amount` tokens if they are available. Requests larger than the
+    // rate only need a full bucket and make the balance negative, delaying
+    // the next ones.
+    fn take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens < amount.min(self.rate) {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+struct Buckets {
+    operations: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<String, Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn with_buckets<F>(&self, principal: &str, closure: F) -> Result<(), ResourceStoreError>
+    where
+        F: FnOnce(&mut Buckets) -> bool,
+    {
+        let mut buckets = self.buckets.lock();
+        let entry = buckets
+            .entry(principal.to_owned())
+            .or_insert_with(|| Buckets {
+                operations: self
+                    .limits
+                    .operations_per_second
+                    .map(|rate| Bucket::new(rate as _)),
+                bytes: self
+                    .limits
+                    .bytes_per_second
+                    .map(|rate| Bucket::new(rate as _)),
+            });
+
+        if closure(entry) {
+            Ok(())
+        } else {
+            Err(ResourceStoreError::RateLimited)
+        }
+    }
+
+    /// Accounts for one operation, failing if this principal exceeded its budget.
+    pub fn check_operation(&self, principal: &str) -> Result<(), ResourceStoreError> {
+        self.with_buckets(principal, |buckets| match &mut buckets.operations {
+            Some(bucket) => bucket.take(1.0),
+            None => true,
+        })
+    }
+
+    /// Accounts for `bytes` transferred, failing if this principal exceeded its budget.
+    pub fn check_bytes(&self, principal: &str, bytes: u64) -> Result<(), ResourceStoreError> {
+        self.with_buckets(principal, |buckets| match &mut buckets.bytes {
+            Some(bucket) => bucket.take(bytes as _),
+            None => true,
+        })
+    }
+
+    /// Forgets about the state of this principal.
+    pub fn reset(&self, principal: &str) {
+        let _ = self.buckets.lock().remove(principal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits() {
+        let limiter = RateLimiter::new(RateLimits {
+            operations_per_second: Some(3),
+            bytes_per_second: Some(100),
+        });
+
+        // Operations are accounted per principal.
+        for _ in 0..3 {
+            limiter.check_operation("app1").unwrap();
+        }
+        assert!(matches!(
+            limiter.check_operation("app1"),
+            Err(ResourceStoreError::RateLimited)
+        ));
+        limiter.check_operation("app2").unwrap();
+
+        // A large transfer goes through, but blocks the next one.
+        limiter.check_bytes("app1", 1000).unwrap();
+        assert!(matches!(
+            limiter.check_bytes("app1", 1),
+            Err(ResourceStoreError::RateLimited)
+        ));
+
+        // Resetting a principal restores its budget.
+        limiter.reset("app1");
+        limiter.check_operation("app1").unwrap();
+        limiter.check_bytes("app1", 1).unwrap();
+
+        // No limits configured.
+        let limiter = RateLimiter::new(RateLimits::default());
+        for _ in 0..1000 {
+            limiter.check_operation("app").unwrap();
+            limiter.check_bytes("app", 1_000_000).unwrap();
+        }
+    }
+}
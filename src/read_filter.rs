@@ -0,0 +1,63 @@
+/// Read side hooks that can veto or transform content delivery.
+///
+/// Filters are consulted by the Manager's get paths and searches with the metadata
+/// of the resource and the current caller, identified by its capability token if any.
+/// That makes it possible to implement policies like parental controls hiding
+/// resources with some tags or mime types for a given caller profile.
+use crate::common::{BoxedReader, ResourceMetadata};
+use std::collections::HashSet;
+
+pub trait ReadFilter {
+    /// Returns `false` if this resource must be hidden from the caller.
+    fn allows(&self, caller: Option<&str>, metadata: &ResourceMetadata) -> bool;
+
+    /// Transforms the content of a variant before it is delivered to the caller.
+    fn transform(
+        &self,
+        _caller: Option<&str>,
+        _metadata: &ResourceMetadata,
+        _variant: &str,
+        content: BoxedReader,
+    ) -> BoxedReader {
+        content
+    }
+}
+
+/// A filter hiding resources with any of the given tags or variant mime types.
+/// Callers listed as trusted are not filtered.
+#[derive(Default)]
+pub struct TagMimeFilter {
+    tags: HashSet<String>,
+    mime_types: HashSet<String>,
+    trusted: HashSet<String>,
+}
+
+impl TagMimeFilter {
+    pub fn new(tags: &[&str], mime_types: &[&str]) -> Self {
+        Self {
+            tags: tags.iter().map(|e| (*e).to_owned()).collect(),
+            mime_types: mime_types.iter().map(|e| (*e).to_owned()).collect(),
+            trusted: HashSet::new(),
+        }
+    }
+
+    pub fn trust(&mut self, caller: &str) {
+        self.trusted.insert(caller.into());
+    }
+}
+
+impl ReadFilter for TagMimeFilter {
+    fn allows(&self, caller: Option<&str>, metadata: &ResourceMetadata) -> bool {
+        if let Some(caller) = caller {
+            if self.trusted.contains(caller) {
+                return true;
+            }
+        }
+
+        !metadata.tags().iter().any(|tag| self.tags.contains(tag))
+            && !metadata
+                .variants()
+                .iter()
+                .any(|variant| self.mime_types.contains(&variant.mime_type()))
+    }
+}
@@ -1,14 +1,57 @@
 /// Scorer based on the frecency algorithm
 /// See https://developer.mozilla.org/en-US/docs/Mozilla/Tech/Places/Frecency_algorithm
+///
+/// Each manager samples and weights visits with its own `Scoring` settings, whose
+/// algorithm can be replaced by another `ScoringPolicy`.
 use chrono::{DateTime, Utc};
 use libsqlite3_sys::{
-    sqlite3_context, sqlite3_result_int, sqlite3_value, sqlite3_value_blob, sqlite3_value_bytes,
+    sqlite3_context, sqlite3_result_int, sqlite3_user_data, sqlite3_value, sqlite3_value_blob,
+    sqlite3_value_bytes,
 };
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
-use std::os::raw::c_int;
+use std::os::raw::{c_int, c_void};
+use std::sync::Arc;
 
 static MAX_VISIT_ENTRIES: usize = 10;
 
+/// Tunes how many visits are sampled and how they fade over time.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScorerConfig {
+    pub max_visit_entries: usize, // The number of sampled visits, between 1 and 255.
+    pub weight_buckets: Vec<(i64, u32)>, // (maximum age in days, weight), by increasing age.
+    pub default_weight: u32,      // The weight of visits older than all the buckets.
+}
+
+impl Default for ScorerConfig {
+    fn default() -> Self {
+        Self {
+            max_visit_entries: MAX_VISIT_ENTRIES,
+            weight_buckets: vec![(4, 100), (14, 70), (31, 50), (90, 30)],
+            default_weight: 10,
+        }
+    }
+}
+
+impl ScorerConfig {
+    fn max_entries(&self) -> usize {
+        self.max_visit_entries.clamp(1, u8::MAX as usize)
+    }
+
+    fn weight_for(&self, when: i64) -> u32 {
+        use chrono::TimeZone;
+
+        let days = (Utc::now() - Utc.timestamp_nanos(when)).num_days();
+        self.weight_buckets
+            .iter()
+            .find(|(max_days, _)| days <= *max_days)
+            .map(|(_, weight)| *weight)
+            .unwrap_or(self.default_weight)
+    }
+}
+
 #[derive(Debug, Clone, Readable, Writable)]
 pub enum VisitPriority {
     Normal,
@@ -36,40 +79,133 @@ pub struct VisitEntry {
 impl VisitEntry {
     pub fn new(when: &DateTime<Utc>, priority: VisitPriority) -> Self {
         Self {
-            timestamp: (*when).naive_utc().timestamp_nanos(),
+            timestamp: when.timestamp_nanos_opt().unwrap_or_default(),
             priority,
         }
     }
 
     pub fn now(priority: VisitPriority) -> Self {
         Self {
-            timestamp: Utc::now().naive_utc().timestamp_nanos(),
+            timestamp: Utc::now().timestamp_nanos_opt().unwrap_or_default(),
             priority,
         }
     }
+
+    pub fn when(&self) -> DateTime<Utc> {
+        use chrono::TimeZone;
+
+        Utc.timestamp_nanos(self.timestamp)
+    }
 }
 
 #[derive(Clone, Debug, Readable, Writable)]
 pub struct Scorer {
     all_time_visits: u32, // The total number of visits, which can be greater than the entries we keep.
-    #[speedy(length_type = u8)] // u8 is enough since MAX_VISIT_ENTRIES < 255
+    #[speedy(length_type = u8)] // u8 is enough since ScorerConfig::max_entries() <= 255
     entries: Vec<VisitEntry>,
 }
 
-fn weight_for(when: i64) -> u32 {
-    use chrono::TimeZone;
-
-    let days = (Utc::now() - Utc.timestamp_nanos(when)).num_days();
-    if days <= 4 {
-        100
-    } else if days <= 14 {
-        70
-    } else if days <= 31 {
-        50
-    } else if days <= 90 {
-        30
-    } else {
-        10
+/// Computes the score of a resource from its visits.
+pub trait ScoringPolicy: Send + Sync {
+    /// `entries` are the sampled visits, oldest first, and `all_time_visits` the total
+    /// number of visits which can be greater than the number of entries. `config` holds
+    /// the settings of the manager, which policies are free to ignore.
+    /// Resources without visits always have a score of 0.
+    fn score(&self, config: &ScorerConfig, all_time_visits: u32, entries: &[VisitEntry]) -> u32;
+}
+
+/// The Places frecency algorithm, used by default.
+pub struct PlacesFrecency;
+
+impl ScoringPolicy for PlacesFrecency {
+    fn score(&self, config: &ScorerConfig, all_time_visits: u32, entries: &[VisitEntry]) -> u32 {
+        // For each sampled visit, the score is (bonus / 100.0) * weight
+        // The final score for each item is ceiling(total visit count * sum of points for sampled visits / number of sampled visits)
+
+        let sum = entries
+            .iter()
+            .map(|item| item.priority.bonus() * config.weight_for(item.timestamp))
+            .sum::<u32>();
+
+        all_time_visits * sum / (100 * entries.len() as u32)
+    }
+}
+
+/// Scores resources by the time of their last visit, in seconds since EPOCH.
+pub struct RecencyPolicy;
+
+impl ScoringPolicy for RecencyPolicy {
+    fn score(&self, _config: &ScorerConfig, _all_time_visits: u32, entries: &[VisitEntry]) -> u32 {
+        entries
+            .iter()
+            .map(|item| item.when().timestamp().clamp(0, u32::MAX as i64) as u32)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_SCORING: Scoring = Scoring::default();
+}
+
+/// The scorer settings and scoring policy of a manager. They are shared with the SQLite
+/// `frecency` function of its database pool, see `crate::manager::pool_options()`.
+pub struct Scoring {
+    config: RwLock<ScorerConfig>,
+    policy: RwLock<Arc<dyn ScoringPolicy>>,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Self::new(ScorerConfig::default())
+    }
+}
+
+impl Scoring {
+    /// Settings using the Places frecency algorithm.
+    pub fn new(config: ScorerConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            policy: RwLock::new(Arc::new(PlacesFrecency)),
+        }
+    }
+
+    pub fn config(&self) -> ScorerConfig {
+        self.config.read().clone()
+    }
+
+    /// Replaces the sampling and weighting settings, and returns the previous ones.
+    pub fn set_config(&self, config: ScorerConfig) -> ScorerConfig {
+        std::mem::replace(&mut *self.config.write(), config)
+    }
+
+    /// Replaces the scoring policy. Stored frecencies need to be recomputed afterwards,
+    /// see `Manager::set_scoring_policy()`.
+    pub fn set_policy(&self, policy: Arc<dyn ScoringPolicy>) {
+        *self.policy.write() = policy;
+    }
+
+    /// Records a visit, dropping the oldest sampled ones to make room for it.
+    pub fn add(&self, scorer: &mut Scorer, entry: &VisitEntry) {
+        scorer.add_sampled(entry, self.config.read().max_entries());
+    }
+
+    /// Same as `Scorer::merge()`, keeping the number of sampled visits of these settings.
+    pub fn merge(&self, scorer: &mut Scorer, entries: &[VisitEntry]) -> usize {
+        scorer.merge_sampled(entries, self.config.read().max_entries())
+    }
+
+    /// Returns the frecency of these visits.
+    pub fn frecency(&self, scorer: &Scorer) -> u32 {
+        if scorer.entries.is_empty() {
+            return 0;
+        }
+
+        // Only sample the most recent visits if the configuration changed.
+        let config = self.config.read();
+        let entries = &scorer.entries[scorer.entries.len().saturating_sub(config.max_entries())..];
+        let policy = self.policy.read().clone();
+        policy.score(&config, scorer.all_time_visits, entries)
     }
 }
 
@@ -83,10 +219,15 @@ impl Default for Scorer {
 }
 
 impl Scorer {
+    /// Records a visit with the default settings, see `Scoring::add()`.
     pub fn add(&mut self, entry: &VisitEntry) {
-        // Remove the oldest entry to make room for the new one.
-        if self.entries.len() == MAX_VISIT_ENTRIES {
-            let _ = self.entries.remove(0);
+        DEFAULT_SCORING.add(self, entry)
+    }
+
+    fn add_sampled(&mut self, entry: &VisitEntry, max_entries: usize) {
+        // Remove the oldest entries to make room for the new one.
+        if self.entries.len() >= max_entries {
+            let _ = self.entries.drain(..=self.entries.len() - max_entries);
         }
 
         self.entries.push(entry.clone());
@@ -110,21 +251,9 @@ impl Scorer {
     //     self.all_time_visits * sum.round() as u32 / self.entries.len() as u32
     // }
 
+    /// The frecency with the default settings, see `Scoring::frecency()`.
     pub fn frecency(&self) -> u32 {
-        if self.entries.is_empty() {
-            return 0;
-        }
-
-        // For each sampled visit, the score is (bonus / 100.0) * weight
-        // The final score for each item is ceiling(total visit count * sum of points for sampled visits / number of sampled visits)
-
-        let sum = self
-            .entries
-            .iter()
-            .map(|item| (item.priority.bonus() * weight_for(item.timestamp)))
-            .sum::<u32>();
-
-        self.all_time_visits * sum / (100 * self.entries.len() as u32)
+        DEFAULT_SCORING.frecency(self)
     }
 
     #[cfg(test)]
@@ -137,6 +266,56 @@ impl Scorer {
         score.frecency()
     }
 
+    /// The sampled visits, oldest first.
+    pub fn entries(&self) -> &[VisitEntry] {
+        &self.entries
+    }
+
+    /// The total number of visits, including the ones no longer sampled.
+    pub fn all_time_visits(&self) -> u32 {
+        self.all_time_visits
+    }
+
+    /// Merge visits coming from another device, skipping the ones we already know
+    /// about based on their timestamp. Only the most recent entries are kept.
+    /// Returns the number of visits that were added.
+    pub fn merge(&mut self, entries: &[VisitEntry]) -> usize {
+        DEFAULT_SCORING.merge(self, entries)
+    }
+
+    fn merge_sampled(&mut self, entries: &[VisitEntry], max_entries: usize) -> usize {
+        let mut added = 0;
+        for entry in entries {
+            if self
+                .entries
+                .iter()
+                .any(|item| item.timestamp == entry.timestamp)
+            {
+                continue;
+            }
+            self.entries.push(entry.clone());
+            added += 1;
+        }
+
+        self.entries.sort_by_key(|item| item.timestamp);
+        if self.entries.len() > max_entries {
+            let _ = self.entries.drain(..self.entries.len() - max_entries);
+        }
+        self.all_time_visits += added as u32;
+        added
+    }
+
+    /// Serialize the sampled visits into a compact payload suitable to sync them
+    /// with another device.
+    pub fn sync_payload(&self) -> Vec<u8> {
+        self.entries.write_to_vec().unwrap()
+    }
+
+    /// Decode the visits of a payload created by `sync_payload()`.
+    pub fn entries_from_payload(input: &[u8]) -> Result<Vec<VisitEntry>, speedy::Error> {
+        Vec::<VisitEntry>::read_from_buffer(input)
+    }
+
     pub fn as_binary(&self) -> Vec<u8> {
         self.write_to_vec().unwrap()
     }
@@ -154,7 +333,8 @@ impl PartialEq for Scorer {
 
 /// # Safety
 ///
-/// SQlite function to return an up to date value of the frecency.
+/// SQlite function to return an up to date value of the frecency. Its user data is
+/// either null, to use the default settings, or a pointer obtained from `Arc<Scoring>`.
 pub unsafe extern "C" fn sqlite_frecency(
     ctx: *mut sqlite3_context,
     argc: c_int,
@@ -183,7 +363,21 @@ pub unsafe extern "C" fn sqlite_frecency(
 
     // 2. Get a Scorer object and return the frecency.
     let scorer = Scorer::from_binary(array);
-    sqlite3_result_int(ctx, scorer.frecency() as _);
+    let scoring = sqlite3_user_data(ctx) as *const Scoring;
+    let frecency = match scoring.as_ref() {
+        Some(scoring) => scoring.frecency(&scorer),
+        None => scorer.frecency(),
+    };
+    sqlite3_result_int(ctx, frecency as _);
+}
+
+/// # Safety
+///
+/// Releases the `Arc<Scoring>` given as user data to `sqlite_frecency`.
+pub unsafe extern "C" fn release_scoring(scoring: *mut c_void) {
+    if !scoring.is_null() {
+        drop(Arc::from_raw(scoring as *const Scoring));
+    }
 }
 
 #[cfg(test)]
@@ -228,6 +422,67 @@ mod tests {
         assert_eq!(score.frecency(), 205);
         // assert_eq!(score.frecency(), score.frecency_float());
     }
+
+    #[test]
+    fn recency_policy() {
+        use chrono::Duration;
+
+        let now = Utc::now();
+        let entries = vec![
+            VisitEntry::new(&(now - Duration::days(10)), VisitPriority::VeryHigh),
+            VisitEntry::new(&now, VisitPriority::Normal),
+        ];
+        let config = ScorerConfig::default();
+        assert_eq!(
+            RecencyPolicy.score(&config, 2, &entries),
+            now.timestamp() as u32
+        );
+        assert!(
+            RecencyPolicy.score(&config, 1, &entries[..1])
+                < RecencyPolicy.score(&config, 1, &entries[1..])
+        );
+        // The Places frecency favors the high priority visit instead.
+        assert!(
+            PlacesFrecency.score(&config, 1, &entries[..1])
+                > PlacesFrecency.score(&config, 1, &entries[1..])
+        );
+    }
+
+    #[test]
+    fn merge_visits() {
+        use chrono::Duration;
+
+        let now = Utc::now();
+        let mut local = Scorer::default();
+        local.add(&VisitEntry::new(&now, VisitPriority::Normal));
+
+        let mut remote = Scorer::default();
+        remote.add(&VisitEntry::new(
+            &(now - Duration::days(10)),
+            VisitPriority::Normal,
+        ));
+        remote.add(&VisitEntry::new(&now, VisitPriority::Normal));
+
+        let entries = Scorer::entries_from_payload(&remote.sync_payload()).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        // The visit with the same timestamp is not counted twice.
+        assert_eq!(local.merge(&entries), 1);
+        assert_eq!(local.entries().len(), 2);
+        assert_eq!(local.frecency(), 170);
+        assert_eq!(local.merge(&entries), 0);
+
+        // Only the most recent entries are kept.
+        let many: Vec<VisitEntry> = (1..=MAX_VISIT_ENTRIES as i64)
+            .map(|i| VisitEntry::new(&(now - Duration::days(i)), VisitPriority::Normal))
+            .collect();
+        assert_eq!(local.merge(&many), MAX_VISIT_ENTRIES - 1);
+        assert_eq!(local.entries().len(), MAX_VISIT_ENTRIES);
+        assert_eq!(
+            local.entries().last().unwrap().timestamp,
+            now.timestamp_nanos_opt().unwrap()
+        );
+    }
 }
 
 // use test::Bencher;
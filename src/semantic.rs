@@ -0,0 +1,61 @@
+/// Semantic search support.
+///
+/// Embeddings are computed by an `Embedder` provided by the embedder of
+/// this crate (a local model, or a remote service) from the text indexed for
+/// each resource, and stored in the database.
+/// Searches compare a query embedding with all the stored ones, which is fine
+/// for the number of resources found on a device.
+use crate::common::ResourceStoreError;
+use async_trait::async_trait;
+
+#[async_trait(?Send)]
+pub trait Embedder {
+    /// Returns the embedding vector for this text.
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, ResourceStoreError>;
+}
+
+pub(crate) fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+pub(crate) fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// Returns the cosine similarity of two vectors, or 0 if they can't be compared.
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors() {
+        let vector = vec![1.0, -2.5, 0.0];
+        assert_eq!(from_blob(&to_blob(&vector)), vector);
+
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]), -1.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -0,0 +1,44 @@
+/// QR codes of share links.
+///
+/// `Manager::share_qr_code()` encodes the content url of a resource variant in a QR code,
+/// so that the UI can show "scan to receive this file" without its own QR library.
+/// Content urls expire, so QR codes are generated on demand instead of being stored as
+/// a derived variant.
+use crate::common::{ResourceStoreError, Variant, VariantMetadata};
+use qrcode::render::svg;
+use qrcode::QrCode;
+
+/// The name of the variant holding the QR code.
+pub const QR_CODE: &str = "qr-code";
+
+/// The minimum width and height of QR codes, in pixels.
+const QR_CODE_SIZE: u32 = 256;
+
+/// Encodes a url as a square SVG QR code, with its quiet zone.
+pub fn qr_code_svg(url: &str) -> Result<String, ResourceStoreError> {
+    let code = QrCode::new(url.as_bytes())
+        .map_err(|err| ResourceStoreError::Custom(format!("QrCodeError: {err}")))?;
+
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE)
+        .build())
+}
+
+/// Encodes a url as an `image/svg+xml` QR code variant.
+pub(crate) fn qr_code_variant(url: &str) -> Result<Variant, ResourceStoreError> {
+    let content = qr_code_svg(url)?.into_bytes();
+    Ok(Variant::new(
+        VariantMetadata::new(QR_CODE, "image/svg+xml", content.len() as _),
+        Box::new(async_std::io::Cursor::new(content)),
+    ))
+}
+
+#[test]
+fn share_qr_codes() {
+    let svg = qr_code_svg("http://127.0.0.1:8080/cmgr/key/id-5/default").unwrap();
+    assert!(svg.starts_with("<?xml"));
+    assert!(svg.contains("<svg"));
+    // 33 modules and the quiet zone, 7 pixels each to be at least `QR_CODE_SIZE` wide.
+    assert!(svg.contains(r#"width="287" height="287""#));
+}
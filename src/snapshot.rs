@@ -0,0 +1,146 @@
+/// Point-in-time views of the database, used to page through search results
+/// consistently while resources are being modified.
+///
+/// A snapshot holds a read transaction open: SQLite keeps serving the database
+/// as it was when the transaction started, and since the database is in WAL mode
+/// writers are not blocked meanwhile. Snapshots should be short lived, since the
+/// WAL file can't be checkpointed past an open snapshot.
+///
+/// Like the searches of the manager, snapshot searches are restricted to the
+/// capability scope of the caller and filtered by its read filter, both captured
+/// when the snapshot is taken.
+use crate::common::{IdFrec, Page, Paged, ResourceId, ResourceStoreError};
+use crate::fts::Fts;
+use crate::manager::{readable_items, readable_page_of, resource_is_within};
+use crate::read_filter::ReadFilter;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::sync::Arc;
+
+pub struct SearchSnapshot {
+    tx: Transaction<'static, Sqlite>,
+    fts: Fts,                  // The search settings when the snapshot was taken.
+    scope: Option<ResourceId>, // The container searches are restricted to, if any.
+    read_filter: Option<Arc<dyn ReadFilter + Send + Sync>>,
+    caller: Option<String>, // The capability token of the caller, for the read filter.
+}
+
+impl SearchSnapshot {
+    pub(crate) async fn new(
+        pool: &SqlitePool,
+        fts: Fts,
+        scope: Option<ResourceId>,
+        read_filter: Option<Arc<dyn ReadFilter + Send + Sync>>,
+        caller: Option<String>,
+    ) -> Result<Self, ResourceStoreError> {
+        let mut tx = pool.begin().await?;
+        // Transactions are deferred, so the snapshot starts with the first read.
+        sqlx::query("SELECT count(*) FROM resources")
+            .fetch_one(&mut *tx)
+            .await?;
+        Ok(Self {
+            tx,
+            fts,
+            scope,
+            read_filter,
+            caller,
+        })
+    }
+
+    // Returns the container a search is run in, like `Manager::search_within()`.
+    async fn search_within(
+        &mut self,
+        within: Option<&ResourceId>,
+    ) -> Result<Option<ResourceId>, ResourceStoreError> {
+        match (within, &self.scope) {
+            (Some(within), Some(scope)) => {
+                if !resource_is_within(&mut *self.tx, within, scope).await? {
+                    return Err(ResourceStoreError::PermissionDenied);
+                }
+                Ok(Some(within.clone()))
+            }
+            (Some(within), None) => Ok(Some(within.clone())),
+            (None, scope) => Ok(scope.clone()),
+        }
+    }
+
+    // Returns the page to query, like `Manager::query_page()`.
+    fn query_page(&self, page: Page) -> Page {
+        if self.read_filter.is_some() {
+            Page::all()
+        } else {
+            page
+        }
+    }
+
+    // Drops the results hidden by the read filter, like `Manager::readable_page()`.
+    async fn readable_page<R>(
+        &mut self,
+        results: Paged<R>,
+        page: Page,
+        id: fn(&R) -> &ResourceId,
+    ) -> Result<Paged<R>, ResourceStoreError> {
+        let filter = match &self.read_filter {
+            Some(filter) => filter.clone(),
+            None => return Ok(results),
+        };
+
+        let readable = readable_items(
+            &mut self.tx,
+            filter.as_ref(),
+            self.caller.as_deref(),
+            results.items,
+            id,
+        )
+        .await?;
+        Ok(readable_page_of(readable, page))
+    }
+
+    /// Returns a page of the resources with this tag, like `Manager::by_tag_paged()`.
+    pub async fn by_tag_paged(
+        &mut self,
+        tag: &str,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<ResourceId>, ResourceStoreError> {
+        if tag.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTagQuery".into()));
+        }
+
+        let within = self.search_within(within).await?;
+
+        let query = self.query_page(page);
+        let results = self
+            .fts
+            .by_tag_in(&mut self.tx, tag, within.as_ref(), query)
+            .await?;
+        self.readable_page(results, page, |id| id).await
+    }
+
+    /// Returns a page of the resources matching this text, like `Manager::by_text_paged()`.
+    pub async fn by_text_paged(
+        &mut self,
+        text: &str,
+        tag: Option<String>,
+        within: Option<&ResourceId>,
+        page: Page,
+    ) -> Result<Paged<IdFrec>, ResourceStoreError> {
+        if text.trim().is_empty() {
+            return Err(ResourceStoreError::Custom("EmptyTextQuery".into()));
+        }
+
+        let within = self.search_within(within).await?;
+
+        let query = self.query_page(page);
+        let results = self
+            .fts
+            .search_in(&mut self.tx, text, tag, within.as_ref(), query)
+            .await?;
+        self.readable_page(results, page, |item| &item.id).await
+    }
+
+    /// Ends the snapshot. Dropping it has the same effect, but this reports errors.
+    pub async fn release(self) -> Result<(), ResourceStoreError> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,539 @@
+/// Helpers to fuzz a Manager and its store: a generator of random operations
+/// and checkers for the invariants that must hold after each of them.
+/// Generation is deterministic for a given seed, so failures can be replayed.
+///
+/// Also provides a conformance suite for `ResourceStore` implementations.
+use crate::common::{
+    ResourceId, ResourceKind, ResourceMetadata, ResourceStore, ResourceStoreError, Variant,
+    VariantMetadata, ROOT_ID,
+};
+use crate::manager::Manager;
+use async_std::io::ReadExt;
+use speedy::Readable;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+
+/// A small pseudo random generator (splitmix64).
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in [0, max[, or 0 if max is 0.
+    pub fn below(&mut self, max: usize) -> usize {
+        if max == 0 {
+            return 0;
+        }
+        (self.next_u64() % max as u64) as usize
+    }
+
+    pub fn pick<'a, I>(&mut self, items: &'a [I]) -> Option<&'a I> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.below(items.len())])
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Operation {
+    CreateLeaf { parent: ResourceId, name: String },
+    CreateContainer { parent: ResourceId, name: String },
+    UpdateVariant { id: ResourceId, size: u32 },
+    AddTag { id: ResourceId, tag: String },
+    RemoveTag { id: ResourceId, tag: String },
+    Rename { id: ResourceId, name: String },
+    Move { id: ResourceId, target: ResourceId },
+    Delete { id: ResourceId },
+}
+
+struct Entry {
+    id: ResourceId,
+    parent: ResourceId,
+    kind: ResourceKind,
+}
+
+/// Generates random operations on the resources it created. Names and tags are
+/// picked from small sets to trigger name clashes, and moves may create cycles:
+/// failing operations are expected and leave the generator state unchanged.
+pub struct OperationGenerator {
+    rng: Rng,
+    entries: Vec<Entry>, // All the resources except the root, in creation order.
+    next_id: u32,
+}
+
+impl OperationGenerator {
+    /// Creates a generator for a manager that only has a root.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            entries: vec![],
+            next_id: 0,
+        }
+    }
+
+    fn containers(&self) -> Vec<ResourceId> {
+        let mut containers = vec![ROOT_ID.clone()];
+        containers.extend(
+            self.entries
+                .iter()
+                .filter(|entry| entry.kind == ResourceKind::Container)
+                .map(|entry| entry.id.clone()),
+        );
+        containers
+    }
+
+    fn ids(&self, kind: Option<ResourceKind>) -> Vec<ResourceId> {
+        self.entries
+            .iter()
+            .filter(|entry| kind.as_ref().is_none_or(|kind| entry.kind == *kind))
+            .map(|entry| entry.id.clone())
+            .collect()
+    }
+
+    fn name(&mut self) -> String {
+        format!("name #{}", self.rng.below(20))
+    }
+
+    fn tag(&mut self) -> String {
+        format!("tag-{}", self.rng.below(5))
+    }
+
+    /// Returns a creation operation, to build an initial hierarchy.
+    pub fn next_creation(&mut self) -> Operation {
+        let parent = self.rng.pick(&self.containers()).cloned().unwrap();
+        let name = self.name();
+        if self.rng.below(4) == 0 {
+            Operation::CreateContainer { parent, name }
+        } else {
+            Operation::CreateLeaf { parent, name }
+        }
+    }
+
+    pub fn next_operation(&mut self) -> Operation {
+        let leaves = self.ids(Some(ResourceKind::Leaf));
+        let all = self.ids(None);
+        if all.is_empty() {
+            return self.next_creation();
+        }
+
+        match self.rng.below(10) {
+            0..=3 => self.next_creation(),
+            4 if !leaves.is_empty() => Operation::UpdateVariant {
+                id: self.rng.pick(&leaves).cloned().unwrap(),
+                size: self.rng.below(1024) as u32,
+            },
+            5 => Operation::AddTag {
+                id: self.rng.pick(&all).cloned().unwrap(),
+                tag: self.tag(),
+            },
+            6 => Operation::RemoveTag {
+                id: self.rng.pick(&all).cloned().unwrap(),
+                tag: self.tag(),
+            },
+            7 => Operation::Rename {
+                id: self.rng.pick(&all).cloned().unwrap(),
+                name: self.name(),
+            },
+            8 => Operation::Move {
+                id: self.rng.pick(&all).cloned().unwrap(),
+                target: self.rng.pick(&self.containers()).cloned().unwrap(),
+            },
+            _ => Operation::Delete {
+                id: self.rng.pick(&all).cloned().unwrap(),
+            },
+        }
+    }
+
+    fn content(&mut self, size: u32) -> Variant {
+        let bytes: Vec<u8> = (0..size).map(|_| self.rng.next_u64() as u8).collect();
+        Variant::new(
+            VariantMetadata::new("default", "application/octet-stream", size),
+            Box::new(async_std::io::Cursor::new(bytes)),
+        )
+    }
+
+    /// Applies an operation to the manager, and tracks the resulting state if it succeeds.
+    pub async fn apply<T>(
+        &mut self,
+        manager: &mut Manager<T>,
+        operation: &Operation,
+    ) -> Result<(), ResourceStoreError> {
+        match operation {
+            Operation::CreateLeaf { parent, name }
+            | Operation::CreateContainer { parent, name } => {
+                self.next_id += 1;
+                let id: ResourceId = format!("gen-{}", self.next_id).into();
+                let (kind, content) = match operation {
+                    Operation::CreateLeaf { .. } => {
+                        let size = self.rng.below(1024) as u32;
+                        (ResourceKind::Leaf, Some(self.content(size)))
+                    }
+                    _ => (ResourceKind::Container, None),
+                };
+                let mut meta = ResourceMetadata::new(&id, parent, kind, name, vec![], vec![]);
+                manager.create(&mut meta, content).await?;
+                self.entries.push(Entry {
+                    id,
+                    parent: parent.clone(),
+                    kind,
+                });
+            }
+            Operation::UpdateVariant { id, size } => {
+                let content = self.content(*size);
+                manager.update_variant(id, content).await?;
+            }
+            Operation::AddTag { id, tag } => {
+                manager.add_tag(id, tag).await?;
+            }
+            Operation::RemoveTag { id, tag } => {
+                manager.remove_tag(id, tag).await?;
+            }
+            Operation::Rename { id, name } => {
+                manager.rename_resource(id, name).await?;
+            }
+            Operation::Move { id, target } => {
+                manager.move_resource(id, target).await?;
+                if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == *id) {
+                    entry.parent = target.clone();
+                }
+            }
+            Operation::Delete { id } => {
+                manager.delete(id).await?;
+                // Forget about this resource and all its descendants.
+                let mut removed: HashSet<ResourceId> = HashSet::new();
+                removed.insert(id.clone());
+                loop {
+                    let before = removed.len();
+                    for entry in &self.entries {
+                        if removed.contains(&entry.parent) {
+                            removed.insert(entry.id.clone());
+                        }
+                    }
+                    if removed.len() == before {
+                        break;
+                    }
+                }
+                self.entries.retain(|entry| !removed.contains(&entry.id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+macro_rules! violation {
+    ($($arg:tt)*) => {
+        Err(ResourceStoreError::Custom(format!($($arg)*)))
+    };
+}
+
+/// Checks that the database and the store agree on every resource, and that
+/// the content of each container lists its children.
+pub async fn check_invariants<T>(manager: &mut Manager<T>) -> Result<(), ResourceStoreError> {
+    let ids = manager.all_ids().await?;
+    let mut children: HashMap<ResourceId, Vec<ResourceId>> = HashMap::new();
+
+    for id in &ids {
+        let meta = manager.get_metadata(id).await?;
+        let stored = match manager.store().get_metadata(id).await {
+            Ok(stored) => stored,
+            Err(err) => return violation!("No store metadata for {id}: {err}"),
+        };
+
+        let mut tags = meta.tags().clone();
+        tags.sort();
+        let mut stored_tags = stored.tags().clone();
+        stored_tags.sort();
+        if meta.parent() != stored.parent()
+            || meta.kind() != stored.kind()
+            || meta.name() != stored.name()
+            || tags != stored_tags
+            || meta.variants() != stored.variants()
+        {
+            return violation!("Metadata mismatch for {id}: {meta:?} != {stored:?}");
+        }
+
+        if meta.kind() == ResourceKind::Leaf {
+            for variant in meta.variants() {
+                if manager
+                    .store()
+                    .get_variant(id, &variant.name())
+                    .await
+                    .is_err()
+                {
+                    return violation!("Missing variant {} for {id}", variant.name());
+                }
+            }
+        }
+
+        if !id.is_root() {
+            let parent = meta.parent();
+            if !manager.is_container(&parent).await? {
+                return violation!("Parent {parent} of {id} is not a container");
+            }
+            children.entry(parent).or_default().push(id.clone());
+        }
+    }
+
+    for id in &ids {
+        if !manager.is_container(id).await? {
+            continue;
+        }
+
+        let mut expected = children.remove(id).unwrap_or_default();
+        let mut listed = match manager.store().get_variant(id, "default").await {
+            Ok(mut reader) => {
+                let mut buffer = vec![];
+                reader.read_to_end(&mut buffer).await?;
+                Vec::<ResourceId>::read_from_buffer(&buffer)?
+            }
+            Err(_) => vec![],
+        };
+        expected.sort_by_key(|id| id.to_string());
+        listed.sort_by_key(|id| id.to_string());
+        if expected != listed {
+            return violation!("Children of {id} are {expected:?} but content lists {listed:?}");
+        }
+    }
+
+    Ok(())
+}
+
+fn variant_with(name: &str, content: &[u8]) -> Variant {
+    Variant::new(
+        VariantMetadata::new(name, "application/octet-stream", content.len() as _),
+        Box::new(async_std::io::Cursor::new(content.to_vec())),
+    )
+}
+
+// Stores can record the hash of the content they write, which callers don't provide.
+fn without_hashes(mut metadata: ResourceMetadata) -> ResourceMetadata {
+    let variants = metadata
+        .variants()
+        .iter()
+        .cloned()
+        .map(|mut variant| {
+            variant.set_hash(None);
+            variant
+        })
+        .collect();
+    metadata.set_variants(variants);
+    metadata
+}
+
+async fn read_variant<S: ResourceStore>(
+    store: &S,
+    id: &ResourceId,
+    variant: &str,
+) -> Result<Vec<u8>, ResourceStoreError> {
+    let mut reader = store.get_variant(id, variant).await?;
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer).await?;
+    Ok(buffer)
+}
+
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            return violation!($($arg)*);
+        }
+    };
+}
+
+/// Runs the conformance suite for a `ResourceStore` implementation. The factory
+/// is called for each check and must return a new, empty store.
+pub async fn check_store_conformance<F, Fut, S>(mut factory: F) -> Result<(), ResourceStoreError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+    S: ResourceStore,
+{
+    let leaf_meta = ResourceMetadata::new(
+        &"leaf".to_owned().into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "leaf",
+        vec!["tag".into()],
+        vec![VariantMetadata::new(
+            "default",
+            "application/octet-stream",
+            5,
+        )],
+    );
+    let leaf = || leaf_meta.clone();
+    let leaf_id: ResourceId = "leaf".to_owned().into();
+
+    // Unknown resources.
+    let store = factory().await;
+    ensure!(
+        store.get_metadata(&leaf_id).await == Err(ResourceStoreError::NoSuchResource),
+        "get_metadata must fail with NoSuchResource for unknown resources"
+    );
+    ensure!(
+        store.get_variant(&leaf_id, "default").await.err()
+            == Some(ResourceStoreError::NoSuchResource),
+        "get_variant must fail with NoSuchResource for unknown resources"
+    );
+    ensure!(
+        store.get_full(&leaf_id, "default").await.err() == Some(ResourceStoreError::NoSuchResource),
+        "get_full must fail with NoSuchResource for unknown resources"
+    );
+
+    // Creation.
+    let store = factory().await;
+    store
+        .create(&leaf(), Some(variant_with("default", b"hello")))
+        .await?;
+    ensure!(
+        without_hashes(store.get_metadata(&leaf_id).await?) == leaf(),
+        "get_metadata must return the created metadata"
+    );
+    ensure!(
+        read_variant(&store, &leaf_id, "default").await? == b"hello",
+        "get_variant must return the created content"
+    );
+    let (meta, mut reader) = store.get_full(&leaf_id, "default").await?;
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer).await?;
+    ensure!(
+        without_hashes(meta) == leaf() && buffer == b"hello",
+        "get_full must return both the metadata and the content"
+    );
+    ensure!(
+        store
+            .create(&leaf(), Some(variant_with("default", b"hello")))
+            .await
+            == Err(ResourceStoreError::ResourceAlreadyExists),
+        "create must fail with ResourceAlreadyExists for existing resources"
+    );
+
+    let store = factory().await;
+    ensure!(
+        store
+            .create(&leaf(), Some(variant_with("unknown", b"hello")))
+            .await
+            == Err(ResourceStoreError::InvalidVariant("unknown".into())),
+        "create must fail with InvalidVariant for variants not in the metadata"
+    );
+
+    let store = factory().await;
+    let container = ResourceMetadata::new(
+        &ROOT_ID,
+        &ROOT_ID,
+        ResourceKind::Container,
+        "/",
+        vec![],
+        vec![],
+    );
+    store.create(&container, None).await?;
+    ensure!(
+        store.get_metadata(&ROOT_ID).await? == container,
+        "containers can be created without content"
+    );
+    store
+        .update_default_variant_from_slice(&ROOT_ID, b"children")
+        .await?;
+    ensure!(
+        read_variant(&store, &ROOT_ID, "default").await? == b"children",
+        "update_default_variant_from_slice must replace the default variant"
+    );
+
+    // Updates.
+    let store = factory().await;
+    store
+        .create(&leaf(), Some(variant_with("default", b"hello")))
+        .await?;
+    let mut meta = leaf();
+    meta.set_name("renamed");
+    store.update(&meta, None).await?;
+    ensure!(
+        without_hashes(store.get_metadata(&leaf_id).await?) == meta,
+        "update must replace the metadata"
+    );
+    ensure!(
+        read_variant(&store, &leaf_id, "default").await? == b"hello",
+        "update without content must preserve the variants"
+    );
+    store
+        .update(&meta, Some(variant_with("default", b"world")))
+        .await?;
+    ensure!(
+        read_variant(&store, &leaf_id, "default").await? == b"world",
+        "update must replace the variant content"
+    );
+    ensure!(
+        store
+            .update(&meta, Some(variant_with("unknown", b"world")))
+            .await
+            == Err(ResourceStoreError::InvalidVariant("unknown".into())),
+        "update must fail with InvalidVariant for variants not in the metadata"
+    );
+
+    meta.add_or_update_variant(VariantMetadata::new(
+        "default",
+        "application/octet-stream",
+        10,
+    ));
+    store.append_to_variant(&meta, "default", b"wide").await?;
+    store.append_to_variant(&meta, "default", b"!").await?;
+    ensure!(
+        read_variant(&store, &leaf_id, "default").await? == b"worldwide!",
+        "append_to_variant must add content at the end of the variant"
+    );
+
+    // Deletions.
+    let store = factory().await;
+    let mut meta = leaf();
+    meta.add_or_update_variant(VariantMetadata::new(
+        "thumbnail",
+        "application/octet-stream",
+        3,
+    ));
+    store
+        .create(&meta, Some(variant_with("default", b"hello")))
+        .await?;
+    store
+        .update(&meta, Some(variant_with("thumbnail", b"img")))
+        .await?;
+    store.delete_variant(&leaf_id, "thumbnail").await?;
+    ensure!(
+        store.get_variant(&leaf_id, "thumbnail").await.is_err(),
+        "delete_variant must remove the variant"
+    );
+    ensure!(
+        read_variant(&store, &leaf_id, "default").await? == b"hello",
+        "delete_variant must preserve the other variants"
+    );
+    store.delete(&leaf_id).await?;
+    ensure!(
+        store.get_metadata(&leaf_id).await == Err(ResourceStoreError::NoSuchResource),
+        "delete must remove the metadata"
+    );
+    ensure!(
+        store.get_variant(&leaf_id, "default").await.is_err(),
+        "delete must remove the variants"
+    );
+    ensure!(
+        store
+            .create(&leaf(), Some(variant_with("default", b"again")))
+            .await
+            .is_ok(),
+        "a deleted resource can be created again"
+    );
+
+    Ok(())
+}
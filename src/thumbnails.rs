@@ -0,0 +1,267 @@
+/// Thumbnails of images and videos, and collages of thumbnails for containers.
+///
+/// `ThumbnailTransformer` generates the `thumbnail` variant of images, and optionally
+/// an `animated-preview` variant for animated images. Videos are handled too when the
+/// embedder provides a `FrameExtractor`, since this crate has no video decoder.
+/// `Manager::container_collage()` composes the thumbnails of the first children
+/// of a container, for folder tiles.
+use crate::common::{ResourceMetadata, ResourceStoreError, Variant, VariantMetadata};
+use crate::transformers::Transformer;
+use async_trait::async_trait;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, Delay, DynamicImage, Frame, GenericImage, Rgb, RgbImage};
+use std::time::Duration;
+
+/// The images that can be decoded.
+pub const IMAGE_MIME_TYPES: [&str; 4] = ["image/gif", "image/jpeg", "image/png", "image/webp"];
+
+/// The number of thumbnails in a collage, as a grid of `COLLAGE_GRID` x `COLLAGE_GRID` tiles.
+pub(crate) const COLLAGE_SIZE: usize = COLLAGE_GRID * COLLAGE_GRID;
+const COLLAGE_GRID: usize = 2;
+
+// The background of collage tiles without a thumbnail.
+const COLLAGE_BACKGROUND: Rgb<u8> = Rgb([224, 224, 224]);
+
+const JPEG_QUALITY: u8 = 80;
+
+/// The name of the variant holding the animated preview of animated images and videos.
+pub const ANIMATED_PREVIEW: &str = "animated-preview";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThumbnailerConfig {
+    pub size: u32, // The maximum width and height of thumbnails, and the size of collages.
+    pub animated_preview: bool, // Whether to generate an animated preview of animated images and videos.
+    pub preview_frames: usize,  // The maximum number of frames of animated previews.
+    pub preview_duration: Duration, // The maximum duration of animated previews.
+}
+
+impl Default for ThumbnailerConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            animated_preview: false,
+            preview_frames: 20,
+            preview_duration: Duration::from_secs(3),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+pub trait FrameExtractor {
+    /// Returns up to `count` frames evenly spread over the first `duration` of a video,
+    /// each encoded in one of the `IMAGE_MIME_TYPES`.
+    async fn frames(
+        &self,
+        mime_type: &str,
+        content: &[u8],
+        count: usize,
+        duration: Duration,
+    ) -> Result<Vec<Vec<u8>>, ResourceStoreError>;
+}
+
+pub(crate) fn image_error(err: image::ImageError) -> ResourceStoreError {
+    ResourceStoreError::Custom(format!("ImageError: {err}"))
+}
+
+pub(crate) fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, ResourceStoreError> {
+    let mut content = vec![];
+    JpegEncoder::new_with_quality(&mut content, JPEG_QUALITY)
+        .encode_image(&image.to_rgb8())
+        .map_err(image_error)?;
+
+    Ok(content)
+}
+
+/// Encodes an image as a JPEG variant.
+pub(crate) fn jpeg_variant(
+    name: &str,
+    image: &DynamicImage,
+) -> Result<Variant, ResourceStoreError> {
+    let content = encode_jpeg(image)?;
+    Ok(Variant::new(
+        VariantMetadata::new(name, "image/jpeg", content.len() as _),
+        Box::new(async_std::io::Cursor::new(content)),
+    ))
+}
+
+/// Encodes frames as an animated GIF variant, looping forever.
+fn gif_variant(name: &str, frames: Vec<Frame>) -> Result<Variant, ResourceStoreError> {
+    let mut content = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut content);
+        encoder.set_repeat(Repeat::Infinite).map_err(image_error)?;
+        encoder.encode_frames(frames).map_err(image_error)?;
+    }
+
+    Ok(Variant::new(
+        VariantMetadata::new(name, "image/gif", content.len() as _),
+        Box::new(async_std::io::Cursor::new(content)),
+    ))
+}
+
+/// Generates the `thumbnail` variant of images and videos, keeping their aspect ratio,
+/// and their `animated-preview` variant if enabled in the config.
+#[derive(Default)]
+pub struct ThumbnailTransformer {
+    config: ThumbnailerConfig,
+    frame_extractor: Option<Box<dyn FrameExtractor + Send + Sync>>,
+}
+
+impl ThumbnailTransformer {
+    pub fn new(config: ThumbnailerConfig) -> Self {
+        Self {
+            config,
+            frame_extractor: None,
+        }
+    }
+
+    /// Sets the extractor of video frames. Videos are skipped without one.
+    pub fn set_frame_extractor(&mut self, extractor: Box<dyn FrameExtractor + Send + Sync>) {
+        self.frame_extractor = Some(extractor);
+    }
+
+    // Returns the downscaled frames of the beginning of an animated image, or nothing
+    // for still images.
+    fn animation_frames(
+        &self,
+        mime_type: &str,
+        content: &[u8],
+    ) -> Result<Vec<Frame>, ResourceStoreError> {
+        let reader = std::io::Cursor::new(content);
+        let frames = match mime_type {
+            "image/gif" => GifDecoder::new(reader).map_err(image_error)?.into_frames(),
+            "image/webp" => {
+                let decoder = WebPDecoder::new(reader).map_err(image_error)?;
+                if !decoder.has_animation() {
+                    return Ok(vec![]);
+                }
+                decoder.into_frames()
+            }
+            _ => return Ok(vec![]),
+        };
+
+        let size = self.config.size;
+        let mut elapsed = Duration::ZERO;
+        let mut result = vec![];
+        for frame in frames.take(self.config.preview_frames) {
+            if elapsed >= self.config.preview_duration {
+                break;
+            }
+            let frame = frame.map_err(image_error)?;
+            let delay = frame.delay();
+            elapsed += Duration::from(delay);
+            let buffer = DynamicImage::ImageRgba8(frame.into_buffer())
+                .thumbnail(size, size)
+                .to_rgba8();
+            result.push(Frame::from_parts(buffer, 0, 0, delay));
+        }
+        if result.len() < 2 {
+            return Ok(vec![]);
+        }
+
+        Ok(result)
+    }
+
+    async fn transform_video(
+        &self,
+        mime_type: &str,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        let extractor = match &self.frame_extractor {
+            Some(extractor) => extractor,
+            None => return Ok(vec![]),
+        };
+        let count = if self.config.animated_preview {
+            self.config.preview_frames
+        } else {
+            1
+        };
+        let images = extractor
+            .frames(mime_type, content, count, self.config.preview_duration)
+            .await?
+            .iter()
+            .map(|frame| image::load_from_memory(frame).map_err(image_error))
+            .collect::<Result<Vec<_>, _>>()?;
+        let size = self.config.size;
+        let mut variants = match images.first() {
+            Some(first) => vec![jpeg_variant("thumbnail", &first.thumbnail(size, size))?],
+            None => return Ok(vec![]),
+        };
+
+        if self.config.animated_preview && images.len() > 1 {
+            let delay =
+                Delay::from_saturating_duration(self.config.preview_duration / images.len() as u32);
+            let frames = images
+                .iter()
+                .map(|image| Frame::from_parts(image.thumbnail(size, size).to_rgba8(), 0, 0, delay))
+                .collect();
+            variants.push(gif_variant(ANIMATED_PREVIEW, frames)?);
+        }
+
+        Ok(variants)
+    }
+}
+
+#[async_trait(?Send)]
+impl Transformer for ThumbnailTransformer {
+    fn name(&self) -> &str {
+        "thumbnail"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    async fn transform(
+        &self,
+        _meta: &ResourceMetadata,
+        source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        let mime_type = source.mime_type();
+        if mime_type.starts_with("video/") {
+            return self.transform_video(&mime_type, content).await;
+        }
+        if !IMAGE_MIME_TYPES.contains(&mime_type.as_str()) {
+            return Ok(vec![]);
+        }
+
+        let image = image::load_from_memory(content).map_err(image_error)?;
+        let size = self.config.size;
+        let mut variants = vec![jpeg_variant("thumbnail", &image.thumbnail(size, size))?];
+        if self.config.animated_preview {
+            let frames = self.animation_frames(&mime_type, content)?;
+            if !frames.is_empty() {
+                variants.push(gif_variant(ANIMATED_PREVIEW, frames)?);
+            }
+        }
+
+        Ok(variants)
+    }
+}
+
+/// Composes a square JPEG collage of `size` pixels from up to `COLLAGE_SIZE` thumbnails,
+/// cropped to fill their tile. Thumbnails that can't be decoded leave their tile empty.
+pub fn compose_collage(thumbnails: &[Vec<u8>], size: u32) -> Result<Vec<u8>, ResourceStoreError> {
+    let tile = size / COLLAGE_GRID as u32;
+    let mut collage = RgbImage::from_pixel(size, size, COLLAGE_BACKGROUND);
+    for (index, thumbnail) in thumbnails.iter().take(COLLAGE_SIZE).enumerate() {
+        let image = match image::load_from_memory(thumbnail) {
+            Ok(image) => image.resize_to_fill(tile, tile, FilterType::Triangle),
+            Err(err) => {
+                log::debug!("Failed to decode thumbnail: {}", err);
+                continue;
+            }
+        };
+        let x = (index % COLLAGE_GRID) as u32 * tile;
+        let y = (index / COLLAGE_GRID) as u32 * tile;
+        collage
+            .copy_from(&image.to_rgb8(), x, y)
+            .map_err(image_error)?;
+    }
+
+    encode_jpeg(&DynamicImage::ImageRgb8(collage))
+}
@@ -0,0 +1,33 @@
+/// Transformers generating derived variants, like thumbnails.
+///
+/// Transformers are registered with `Manager::add_transformer()` and run on a resource
+/// with `Manager::run_transformers()`, which stores the variants they generate and
+/// records their derivation. Variants that became stale, because their source changed
+/// or their transformer version was bumped, are generated again by
+/// `Manager::update_stale_variants()`. Failures are recorded so that they can be listed
+/// with `Manager::failed_transformations()` and retried.
+use crate::common::{ResourceMetadata, ResourceStoreError, Variant, VariantMetadata};
+use async_trait::async_trait;
+
+#[async_trait(?Send)]
+pub trait Transformer {
+    /// The name recorded with the generated variants, see `DerivedVariant::transformer`.
+    fn name(&self) -> &str;
+
+    /// The version recorded with the generated variants, to bump when their content changes.
+    fn version(&self) -> u32;
+
+    /// The name of the variant that the output is generated from.
+    fn source(&self) -> &str {
+        "default"
+    }
+
+    /// Returns the variants generated from the content of the source variant, or
+    /// nothing if this transformer doesn't handle this kind of content.
+    async fn transform(
+        &self,
+        meta: &ResourceMetadata,
+        source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError>;
+}
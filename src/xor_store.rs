@@ -27,7 +27,8 @@ impl XorNameProvider {
     /// Transform a string in a xored + base64 version, safely usable as a file name.
     pub fn transform(&self, what: &str) -> String {
         let xored: Vec<u8> = what.chars().map(|c| (c as u8) ^ self.xor).collect();
-        self.base64.encode(xored)
+        // The bcrypt alphabet includes '/' which is not valid in file names.
+        self.base64.encode(xored).replace('/', "_")
     }
 }
 
@@ -39,6 +40,10 @@ impl ResourceNameProvider for XorNameProvider {
     fn variant_name(&self, id: &ResourceId, variant: &str) -> String {
         self.transform(&format!("{id}.{variant}.content"))
     }
+
+    fn blob_name(&self, hash: &str) -> String {
+        self.transform(&format!("{hash}.blob"))
+    }
 }
 
 pub struct XorTransformer {
@@ -0,0 +1,385 @@
+use async_std::fs;
+use costaeres::common::*;
+use costaeres::config::Config;
+use costaeres::faulty_store::*;
+use costaeres::file_store::FileStore;
+use costaeres::manager::*;
+use std::time::{Duration, Instant};
+
+fn leaf_meta(id: i32) -> ResourceMetadata {
+    ResourceMetadata::new(
+        &id.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        &format!("leaf #{id}"),
+        vec![],
+        vec![VariantMetadata::new(
+            "default",
+            "application/octet-stream",
+            42,
+        )],
+    )
+}
+
+async fn default_content() -> Variant {
+    let file = fs::File::open("./create_db.sh").await.unwrap();
+    Variant::new(
+        VariantMetadata::new("default", "application/octet-stream", 42),
+        Box::new(file),
+    )
+}
+
+#[async_std::test]
+async fn faulty_store() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/101";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let plan = FaultPlan::default();
+    let store = FaultyStore::new(Box::new(store), plan.clone());
+
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    // A failed store write rolls back the database changes.
+    plan.fail_nth_write(plan.writes() + 1);
+    let mut leaf = leaf_meta(1);
+    assert!(manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .is_err());
+    assert_eq!(
+        manager.get_metadata(&1.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+    assert_eq!(manager.get_container(&ROOT_ID).await.unwrap().1.len(), 0);
+
+    // Retrying succeeds once the fault is consumed.
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+    assert_eq!(manager.get_container(&ROOT_ID).await.unwrap().1.len(), 1);
+
+    // A failed deletion keeps the resource around.
+    plan.fail_delete(&1.into());
+    assert!(manager.delete(&1.into()).await.is_err());
+    assert_eq!(manager.get_metadata(&1.into()).await.unwrap(), leaf);
+    assert_eq!(manager.get_container(&ROOT_ID).await.unwrap().1.len(), 1);
+
+    plan.clear();
+    manager.delete(&1.into()).await.unwrap();
+    assert_eq!(manager.get_container(&ROOT_ID).await.unwrap().1.len(), 0);
+
+    // Slow reads.
+    let mut leaf = leaf_meta(2);
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+    plan.slow_reads(Duration::from_millis(50));
+    let start = Instant::now();
+    manager.get_leaf(&2.into(), "default").await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[async_std::test]
+async fn faulty_store_tags() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/106";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let plan = FaultPlan::default();
+    let store = FaultyStore::new(Box::new(store), plan.clone());
+
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    let mut leaf = leaf_meta(1);
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+
+    // Tags are searchable as soon as they are added.
+    manager.add_tag(&1.into(), "holidays").await.unwrap();
+    assert_eq!(manager.by_tag("holidays").await.unwrap(), vec![1.into()]);
+    assert_eq!(manager.by_text("holidays", None).await.unwrap().len(), 1);
+
+    // Failing to update the store leaves the tags unchanged.
+    plan.fail_nth_write(plan.writes() + 1);
+    assert!(manager.add_tag(&1.into(), "beach").await.is_err());
+    assert!(manager.by_tag("beach").await.unwrap().is_empty());
+    assert!(!manager
+        .get_metadata(&1.into())
+        .await
+        .unwrap()
+        .has_tag("beach"));
+
+    plan.fail_nth_write(plan.writes() + 1);
+    assert!(manager.remove_tag(&1.into(), "holidays").await.is_err());
+    assert_eq!(manager.by_tag("holidays").await.unwrap(), vec![1.into()]);
+    assert!(manager
+        .get_metadata(&1.into())
+        .await
+        .unwrap()
+        .has_tag("holidays"));
+
+    manager.remove_tag(&1.into(), "holidays").await.unwrap();
+    assert!(manager.by_tag("holidays").await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn diagnostics() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/108";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let plan = FaultPlan::default();
+    let store = FaultyStore::new(Box::new(store), plan.clone());
+
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: path.into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    let mut leaf = leaf_meta(1);
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+    manager.add_tag(&1.into(), "holidays").await.unwrap();
+
+    let diagnostics = manager.diagnostics().await.unwrap();
+    // The version of the last migration.
+    assert!(diagnostics.schema_version >= 16);
+    let schema_version = diagnostics.schema_version;
+    assert_eq!(diagnostics.config.db_path, format!("{path}/test_db.sqlite"));
+    assert_eq!(diagnostics.config.metadata_cache_capacity, 100);
+    assert!(diagnostics.problems.is_empty());
+    assert_eq!(diagnostics.resources, 2);
+    assert_eq!(diagnostics.variants, 2);
+    assert_eq!(diagnostics.tags, 1);
+    assert_eq!(diagnostics.bytes, 42);
+    assert_eq!(diagnostics.pending_indexing, 0);
+    assert!(diagnostics.last_errors.is_empty());
+
+    // Failed store operations are reported. The first write updates the parent.
+    plan.fail_nth_write(plan.writes() + 2);
+    let mut other = leaf_meta(2);
+    assert!(manager
+        .create(&mut other, Some(default_content().await))
+        .await
+        .is_err());
+    plan.fail_delete(&1.into());
+    assert!(manager.delete(&1.into()).await.is_err());
+
+    let diagnostics = manager.diagnostics().await.unwrap();
+    assert_eq!(diagnostics.resources, 2);
+    let errors: Vec<(&str, &str)> = diagnostics
+        .last_errors
+        .iter()
+        .map(|error| (error.operation.as_str(), error.id.as_str()))
+        .collect();
+    assert_eq!(errors, vec![("create", "id-2"), ("delete", "id-1")]);
+    assert_eq!(
+        diagnostics.last_errors[1].message,
+        "Custom Error: Injected fault for deletion of id-1"
+    );
+
+    // The bundle written to a file is redacted by default.
+    let bundle = format!("{path}/diagnostics.json");
+    let redacted = manager.write_diagnostics(&bundle, true).await.unwrap();
+    assert_eq!(redacted.last_errors[1].message, "Custom Error");
+    let content = fs::read_to_string(&bundle).await.unwrap();
+    assert!(!content.contains(path));
+    assert!(content.contains(&format!("\"schema_version\": {schema_version}")));
+
+    manager.write_diagnostics(&bundle, false).await.unwrap();
+    let content = fs::read_to_string(&bundle).await.unwrap();
+    assert!(content.contains("Injected fault for deletion of id-1"));
+}
+
+#[async_std::test]
+async fn faulty_store_create_with_variants() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/109";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let plan = FaultPlan::default();
+    let store = FaultyStore::new(Box::new(store), plan.clone());
+
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let thumbnail = || async {
+        let file = fs::File::open("./create_db.sh").await.unwrap();
+        Variant::new(
+            VariantMetadata::new("thumbnail", "application/octet-stream", 42),
+            Box::new(file),
+        )
+    };
+
+    // Failing to store the second variant rolls back the whole creation. The first write
+    // updates the parent and the second one creates the resource with its first variant.
+    plan.fail_nth_write(plan.writes() + 3);
+    let mut leaf = leaf_meta(1);
+    assert!(manager
+        .create_with_variants(&mut leaf, vec![default_content().await, thumbnail().await])
+        .await
+        .is_err());
+    assert_eq!(
+        manager.get_metadata(&1.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+    assert_eq!(manager.get_container(&ROOT_ID).await.unwrap().1.len(), 0);
+    assert!(fs::metadata(format!("{path}/id-1.meta")).await.is_err());
+
+    let mut leaf = leaf_meta(1);
+    manager
+        .create_with_variants(&mut leaf, vec![default_content().await, thumbnail().await])
+        .await
+        .unwrap();
+    assert_eq!(
+        manager
+            .get_metadata(&1.into())
+            .await
+            .unwrap()
+            .variants()
+            .len(),
+        2
+    );
+}
+
+#[async_std::test]
+async fn faulty_store_sidecars() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/127";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let plan = FaultPlan::default();
+    let store = FaultyStore::new(Box::new(store), plan.clone());
+
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    let mut leaf = leaf_meta(1);
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+    let quota = manager.quota_usage().await.unwrap();
+    let options = SidecarOptions {
+        exclude_from_quota: true,
+        exclude_from_sync: true,
+    };
+
+    // Failing to update the store doesn't record the sidecar options.
+    plan.fail_nth_write(plan.writes() + 1);
+    assert!(manager
+        .set_sidecar(&1.into(), "app", default_content().await, options)
+        .await
+        .is_err());
+    assert!(manager.sidecars(&1.into(), "app").await.unwrap().is_empty());
+
+    // Retrying records the sidecar along with its options.
+    manager
+        .set_sidecar(&1.into(), "app", default_content().await, options)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.sidecars(&1.into(), "app").await.unwrap(),
+        vec!["default".to_owned()]
+    );
+    assert_eq!(manager.quota_usage().await.unwrap(), quota);
+}
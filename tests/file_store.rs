@@ -46,14 +46,16 @@ async fn file_store() {
         vec![default_variant()],
     );
 
-    let res = store
+    // The store returns the hash of the content it computed.
+    let hash = store
         .create(&meta, Some(default_content().await))
         .await
-        .ok();
-    assert_eq!(res, Some(()));
+        .unwrap();
+    assert!(hash.is_some());
 
     // Now check that we can get it.
     let res = store.get_full(&ROOT_ID, "default").await.ok().unwrap().0;
+    assert_eq!(res.variants()[0].hash(), hash);
     assert!(res.id().is_root());
     assert_eq!(*res.tags(), vec!["one".to_owned(), "two".to_owned()]);
     assert_eq!(&res.name(), "object 0");
@@ -99,17 +101,21 @@ async fn file_store() {
     // Get the new variant.
     store.get_variant(&ROOT_ID, "new-variant").await.unwrap();
 
-    // Get the native path for the new variant.
+    // Get the native path for the new variant, which gets its own copy of its blob.
+    let stored = store.get_metadata(&ROOT_ID).await.unwrap();
+    let hash = stored
+        .variants()
+        .iter()
+        .find(|variant| variant.name() == "new-variant")
+        .and_then(|variant| variant.hash())
+        .unwrap();
+    let refcount = store.blob_refcount(&hash).await.unwrap();
     let path = store
         .get_native_path(&ROOT_ID, "new-variant")
         .await
         .unwrap();
-    assert_eq!(
-        path,
-        async_std::path::PathBuf::from(
-            "./test-content/0/9e48b88d-4ab5-496b-ad7f-9ecc685128db.variant.new-variant"
-        )
-    );
+    assert_eq!(path, store.variant_path(&ROOT_ID, "new-variant"));
+    assert_eq!(store.blob_refcount(&hash).await.unwrap(), refcount - 1);
 
     assert!(store
         .get_native_path(&ROOT_ID, "unknown-variant")
@@ -132,3 +138,233 @@ async fn file_store() {
     let res = store.get_full(&ROOT_ID, "default").await.err();
     assert_eq!(res, Some(ResourceStoreError::NoSuchResource));
 }
+
+#[async_std::test]
+async fn file_store_conformance() {
+    use costaeres::testing::check_store_conformance;
+
+    let _ = fs::remove_dir_all("./test-content/102").await;
+
+    let mut count = 0;
+    check_store_conformance(|| {
+        count += 1;
+        let path = format!("./test-content/102/{count}");
+        async move {
+            let _ = fs::create_dir_all(&path).await;
+            FileStore::new(
+                &path,
+                Box::new(DefaultResourceNameProvider),
+                Box::new(IdentityTransformer),
+            )
+            .await
+            .unwrap()
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[async_std::test]
+async fn hmac_names_conformance() {
+    use costaeres::hmac_names::HmacNameProvider;
+    use costaeres::testing::check_store_conformance;
+
+    let _ = fs::remove_dir_all("./test-content/104").await;
+
+    let mut count = 0;
+    check_store_conformance(|| {
+        count += 1;
+        let path = format!("./test-content/104/{count}");
+        async move {
+            let _ = fs::create_dir_all(&path).await;
+            FileStore::new(
+                &path,
+                Box::new(HmacNameProvider::new(b"secret")),
+                Box::new(IdentityTransformer),
+            )
+            .await
+            .unwrap()
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[async_std::test]
+async fn file_store_dedup() {
+    use async_std::io::ReadExt;
+
+    let _ = fs::remove_dir_all("./test-content/107").await;
+    let _ = fs::create_dir_all("./test-content/107").await;
+
+    let store = FileStore::new(
+        "./test-content/107",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+
+    let content = |text: &str| {
+        Variant::new(
+            VariantMetadata::new("default", "text/plain", text.len() as _),
+            Box::new(async_std::io::Cursor::new(text.as_bytes().to_vec())),
+        )
+    };
+    let hash_of = |text: &str| {
+        let mut variant = content(text);
+        async move { variant.update_hash().await.unwrap() }
+    };
+    let leaf = |id: i32, content: &Variant| {
+        ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            &format!("leaf {id}"),
+            vec![],
+            vec![content.metadata.clone()],
+        )
+    };
+    let read = |id: i32| {
+        let store = &store;
+        async move {
+            let mut reader = store.get_variant(&id.into(), "default").await.unwrap();
+            let mut text = String::new();
+            reader.read_to_string(&mut text).await.unwrap();
+            text
+        }
+    };
+
+    // Both resources share the same blob.
+    for id in [1, 2] {
+        let variant = content("same");
+        store
+            .create(&leaf(id, &variant), Some(variant))
+            .await
+            .unwrap();
+    }
+    let same = hash_of("same").await;
+    assert!(store.blob_path(&same).exists().await);
+    assert!(!store.variant_path(&1.into(), "default").exists().await);
+    assert_eq!(store.blob_refcount(&same).await.unwrap(), 2);
+
+    // Rewriting the same content doesn't add a reference.
+    let variant = content("same");
+    store
+        .update(&leaf(2, &variant), Some(variant))
+        .await
+        .unwrap();
+    assert_eq!(store.blob_refcount(&same).await.unwrap(), 2);
+
+    // Updating one of them doesn't change the other one.
+    let variant = content("other");
+    store
+        .update(&leaf(1, &variant), Some(variant))
+        .await
+        .unwrap();
+    assert_eq!(read(1).await, "other");
+    assert_eq!(read(2).await, "same");
+    assert_eq!(store.blob_refcount(&same).await.unwrap(), 1);
+
+    // Neither does appending.
+    let variant = content("other");
+    store
+        .create(&leaf(3, &variant), Some(variant))
+        .await
+        .unwrap();
+    let mut meta = leaf(3, &content("other!"));
+    meta.add_or_update_variant(VariantMetadata::new("default", "text/plain", 6));
+    store
+        .append_to_variant(&meta, "default", b"!")
+        .await
+        .unwrap();
+    assert_eq!(read(3).await, "other!");
+    assert_eq!(read(1).await, "other");
+    assert_eq!(
+        store.blob_refcount(&hash_of("other").await).await.unwrap(),
+        1
+    );
+
+    // Nor does changing the file at the native path.
+    let variant = content("other");
+    store
+        .create(&leaf(4, &variant), Some(variant))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.blob_refcount(&hash_of("other").await).await.unwrap(),
+        2
+    );
+    let path = store.get_native_path(&4.into(), "default").await.unwrap();
+    assert_eq!(path, store.variant_path(&4.into(), "default"));
+    fs::write(&path, b"changed").await.unwrap();
+    assert_eq!(read(4).await, "changed");
+    assert_eq!(read(1).await, "other");
+    assert_eq!(
+        store.blob_refcount(&hash_of("other").await).await.unwrap(),
+        1
+    );
+    // The variant doesn't hold a reference to the blob anymore.
+    store.delete(&4.into()).await.unwrap();
+    assert_eq!(
+        store.blob_refcount(&hash_of("other").await).await.unwrap(),
+        1
+    );
+
+    // Reference counts are persisted.
+    let store = FileStore::new(
+        "./test-content/107",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    assert_eq!(store.blob_refcount(&same).await.unwrap(), 1);
+
+    // Blobs are removed with their last user.
+    store.delete(&2.into()).await.unwrap();
+    assert!(!store.blob_path(&same).exists().await);
+    assert_eq!(store.blob_refcount(&same).await.unwrap(), 0);
+    store.delete(&1.into()).await.unwrap();
+    assert!(!store.blob_path(&hash_of("other").await).exists().await);
+}
+
+#[async_std::test]
+async fn file_store_legacy_metadata() {
+    let _ = fs::remove_dir_all("./test-content/121").await;
+    let _ = fs::create_dir_all("./test-content/121").await;
+
+    let store = FileStore::new(
+        "./test-content/121",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+
+    // Metadata written before the format was versioned, without revisions nor hashes.
+    let id: ResourceId = "legacy-leaf".to_owned().into();
+    fs::copy("./test-fixtures/legacy.meta", store.metadata_path(&id))
+        .await
+        .unwrap();
+
+    let mut meta = store.get_metadata(&id).await.unwrap();
+    assert_eq!(meta.parent(), "legacy-parent".to_owned().into());
+    assert_eq!(meta.kind(), ResourceKind::Leaf);
+    assert_eq!(&meta.name(), "Legacy notes");
+    assert_eq!(*meta.tags(), vec!["work".to_owned(), "notes".to_owned()]);
+    assert_eq!(meta.variants().len(), 1);
+    assert_eq!(meta.variants()[0].name(), "default");
+    assert_eq!(meta.variants()[0].mime_type(), "text/plain");
+    assert_eq!(meta.variants()[0].size(), 42);
+    assert_eq!(meta.variants()[0].hash(), None);
+    assert_eq!(meta.created().timestamp(), 1_600_000_000);
+    assert_eq!(meta.scorer().all_time_visits(), 1);
+    assert_eq!(meta.scorer().entries().len(), 1);
+    assert_eq!(meta.rev(), 0);
+
+    // It is written back in the current format.
+    meta.set_rev(3);
+    store.update(&meta, None).await.unwrap();
+    assert_eq!(store.get_metadata(&id).await.unwrap(), meta);
+}
@@ -1,8 +1,9 @@
 use async_std::fs;
-use chrono::Utc;
+use chrono::{Datelike, Utc};
 use costaeres::common::*;
 use costaeres::config::Config;
 use costaeres::file_store::FileStore;
+use costaeres::fts::CollapsedMatch;
 use costaeres::indexer::*;
 use costaeres::manager::*;
 use costaeres::scorer::{VisitEntry, VisitPriority};
@@ -49,6 +50,11 @@ async fn prepare_test(index: u32) -> (Config, FileStore) {
         db_path: format!("{}/test_db.sqlite", &path),
         data_dir: ".".into(),
         metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
     };
 
     (config, store)
@@ -160,7 +166,7 @@ async fn rehydrate_single() {
     let (config, store) = prepare_test(2).await;
 
     // Adding an object to the file store
-    let meta = ResourceMetadata::new(
+    let mut meta = ResourceMetadata::new(
         &1.into(),
         &ROOT_ID,
         ResourceKind::Leaf,
@@ -177,6 +183,11 @@ async fn rehydrate_single() {
 
     assert!(!manager.has_object(&meta.id()).await.unwrap());
 
+    // The store recorded the content hash.
+    let mut variant = default_variant();
+    variant.set_hash(Some(&default_content().await.update_hash().await.unwrap()));
+    meta.add_or_update_variant(variant);
+
     let res = manager.get_metadata(&meta.id()).await.unwrap();
     assert_eq!(res, meta);
 
@@ -840,6 +851,8 @@ struct Tracker {
     child_created: usize,
     child_modified: usize,
     child_deleted: usize,
+    variant_changed: usize,
+    visited: usize,
 }
 
 impl Tracker {
@@ -874,6 +887,9 @@ impl ModificationObserver for Observer {
             ResourceModification::ChildCreated(_) => tracker.child_created += 1,
             ResourceModification::ChildModified(_) => tracker.child_modified += 1,
             ResourceModification::ChildDeleted(_) => tracker.child_deleted += 1,
+            ResourceModification::VariantChanged(_) => tracker.variant_changed += 1,
+            ResourceModification::Visited(_) => tracker.visited += 1,
+            ResourceModification::Rehydrated(_) | ResourceModification::Repaired(_) => {}
         }
     }
 
@@ -973,6 +989,30 @@ async fn observers() {
         assert_eq!(tracker.deleted, 1);
     });
 
+    // Visit the leaf and change its variants.
+    manager
+        .visit(&leaf_meta.id(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+    manager
+        .update_variant(&leaf_meta.id(), default_content().await)
+        .await
+        .unwrap();
+    manager
+        .delete_variant(&leaf_meta.id(), "default")
+        .await
+        .unwrap();
+    manager.with_observer(observer_id, &mut |observer: &mut Box<
+        dyn ModificationObserver<Inner = Rc<Tracker>>,
+    >| {
+        let tracker = observer.get_inner();
+        assert_eq!(tracker.visited, 1);
+        assert_eq!(tracker.variant_changed, 2);
+        assert_eq!(tracker.created, 4);
+        assert_eq!(tracker.modified, 7);
+        assert_eq!(tracker.deleted, 1);
+    });
+
     // Remove the sub container.
     // println!("Removing sub container");
     manager.delete(&container_meta.id()).await.unwrap();
@@ -981,7 +1021,7 @@ async fn observers() {
     >| {
         let tracker = observer.get_inner();
         assert_eq!(tracker.created, 4);
-        assert_eq!(tracker.modified, 5);
+        assert_eq!(tracker.modified, 8);
         assert_eq!(tracker.deleted, 3);
     });
 
@@ -1216,8 +1256,7 @@ async fn copy_resource() {
     let mut variant = manager.get_leaf(&new_meta.id(), "default").await.unwrap();
     let mut content = String::new();
     let _ = variant.1.read_to_string(&mut content).await.unwrap();
-    assert_eq!(content.len(), 93);
-    assert_eq!(&content[0..32], "#!/bin/bash\n\nset -x -e\n\nrm build");
+    assert_eq!(content, fs::read_to_string("./create_db.sh").await.unwrap());
 
     manager.with_observer(observer_id, &mut |observer: &mut Box<
         dyn ModificationObserver<Inner = Rc<Tracker>>,
@@ -1485,6 +1524,19 @@ async fn rename_resource() {
         let meta = manager.get_metadata(&leaf_meta.id()).await.unwrap();
         assert_eq!(meta.name(), "new-wallpaper");
 
+        // The name index is updated.
+        let results = manager.by_text("default", None).await.unwrap();
+        assert!(results.is_empty());
+        let results = manager.by_text("new-wall", None).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, leaf_meta.id());
+
+        // Renaming to the current name is a no-op.
+        manager
+            .rename_resource(&leaf_meta.id(), "new-wallpaper")
+            .await
+            .unwrap();
+
         // Add a second leaf.
         let mut second_leaf_meta = ResourceMetadata::new(
             &2.into(),
@@ -1497,10 +1549,12 @@ async fn rename_resource() {
         manager.create(&mut second_leaf_meta, None).await.unwrap();
 
         // Check that we can't rename to an existing name.
-        assert!(manager
-            .rename_resource(&leaf_meta.id(), "second-wallpaper")
-            .await
-            .is_err());
+        assert_eq!(
+            manager
+                .rename_resource(&leaf_meta.id(), "second-wallpaper")
+                .await,
+            Err(ResourceStoreError::ResourceAlreadyExists)
+        );
     }
 
     // Check that the new name persisted.
@@ -1521,3 +1575,6498 @@ async fn rename_resource() {
         assert_eq!(meta.name(), "new-wallpaper");
     }
 }
+
+#[async_std::test]
+async fn capabilities() {
+    use chrono::Duration;
+    use costaeres::capability::Operations;
+
+    let (config, store) = prepare_test(30).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // Capabilities can only be issued for known resources.
+    assert_eq!(
+        manager
+            .issue_capability(&42.into(), Operations::READ, None)
+            .await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+
+    // A read-only capability on the #10 sub-container.
+    let read_only = manager
+        .issue_capability(&10.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.get_capability(&read_only.token).await.unwrap(),
+        read_only
+    );
+
+    manager.set_capability_context(Some(&read_only.token));
+
+    // Reading inside the scope is allowed.
+    manager.get_leaf(&25.into(), "default").await.unwrap();
+    let (_, children) = manager.get_container(&10.into()).await.unwrap();
+    assert_eq!(children.len(), 10);
+
+    // Reading outside of the scope is not.
+    assert!(matches!(
+        manager.get_leaf(&5.into(), "default").await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+    assert!(matches!(
+        manager.get_root().await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+    assert_eq!(
+        manager.get_metadata(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Searches only return resources in the scope.
+    let within = |ids: Vec<ResourceId>| ids.iter().all(|id| (25..35).any(|i| *id == i.into()));
+    let results = manager.by_text("child", None).await.unwrap();
+    assert_eq!(results.len(), 10);
+    assert!(within(results.into_iter().map(|item| item.id).collect()));
+    assert!(manager.by_name("child #5", None).await.unwrap().is_empty());
+    assert_eq!(manager.by_tag("sub-child").await.unwrap().len(), 10);
+    let results = manager.top_by_frecency(None, 100).await.unwrap();
+    assert_eq!(results.len(), 10);
+    assert!(within(results.into_iter().map(|item| item.id).collect()));
+    assert_eq!(
+        manager
+            .by_text_paged("child", None, Some(&1.into()), Page::new(0, 10))
+            .await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Writing is not allowed.
+    assert_eq!(
+        manager.add_tag(&25.into(), "tag").await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.delete_tag("sub-child").await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager
+            .visit(&25.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.delete(&25.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Checks of resources follow the scope.
+    assert!(manager.verify(&25.into()).await.unwrap().is_empty());
+    assert!(matches!(
+        manager.verify(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+    assert_eq!(
+        manager.evicted_variant(&25.into(), "default").await,
+        Ok(None)
+    );
+    assert_eq!(
+        manager.evicted_variant(&5.into(), "default").await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Store wide and maintenance operations need write access to the root.
+    let denied = |result: Result<(), ResourceStoreError>| {
+        assert_eq!(result, Err(ResourceStoreError::PermissionDenied))
+    };
+    denied(manager.verify_all(None).await.map(|_| ()));
+    denied(manager.check_consistency(false).await.map(|_| ()));
+    denied(manager.rehydrate_all(None).await.map(|_| ()));
+    denied(manager.maintain().await.map(|_| ()));
+    denied(manager.remove_stale_text().await.map(|_| ()));
+    denied(manager.reindex_ancestor_names().await);
+    denied(
+        manager
+            .system_container(SystemContainer::Downloads)
+            .await
+            .map(|_| ()),
+    );
+    denied(
+        manager
+            .declare_system_containers(&[SystemContainer::Camera])
+            .await
+            .map(|_| ()),
+    );
+    denied(manager.diagnostics().await.map(|_| ()));
+    denied(manager.set_derived_variants_budget(None).await.map(|_| ()));
+
+    // Delegating more than we have is not allowed.
+    assert_eq!(
+        manager
+            .issue_capability(&10.into(), Operations::WRITE, None)
+            .await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Expired capabilities are rejected.
+    manager.set_capability_context(None);
+    let expired = manager
+        .issue_capability(
+            &ROOT_ID,
+            Operations::ALL,
+            Some(Utc::now() - Duration::minutes(1)),
+        )
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&expired.token));
+    assert!(matches!(
+        manager.get_leaf(&25.into(), "default").await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+
+    // Revoked capabilities are rejected.
+    manager.set_capability_context(None);
+    manager.revoke_capability(&read_only.token).await.unwrap();
+    manager.set_capability_context(Some(&read_only.token));
+    assert!(matches!(
+        manager.get_leaf(&25.into(), "default").await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+
+    // Without any context, everything is allowed again.
+    manager.set_capability_context(None);
+    manager.delete(&25.into()).await.unwrap();
+}
+
+#[async_std::test]
+async fn rate_limited_capability() {
+    use costaeres::capability::Operations;
+    use costaeres::rate_limiter::{RateLimiter, RateLimits};
+
+    let (config, store) = prepare_test(31).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    manager.set_rate_limiter(Some(RateLimiter::new(RateLimits {
+        operations_per_second: Some(2),
+        bytes_per_second: None,
+    })));
+
+    let capability = manager
+        .issue_capability(&ROOT_ID, Operations::ALL, None)
+        .await
+        .unwrap();
+
+    // Calls without a token context are not rate limited.
+    for _ in 0..5 {
+        manager.get_leaf(&25.into(), "default").await.unwrap();
+    }
+
+    manager.set_capability_context(Some(&capability.token));
+    for _ in 0..2 {
+        manager.get_leaf(&25.into(), "default").await.unwrap();
+    }
+    assert!(matches!(
+        manager.get_leaf(&25.into(), "default").await,
+        Err(ResourceStoreError::RateLimited)
+    ));
+}
+
+#[async_std::test]
+async fn read_filter() {
+    use costaeres::capability::Operations;
+    use costaeres::read_filter::TagMimeFilter;
+
+    let (config, store) = prepare_test(32).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let trusted = manager
+        .issue_capability(&ROOT_ID, Operations::ALL, None)
+        .await
+        .unwrap();
+
+    // Hide resources tagged as `sub-child`, except for the trusted caller.
+    let mut filter = TagMimeFilter::new(&["sub-child"], &[]);
+    filter.trust(&trusted.token);
+    manager.set_read_filter(Some(Box::new(filter)));
+
+    let (_, children) = manager.get_container(&10.into()).await.unwrap();
+    assert_eq!(children.len(), 0);
+    assert!(matches!(
+        manager.get_leaf(&25.into(), "default").await,
+        Err(ResourceStoreError::NoSuchResource)
+    ));
+
+    assert_eq!(
+        manager.get_metadata(&25.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+
+    // Hidden resources are not part of search results.
+    assert!(manager.by_tag("sub-child").await.unwrap().is_empty());
+    assert!(manager.by_name("child #25", None).await.unwrap().is_empty());
+    let results = manager
+        .by_text_paged("child", None, None, Page::new(0, 100))
+        .await
+        .unwrap();
+    assert_eq!(results.total as usize, results.items.len());
+    assert!(results
+        .items
+        .iter()
+        .all(|item| !(25..35).any(|i| item.id == i.into())));
+    let results = manager.top_by_frecency(None, 100).await.unwrap();
+    assert!(!results.iter().any(|item| item.id == 25.into()));
+
+    // Pages account for all the hidden resources, not only the ones they would hold.
+    let mut ids = vec![];
+    for offset in (0..20).step_by(3) {
+        let page = manager
+            .top_by_frecency_paged(None, Page::new(offset, 3))
+            .await
+            .unwrap();
+        assert_eq!(page.total, 12);
+        ids.extend(page.items.into_iter().map(|item| item.id));
+    }
+    assert_eq!(ids.len(), 12);
+    assert!(!ids.iter().any(|id| (25..35).any(|i| *id == i.into())));
+
+    // Hidden resources can't be found by name or path either.
+    assert_eq!(
+        manager.child_by_name(&10.into(), "child #25").await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+    assert_eq!(
+        manager.get_full_path(&25.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+    assert_eq!(
+        manager.get_metadata_batch(&[5.into(), 25.into()]).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+    assert!(manager
+        .get_native_path(&25.into(), "default")
+        .await
+        .is_none());
+
+    // Other resources are still available.
+    manager.get_leaf(&5.into(), "default").await.unwrap();
+    manager.child_by_name(&1.into(), "child #5").await.unwrap();
+    assert_eq!(manager.get_full_path(&5.into()).await.unwrap().len(), 3);
+    assert!(manager
+        .get_native_path(&5.into(), "default")
+        .await
+        .is_some());
+
+    manager.set_capability_context(Some(&trusted.token));
+    let (_, children) = manager.get_container(&10.into()).await.unwrap();
+    assert_eq!(children.len(), 10);
+    manager.get_leaf(&25.into(), "default").await.unwrap();
+    assert_eq!(manager.by_tag("sub-child").await.unwrap().len(), 10);
+
+    // Filter by mime type.
+    manager.set_capability_context(None);
+    manager.set_read_filter(Some(Box::new(TagMimeFilter::new(
+        &[],
+        &["application/octet-stream"],
+    ))));
+    assert!(matches!(
+        manager.get_leaf(&5.into(), "default").await,
+        Err(ResourceStoreError::NoSuchResource)
+    ));
+
+    manager.set_read_filter(None);
+    manager.get_leaf(&5.into(), "default").await.unwrap();
+}
+
+#[async_std::test]
+async fn derived_variants() {
+    let (config, store) = prepare_test(33).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    let thumbnail = DerivedVariant {
+        id: 5.into(),
+        variant: "thumbnail".into(),
+        source: "default".into(),
+        transformer: "thumbnailer".into(),
+        version: 1,
+    };
+    manager.record_derived_variant(&thumbnail).await.unwrap();
+    assert_eq!(manager.stale_variants().await.unwrap().len(), 0);
+
+    // Updating the source marks the derived variant as stale.
+    manager
+        .update_variant(&5.into(), default_content().await)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.stale_variants().await.unwrap(),
+        vec![thumbnail.clone()]
+    );
+
+    // Regenerating it clears the stale state.
+    manager.record_derived_variant(&thumbnail).await.unwrap();
+    assert_eq!(manager.stale_variants().await.unwrap().len(), 0);
+
+    // Bumping the transformer version marks it as stale.
+    manager
+        .set_transformer_version("thumbnailer", 1)
+        .await
+        .unwrap();
+    assert_eq!(manager.stale_variants().await.unwrap().len(), 0);
+    manager
+        .set_transformer_version("thumbnailer", 2)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.stale_variants().await.unwrap(),
+        vec![thumbnail.clone()]
+    );
+
+    // Deleting the derived variant forgets about it.
+    manager
+        .delete_variant(&5.into(), "thumbnail")
+        .await
+        .unwrap();
+    assert_eq!(manager.stale_variants().await.unwrap().len(), 0);
+}
+
+// Generates an upper case `loud` variant of text resources.
+struct UpperCase;
+
+#[async_trait::async_trait(?Send)]
+impl costaeres::transformers::Transformer for UpperCase {
+    fn name(&self) -> &str {
+        "upper-case"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    async fn transform(
+        &self,
+        _meta: &ResourceMetadata,
+        source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        if source.mime_type() != "text/plain" {
+            return Ok(vec![]);
+        }
+        let content = content.to_ascii_uppercase();
+        Ok(vec![Variant::new(
+            VariantMetadata::new("loud", "text/plain", content.len() as _),
+            Box::new(async_std::io::Cursor::new(content)),
+        )])
+    }
+}
+
+#[async_std::test]
+async fn transformers() {
+    use async_std::io::ReadExt;
+
+    let (config, store) = prepare_test(129).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.add_transformer(Box::new(UpperCase));
+
+    let text = |content: &str| {
+        Variant::new(
+            VariantMetadata::new("default", "text/plain", content.len() as _),
+            Box::new(async_std::io::Cursor::new(content.as_bytes().to_vec())),
+        )
+    };
+    manager
+        .update_variant(&5.into(), text("Hello World"))
+        .await
+        .unwrap();
+
+    // Transformers skip the content they don't handle.
+    assert!(manager
+        .run_transformers(&6.into())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        manager.run_transformers(&5.into()).await.unwrap(),
+        vec!["loud"]
+    );
+    let (_, mut reader) = manager.get_leaf(&5.into(), "loud").await.unwrap();
+    let mut content = String::new();
+    reader.read_to_string(&mut content).await.unwrap();
+    assert_eq!(content, "HELLO WORLD");
+    assert!(manager.stale_variants().await.unwrap().is_empty());
+
+    // Stale variants are generated again.
+    manager
+        .update_variant(&5.into(), text("Goodbye"))
+        .await
+        .unwrap();
+    assert_eq!(manager.stale_variants().await.unwrap().len(), 1);
+    assert_eq!(manager.update_stale_variants().await.unwrap(), 1);
+    assert!(manager.stale_variants().await.unwrap().is_empty());
+    let (_, mut reader) = manager.get_leaf(&5.into(), "loud").await.unwrap();
+    let mut content = String::new();
+    reader.read_to_string(&mut content).await.unwrap();
+    assert_eq!(content, "GOODBYE");
+
+    // As well as the ones of older transformer versions.
+    manager
+        .set_transformer_version("upper-case", 2)
+        .await
+        .unwrap();
+    assert_eq!(manager.update_stale_variants().await.unwrap(), 1);
+}
+
+// Fails on content that mentions oops.
+struct Picky;
+
+#[async_trait::async_trait(?Send)]
+impl costaeres::transformers::Transformer for Picky {
+    fn name(&self) -> &str {
+        "picky"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    async fn transform(
+        &self,
+        _meta: &ResourceMetadata,
+        _source: &VariantMetadata,
+        content: &[u8],
+    ) -> Result<Vec<Variant>, ResourceStoreError> {
+        if content.windows(4).any(|word| word == b"oops") {
+            return Err(ResourceStoreError::Custom("Oops".into()));
+        }
+        Ok(vec![Variant::new(
+            VariantMetadata::new("picked", "text/plain", content.len() as _),
+            Box::new(async_std::io::Cursor::new(content.to_vec())),
+        )])
+    }
+}
+
+#[async_std::test]
+async fn transformer_failures() {
+    let (config, store) = prepare_test(135).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.add_transformer(Box::new(Picky));
+    manager.add_transformer(Box::new(UpperCase));
+
+    let text = |content: &str| {
+        Variant::new(
+            VariantMetadata::new("default", "text/plain", content.len() as _),
+            Box::new(async_std::io::Cursor::new(content.as_bytes().to_vec())),
+        )
+    };
+    manager
+        .update_variant(&5.into(), text("oops"))
+        .await
+        .unwrap();
+
+    // The other transformers still run.
+    assert_eq!(
+        manager.run_transformers(&5.into()).await.unwrap(),
+        vec!["loud"]
+    );
+    let failures = manager.failed_transformations().await.unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].id, 5.into());
+    assert_eq!(failures[0].transformer, "picky");
+    assert!(failures[0].error.contains("Oops"));
+    assert_eq!(failures[0].attempts, 1);
+
+    assert_eq!(
+        manager
+            .retry_transformations(&TransformationFilter::default())
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        manager.failed_transformations().await.unwrap()[0].attempts,
+        2
+    );
+
+    // Filtered out failures are not retried.
+    manager
+        .update_variant(&5.into(), text("fixed"))
+        .await
+        .unwrap();
+    let filter = TransformationFilter {
+        transformer: Some("upper-case".into()),
+        ..Default::default()
+    };
+    assert_eq!(manager.retry_transformations(&filter).await.unwrap(), 0);
+    let filter = TransformationFilter {
+        id: Some(6.into()),
+        ..Default::default()
+    };
+    assert_eq!(manager.retry_transformations(&filter).await.unwrap(), 0);
+    assert_eq!(manager.failed_transformations().await.unwrap().len(), 1);
+
+    // Succeeding clears the failure.
+    let filter = TransformationFilter {
+        id: Some(5.into()),
+        transformer: Some("picky".into()),
+    };
+    assert_eq!(manager.retry_transformations(&filter).await.unwrap(), 1);
+    assert!(manager.failed_transformations().await.unwrap().is_empty());
+
+    // As well as deleting the resource.
+    manager
+        .update_variant(&6.into(), text("oops again"))
+        .await
+        .unwrap();
+    manager.run_transformers(&6.into()).await.unwrap();
+    assert_eq!(manager.failed_transformations().await.unwrap().len(), 1);
+    manager.delete(&6.into()).await.unwrap();
+    assert!(manager.failed_transformations().await.unwrap().is_empty());
+}
+
+#[cfg(feature = "transformers")]
+fn png_image(width: u32, height: u32, color: [u8; 3]) -> Vec<u8> {
+    let image = image::RgbImage::from_pixel(width, height, image::Rgb(color));
+    let mut content = std::io::Cursor::new(vec![]);
+    image
+        .write_to(&mut content, image::ImageFormat::Png)
+        .unwrap();
+    content.into_inner()
+}
+
+#[cfg(feature = "transformers")]
+fn gif_animation(colors: &[[u8; 3]]) -> Vec<u8> {
+    let mut content = vec![];
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut content);
+        for color in colors {
+            let buffer = image::RgbaImage::from_pixel(
+                64,
+                64,
+                image::Rgba([color[0], color[1], color[2], 255]),
+            );
+            encoder
+                .encode_frame(image::Frame::from_parts(
+                    buffer,
+                    0,
+                    0,
+                    image::Delay::from_numer_denom_ms(100, 1),
+                ))
+                .unwrap();
+        }
+    }
+    content
+}
+
+#[cfg(feature = "transformers")]
+// Adds a leaf to the #40 container, returning the variants generated by the transformers.
+async fn add_media(
+    manager: &mut Manager<()>,
+    id: i32,
+    name: &str,
+    mime_type: &str,
+    content: Vec<u8>,
+) -> Vec<String> {
+    let variant = VariantMetadata::new("default", mime_type, content.len() as _);
+    let mut photo = ResourceMetadata::new(
+        &id.into(),
+        &40.into(),
+        ResourceKind::Leaf,
+        name,
+        vec![],
+        vec![variant.clone()],
+    );
+    manager
+        .create(
+            &mut photo,
+            Some(Variant::new(
+                variant,
+                Box::new(async_std::io::Cursor::new(content)),
+            )),
+        )
+        .await
+        .unwrap();
+    manager.run_transformers(&id.into()).await.unwrap()
+}
+
+#[cfg(feature = "transformers")]
+#[async_std::test]
+async fn thumbnails_and_collages() {
+    use async_std::io::ReadExt;
+    use costaeres::thumbnails::ThumbnailTransformer;
+    use image::GenericImageView;
+
+    let (config, store) = prepare_test(130).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.add_transformer(Box::new(ThumbnailTransformer::default()));
+
+    let mut album = ResourceMetadata::new(
+        &40.into(),
+        &ROOT_ID,
+        ResourceKind::Container,
+        "album",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut album, None).await.unwrap();
+    assert_eq!(
+        add_media(
+            &mut manager,
+            41,
+            "b",
+            "image/png",
+            png_image(800, 600, [255, 0, 0])
+        )
+        .await,
+        vec!["thumbnail"]
+    );
+    assert_eq!(
+        add_media(
+            &mut manager,
+            42,
+            "c",
+            "image/png",
+            png_image(800, 600, [0, 255, 0])
+        )
+        .await,
+        vec!["thumbnail"]
+    );
+
+    let (meta, mut reader) = manager.get_leaf(&41.into(), "thumbnail").await.unwrap();
+    assert_eq!(
+        meta.mime_type_for_variant("thumbnail").as_deref(),
+        Some("image/jpeg")
+    );
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    let thumbnail = image::load_from_memory(&content).unwrap();
+    assert_eq!(thumbnail.dimensions(), (256, 192));
+
+    // Containers without thumbnails have no collage.
+    assert!(manager
+        .container_collage(&10.into(), 128)
+        .await
+        .unwrap()
+        .is_none());
+
+    let collage = |variant: Option<Variant>| async {
+        let mut variant = variant.unwrap();
+        assert_eq!(variant.metadata.name(), "collage");
+        let mut content = vec![];
+        variant.reader.read_to_end(&mut content).await.unwrap();
+        image::load_from_memory(&content).unwrap().to_rgb8()
+    };
+    let before = manager.get_metadata(&40.into()).await.unwrap();
+    let image = collage(manager.container_collage(&40.into(), 128).await.unwrap()).await;
+    assert_eq!(image.dimensions(), (128, 128));
+    assert!(image.get_pixel(32, 32)[0] > 200);
+    assert!(image.get_pixel(96, 32)[1] > 200);
+    assert_eq!(image.get_pixel(32, 96)[2], image.get_pixel(32, 96)[0]);
+
+    // The collage is kept, without modifying the container.
+    let after = manager.get_metadata(&40.into()).await.unwrap();
+    assert!(after.has_variant("collage"));
+    assert_eq!(after.modified(), before.modified());
+    assert_eq!(after.rev(), before.rev());
+    let image = collage(manager.container_collage(&40.into(), 128).await.unwrap()).await;
+    assert!(image.get_pixel(32, 32)[0] > 200);
+
+    // And composed again when the children change.
+    assert_eq!(
+        add_media(
+            &mut manager,
+            43,
+            "a",
+            "image/png",
+            png_image(800, 600, [0, 0, 255])
+        )
+        .await,
+        vec!["thumbnail"]
+    );
+    let image = collage(manager.container_collage(&40.into(), 128).await.unwrap()).await;
+    assert!(image.get_pixel(32, 32)[2] > 200);
+    assert!(image.get_pixel(96, 32)[0] > 200);
+    assert!(image.get_pixel(32, 96)[1] > 200);
+}
+
+#[cfg(feature = "transformers")]
+#[async_std::test]
+async fn animated_previews() {
+    use async_std::io::ReadExt;
+    use async_trait::async_trait;
+    use costaeres::thumbnails::*;
+    use costaeres::transformers::Transformer;
+    use image::AnimationDecoder;
+    use std::time::Duration;
+
+    // Returns still frames of the requested colors instead of decoding videos.
+    struct ColorFrames;
+
+    #[async_trait(?Send)]
+    impl FrameExtractor for ColorFrames {
+        async fn frames(
+            &self,
+            _mime_type: &str,
+            content: &[u8],
+            count: usize,
+            _duration: Duration,
+        ) -> Result<Vec<Vec<u8>>, ResourceStoreError> {
+            Ok(content
+                .chunks(3)
+                .take(count)
+                .map(|color| png_image(640, 480, [color[0], color[1], color[2]]))
+                .collect())
+        }
+    }
+
+    let (config, store) = prepare_test(131).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let mut album = ResourceMetadata::new(
+        &40.into(),
+        &ROOT_ID,
+        ResourceKind::Container,
+        "album",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut album, None).await.unwrap();
+
+    // Without an extractor, videos are skipped, and animated previews are only
+    // generated when enabled.
+    let video = vec![255, 0, 0, 0, 255, 0, 0, 0, 255];
+    let animation = gif_animation(&[[255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+    let transform = |mime_type: &str, content: Vec<u8>| {
+        let source = VariantMetadata::new("default", mime_type, content.len() as _);
+        let album = album.clone();
+        async move {
+            ThumbnailTransformer::default()
+                .transform(&album, &source, &content)
+                .await
+                .unwrap()
+                .iter()
+                .map(|variant| variant.metadata.name())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert!(transform("video/mp4", video.clone()).await.is_empty());
+    assert_eq!(
+        transform("image/gif", animation.clone()).await,
+        vec!["thumbnail"]
+    );
+
+    let mut thumbnailer = ThumbnailTransformer::new(ThumbnailerConfig {
+        animated_preview: true,
+        preview_frames: 2,
+        ..Default::default()
+    });
+    thumbnailer.set_frame_extractor(Box::new(ColorFrames));
+    manager.add_transformer(Box::new(thumbnailer));
+
+    let frame_count = |content: Vec<u8>| {
+        image::codecs::gif::GifDecoder::new(std::io::Cursor::new(content))
+            .unwrap()
+            .into_frames()
+            .count()
+    };
+    assert_eq!(
+        add_media(&mut manager, 42, "b", "image/gif", animation).await,
+        vec!["thumbnail", ANIMATED_PREVIEW]
+    );
+    let (meta, mut reader) = manager
+        .get_leaf(&42.into(), ANIMATED_PREVIEW)
+        .await
+        .unwrap();
+    assert_eq!(
+        meta.mime_type_for_variant(ANIMATED_PREVIEW).as_deref(),
+        Some("image/gif")
+    );
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    assert_eq!(frame_count(content), 2);
+
+    // Still images only get a thumbnail.
+    assert_eq!(
+        add_media(
+            &mut manager,
+            43,
+            "c",
+            "image/png",
+            png_image(640, 480, [0, 0, 0])
+        )
+        .await,
+        vec!["thumbnail"]
+    );
+
+    assert_eq!(
+        add_media(&mut manager, 41, "a", "video/mp4", video).await,
+        vec!["thumbnail", ANIMATED_PREVIEW]
+    );
+    let (_, mut reader) = manager.get_leaf(&41.into(), "thumbnail").await.unwrap();
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    let thumbnail = image::load_from_memory(&content).unwrap().to_rgb8();
+    assert_eq!(thumbnail.dimensions(), (256, 192));
+    assert!(thumbnail.get_pixel(10, 10)[0] > 200);
+    let (_, mut reader) = manager
+        .get_leaf(&41.into(), ANIMATED_PREVIEW)
+        .await
+        .unwrap();
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    assert_eq!(frame_count(content), 2);
+}
+
+#[cfg(feature = "transformers")]
+fn pdf_document(pages: usize) -> Vec<u8> {
+    use lopdf::{dictionary, Document, Object};
+
+    let mut document = Document::with_version("1.5");
+    let pages_id = document.new_object_id();
+    let kids: Vec<Object> = (0..pages)
+        .map(|_| {
+            document
+                .add_object(dictionary! { "Type" => "Page", "Parent" => pages_id })
+                .into()
+        })
+        .collect();
+    document.objects.insert(
+        pages_id,
+        Object::Dictionary(
+            dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => pages as i64 },
+        ),
+    );
+    let catalog_id = document.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+    document.trailer.set("Root", catalog_id);
+    let mut content = vec![];
+    document.save_to(&mut content).unwrap();
+    content
+}
+
+#[cfg(feature = "transformers")]
+#[async_std::test]
+async fn document_previews() {
+    use async_std::io::ReadExt;
+    use async_trait::async_trait;
+    use costaeres::documents::*;
+    use costaeres::thumbnails::ThumbnailerConfig;
+
+    // Renders blank pages.
+    struct BlankPages;
+
+    #[async_trait(?Send)]
+    impl PageRenderer for BlankPages {
+        async fn render_first_page(
+            &self,
+            _mime_type: &str,
+            _content: &[u8],
+        ) -> Result<Vec<u8>, ResourceStoreError> {
+            Ok(png_image(595, 842, [255, 255, 255]))
+        }
+    }
+
+    let (config, store) = prepare_test(132).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let mut library = ResourceMetadata::new(
+        &40.into(),
+        &ROOT_ID,
+        ResourceKind::Container,
+        "library",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut library, None).await.unwrap();
+    let mut transformer = DocumentTransformer::new(ThumbnailerConfig::default());
+    transformer.set_page_renderer(Box::new(BlankPages));
+    manager.add_transformer(Box::new(transformer));
+
+    async fn page_count(manager: &mut Manager<()>, id: i32) -> serde_json::Value {
+        let (_, mut reader) = manager.get_leaf(&id.into(), "document-info").await.unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&content).unwrap();
+        info["pageCount"].clone()
+    }
+
+    assert_eq!(
+        add_media(
+            &mut manager,
+            41,
+            "report.pdf",
+            PDF_MIME_TYPE,
+            pdf_document(3)
+        )
+        .await,
+        vec!["preview", "document-info"]
+    );
+    assert_eq!(page_count(&mut manager, 41).await, 3);
+    let (_, mut reader) = manager.get_leaf(&41.into(), "preview").await.unwrap();
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    let preview = image::load_from_memory(&content).unwrap();
+    assert_eq!((preview.width(), preview.height()), (181, 256));
+
+    // The preview of EPUB documents is their cover.
+    let cover = png_image(600, 800, [0, 0, 255]);
+    let book = plain_zip(
+        &[
+            ("mimetype", EPUB_MIME_TYPE.as_bytes()),
+            (
+                "META-INF/container.xml",
+                br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#,
+            ),
+            (
+                "OEBPS/content.opf",
+                br#"<package><metadata><meta name="cover" content="cover"/></metadata>
+                <manifest><item id="cover" href="images/cover.png" media-type="image/png"/>
+                <item id="one" href="one.xhtml"/><item id="two" href="two.xhtml"/></manifest>
+                <spine><itemref idref="one"/><itemref idref="two"/></spine></package>"#,
+            ),
+            ("OEBPS/images/cover.png", &cover),
+        ],
+        true,
+    );
+    assert_eq!(
+        add_media(&mut manager, 42, "book.epub", EPUB_MIME_TYPE, book).await,
+        vec!["preview", "document-info"]
+    );
+    assert_eq!(page_count(&mut manager, 42).await, 2);
+    let (_, mut reader) = manager.get_leaf(&42.into(), "preview").await.unwrap();
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    let preview = image::load_from_memory(&content).unwrap().to_rgb8();
+    assert_eq!(preview.dimensions(), (192, 256));
+    assert!(preview.get_pixel(10, 10)[2] > 200);
+
+    // Documents that can't be parsed fail to transform.
+    assert!(manager
+        .update_variant(
+            &41.into(),
+            Variant::new(
+                VariantMetadata::new("default", PDF_MIME_TYPE, 7),
+                Box::new(async_std::io::Cursor::new(b"garbage".to_vec())),
+            ),
+        )
+        .await
+        .is_ok());
+    assert!(manager
+        .run_transformers(&41.into())
+        .await
+        .unwrap()
+        .is_empty());
+    let failures = manager.failed_transformations().await.unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].transformer, "document");
+}
+
+#[cfg(feature = "transformers")]
+#[async_std::test]
+async fn contact_photos() {
+    use async_std::io::ReadExt;
+    use base64::Engine;
+    use costaeres::contacts::*;
+
+    let (config, store) = prepare_test(133).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let mut contacts = ResourceMetadata::new(
+        &40.into(),
+        &ROOT_ID,
+        ResourceKind::Container,
+        "contacts",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut contacts, None).await.unwrap();
+    manager.add_transformer(Box::new(ContactPhotoTransformer::default()));
+
+    let photo = png_image(300, 200, [255, 0, 0]);
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&photo);
+    let contact = serde_json::json!({
+        "name": "Jean Dupont",
+        "photo": format!("data:image/png;base64,{encoded}"),
+    });
+    assert_eq!(
+        add_media(
+            &mut manager,
+            41,
+            "Jean Dupont",
+            CONTACT_MIME_TYPE,
+            serde_json::to_vec(&contact).unwrap()
+        )
+        .await,
+        vec!["photo", "avatar"]
+    );
+    let (meta, mut reader) = manager.get_leaf(&41.into(), "photo").await.unwrap();
+    assert_eq!(
+        meta.mime_type_for_variant("photo").as_deref(),
+        Some("image/png")
+    );
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, photo);
+    let (_, mut reader) = manager.get_leaf(&41.into(), "avatar").await.unwrap();
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await.unwrap();
+    let avatar = image::load_from_memory(&content).unwrap();
+    assert_eq!((avatar.width(), avatar.height()), (96, 64));
+
+    // vCards have their photo in a folded line.
+    let (head, tail) = encoded.split_at(40);
+    let vcard = format!(
+        "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Marie Curie\r\nPHOTO;ENCODING=b;TYPE=PNG:{head}\r\n {tail}\r\nEND:VCARD\r\n"
+    );
+    assert_eq!(
+        add_media(
+            &mut manager,
+            42,
+            "Marie Curie",
+            "text/vcard",
+            vcard.into_bytes()
+        )
+        .await,
+        vec!["photo", "avatar"]
+    );
+
+    // Contacts without a photo get nothing.
+    let contact = serde_json::json!({ "name": "Anonymous" });
+    assert!(add_media(
+        &mut manager,
+        43,
+        "Anonymous",
+        CONTACT_MIME_TYPE,
+        serde_json::to_vec(&contact).unwrap()
+    )
+    .await
+    .is_empty());
+}
+
+#[async_std::test]
+async fn move_container() {
+    let (config, store) = prepare_test(34).await;
+
+    {
+        let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+            .await
+            .unwrap();
+
+        create_hierarchy(&mut manager).await;
+        manager.add_tag(&10.into(), "keep-me").await.unwrap();
+        manager
+            .visit(&10.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await
+            .unwrap();
+        let before = manager.get_metadata(&10.into()).await.unwrap();
+
+        // Add a container inside #10.
+        let mut inner = ResourceMetadata::new(
+            &40.into(),
+            &10.into(),
+            ResourceKind::Container,
+            "inner",
+            vec![],
+            vec![],
+        );
+        manager.create(&mut inner, None).await.unwrap();
+
+        // Can't move a container into itself or one of its descendants.
+        assert_eq!(
+            manager.move_resource(&10.into(), &10.into()).await,
+            Err(ResourceStoreError::ResourceCycle)
+        );
+        assert_eq!(
+            manager.move_resource(&10.into(), &40.into()).await,
+            Err(ResourceStoreError::ResourceCycle)
+        );
+        assert_eq!(
+            manager.move_resource(&1.into(), &40.into()).await,
+            Err(ResourceStoreError::ResourceCycle)
+        );
+
+        // Can't move the root, or into a leaf.
+        assert_eq!(
+            manager.move_resource(&ROOT_ID, &1.into()).await,
+            Err(ResourceStoreError::InvalidResourceId)
+        );
+        assert_eq!(
+            manager.move_resource(&10.into(), &5.into()).await,
+            Err(ResourceStoreError::InvalidContainerId)
+        );
+
+        // Can't move over a resource with the same name.
+        let mut twin = ResourceMetadata::new(
+            &41.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            "child #5",
+            vec![],
+            vec![],
+        );
+        manager.create(&mut twin, None).await.unwrap();
+        assert_eq!(
+            manager.move_resource(&5.into(), &ROOT_ID).await,
+            Err(ResourceStoreError::ResourceAlreadyExists)
+        );
+
+        // Move the container to the root.
+        let moved = manager.move_resource(&10.into(), &ROOT_ID).await.unwrap();
+        assert_eq!(moved.id(), before.id());
+        assert_eq!(moved.parent(), *ROOT_ID);
+        assert_eq!(moved.created(), before.created());
+        assert_eq!(moved.scorer().frecency(), before.scorer().frecency());
+        assert!(moved.scorer().frecency() > 0);
+        assert!(moved.has_tag("keep-me"));
+
+        let (_, children) = manager.get_container(&1.into()).await.unwrap();
+        assert_eq!(children.len(), 9);
+        let (_, children) = manager.get_container(&ROOT_ID).await.unwrap();
+        assert_eq!(children.len(), 3);
+        let (_, children) = manager.get_container(&10.into()).await.unwrap();
+        assert_eq!(children.len(), 11);
+        assert!(manager.is_within(&25.into(), &ROOT_ID).await.unwrap());
+        assert!(!manager.is_within(&25.into(), &1.into()).await.unwrap());
+    }
+
+    // Check that the move is persisted.
+    {
+        let path = format!("./test-content/{}", 34);
+        let store = FileStore::new(
+            &path,
+            Box::new(DefaultResourceNameProvider),
+            Box::new(IdentityTransformer),
+        )
+        .await
+        .unwrap();
+        let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+        let meta = manager.get_metadata(&10.into()).await.unwrap();
+        assert_eq!(meta.parent(), *ROOT_ID);
+        assert!(meta.has_tag("keep-me"));
+        let (_, children) = manager.get_container(&ROOT_ID).await.unwrap();
+        assert_eq!(children.len(), 3);
+    }
+}
+
+#[async_std::test]
+async fn indexing_status() {
+    let (config, store) = prepare_test(35).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // 19 leaves with content, all indexed.
+    let progress = manager.indexing_progress().await.unwrap();
+    assert_eq!(
+        progress,
+        IndexingProgress {
+            indexed: 19,
+            total: 19
+        }
+    );
+    assert!(progress.is_complete());
+
+    // Bumping the indexing version makes all leaves pending.
+    manager.set_indexing_version(1);
+    let progress = manager.indexing_progress().await.unwrap();
+    assert_eq!(
+        progress,
+        IndexingProgress {
+            indexed: 0,
+            total: 19
+        }
+    );
+    assert_eq!(progress.percent(), 0);
+    assert_eq!(
+        manager.indexing_status(&5.into()).await.unwrap(),
+        IndexingStatus::Pending
+    );
+    // Containers have nothing to index.
+    assert_eq!(
+        manager.indexing_status(&10.into()).await.unwrap(),
+        IndexingStatus::Indexed
+    );
+
+    // Updating a variant re-indexes the resource.
+    manager
+        .update_variant(&5.into(), default_content().await)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.indexing_status(&5.into()).await.unwrap(),
+        IndexingStatus::Indexed
+    );
+    assert_eq!(manager.indexing_progress().await.unwrap().indexed, 1);
+
+    // Stale derived variants make the resource pending.
+    manager
+        .record_derived_variant(&DerivedVariant {
+            id: 5.into(),
+            variant: "thumbnail".into(),
+            source: "default".into(),
+            transformer: "thumbnailer".into(),
+            version: 1,
+        })
+        .await
+        .unwrap();
+    manager
+        .set_transformer_version("thumbnailer", 2)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.indexing_status(&5.into()).await.unwrap(),
+        IndexingStatus::Pending
+    );
+    assert_eq!(manager.indexing_progress().await.unwrap().indexed, 0);
+
+    // Deleted resources are not accounted for anymore.
+    manager.delete(&10.into()).await.unwrap();
+    let progress = manager.indexing_progress().await.unwrap();
+    assert_eq!(
+        progress,
+        IndexingProgress {
+            indexed: 0,
+            total: 9
+        }
+    );
+
+    assert_eq!(
+        manager.indexing_status(&42.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+}
+
+#[async_std::test]
+async fn top_words() {
+    let (config, store) = prepare_test(36).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, name) in [(1, "Café menu"), (2, "cafe hours"), (3, "menu")] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    let words: Vec<(String, u32)> = manager
+        .top_words(10)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|item| (item.word, item.frequency))
+        .collect();
+    assert_eq!(
+        words,
+        vec![
+            ("cafe".to_owned(), 2),
+            ("menu".to_owned(), 2),
+            ("hours".to_owned(), 1)
+        ]
+    );
+
+    assert_eq!(manager.top_words(1).await.unwrap().len(), 1);
+}
+
+#[async_std::test]
+async fn search_field_boosts() {
+    use costaeres::fts::FieldBoosts;
+
+    let (config, store) = prepare_test(37).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, name, tags) in [
+        (1, "holiday", vec![]),
+        (2, "beach", vec!["holiday".to_owned()]),
+        (3, "holiday notes", vec![]),
+    ] {
+        let mut leaf =
+            ResourceMetadata::new(&id.into(), &ROOT_ID, ResourceKind::Leaf, name, tags, vec![]);
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+    manager
+        .visit(&1.into(), &VisitEntry::now(VisitPriority::High))
+        .await
+        .unwrap();
+
+    // By default tag matches rank above name matches, then frecency is used.
+    let ids: Vec<ResourceId> = manager
+        .by_text("holiday", None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|item| item.id)
+        .collect();
+    assert_eq!(ids, vec![2.into(), 1.into(), 3.into()]);
+
+    manager.set_field_boosts(FieldBoosts {
+        name: 5,
+        tags: 1,
+        content: 1,
+        ancestors: 1,
+    });
+    let ids: Vec<ResourceId> = manager
+        .by_text("holiday", None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|item| item.id)
+        .collect();
+    assert_eq!(ids, vec![1.into(), 3.into(), 2.into()]);
+}
+
+#[async_std::test]
+async fn create_batch() {
+    let (config, store) = prepare_test(38).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    let mut items = vec![(
+        ResourceMetadata::new(
+            &1.into(),
+            &ROOT_ID,
+            ResourceKind::Container,
+            "container",
+            vec![],
+            vec![],
+        ),
+        None,
+    )];
+    for i in 5..105 {
+        items.push((
+            ResourceMetadata::new(
+                &i.into(),
+                &1.into(),
+                ResourceKind::Leaf,
+                &format!("child #{i}"),
+                vec!["batch".into()],
+                vec![],
+            ),
+            Some(default_content().await),
+        ));
+    }
+    // A leaf can't be a parent.
+    items.push((
+        ResourceMetadata::new(
+            &200.into(),
+            &5.into(),
+            ResourceKind::Leaf,
+            "orphan",
+            vec![],
+            vec![],
+        ),
+        None,
+    ));
+    // Names must be unique in a container.
+    items.push((
+        ResourceMetadata::new(
+            &201.into(),
+            &1.into(),
+            ResourceKind::Leaf,
+            "child #5",
+            vec![],
+            vec![],
+        ),
+        None,
+    ));
+
+    let results = manager.create_batch(items).await.unwrap();
+    assert_eq!(results.len(), 103);
+    assert!(results[..101].iter().all(|result| result.is_ok()));
+    assert_eq!(results[101], Err(ResourceStoreError::InvalidContainerId));
+    assert!(results[102].is_err());
+
+    let (_, children) = manager.get_container(&1.into()).await.unwrap();
+    assert_eq!(children.len(), 100);
+    let (_, children) = manager.get_root().await.unwrap();
+    assert_eq!(children.len(), 1);
+
+    assert_eq!(manager.by_tag("batch").await.unwrap().len(), 100);
+    assert!(!manager.has_object(&200.into()).await.unwrap());
+    assert!(!manager.has_object(&201.into()).await.unwrap());
+    let leaf = manager.get_metadata(&42.into()).await.unwrap();
+    assert!(leaf.has_variant("default"));
+}
+
+#[async_std::test]
+async fn search_phrases() {
+    let (config, store) = prepare_test(39).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, name, tags) in [
+        (1, "Project plan", vec![]),
+        (2, "plan for the new project", vec![]),
+        (3, "budget", vec!["project".to_owned()]),
+    ] {
+        let mut leaf =
+            ResourceMetadata::new(&id.into(), &ROOT_ID, ResourceKind::Leaf, name, tags, vec![]);
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    // Words can match anywhere, in any order.
+    let results = manager.by_text("project plan", None).await.unwrap();
+    assert_eq!(results.len(), 2);
+    let results = manager.by_text("plan project", None).await.unwrap();
+    assert_eq!(results.len(), 2);
+
+    // Terms can match different fields.
+    let results = manager.by_text("project budget", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, 3.into());
+
+    // Phrases need to be contiguous.
+    let results = manager.by_text(r#""project plan""#, None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, 1.into());
+    let results = manager.by_text(r#""plan project""#, None).await.unwrap();
+    assert!(results.is_empty());
+}
+
+#[async_std::test]
+async fn search_exclusions() {
+    let (config, store) = prepare_test(40).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let results = manager.by_text("child -#27", None).await.unwrap();
+    assert_eq!(results.len(), 19);
+
+    let results = manager.by_text("child -tag:sub-child", None).await.unwrap();
+    assert_eq!(results.len(), 10);
+
+    // Excluded words also apply to tags.
+    let results = manager.by_text("child -sub", None).await.unwrap();
+    assert_eq!(results.len(), 10);
+
+    let results = manager
+        .by_text(r#"child -"child #2" -"child #3""#, None)
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 10);
+
+    // Only excluding terms doesn't match anything.
+    let results = manager.by_text("-child", None).await.unwrap();
+    assert!(results.is_empty());
+}
+
+#[async_std::test]
+async fn search_pagination() {
+    let (config, store) = prepare_test(41).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let page = manager
+        .by_tag_paged("sub-child", None, Page::new(0, 4))
+        .await
+        .unwrap();
+    assert_eq!(page.items.len(), 4);
+    assert_eq!(page.total, 10);
+    let page = manager
+        .by_tag_paged("sub-child", None, Page::new(8, 4))
+        .await
+        .unwrap();
+    assert_eq!(page.items.len(), 2);
+    assert_eq!(page.total, 10);
+
+    let first = manager
+        .by_text_paged("child", None, None, Page::new(0, 15))
+        .await
+        .unwrap();
+    assert_eq!(first.items.len(), 15);
+    assert_eq!(first.total, 20);
+    let second = manager
+        .by_text_paged("child", None, None, Page::new(15, 15))
+        .await
+        .unwrap();
+    assert_eq!(second.items.len(), 5);
+    assert_eq!(second.total, 20);
+    assert!(second
+        .items
+        .iter()
+        .all(|item| !first.items.iter().any(|other| other.id == item.id)));
+    let past_end = manager
+        .by_text_paged("child", None, None, Page::new(30, 15))
+        .await
+        .unwrap();
+    assert!(past_end.items.is_empty());
+    assert_eq!(past_end.total, 20);
+
+    let page = manager
+        .top_by_frecency_paged(None, Page::new(20, 5))
+        .await
+        .unwrap();
+    assert_eq!(page.items.len(), 2);
+    assert_eq!(page.total, 22);
+
+    manager.add_tag(&12.into(), "twelve").await.unwrap();
+    let page = manager
+        .by_name_paged("child #12", Some("twelve"), Page::new(0, 10))
+        .await
+        .unwrap();
+    assert_eq!(page.items, vec![12.into()]);
+    assert_eq!(page.total, 1);
+}
+
+#[async_std::test]
+async fn children_page() {
+    let (config, store) = prepare_test(42).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let mut names = vec![];
+    let mut cursor: Option<String> = None;
+    let mut pages = 0;
+    loop {
+        let page = manager
+            .children_page(&1.into(), cursor.as_deref(), 4)
+            .await
+            .unwrap();
+        pages += 1;
+        for child in &page.children {
+            assert_eq!(child.parent(), 1.into());
+            assert!(child.has_variant("default"));
+            names.push(child.name());
+        }
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(names.len(), 10);
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted);
+
+    // Tags are loaded too.
+    let page = manager.children_page(&10.into(), None, 100).await.unwrap();
+    assert_eq!(page.children.len(), 10);
+    assert!(page.cursor.is_none());
+    assert!(page.children.iter().all(|child| child.has_tag("sub-child")));
+
+    // Only containers have children.
+    assert_eq!(
+        manager.children_page(&5.into(), None, 10).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+}
+
+#[async_std::test]
+async fn search_grouped() {
+    use costaeres::read_filter::TagMimeFilter;
+
+    let (config, store) = prepare_test(43).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let groups = manager.by_text_grouped("child", None, 3).await.unwrap();
+    assert_eq!(groups.len(), 2);
+    for group in &groups {
+        assert_eq!(group.count, 10);
+        assert_eq!(group.top.len(), 3);
+    }
+    let containers: Vec<ResourceId> = groups.iter().map(|group| group.container.clone()).collect();
+    assert!(containers.contains(&1.into()));
+    assert!(containers.contains(&10.into()));
+
+    // Containers with more matches come first.
+    let groups = manager.by_text_grouped("child -#3", None, 2).await.unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].container, 1.into());
+    assert_eq!(groups[0].count, 10);
+    assert_eq!(groups[1].container, 10.into());
+    assert_eq!(groups[1].count, 5);
+    assert_eq!(groups[1].top.len(), 2);
+
+    let groups = manager.by_text_grouped("#1 -#10", None, 10).await.unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].container, 1.into());
+    assert_eq!(groups[0].count, 4);
+
+    // Counts only include the readable matches, and groups without any are left out.
+    manager.add_tag(&12.into(), "private").await.unwrap();
+    manager.set_read_filter(Some(Box::new(TagMimeFilter::new(
+        &["private", "sub-child"],
+        &[],
+    ))));
+    let groups = manager.by_text_grouped("child", None, 3).await.unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].container, 1.into());
+    assert_eq!(groups[0].count, 9);
+    assert_eq!(groups[0].top.len(), 3);
+    assert!(!groups[0].top.iter().any(|item| item.id == 12.into()));
+}
+
+#[async_std::test]
+async fn search_ancestor_names() {
+    let (config, store) = prepare_test(44).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, parent, kind, name) in [
+        (1, 0, ResourceKind::Container, "documents"),
+        (2, 1, ResourceKind::Container, "taxes"),
+        (3, 2, ResourceKind::Container, "2023"),
+        (4, 3, ResourceKind::Leaf, "scan.pdf"),
+    ] {
+        let parent = if parent == 0 {
+            ROOT_ID.clone()
+        } else {
+            parent.into()
+        };
+        let mut meta = ResourceMetadata::new(&id.into(), &parent, kind, name, vec![], vec![]);
+        manager.create(&mut meta, None).await.unwrap();
+    }
+
+    // Ancestor names are not indexed by default.
+    let results = manager.by_text("taxes 2023", None).await.unwrap();
+    assert!(results.is_empty());
+
+    manager.set_index_ancestor_names(true);
+    manager.reindex_ancestor_names().await.unwrap();
+    let results = manager.by_text("taxes 2023", None).await.unwrap();
+    let ids: Vec<ResourceId> = results.into_iter().map(|item| item.id).collect();
+    // Name matches rank above ancestor matches.
+    assert_eq!(ids, vec![3.into(), 4.into()]);
+
+    // New resources are indexed with their ancestors.
+    let mut receipt = ResourceMetadata::new(
+        &5.into(),
+        &3.into(),
+        ResourceKind::Leaf,
+        "receipt.jpg",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut receipt, None).await.unwrap();
+    let results = manager.by_text("taxes 2023", None).await.unwrap();
+    assert_eq!(results.len(), 3);
+
+    // Renaming a container updates its descendants.
+    manager.rename_resource(&2.into(), "impots").await.unwrap();
+    let results = manager.by_text("taxes", None).await.unwrap();
+    assert!(results.is_empty());
+    let results = manager.by_text("impots", None).await.unwrap();
+    assert_eq!(results.len(), 4);
+
+    // Moving a container updates it and its descendants.
+    manager.move_resource(&3.into(), &1.into()).await.unwrap();
+    let results = manager.by_text("impots", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    let results = manager.by_text("documents 2023", None).await.unwrap();
+    assert_eq!(results.len(), 3);
+}
+
+#[async_std::test]
+async fn suggest_tags() {
+    let (config, store) = prepare_test(45).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, name) in [
+        (1, "holiday beach photos beach"),
+        (2, "holiday plans"),
+        (3, "work plans 2023"),
+    ] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    // Frequent words in the resource that are rare elsewhere come first.
+    let tags = manager.suggest_tags(&1.into(), 10).await.unwrap();
+    assert_eq!(tags, vec!["beach", "photos", "holiday"]);
+    let tags = manager.suggest_tags(&1.into(), 1).await.unwrap();
+    assert_eq!(tags, vec!["beach"]);
+
+    // Existing tags are not suggested.
+    manager.add_tag(&1.into(), "Photos").await.unwrap();
+    let tags = manager.suggest_tags(&1.into(), 10).await.unwrap();
+    assert_eq!(tags, vec!["beach", "holiday"]);
+
+    // Numbers are not suggested.
+    let tags = manager.suggest_tags(&3.into(), 10).await.unwrap();
+    assert_eq!(tags, vec!["work", "plans"]);
+}
+
+#[cfg(feature = "semantic")]
+#[async_std::test]
+async fn semantic_search() {
+    use async_trait::async_trait;
+    use costaeres::capability::Operations;
+    use costaeres::semantic::Embedder;
+
+    // Counts a few words, which is enough to compare texts.
+    struct WordCounter;
+
+    #[async_trait(?Send)]
+    impl Embedder for WordCounter {
+        async fn embed(&self, text: &str) -> Result<Vec<f32>, ResourceStoreError> {
+            Ok(["cat", "dog", "car"]
+                .iter()
+                .map(|word| text.matches(word).count() as f32)
+                .collect())
+        }
+    }
+
+    let (config, store) = prepare_test(46).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    manager.create_root().await.unwrap();
+
+    for (id, name) in [(1, "cat and dog"), (2, "cat cat"), (3, "red car")] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    assert!(manager.update_embedding(&1.into()).await.is_err());
+    manager.set_embedder(Box::new(WordCounter));
+    for id in 1..4 {
+        manager.update_embedding(&id.into()).await.unwrap();
+    }
+
+    let results = manager.semantic_search(&[1.0, 0.0, 0.0], 2).await.unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0], (2.into(), 1.0));
+    assert_eq!(results[1].0, 1.into());
+
+    manager.delete(&2.into()).await.unwrap();
+    let results = manager.semantic_search(&[0.0, 0.0, 1.0], 10).await.unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0], (3.into(), 1.0));
+
+    // Resources hidden by the read filter are not found.
+    manager.add_tag(&3.into(), "private").await.unwrap();
+    manager.set_read_filter(Some(Box::new(costaeres::read_filter::TagMimeFilter::new(
+        &["private"],
+        &[],
+    ))));
+    let results = manager.semantic_search(&[0.0, 0.0, 1.0], 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, 1.into());
+    manager.set_read_filter(None);
+
+    // Capabilities restricted to a container only find its descendants.
+    let mut container = ResourceMetadata::new(
+        &4.into(),
+        &ROOT_ID,
+        ResourceKind::Container,
+        "pets",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut container, None).await.unwrap();
+    let mut leaf = ResourceMetadata::new(
+        &5.into(),
+        &4.into(),
+        ResourceKind::Leaf,
+        "dog",
+        vec![],
+        vec![],
+    );
+    manager.create(&mut leaf, None).await.unwrap();
+    manager.update_embedding(&5.into()).await.unwrap();
+    let capability = manager
+        .issue_capability(&4.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    let results = manager.semantic_search(&[1.0, 1.0, 1.0], 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, 5.into());
+}
+
+#[async_std::test]
+async fn related_resources() {
+    use costaeres::read_filter::TagMimeFilter;
+
+    let (config, store) = prepare_test(47).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // Siblings sharing tags come first, and containers are not included.
+    let related = manager.related(&25.into(), 100).await.unwrap();
+    assert_eq!(related.len(), 18);
+    for id in &related[..9] {
+        let meta = manager.get_metadata(id).await.unwrap();
+        assert_eq!(meta.parent(), 10.into());
+    }
+    assert!(!related.contains(&10.into()));
+    assert!(!related.contains(&25.into()));
+
+    // Sharing more tags wins over being in the same container.
+    manager.add_tag(&25.into(), "beach").await.unwrap();
+    manager.add_tag(&25.into(), "sun").await.unwrap();
+    manager.add_tag(&7.into(), "beach").await.unwrap();
+    manager.add_tag(&7.into(), "sun").await.unwrap();
+    let related = manager.related(&25.into(), 3).await.unwrap();
+    assert_eq!(related.len(), 3);
+    assert_eq!(related[0], 7.into());
+
+    // Resources hidden by the read filter are not suggested.
+    manager.add_tag(&7.into(), "private").await.unwrap();
+    manager.set_read_filter(Some(Box::new(TagMimeFilter::new(&["private"], &[]))));
+    let related = manager.related(&25.into(), 3).await.unwrap();
+    assert_eq!(related.len(), 3);
+    assert!(!related.contains(&7.into()));
+}
+
+#[async_std::test]
+async fn prefetch_candidates() {
+    use costaeres::read_filter::TagMimeFilter;
+
+    let (config, store) = prepare_test(48).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // The closest siblings in name order come first, and containers are left out.
+    let candidates = manager.prefetch_candidates(&7.into(), 4).await.unwrap();
+    assert_eq!(candidates.len(), 4);
+    assert!(candidates[..2].contains(&6.into()));
+    assert!(candidates[..2].contains(&8.into()));
+    assert!(!candidates.contains(&10.into()));
+
+    // At the same distance, the most frecent one wins.
+    manager
+        .visit(&6.into(), &VisitEntry::now(VisitPriority::High))
+        .await
+        .unwrap();
+    let candidates = manager.prefetch_candidates(&7.into(), 1).await.unwrap();
+    assert_eq!(candidates, vec![6.into()]);
+
+    let hinted = manager.prefetch(&25.into(), "default", 2).await.unwrap();
+    assert_eq!(hinted, vec![26.into(), 27.into()]);
+
+    // Resources hidden by the read filter are not candidates.
+    manager.add_tag(&6.into(), "private").await.unwrap();
+    manager.set_read_filter(Some(Box::new(TagMimeFilter::new(&["private"], &[]))));
+    let candidates = manager.prefetch_candidates(&7.into(), 1).await.unwrap();
+    assert_eq!(candidates, vec![8.into()]);
+}
+
+#[async_std::test]
+async fn get_metadata_batch() {
+    let (config, store) = prepare_test(49).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // Results follow the order of the requested ids.
+    let ids: Vec<ResourceId> = vec![34.into(), 5.into(), 10.into(), 25.into(), 5.into()];
+    let metas = manager.get_metadata_batch(&ids).await.unwrap();
+    assert_eq!(metas.len(), 5);
+    for (id, meta) in ids.iter().zip(metas.iter()) {
+        assert_eq!(&meta.id(), id);
+        assert_eq!(meta, &manager.get_metadata(id).await.unwrap());
+    }
+    assert_eq!(metas[3].tags(), &vec!["sub-child".to_owned()]);
+    assert_eq!(metas[3].variants().len(), 1);
+
+    let container = manager.get_container(&10.into()).await.unwrap();
+    assert_eq!(container.1.len(), 10);
+
+    // Unknown resources are still reported as such.
+    assert_eq!(
+        manager.get_metadata_batch(&[5.into(), 1000.into()]).await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+}
+
+#[async_std::test]
+async fn append_to_variant() {
+    use async_std::io::ReadExt;
+
+    let (config, store) = prepare_test(50).await;
+
+    {
+        let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+            .await
+            .unwrap();
+
+        create_hierarchy(&mut manager).await;
+
+        let mut log = ResourceMetadata::new(
+            &50.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            "journal.log",
+            vec![],
+            vec![VariantMetadata::new("default", "text/plain", 11)],
+        );
+        manager
+            .create(
+                &mut log,
+                Some(Variant::new(
+                    VariantMetadata::new("default", "text/plain", 11),
+                    Box::new(async_std::io::Cursor::new(&b"first line\n"[..])),
+                )),
+            )
+            .await
+            .unwrap();
+
+        manager
+            .append_to_variant(&50.into(), "default", b"second line\n")
+            .await
+            .unwrap();
+
+        let meta = manager.get_metadata(&50.into()).await.unwrap();
+        assert_eq!(meta.variants()[0].size(), 23);
+
+        // Only existing variants of leaves can be appended to.
+        assert_eq!(
+            manager
+                .append_to_variant(&50.into(), "thumbnail", b"nope")
+                .await,
+            Err(ResourceStoreError::InvalidVariant("thumbnail".into()))
+        );
+        assert_eq!(
+            manager
+                .append_to_variant(&1.into(), "default", b"nope")
+                .await,
+            Err(ResourceStoreError::InvalidResourceId)
+        );
+    }
+
+    // Check that both the content and the size are persisted.
+    let store = FileStore::new(
+        &format!("./test-content/{}", 50),
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    let (meta, mut reader) = manager.get_leaf(&50.into(), "default").await.unwrap();
+    assert_eq!(meta.variants()[0].size(), 23);
+    let mut content = String::new();
+    reader.read_to_string(&mut content).await.unwrap();
+    assert_eq!(content, "first line\nsecond line\n");
+}
+
+#[async_std::test]
+async fn revisions() {
+    let (config, store) = prepare_test(51).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let leaf = manager.get_metadata(&5.into()).await.unwrap();
+    assert_eq!(leaf.rev(), 1);
+
+    manager
+        .update_variant(&5.into(), default_content().await)
+        .await
+        .unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 2);
+
+    // Updating from a stale revision fails.
+    assert_eq!(
+        manager
+            .update_variant_at_rev(&5.into(), default_content().await, 1)
+            .await,
+        Err(ResourceStoreError::Conflict)
+    );
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 2);
+
+    manager
+        .update_variant_at_rev(&5.into(), default_content().await, 2)
+        .await
+        .unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 3);
+
+    // Adding or removing a tag gives a new revision too.
+    manager.add_tag(&5.into(), "new").await.unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 4);
+    manager.remove_tag(&5.into(), "new").await.unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 5);
+    assert_eq!(
+        manager
+            .update_variant_at_rev(&5.into(), default_content().await, 4)
+            .await,
+        Err(ResourceStoreError::Conflict)
+    );
+    manager
+        .update_variant_at_rev(&5.into(), default_content().await, 5)
+        .await
+        .unwrap();
+
+    manager
+        .append_to_variant(&5.into(), "default", b"more")
+        .await
+        .unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 7);
+
+    // The revision is also known by the store.
+    manager.clear().await.unwrap();
+    assert_eq!(manager.get_metadata(&5.into()).await.unwrap().rev(), 7);
+    assert_eq!(
+        manager.get_metadata_batch(&[5.into()]).await.unwrap()[0].rev(),
+        7
+    );
+}
+
+#[async_std::test]
+async fn random_operations() {
+    use costaeres::testing::*;
+
+    let (config, store) = prepare_test(52).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let mut generator = OperationGenerator::new(1267);
+    for _ in 0..20 {
+        let operation = generator.next_creation();
+        // Name clashes are expected.
+        let _ = generator.apply(&mut manager, &operation).await;
+    }
+    check_invariants(&mut manager).await.unwrap();
+
+    let mut succeeded = 0;
+    for _ in 0..200 {
+        let operation = generator.next_operation();
+        // Failures are expected, but must not break the invariants.
+        if generator.apply(&mut manager, &operation).await.is_ok() {
+            succeeded += 1;
+        }
+        if let Err(err) = check_invariants(&mut manager).await {
+            panic!("After {:?}: {}", operation, err);
+        }
+    }
+    assert!(succeeded > 100);
+}
+
+struct PrefixedNameProvider;
+
+impl ResourceNameProvider for PrefixedNameProvider {
+    fn metadata_name(&self, id: &ResourceId) -> String {
+        format!("v2-{id}.meta")
+    }
+
+    fn variant_name(&self, id: &ResourceId, variant: &str) -> String {
+        format!("v2-{id}.{variant}.content")
+    }
+
+    fn blob_name(&self, hash: &str) -> String {
+        format!("v2-{hash}.blob")
+    }
+}
+
+#[async_std::test]
+async fn migrate_names() {
+    let (config, store) = prepare_test(53).await;
+
+    {
+        let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+        create_hierarchy(&mut manager).await;
+    }
+
+    let path = format!("./test-content/{}", 53);
+    let store = FileStore::new(
+        &path,
+        Box::new(PrefixedNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+
+    // Simulate an interrupted migration where the root was already moved.
+    fs::rename(
+        format!("{path}/{}.meta", *ROOT_ID),
+        format!("{path}/v2-{}.meta", *ROOT_ID),
+    )
+    .await
+    .unwrap();
+
+    // 22 metadata files minus the root one, 3 container contents and the blob
+    // shared by all the leaves.
+    let renamed = store
+        .migrate_names(&DefaultResourceNameProvider)
+        .await
+        .unwrap();
+    assert_eq!(renamed, 25);
+
+    // Running it again is a no-op.
+    let renamed = store
+        .migrate_names(&DefaultResourceNameProvider)
+        .await
+        .unwrap();
+    assert_eq!(renamed, 0);
+
+    let meta = store.get_metadata(&27.into()).await.unwrap();
+    assert_eq!(meta.name(), "child #27");
+    assert!(store.get_variant(&27.into(), "default").await.is_ok());
+    let hash = meta.variants()[0].hash().unwrap();
+    assert!(fs::metadata(format!("{path}/v2-{hash}.blob")).await.is_ok());
+    assert!(
+        fs::metadata(format!("{path}/{}.meta", ResourceId::from(27)))
+            .await
+            .is_err()
+    );
+}
+
+#[async_std::test]
+async fn sorted_container() {
+    let (config, store) = prepare_test(54).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let ids = |children: Vec<ResourceMetadata>| -> Vec<ResourceId> {
+        children.iter().map(|child| child.id()).collect()
+    };
+
+    let (_, children) = manager
+        .get_container_sorted(&1.into(), SortOrder::new(SortKey::Name), Page::new(0, 3))
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![10.into(), 11.into(), 12.into()]);
+
+    let (_, children) = manager
+        .get_container_sorted(
+            &1.into(),
+            SortOrder::new(SortKey::Name).descending(),
+            Page::new(0, 2),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![9.into(), 8.into()]);
+
+    let (_, children) = manager
+        .get_container_sorted(
+            &1.into(),
+            SortOrder::new(SortKey::Created)
+                .descending()
+                .containers_first(),
+            Page::new(0, 2),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![10.into(), 14.into()]);
+
+    let (_, children) = manager
+        .get_container_sorted(&1.into(), SortOrder::new(SortKey::Name), Page::new(8, 5))
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![8.into(), 9.into()]);
+
+    manager
+        .update_variant(&7.into(), default_content().await)
+        .await
+        .unwrap();
+    let (_, children) = manager
+        .get_container_sorted(
+            &1.into(),
+            SortOrder::new(SortKey::Modified).descending(),
+            Page::new(0, 1),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![7.into()]);
+
+    manager
+        .append_to_variant(&6.into(), "default", b"bigger")
+        .await
+        .unwrap();
+    let (_, children) = manager
+        .get_container_sorted(
+            &1.into(),
+            SortOrder::new(SortKey::Size).descending(),
+            Page::new(0, 1),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(children), vec![6.into()]);
+
+    manager
+        .visit(&12.into(), &VisitEntry::now(VisitPriority::High))
+        .await
+        .unwrap();
+    let (_, children) = manager
+        .get_container_sorted(
+            &1.into(),
+            SortOrder::new(SortKey::Frecency).descending(),
+            Page::all(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(children.len(), 10);
+    assert_eq!(children[0].id(), 12.into());
+}
+
+#[async_std::test]
+async fn search_within() {
+    let (config, store) = prepare_test(55).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+    manager.add_tag(&6.into(), "sub-child").await.unwrap();
+
+    let results = manager
+        .by_text_paged("child", None, Some(&1.into()), Page::all())
+        .await
+        .unwrap();
+    assert_eq!(results.total, 20);
+
+    // Only descendants are returned, not the container itself.
+    let results = manager
+        .by_text_paged("child", None, Some(&10.into()), Page::all())
+        .await
+        .unwrap();
+    assert_eq!(results.total, 10);
+    assert!(results
+        .items
+        .iter()
+        .all(|item| item.id != 10.into() && item.id != 6.into()));
+
+    let results = manager
+        .by_tag_paged("sub-child", None, Page::all())
+        .await
+        .unwrap();
+    assert_eq!(results.total, 11);
+
+    let results = manager
+        .by_tag_paged("sub-child", Some(&10.into()), Page::new(0, 4))
+        .await
+        .unwrap();
+    assert_eq!(results.items.len(), 4);
+    assert_eq!(results.total, 10);
+
+    let results = manager
+        .by_tag_paged("sub-child", Some(&5.into()), Page::all())
+        .await
+        .unwrap();
+    assert_eq!(results.total, 0);
+}
+
+#[async_std::test]
+async fn tag_policies() {
+    let (config, store) = prepare_test(56).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager.add_tag_policy(TagPolicy::new("archive", &["thumbnail", "preview"]));
+
+    // Other tags don't trigger the policy.
+    let meta = manager.add_tag(&5.into(), "keep").await.unwrap();
+    assert!(meta.has_variant("thumbnail"));
+
+    let meta = manager.add_tag(&5.into(), "archive").await.unwrap();
+    assert!(!meta.has_variant("thumbnail"));
+    assert!(meta.has_variant("default"));
+    assert!(!manager
+        .get_metadata(&5.into())
+        .await
+        .unwrap()
+        .has_variant("thumbnail"));
+    assert!(manager.get_leaf(&5.into(), "thumbnail").await.is_err());
+
+    // Resources without the variants are just tagged.
+    let meta = manager.add_tag(&6.into(), "archive").await.unwrap();
+    assert!(meta.has_tag("archive"));
+    assert_eq!(meta.variants().len(), 1);
+
+    // Variants required in the container are kept.
+    manager
+        .update_variant(&25.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager
+        .set_required_variants(&10.into(), &["default", "thumbnail"])
+        .await
+        .unwrap();
+    let meta = manager.add_tag(&25.into(), "archive").await.unwrap();
+    assert!(meta.has_tag("archive"));
+    assert!(meta.has_variant("thumbnail"));
+}
+
+#[async_std::test]
+async fn inherited_tags() {
+    let (config, store) = prepare_test(57).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+    manager.add_tag(&10.into(), "work").await.unwrap();
+
+    assert_eq!(manager.by_tag("work").await.unwrap(), vec![10.into()]);
+    assert_eq!(
+        manager.effective_tags(&25.into()).await.unwrap(),
+        vec!["sub-child".to_owned()]
+    );
+
+    manager.set_inherit_tags(true);
+
+    let tagged = manager.by_tag("work").await.unwrap();
+    assert_eq!(tagged.len(), 11);
+    assert!(tagged.contains(&10.into()));
+    assert!(tagged.contains(&25.into()));
+    assert!(!tagged.contains(&5.into()));
+
+    let results = manager
+        .by_text_paged("child", Some("work".into()), None, Page::all())
+        .await
+        .unwrap();
+    assert_eq!(results.total, 11);
+
+    assert_eq!(
+        manager.effective_tags(&25.into()).await.unwrap(),
+        vec!["sub-child".to_owned(), "work".to_owned()]
+    );
+
+    // Tags on the root apply to everything.
+    manager.add_tag(&ROOT_ID, "everything").await.unwrap();
+    assert_eq!(manager.by_tag("everything").await.unwrap().len(), 22);
+}
+
+#[async_std::test]
+async fn rename_and_delete_tags() {
+    let (config, store) = prepare_test(58).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+    manager.add_tag(&25.into(), "kid").await.unwrap();
+    manager.add_tag(&5.into(), "kid").await.unwrap();
+    let rev = manager.get_metadata(&25.into()).await.unwrap().rev();
+
+    // Resource 25 has both tags, and ends up with a single one.
+    assert_eq!(manager.rename_tag("sub-child", "kid").await.unwrap(), 10);
+    // Updates based on the previous revision conflict with the renaming.
+    assert_eq!(
+        manager
+            .update_variant_at_rev(&25.into(), default_content().await, rev)
+            .await,
+        Err(ResourceStoreError::Conflict)
+    );
+    assert!(manager.by_tag("sub-child").await.unwrap().is_empty());
+    assert_eq!(manager.by_tag("kid").await.unwrap().len(), 11);
+    assert_eq!(
+        manager.get_metadata(&25.into()).await.unwrap().tags(),
+        &vec!["kid".to_owned()]
+    );
+
+    assert_eq!(manager.rename_tag("unknown", "kid").await.unwrap(), 0);
+
+    // A store failure doesn't prevent the other resources from being updated, and
+    // leaves stale metadata behind until it is repaired.
+    let meta_path = format!("./test-content/58/{}.meta", ResourceId::from(5));
+    let saved = fs::read(&meta_path).await.unwrap();
+    fs::remove_file(&meta_path).await.unwrap();
+    fs::create_dir(&meta_path).await.unwrap();
+    assert!(manager.delete_tag("kid").await.is_err());
+    fs::remove_dir(&meta_path).await.unwrap();
+    fs::write(&meta_path, saved).await.unwrap();
+    assert!(manager.by_tag("kid").await.unwrap().is_empty());
+    assert_eq!(
+        manager.check_consistency(true).await.unwrap(),
+        vec![Inconsistency::StaleMetadata(5.into())]
+    );
+    assert!(manager.check_consistency(false).await.unwrap().is_empty());
+    assert!(manager
+        .get_metadata(&5.into())
+        .await
+        .unwrap()
+        .tags()
+        .is_empty());
+
+    // The store metadata is updated too.
+    manager.clear().await.unwrap();
+    let metadata = manager.get_metadata(&25.into()).await.unwrap();
+    assert!(metadata.tags().is_empty());
+    assert_eq!(metadata.rev(), rev + 2);
+}
+
+#[async_std::test]
+async fn merge_visits() {
+    let (config, store) = prepare_test(59).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    // Visits recorded on another device for resource 5.
+    let remote = vec![
+        VisitEntry::now(VisitPriority::Normal),
+        VisitEntry::now(VisitPriority::High),
+    ];
+    let mut payload = costaeres::scorer::Scorer::default();
+    for entry in &remote {
+        payload.add(entry);
+    }
+    let entries = costaeres::scorer::Scorer::entries_from_payload(&payload.sync_payload()).unwrap();
+
+    manager
+        .visit(&6.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+    assert_eq!(manager.merge_visits(&5.into(), &entries).await.unwrap(), 2);
+    // Merging the same visits again is a no-op.
+    assert_eq!(manager.merge_visits(&5.into(), &entries).await.unwrap(), 0);
+
+    let results = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(results[0].id, 5.into());
+    assert_eq!(results[1].id, 6.into());
+
+    // The merged scorer is persisted in the store.
+    manager.clear().await.unwrap();
+    let meta = manager.get_metadata(&5.into()).await.unwrap();
+    assert_eq!(meta.scorer().entries().len(), 2);
+}
+
+#[async_std::test]
+async fn check_consistency() {
+    let (config, store) = prepare_test(60).await;
+    let db_path = config.db_path.clone();
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+    assert!(manager.check_consistency(false).await.unwrap().is_empty());
+
+    // Damage the store behind the manager's back.
+    let path = "./test-content/60";
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    fs::remove_file(format!("{path}/id-5.meta")).await.unwrap();
+    store.delete_variant(&6.into(), "default").await.unwrap();
+    let mut meta = store.get_metadata(&7.into()).await.unwrap();
+    meta.add_or_update_variant(named_variant("extra", "text/plain"));
+    store
+        .update(&meta, Some(named_content("extra").await))
+        .await
+        .unwrap();
+    store
+        // An empty children list.
+        .update_default_variant_from_slice(&10.into(), &[0, 0, 0, 0])
+        .await
+        .unwrap();
+
+    let mut expected = vec![
+        Inconsistency::MissingFromStore(5.into()),
+        Inconsistency::MissingVariant(6.into(), "default".into()),
+        Inconsistency::OrphanVariant(7.into(), "extra".into()),
+        Inconsistency::ChildrenMismatch(10.into()),
+    ];
+    assert_eq!(manager.check_consistency(false).await.unwrap(), expected);
+
+    // Once resource 5 is removed from the database, container 1 must not list it anymore.
+    expected.insert(3, Inconsistency::ChildrenMismatch(1.into()));
+    assert_eq!(manager.check_consistency(true).await.unwrap(), expected);
+    assert!(manager.check_consistency(false).await.unwrap().is_empty());
+    costaeres::testing::check_invariants(&mut manager)
+        .await
+        .unwrap();
+    assert!(!manager.has_object(&5.into()).await.unwrap());
+    assert!(!manager
+        .get_metadata(&6.into())
+        .await
+        .unwrap()
+        .has_variant("default"));
+    assert!(store.get_variant(&7.into(), "extra").await.is_err());
+    assert_eq!(manager.get_container(&10.into()).await.unwrap().1.len(), 10);
+
+    // Create a cycle between 10 and one of its children.
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{db_path}"))
+        .await
+        .unwrap();
+    sqlx::query("UPDATE resources SET parent = 'id-26' WHERE id = 'id-10'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let issues = manager.check_consistency(false).await.unwrap();
+    let unreachable = issues
+        .iter()
+        .filter(|issue| matches!(issue, Inconsistency::Unreachable(_)))
+        .count();
+    assert_eq!(unreachable, 11);
+}
+
+#[async_std::test]
+async fn refresh_scores() {
+    use chrono::Duration;
+
+    let (config, store) = prepare_test(61).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+
+    create_hierarchy(&mut manager).await;
+
+    let when = Utc::now() - Duration::days(20);
+    manager
+        .visit(&5.into(), &VisitEntry::new(&when, VisitPriority::Normal))
+        .await
+        .unwrap();
+    manager
+        .visit(&7.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        manager.get_metadata(&5.into()).await.unwrap().frecency(),
+        50
+    );
+
+    let scores = manager
+        .refresh_scores(&[6.into(), 5.into(), 7.into()])
+        .await
+        .unwrap();
+    assert_eq!(
+        scores,
+        vec![
+            IdFrec::new(&6.into(), 0),
+            IdFrec::new(&5.into(), 50),
+            IdFrec::new(&7.into(), 100),
+        ]
+    );
+
+    assert!(manager.refresh_scores(&[100.into()]).await.is_err());
+}
+
+#[async_std::test]
+async fn rehydrate_all() {
+    let (config, store) = prepare_test(62).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.close().await;
+
+    // Open the same store with an empty database.
+    let path = "./test-content/62";
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let config = Config {
+        db_path: format!("{path}/rehydrated_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    assert_eq!(manager.resource_count().await.unwrap(), 0);
+
+    let mut calls = vec![];
+    let mut sink = |done: usize, total: usize, _: &ResourceId| calls.push((done, total));
+    let count = manager.rehydrate_all(Some(&mut sink)).await.unwrap();
+    assert_eq!(count, 22);
+    assert_eq!(calls.len(), 22);
+    assert_eq!(calls.last(), Some(&(22, 22)));
+
+    assert_eq!(manager.resource_count().await.unwrap(), 22);
+    assert_eq!(manager.by_tag("sub-child").await.unwrap().len(), 10);
+    assert_eq!(manager.by_text("child", None).await.unwrap().len(), 20);
+    costaeres::testing::check_invariants(&mut manager)
+        .await
+        .unwrap();
+
+    // Running it again replaces the existing entries.
+    assert_eq!(manager.rehydrate_all(None).await.unwrap(), 22);
+    assert_eq!(manager.resource_count().await.unwrap(), 22);
+    assert_eq!(manager.by_tag("sub-child").await.unwrap().len(), 10);
+}
+
+#[cfg(feature = "codecs")]
+#[async_std::test]
+async fn typed_content() {
+    use costaeres::codecs::{Contact, Place};
+
+    let (config, store) = prepare_test(63).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let contact = Contact {
+        name: "Jean Dupont".into(),
+        phone: vec!["+33 1 23 45 67 89".into()],
+        email: vec!["jean@example.com".into()],
+    };
+    let place = Place {
+        url: "https://example.com/".into(),
+        title: "".into(),
+        icon: None,
+    };
+
+    let contact_meta = manager.create_typed(&ROOT_ID, &contact).await.unwrap();
+    assert_eq!(contact_meta.name(), "Jean Dupont");
+    let place_meta = manager.create_typed(&ROOT_ID, &place).await.unwrap();
+    assert_eq!(place_meta.name(), "https://example.com/");
+
+    assert_eq!(
+        manager
+            .get_typed::<Contact>(&contact_meta.id())
+            .await
+            .unwrap(),
+        contact
+    );
+    assert_eq!(
+        manager.get_typed::<Place>(&place_meta.id()).await.unwrap(),
+        place
+    );
+    assert_eq!(
+        manager.get_typed::<Contact>(&place_meta.id()).await.err(),
+        Some(ResourceStoreError::InvalidVariant("default".into()))
+    );
+
+    // The content is indexed.
+    let results = manager.by_text("jean@example.com", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, contact_meta.id());
+    let other = Contact {
+        name: "Marie Curie".into(),
+        ..Default::default()
+    };
+    manager.create_typed(&ROOT_ID, &other).await.unwrap();
+    assert_eq!(manager.by_text("marie", None).await.unwrap().len(), 1);
+}
+
+#[cfg(feature = "codecs")]
+#[async_std::test]
+async fn find_duplicates() {
+    use costaeres::capability::Operations;
+    use costaeres::codecs::{Contact, Place};
+
+    let (config, store) = prepare_test(64).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let contact = |name: &str, phone: &[&str], email: &[&str]| Contact {
+        name: name.into(),
+        phone: phone.iter().map(|s| s.to_string()).collect(),
+        email: email.iter().map(|s| s.to_string()).collect(),
+    };
+
+    let a = manager
+        .create_typed(&ROOT_ID, &contact("Jean", &["+33 1 23 45 67 89"], &[]))
+        .await
+        .unwrap();
+    // Same phone number, differently formatted, in another container.
+    let b = manager
+        .create_typed(
+            &1.into(),
+            &contact("Jean Dupont", &["+33123456789"], &["Jean@Example.com"]),
+        )
+        .await
+        .unwrap();
+    // Same email as b, so a duplicate of a as well.
+    let c = manager
+        .create_typed(
+            &10.into(),
+            &contact("J. Dupont", &[], &["jean@example.com "]),
+        )
+        .await
+        .unwrap();
+    manager
+        .create_typed(&ROOT_ID, &contact("Marie", &["+33 9 87 65 43 21"], &[]))
+        .await
+        .unwrap();
+
+    let mut expected = vec![a.id(), b.id(), c.id()];
+    expected.sort_by_key(|id| id.to_string());
+    assert_eq!(
+        manager.find_duplicates::<Contact>().await.unwrap(),
+        vec![expected]
+    );
+
+    let place = |url: &str| Place {
+        url: url.into(),
+        title: url.into(),
+        icon: None,
+    };
+    let d = manager
+        .create_typed(&ROOT_ID, &place("https://www.example.com/news/"))
+        .await
+        .unwrap();
+    let e = manager
+        .create_typed(&ROOT_ID, &place("HTTPS://example.com/news#latest"))
+        .await
+        .unwrap();
+    manager
+        .create_typed(&ROOT_ID, &place("https://example.com/sports"))
+        .await
+        .unwrap();
+
+    let mut expected = vec![d.id(), e.id()];
+    expected.sort_by_key(|id| id.to_string());
+    assert_eq!(
+        manager.find_duplicates::<Place>().await.unwrap(),
+        vec![expected]
+    );
+
+    // Capabilities restricted to a container only find duplicates among its descendants.
+    let capability = manager
+        .issue_capability(&1.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    let mut expected = vec![b.id(), c.id()];
+    expected.sort_by_key(|id| id.to_string());
+    assert_eq!(
+        manager.find_duplicates::<Contact>().await.unwrap(),
+        vec![expected]
+    );
+}
+
+#[async_std::test]
+async fn progress_reports() {
+    let (config, store) = prepare_test(65).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let mut reports = vec![];
+    let mut sink =
+        |done: usize, total: usize, id: &ResourceId| reports.push((done, total, id.clone()));
+
+    let copy = manager
+        .copy_resource_with_progress(&5.into(), &10.into(), Some(&mut sink))
+        .await
+        .unwrap();
+    let imported = manager
+        .import_from_path_with_progress(&ROOT_ID, "./create_db.sh", false, Some(&mut sink))
+        .await
+        .unwrap();
+    assert_eq!(reports, vec![(1, 1, copy.id()), (1, 1, imported.id())]);
+
+    // Deleting container 10 removes its 11 children, then the container itself.
+    reports.clear();
+    let mut sink =
+        |done: usize, total: usize, id: &ResourceId| reports.push((done, total, id.clone()));
+    manager
+        .delete_with_progress(&10.into(), Some(&mut sink))
+        .await
+        .unwrap();
+    assert_eq!(reports.len(), 12);
+    assert!(reports.iter().all(|(_, total, _)| *total == 12));
+    assert_eq!(
+        reports.iter().map(|(done, _, _)| *done).collect::<Vec<_>>(),
+        (1..=12).collect::<Vec<_>>()
+    );
+    assert_eq!(reports.last().unwrap().2, 10.into());
+}
+
+// Returns the names and contents of the entries of a tar archive.
+fn tar_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut pos = 0;
+    while pos + 512 <= archive.len() && archive[pos] != 0 {
+        let header = &archive[pos..pos + 512];
+        let field = |range: std::ops::Range<usize>| {
+            let bytes: Vec<u8> = header[range]
+                .iter()
+                .take_while(|b| **b != 0)
+                .cloned()
+                .collect();
+            String::from_utf8(bytes).unwrap()
+        };
+        let size = usize::from_str_radix(&field(124..136), 8).unwrap();
+        let content = archive[pos + 512..pos + 512 + size].to_vec();
+        pos += 512 + size.div_ceil(512) * 512;
+        if header[156] == b'L' {
+            long_name = Some(String::from_utf8(content[..size - 1].to_vec()).unwrap());
+            continue;
+        }
+        let name = long_name.take().unwrap_or_else(|| field(0..100));
+        entries.push((name, content));
+    }
+    entries
+}
+
+#[async_std::test]
+async fn export_archive() {
+    let (config, store) = prepare_test(66).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    // Give a leaf a second variant, and a long name.
+    manager
+        .update_variant(&25.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    let long_name = "a".repeat(120);
+    manager
+        .rename_resource(&26.into(), &long_name)
+        .await
+        .unwrap();
+
+    let mut archive = vec![];
+    let count = manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+    assert_eq!(count, 11);
+    assert_eq!(archive.len() % 512, 0);
+
+    let entries = tar_entries(&archive);
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names[0], "child #10/");
+    assert_eq!(names[1], "child #10/.metadata.json");
+    assert!(names.contains(&"child #10/child #25.metadata.json"));
+    assert!(names.contains(&"child #10/child #25.variants/thumbnail"));
+    assert!(names.contains(&format!("child #10/{long_name}").as_str()));
+    // 1 container with 2 entries, 10 leaves with 2 entries and one extra variant.
+    assert_eq!(entries.len(), 23);
+
+    let expected = fs::read("./create_db.sh").await.unwrap();
+    let (_, content) = entries
+        .iter()
+        .find(|(name, _)| name == "child #10/child #27")
+        .unwrap();
+    assert_eq!(content, &expected);
+
+    let (_, json) = entries
+        .iter()
+        .find(|(name, _)| name == "child #10/child #27.metadata.json")
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(json).unwrap();
+    assert_eq!(json["id"], "id-27");
+    assert_eq!(json["container"], false);
+    assert_eq!(json["tags"], serde_json::json!(["sub-child"]));
+    assert_eq!(json["variants"][0]["mimeType"], "application/octet-stream");
+}
+
+#[async_std::test]
+async fn export_hidden_resources() {
+    let (config, store) = prepare_test(125).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let mut dotfile = ResourceMetadata::new(
+        &35.into(),
+        &10.into(),
+        ResourceKind::Leaf,
+        ".config",
+        vec![],
+        vec![default_variant()],
+    );
+    manager
+        .create(&mut dotfile, Some(default_content().await))
+        .await
+        .unwrap();
+
+    // Hidden resources are part of backups.
+    let mut archive = vec![];
+    let count = manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+    assert_eq!(count, 12);
+    let entries = tar_entries(&archive);
+    assert!(entries.iter().any(|(name, _)| name == "child #10/.config"));
+
+    let count = manager
+        .import_archive(&ROOT_ID, &mut async_std::io::Cursor::new(&archive))
+        .await
+        .unwrap();
+    assert_eq!(count, 12);
+    let imported = manager.child_by_name(&ROOT_ID, "child #10").await.unwrap();
+    let leaf = manager
+        .child_by_name(&imported.id(), ".config")
+        .await
+        .unwrap();
+    assert_ne!(leaf.id(), 35.into());
+    assert!(leaf.has_variant("default"));
+}
+
+// Hides child #26 and redacts the content of the other resources.
+struct Redacting;
+
+impl costaeres::read_filter::ReadFilter for Redacting {
+    fn allows(&self, _caller: Option<&str>, metadata: &ResourceMetadata) -> bool {
+        metadata.name() != "child #26"
+    }
+
+    fn transform(
+        &self,
+        _caller: Option<&str>,
+        _metadata: &ResourceMetadata,
+        _variant: &str,
+        _content: BoxedReader,
+    ) -> BoxedReader {
+        Box::new(async_std::io::Cursor::new(b"redacted".to_vec()))
+    }
+}
+
+#[async_std::test]
+async fn export_read_filter() {
+    let (config, store) = prepare_test(136).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.set_read_filter(Some(Box::new(Redacting)));
+
+    let mut archive = vec![];
+    let count = manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+    assert_eq!(count, 10);
+    let entries = tar_entries(&archive);
+    assert!(!entries
+        .iter()
+        .any(|(name, _)| name.starts_with("child #10/child #26")));
+    let (_, content) = entries
+        .iter()
+        .find(|(name, _)| name == "child #10/child #27")
+        .unwrap();
+    assert_eq!(content, b"redacted");
+}
+
+#[cfg(feature = "encryption")]
+#[async_std::test]
+async fn encrypted_archive() {
+    use age::secrecy::ExposeSecret;
+
+    let (config, store) = prepare_test(128).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public().to_string();
+    let mut archive = vec![];
+    let count = manager
+        .export_encrypted_archive(
+            &10.into(),
+            &mut archive,
+            &[&recipient],
+            ExportOptions::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(count, 11);
+    assert!(!archive.windows(9).any(|window| window == b"child #10"));
+
+    // Only the recipient can import it.
+    let other = age::x25519::Identity::generate();
+    assert_eq!(
+        manager
+            .import_encrypted_archive(
+                &ROOT_ID,
+                &mut async_std::io::Cursor::new(&archive),
+                other.to_string().expose_secret(),
+            )
+            .await,
+        Err(ResourceStoreError::Custom("DecryptionFailed".into()))
+    );
+    let count = manager
+        .import_encrypted_archive(
+            &ROOT_ID,
+            &mut async_std::io::Cursor::new(&archive),
+            identity.to_string().expose_secret(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(count, 11);
+    let imported = manager.child_by_name(&ROOT_ID, "child #10").await.unwrap();
+    assert_ne!(imported.id(), 10.into());
+    let (_, children) = manager.get_container(&imported.id()).await.unwrap();
+    assert_eq!(children.len(), 10);
+
+    assert_eq!(
+        manager
+            .export_encrypted_archive(
+                &10.into(),
+                &mut vec![],
+                &["age1invalid"],
+                ExportOptions::default()
+            )
+            .await,
+        Err(ResourceStoreError::Custom("InvalidRecipient".into()))
+    );
+}
+
+#[async_std::test]
+async fn places_by_origin() {
+    let (config, store) = prepare_test(67).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.add_indexer(Box::new(create_places_indexer()));
+    manager.create_root().await.unwrap();
+
+    let urls = [
+        "https://WWW.Example.com/news/",
+        "http://example.com/sports#scores",
+        "https://example.org/",
+        "https://mdn.github.io/webextensions-examples/",
+    ];
+    for (i, url) in urls.iter().enumerate() {
+        let content = format!(r#"{{ "url": "{url}", "title": "Place {i}" }}"#).into_bytes();
+        let mut meta = ResourceMetadata::new(
+            &(i as i32 + 1).into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            &format!("place {i}"),
+            vec![],
+            vec![],
+        );
+        manager
+            .create(
+                &mut meta,
+                Some(Variant::new(
+                    VariantMetadata::new(
+                        "default",
+                        "application/x-places+json",
+                        content.len() as _,
+                    ),
+                    Box::new(async_std::io::Cursor::new(content)),
+                )),
+            )
+            .await
+            .unwrap();
+    }
+    manager
+        .visit(&2.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+
+    let ids = |results: Vec<IdFrec>| -> Vec<ResourceId> {
+        results.into_iter().map(|item| item.id).collect()
+    };
+
+    // Most frecent first.
+    let results = manager.places_by_origin("example.com", 10).await.unwrap();
+    assert_eq!(ids(results), vec![2.into(), 1.into()]);
+
+    // Prefix matching, ignoring case and "www.".
+    let results = manager.places_by_origin("www.EXAMPLE", 10).await.unwrap();
+    assert_eq!(results.len(), 3);
+    let results = manager.places_by_origin("mdn", 10).await.unwrap();
+    assert_eq!(ids(results), vec![4.into()]);
+    assert!(manager
+        .places_by_origin("github", 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Updating the url moves the place to another origin.
+    let content = br#"{ "url": "https://github.com/" }"#.to_vec();
+    manager
+        .update_variant(
+            &3.into(),
+            Variant::new(
+                VariantMetadata::new("default", "application/x-places+json", content.len() as _),
+                Box::new(async_std::io::Cursor::new(content)),
+            ),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        ids(manager.places_by_origin("github", 10).await.unwrap()),
+        vec![3.into()]
+    );
+    assert_eq!(
+        manager.places_by_origin("example", 10).await.unwrap().len(),
+        2
+    );
+
+    // Deleted places are forgotten.
+    manager.delete(&1.into()).await.unwrap();
+    assert_eq!(
+        ids(manager.places_by_origin("example", 10).await.unwrap()),
+        vec![2.into()]
+    );
+
+    // Hidden places follow the visibility.
+    manager
+        .rename_resource(&2.into(), ".place 1")
+        .await
+        .unwrap();
+    assert!(manager
+        .places_by_origin("example", 10)
+        .await
+        .unwrap()
+        .is_empty());
+    manager.set_visibility(Visibility::All);
+    assert_eq!(
+        ids(manager.places_by_origin("example", 10).await.unwrap()),
+        vec![2.into()]
+    );
+}
+
+#[async_std::test]
+async fn favicons() {
+    use async_trait::async_trait;
+    use costaeres::favicons::IconFetcher;
+    use std::cell::Cell;
+
+    struct MockFetcher {
+        calls: Rc<Cell<usize>>,
+    }
+
+    #[async_trait(?Send)]
+    impl IconFetcher for MockFetcher {
+        async fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), ResourceStoreError> {
+            self.calls.set(self.calls.get() + 1);
+            if url == "https://example.com/favicon.ico" {
+                Ok(("image/x-icon".into(), b"icon".to_vec()))
+            } else {
+                Err(ResourceStoreError::NoSuchResource)
+            }
+        }
+    }
+
+    let (config, store) = prepare_test(68).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    for (i, url) in ["https://example.com/news", "https://example.org/"]
+        .iter()
+        .enumerate()
+    {
+        let content = format!(r#"{{ "url": "{url}", "icon": "/missing.png" }}"#).into_bytes();
+        let mut meta = ResourceMetadata::new(
+            &(i as i32 + 1).into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            &format!("place {i}"),
+            vec![],
+            vec![],
+        );
+        manager
+            .create(
+                &mut meta,
+                Some(Variant::new(
+                    VariantMetadata::new(
+                        "default",
+                        "application/x-places+json",
+                        content.len() as _,
+                    ),
+                    Box::new(async_std::io::Cursor::new(content)),
+                )),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        manager.update_favicon(&1.into()).await.err(),
+        Some(ResourceStoreError::Custom("NoIconFetcher".into()))
+    );
+
+    let calls = Rc::new(Cell::new(0));
+    manager.set_icon_fetcher(Box::new(MockFetcher {
+        calls: calls.clone(),
+    }));
+
+    // The declared icon is tried first, then the origin's favicon.
+    let week = chrono::Duration::days(7);
+    assert_eq!(manager.refresh_favicons(week).await.unwrap(), 1);
+    assert_eq!(calls.get(), 4);
+
+    let (meta, mut reader) = manager.get_leaf(&1.into(), "icon").await.unwrap();
+    assert_eq!(meta.mime_type_for_variant("icon").unwrap(), "image/x-icon");
+    let mut icon = vec![];
+    async_std::io::ReadExt::read_to_end(&mut reader, &mut icon)
+        .await
+        .unwrap();
+    assert_eq!(icon, b"icon");
+    assert!(manager.get_leaf(&2.into(), "icon").await.is_err());
+
+    // Nothing to refresh until the icons are old enough, even if none was found.
+    assert_eq!(manager.refresh_favicons(week).await.unwrap(), 0);
+    assert_eq!(calls.get(), 4);
+    assert_eq!(
+        manager
+            .refresh_favicons(chrono::Duration::zero())
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(calls.get(), 8);
+}
+
+// Builds a tar archive with plain files, as produced by common tools.
+fn plain_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = vec![];
+    for (name, content) in files {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+        header[156] = b'0';
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(content);
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+// Builds a zip archive, deflating the entries when `deflate` is true.
+fn plain_zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut archive = vec![];
+    let mut directory = vec![];
+    for (path, content) in files {
+        let data = if deflate {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap()
+        } else {
+            content.to_vec()
+        };
+        let mut crc = flate2::Crc::new();
+        crc.update(content);
+        let method: u16 = if deflate { 8 } else { 0 };
+
+        // The fields shared by the local and central headers, from the version needed.
+        let mut fields = vec![];
+        fields.extend_from_slice(&20u16.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // flags
+        fields.extend_from_slice(&method.to_le_bytes());
+        fields.extend_from_slice(&[0; 4]); // time and date
+        fields.extend_from_slice(&crc.sum().to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // extra length
+
+        directory.extend_from_slice(b"PK\x01\x02");
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        directory.extend_from_slice(&fields);
+        directory.extend_from_slice(&[0; 10]); // comment, disk, attributes
+        directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+        directory.extend_from_slice(path.as_bytes());
+
+        archive.extend_from_slice(b"PK\x03\x04");
+        archive.extend_from_slice(&fields);
+        archive.extend_from_slice(path.as_bytes());
+        archive.extend_from_slice(&data);
+    }
+    let offset = archive.len() as u32;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(b"PK\x05\x06");
+    archive.extend_from_slice(&[0; 4]); // disks
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    archive
+}
+
+#[async_std::test]
+async fn import_archive() {
+    let (config, store) = prepare_test(69).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager
+        .update_variant(&25.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+
+    let mut archive = vec![];
+    manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+
+    // Import twice, the second copy being renamed.
+    for expected_name in ["child #10", "child #10(1)"] {
+        let count = manager
+            .import_archive(&ROOT_ID, &mut async_std::io::Cursor::new(&archive))
+            .await
+            .unwrap();
+        assert_eq!(count, 11);
+
+        let imported = manager
+            .child_by_name(&ROOT_ID, expected_name)
+            .await
+            .unwrap();
+        assert_ne!(imported.id(), 10.into());
+        let (_, children) = manager.get_container(&imported.id()).await.unwrap();
+        assert_eq!(children.len(), 10);
+
+        let leaf = manager
+            .child_by_name(&imported.id(), "child #25")
+            .await
+            .unwrap();
+        assert_eq!(leaf.tags(), &vec!["sub-child".to_owned()]);
+        assert_eq!(leaf.variants().len(), 2);
+        assert!(leaf.has_variant("thumbnail"));
+        let original = manager.get_metadata(&25.into()).await.unwrap();
+        assert_eq!(leaf.created(), original.created());
+    }
+    assert_eq!(manager.by_tag("sub-child").await.unwrap().len(), 30);
+
+    // A plain archive, with implicit directories.
+    let archive = plain_tar(&[
+        ("docs/notes.txt", b"Some notes"),
+        ("docs/sub/data.json", b"{}"),
+        ("readme.md", b"# Read me"),
+    ]);
+    let count = manager
+        .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+        .await
+        .unwrap();
+    assert_eq!(count, 5);
+    let docs = manager.child_by_name(&1.into(), "docs").await.unwrap();
+    assert_eq!(docs.kind(), ResourceKind::Container);
+    let notes = manager
+        .child_by_name(&docs.id(), "notes.txt")
+        .await
+        .unwrap();
+    assert_eq!(notes.variants()[0].mime_type(), "text/plain");
+    assert_eq!(manager.by_text("notes", None).await.unwrap().len(), 1);
+
+    // Importing again renames the conflicting resources at the top level.
+    manager
+        .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+        .await
+        .unwrap();
+    manager.child_by_name(&1.into(), "docs(1)").await.unwrap();
+    manager
+        .child_by_name(&1.into(), "readme(1).md")
+        .await
+        .unwrap();
+
+    // Corrupted archives are rejected.
+    let mut archive = archive;
+    archive[0] = b'D';
+    assert!(manager
+        .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+        .await
+        .is_err());
+
+    // Zip archives, with stored or deflated entries.
+    for deflate in [false, true] {
+        let archive = plain_zip(
+            &[
+                ("photos/", b""),
+                ("photos/list.txt", b"Holiday pictures"),
+                ("todo.txt", b"Sort the pictures"),
+            ],
+            deflate,
+        );
+        let count = manager
+            .import_archive(&10.into(), &mut async_std::io::Cursor::new(&archive))
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+    let photos = manager.child_by_name(&10.into(), "photos").await.unwrap();
+    let list = manager
+        .child_by_name(&photos.id(), "list.txt")
+        .await
+        .unwrap();
+    assert_eq!(list.variants()[0].size(), 16);
+    manager
+        .child_by_name(&10.into(), "todo(1).txt")
+        .await
+        .unwrap();
+    manager
+        .child_by_name(&10.into(), "photos(1)")
+        .await
+        .unwrap();
+
+    let mut archive = plain_zip(&[("todo.txt", b"Sort the pictures")], true);
+    archive[40] ^= 0xff;
+    assert!(manager
+        .import_archive(&10.into(), &mut async_std::io::Cursor::new(&archive))
+        .await
+        .is_err());
+}
+
+#[async_std::test]
+async fn import_malicious_archives() {
+    let (config, store) = prepare_test(137).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let invalid = Err(ResourceStoreError::Custom("InvalidArchive".into()));
+
+    // A tar entry claiming a huge size is rejected without allocating it.
+    let mut archive = vec![];
+    manager
+        .export_archive(&5.into(), &mut archive)
+        .await
+        .unwrap();
+    archive[124..136].copy_from_slice(b"77777777777\0");
+    let checksum: u32 = archive[..512]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                *b as u32
+            }
+        })
+        .sum();
+    archive[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    assert_eq!(
+        manager
+            .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+            .await,
+        invalid
+    );
+
+    // Zip entries don't inflate past their declared size, nor declare impossible ones.
+    let zeros = vec![0u8; 100_000];
+    let archive = plain_zip(&[("zeros", &zeros)], true);
+    let central = archive
+        .windows(4)
+        .position(|window| window == b"PK\x01\x02")
+        .unwrap();
+    for size in [10u32, u32::MAX] {
+        let mut archive = archive.clone();
+        archive[central + 24..central + 28].copy_from_slice(&size.to_le_bytes());
+        assert_eq!(
+            manager
+                .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+                .await,
+            invalid
+        );
+    }
+
+    // Metadata of a leaf under the variants of another leaf.
+    let metadata = |name: &str, variants: &str| {
+        format!(
+            r#"{{ "id": "id-1", "name": "{name}", "container": false, "tags": [],
+            "variants": [{variants}],
+            "created": "2024-01-01T00:00:00Z", "modified": "2024-01-01T00:00:00Z" }}"#
+        )
+    };
+    let default = r#"{ "name": "default", "mimeType": "text/plain", "size": 5 }"#;
+    let outer = metadata("outer", default);
+    let inner = metadata("inner", default);
+    let archive = plain_zip(
+        &[
+            ("outer", b"outer"),
+            ("outer.metadata.json", outer.as_bytes()),
+            ("outer.variants/inner.metadata.json", inner.as_bytes()),
+        ],
+        false,
+    );
+    assert_eq!(
+        manager
+            .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+            .await,
+        invalid
+    );
+
+    // Leaves are created with all their variants, or not at all.
+    let variants =
+        format!(r#"{default}, {{ "name": "default", "mimeType": "text/plain", "size": 5 }}"#);
+    let twice = metadata("twice", &variants);
+    let archive = plain_zip(
+        &[
+            ("twice", b"twice"),
+            ("twice.metadata.json", twice.as_bytes()),
+        ],
+        false,
+    );
+    assert!(manager
+        .import_archive(&1.into(), &mut async_std::io::Cursor::new(&archive))
+        .await
+        .is_err());
+    assert_eq!(
+        manager.child_by_name(&1.into(), "twice").await,
+        Err(ResourceStoreError::NoSuchResource)
+    );
+}
+
+#[async_std::test]
+async fn visits_timeline() {
+    use chrono::TimeZone;
+
+    let (config, store) = prepare_test(70).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let at = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap();
+    // 2024-01-01 is a Monday.
+    for (id, when) in [
+        (5, at(1, 10)),
+        (25, at(3, 9)),
+        (25, at(7, 23)),
+        (26, at(8, 0)),
+        (5, at(31, 12)),
+    ] {
+        manager
+            .visit(&id.into(), &VisitEntry::new(&when, VisitPriority::Normal))
+            .await
+            .unwrap();
+    }
+    // Merged visits are only counted once.
+    let remote = vec![VisitEntry::new(&at(3, 18), VisitPriority::High)];
+    manager.merge_visits(&6.into(), &remote).await.unwrap();
+    manager.merge_visits(&6.into(), &remote).await.unwrap();
+
+    let counts = |buckets: Vec<VisitsBucket>| -> Vec<(u32, u32)> {
+        buckets
+            .into_iter()
+            .map(|b| (b.start.day(), b.visits))
+            .collect()
+    };
+
+    let daily = manager
+        .visits_timeline(at(1, 0)..at(10, 0), TimeBucket::Day, None)
+        .await
+        .unwrap();
+    assert_eq!(counts(daily), vec![(1, 1), (3, 2), (7, 1), (8, 1)]);
+
+    let weekly = manager
+        .visits_timeline(at(1, 0)..at(10, 0), TimeBucket::Week, None)
+        .await
+        .unwrap();
+    assert_eq!(counts(weekly), vec![(1, 4), (8, 1)]);
+
+    let tagged = manager
+        .visits_timeline(at(1, 0)..at(31, 0), TimeBucket::Week, Some("sub-child"))
+        .await
+        .unwrap();
+    assert_eq!(counts(tagged), vec![(1, 2), (8, 1)]);
+
+    // Visits of deleted resources are forgotten.
+    manager.delete(&10.into()).await.unwrap();
+    let weekly = manager
+        .visits_timeline(at(1, 0)..at(31, 0), TimeBucket::Week, None)
+        .await
+        .unwrap();
+    assert_eq!(counts(weekly), vec![(1, 2)]);
+}
+
+#[async_std::test]
+async fn variant_hashes() {
+    let (config, store) = prepare_test(71).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let text_content = |text: &str| {
+        Variant::new(
+            VariantMetadata::new("default", "text/plain", text.len() as _),
+            Box::new(async_std::io::Cursor::new(text.as_bytes().to_vec())),
+        )
+    };
+    let hash_of = |meta: &ResourceMetadata| meta.variants()[0].hash();
+    let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    let mut meta = ResourceMetadata::new(
+        &1.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "hello.txt",
+        vec![],
+        vec![],
+    );
+    manager
+        .create(&mut meta, Some(text_content("hello")))
+        .await
+        .unwrap();
+    assert_eq!(hash_of(&meta).as_deref(), Some(hello));
+
+    // The hash is stored in the database and in the store.
+    let (_, children) = manager.get_container(&ROOT_ID).await.unwrap();
+    assert_eq!(hash_of(&children[0]).as_deref(), Some(hello));
+    manager.clear().await.unwrap();
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_eq!(hash_of(&meta).as_deref(), Some(hello));
+
+    // The same content keeps the same hash, which is computed by the store
+    // regardless of the one provided by the caller.
+    let mut content = text_content("hello");
+    content.metadata.set_hash(Some("not the hash"));
+    manager.update_variant(&1.into(), content).await.unwrap();
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_eq!(hash_of(&meta).as_deref(), Some(hello));
+    manager.clear().await.unwrap();
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_eq!(hash_of(&meta).as_deref(), Some(hello));
+
+    manager
+        .update_variant(&1.into(), text_content("hello world"))
+        .await
+        .unwrap();
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_ne!(hash_of(&meta).as_deref(), Some(hello));
+
+    // The hash is unknown after appending content.
+    manager
+        .append_to_variant(&1.into(), "default", b"!")
+        .await
+        .unwrap();
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_eq!(hash_of(&meta), None);
+}
+
+#[async_std::test]
+async fn dry_runs() {
+    let (config, store) = prepare_test(72).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    // Container 10 holds 10 leaves, each with a 42 bytes default variant.
+    let report = manager.delete_dry_run(&10.into()).await.unwrap();
+    assert_eq!(report.resources.len(), 11);
+    assert_eq!(report.resources[0], 10.into());
+    assert!(report.resources.contains(&34.into()));
+    assert_eq!(report.bytes, 420);
+
+    let report = manager.delete_dry_run(&5.into()).await.unwrap();
+    assert_eq!(report.resources, vec![5.into()]);
+    assert_eq!(report.bytes, 42);
+    assert!(manager.delete_dry_run(&100.into()).await.is_err());
+
+    // Hidden resources are deleted too, so they are part of the report.
+    let mut hidden = ResourceMetadata::new(
+        &35.into(),
+        &10.into(),
+        ResourceKind::Leaf,
+        ".hidden",
+        vec![],
+        vec![default_variant()],
+    );
+    manager
+        .create(&mut hidden, Some(default_content().await))
+        .await
+        .unwrap();
+    let report = manager.delete_dry_run(&10.into()).await.unwrap();
+    assert_eq!(report.resources.len(), 12);
+    assert!(report.resources.contains(&35.into()));
+    assert_eq!(report.bytes, 462);
+
+    let report = manager
+        .move_resource_dry_run(&10.into(), &ROOT_ID)
+        .await
+        .unwrap();
+    assert_eq!(report.resources.len(), 12);
+    // Moving in the current parent is a no-op.
+    let report = manager
+        .move_resource_dry_run(&10.into(), &1.into())
+        .await
+        .unwrap();
+    assert!(report.resources.is_empty());
+    // Same errors as the real move.
+    assert!(matches!(
+        manager.move_resource_dry_run(&1.into(), &10.into()).await,
+        Err(ResourceStoreError::ResourceCycle)
+    ));
+
+    // Nothing changed.
+    assert_eq!(
+        manager.get_metadata(&10.into()).await.unwrap().parent(),
+        1.into()
+    );
+    manager.delete(&10.into()).await.unwrap();
+    assert!(manager.get_metadata(&34.into()).await.is_err());
+}
+
+async fn create_once(
+    manager: &mut Manager<()>,
+    key: &str,
+) -> Result<ResourceMetadata, ResourceStoreError> {
+    manager
+        .idempotent(key, |manager| {
+            Box::pin(async move {
+                let mut meta = ResourceMetadata::new(
+                    &1.into(),
+                    &ROOT_ID,
+                    ResourceKind::Leaf,
+                    "leaf",
+                    vec![],
+                    vec![],
+                );
+                manager.create(&mut meta, None).await?;
+                Ok(meta)
+            })
+        })
+        .await
+}
+
+#[async_std::test]
+async fn idempotency_keys() {
+    let (config, store) = prepare_test(73).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let first = create_once(&mut manager, "create-1").await.unwrap();
+    // Retrying returns the same result instead of failing.
+    let retry = create_once(&mut manager, "create-1").await.unwrap();
+    assert_eq!(first, retry);
+    // Another key runs the operation again.
+    assert!(create_once(&mut manager, "create-2").await.is_err());
+
+    // The operation runs once, so deleting twice doesn't fail.
+    for _ in 0..2 {
+        manager
+            .idempotent("delete-1", |manager| {
+                Box::pin(async move { manager.delete(&1.into()).await })
+            })
+            .await
+            .unwrap();
+    }
+    // Failures are not recorded.
+    for _ in 0..2 {
+        assert_eq!(
+            manager
+                .idempotent("delete-2", |manager| {
+                    Box::pin(async move { manager.delete(&1.into()).await })
+                })
+                .await
+                .err(),
+            Some(ResourceStoreError::NoSuchResource)
+        );
+    }
+
+    // Keys expire.
+    manager.set_idempotency_ttl(chrono::Duration::zero());
+    assert!(create_once(&mut manager, "create-1").await.is_ok());
+    assert!(manager.get_metadata(&1.into()).await.is_ok());
+}
+
+#[async_std::test]
+async fn verify_variants() {
+    let (config, store) = prepare_test(74).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    for (id, text) in [(1, "first"), (2, "second"), (3, "third")] {
+        let mut meta = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            &format!("leaf {id}"),
+            vec![],
+            vec![],
+        );
+        let content = Variant::new(
+            VariantMetadata::new("default", "text/plain", text.len() as _),
+            Box::new(async_std::io::Cursor::new(text.as_bytes().to_vec())),
+        );
+        manager.create(&mut meta, Some(content)).await.unwrap();
+    }
+    assert!(manager.verify(&1.into()).await.unwrap().is_empty());
+    assert!(manager.verify_all(None).await.unwrap().is_empty());
+
+    // Corrupt a variant and lose another one.
+    let native_path = |id: i32| {
+        let manager = &manager;
+        async move {
+            manager
+                .get_native_path(&id.into(), "default")
+                .await
+                .unwrap()
+        }
+    };
+    let (path_1, path_2) = (native_path(1).await, native_path(2).await);
+    fs::write(&path_1, b"frist").await.unwrap();
+    fs::remove_file(&path_2).await.unwrap();
+
+    let corrupted = manager.verify(&1.into()).await.unwrap();
+    assert_eq!(corrupted.len(), 1);
+    assert_eq!(corrupted[0].variant, "default");
+    assert!(corrupted[0].actual.is_some());
+    assert_ne!(corrupted[0].actual, Some(corrupted[0].expected.clone()));
+
+    let mut calls = 0;
+    let mut sink = |_done: usize, _total: usize, _id: &ResourceId| calls += 1;
+    let corrupted = manager.verify_all(Some(&mut sink)).await.unwrap();
+    assert_eq!(calls, 3);
+    let ids: Vec<ResourceId> = corrupted.iter().map(|item| item.id.clone()).collect();
+    assert_eq!(ids, vec![1.into(), 2.into()]);
+    assert_eq!(corrupted[1].actual, None);
+}
+
+#[async_std::test]
+async fn whois() {
+    let (config, store) = prepare_test(75).await;
+    let meta_path = store.metadata_path(&5.into());
+    let variant_path = store.variant_path(&5.into(), "default");
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.set_index_storage_names(true);
+    create_hierarchy(&mut manager).await;
+
+    let owners = manager.whois(&meta_path.to_string_lossy()).await.unwrap();
+    assert_eq!(
+        owners,
+        vec![StorageOwner {
+            id: 5.into(),
+            variant: None
+        }]
+    );
+    let file_name = variant_path.file_name().unwrap().to_string_lossy();
+    let owners = manager.whois(&file_name).await.unwrap();
+    assert_eq!(owners[0].variant.as_deref(), Some("default"));
+    assert!(manager.whois("unknown").await.unwrap().is_empty());
+
+    // All the leaves have the same content, stored once. The content of containers
+    // is their children list, which is not shared.
+    let hash = manager.get_metadata(&5.into()).await.unwrap().variants()[0]
+        .hash()
+        .unwrap();
+    let blob_name = DefaultResourceNameProvider.blob_name(&hash);
+    assert_eq!(manager.whois(&blob_name).await.unwrap().len(), 19);
+
+    manager.delete(&5.into()).await.unwrap();
+    assert!(manager
+        .whois(&meta_path.to_string_lossy())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(manager.whois(&blob_name).await.unwrap().len(), 18);
+
+    // Rebuilding the index.
+    manager.set_index_storage_names(false);
+    manager.delete(&6.into()).await.unwrap();
+    manager
+        .update_variant(&7.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    // The new thumbnail variant is not recorded.
+    assert_eq!(manager.whois(&blob_name).await.unwrap().len(), 17);
+    assert_eq!(manager.rebuild_storage_names().await.unwrap(), 20);
+    assert_eq!(manager.whois(&blob_name).await.unwrap().len(), 18);
+}
+
+#[async_std::test]
+async fn derived_variants_budget() {
+    let (config, store) = prepare_test(76).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let thumbnail = |id: i32| DerivedVariant {
+        id: id.into(),
+        variant: "thumbnail".into(),
+        source: "default".into(),
+        transformer: "thumbnailer".into(),
+        version: 1,
+    };
+    for id in [5, 6, 7] {
+        manager
+            .update_variant(&id.into(), named_content("thumbnail").await)
+            .await
+            .unwrap();
+        manager
+            .record_derived_variant(&thumbnail(id))
+            .await
+            .unwrap();
+    }
+    // 6 is the most frecent resource, then 7.
+    for id in [6, 6, 7] {
+        manager
+            .visit(&id.into(), &VisitEntry::now(VisitPriority::High))
+            .await
+            .unwrap();
+    }
+    assert_eq!(manager.derived_variants_size().await.unwrap(), 3 * 42);
+
+    // Shrinking the budget evicts the thumbnail of the least frecent resource.
+    let evicted = manager
+        .set_derived_variants_budget(Some(2 * 42))
+        .await
+        .unwrap();
+    assert_eq!(evicted, vec![(5.into(), "thumbnail".to_string())]);
+    assert_eq!(manager.derived_variants_size().await.unwrap(), 2 * 42);
+    assert!(matches!(
+        manager.get_leaf(&5.into(), "thumbnail").await,
+        Err(ResourceStoreError::EvictedVariant(variant)) if variant == "thumbnail"
+    ));
+    assert_eq!(
+        manager
+            .evicted_variant(&5.into(), "thumbnail")
+            .await
+            .unwrap(),
+        Some(thumbnail(5))
+    );
+    assert!(manager
+        .evicted_variant(&6.into(), "thumbnail")
+        .await
+        .unwrap()
+        .is_none());
+
+    // Evicted variants are not stale, even if their source changes.
+    manager
+        .update_variant(&5.into(), default_content().await)
+        .await
+        .unwrap();
+    assert!(manager.stale_variants().await.unwrap().is_empty());
+
+    // Regenerating it evicts the next least frecent thumbnail instead.
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager.record_derived_variant(&thumbnail(5)).await.unwrap();
+    assert!(manager
+        .evicted_variant(&5.into(), "thumbnail")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        manager
+            .evicted_variant(&7.into(), "thumbnail")
+            .await
+            .unwrap(),
+        Some(thumbnail(7))
+    );
+    manager.get_leaf(&5.into(), "thumbnail").await.unwrap();
+    assert_eq!(manager.derived_variants_size().await.unwrap(), 2 * 42);
+
+    // Callers restricted to a container can evict variants outside of it.
+    let scoped = manager
+        .issue_capability(&10.into(), costaeres::capability::Operations::ALL, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&scoped.token));
+    manager
+        .update_variant(&25.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager
+        .record_derived_variant(&thumbnail(25))
+        .await
+        .unwrap();
+    manager.set_capability_context(None);
+    assert_eq!(
+        manager
+            .evicted_variant(&5.into(), "thumbnail")
+            .await
+            .unwrap(),
+        Some(thumbnail(5))
+    );
+
+    // Removing the budget doesn't evict anything.
+    assert!(manager
+        .set_derived_variants_budget(None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Variants required in the container are not evicted.
+    manager
+        .set_required_variants(&1.into(), &["default", "thumbnail"])
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.set_derived_variants_budget(Some(42)).await.unwrap(),
+        vec![(25.into(), "thumbnail".to_string())]
+    );
+    manager.get_leaf(&6.into(), "thumbnail").await.unwrap();
+}
+
+#[async_std::test]
+async fn clean_shutdown() {
+    let (config, store) = prepare_test(77).await;
+
+    let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+        .await
+        .unwrap();
+    assert!(!manager.unclean_shutdown());
+    create_hierarchy(&mut manager).await;
+    // Only closing the database is not a clean shutdown.
+    manager.close().await;
+
+    let reopen = || async {
+        let store = FileStore::new(
+            "./test-content/77",
+            Box::new(DefaultResourceNameProvider),
+            Box::new(IdentityTransformer),
+        )
+        .await
+        .unwrap();
+        Manager::<()>::new(config.clone(), Box::new(store))
+            .await
+            .unwrap()
+    };
+
+    let mut manager = reopen().await;
+    assert!(manager.unclean_shutdown());
+    let diagnostics = manager.diagnostics().await.unwrap();
+    assert!(diagnostics.unclean_shutdown);
+    assert!(diagnostics.problems.is_empty());
+    manager.shutdown().await.unwrap();
+
+    let manager = reopen().await;
+    assert!(!manager.unclean_shutdown());
+    assert_eq!(manager.resource_count().await.unwrap(), 22);
+}
+
+#[async_std::test]
+async fn required_variants() {
+    let (config, store) = prepare_test(78).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    assert_eq!(
+        manager.set_required_variants(&5.into(), &["default"]).await,
+        Err(ResourceStoreError::InvalidContainerId)
+    );
+    manager
+        .set_required_variants(&10.into(), &["thumbnail", "default"])
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.required_variants(&10.into()).await.unwrap(),
+        vec!["default".to_string(), "thumbnail".to_string()]
+    );
+
+    // All the children lack a thumbnail.
+    let children = manager.non_conforming_children(&10.into()).await.unwrap();
+    assert_eq!(children.len(), 10);
+    assert_eq!(
+        children[0],
+        NonConformingChild {
+            id: 25.into(),
+            missing: vec!["thumbnail".into()]
+        }
+    );
+    for i in 26..35 {
+        manager
+            .update_variant(&i.into(), named_content("thumbnail").await)
+            .await
+            .unwrap();
+    }
+    let children = manager.non_conforming_children(&10.into()).await.unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].id, 25.into());
+
+    // Required variants can't be deleted.
+    assert_eq!(
+        manager.delete_variant(&26.into(), "thumbnail").await,
+        Err(ResourceStoreError::RequiredVariant("thumbnail".into()))
+    );
+
+    // Leaves need the required variants to be moved or copied in the container.
+    assert_eq!(
+        manager.move_resource(&5.into(), &10.into()).await,
+        Err(ResourceStoreError::MissingVariants(
+            vec!["thumbnail".into()]
+        ))
+    );
+    assert_eq!(
+        manager.copy_resource(&5.into(), &10.into()).await,
+        Err(ResourceStoreError::MissingVariants(
+            vec!["thumbnail".into()]
+        ))
+    );
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager.move_resource(&5.into(), &10.into()).await.unwrap();
+
+    // New leaves need them too.
+    let leaf = |variants: Vec<VariantMetadata>| {
+        ResourceMetadata::new(
+            &40.into(),
+            &10.into(),
+            ResourceKind::Leaf,
+            "new child",
+            vec![],
+            variants,
+        )
+    };
+    assert_eq!(
+        manager
+            .create(&mut leaf(vec![]), Some(default_content().await))
+            .await,
+        Err(ResourceStoreError::MissingVariants(
+            vec!["thumbnail".into()]
+        ))
+    );
+    assert_eq!(
+        manager
+            .create_batch(vec![(leaf(vec![]), Some(default_content().await))])
+            .await
+            .unwrap(),
+        vec![Err(ResourceStoreError::MissingVariants(vec![
+            "thumbnail".into()
+        ]))]
+    );
+    assert!(!manager.has_object(&40.into()).await.unwrap());
+    manager
+        .create_with_variants(
+            &mut leaf(vec![]),
+            vec![default_content().await, named_content("thumbnail").await],
+        )
+        .await
+        .unwrap();
+    // Other containers have no requirement.
+    manager.delete_variant(&6.into(), "default").await.unwrap();
+
+    // Removing the requirement.
+    manager
+        .set_required_variants(&10.into(), &[])
+        .await
+        .unwrap();
+    assert!(manager
+        .non_conforming_children(&10.into())
+        .await
+        .unwrap()
+        .is_empty());
+    manager
+        .delete_variant(&26.into(), "thumbnail")
+        .await
+        .unwrap();
+
+    // Requirements are removed with their container.
+    manager
+        .set_required_variants(&10.into(), &["default"])
+        .await
+        .unwrap();
+    manager.delete(&10.into()).await.unwrap();
+    assert!(manager
+        .required_variants(&10.into())
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[async_std::test]
+async fn update_variant_metadata() {
+    let (config, store) = prepare_test(79).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    let before = manager.get_metadata(&5.into()).await.unwrap();
+
+    let updated = manager
+        .update_variant_metadata(
+            &5.into(),
+            VariantMetadata::new("default", "text/plain", 100),
+        )
+        .await
+        .unwrap();
+    assert_eq!(updated, manager.get_metadata(&5.into()).await.unwrap());
+    // This is a modification like any other.
+    assert_eq!(updated.rev(), before.rev() + 1);
+    assert!(*updated.modified() >= *before.modified());
+    // The order of the variants is preserved.
+    assert_eq!(updated.variants()[0].name(), "default");
+    assert_eq!(updated.variants()[0].mime_type(), "text/plain");
+    assert_eq!(updated.variants()[0].size(), 100);
+    assert_eq!(updated.variants()[0].hash(), before.variants()[0].hash());
+    assert_eq!(updated.variants()[1], before.variants()[1]);
+
+    // The stored metadata is updated too.
+    let store = FileStore::new(
+        "./test-content/79",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    assert_eq!(store.get_metadata(&5.into()).await.unwrap(), updated);
+
+    assert_eq!(
+        manager
+            .update_variant_metadata(&5.into(), VariantMetadata::new("unknown", "text/plain", 1))
+            .await,
+        Err(ResourceStoreError::InvalidVariant("unknown".into()))
+    );
+
+    // Updates based on the previous revision conflict.
+    assert_eq!(
+        manager
+            .update_variant_at_rev(&5.into(), default_content().await, before.rev())
+            .await,
+        Err(ResourceStoreError::Conflict)
+    );
+}
+
+#[async_std::test]
+async fn update_preserves_created_and_scorer() {
+    let (config, store) = prepare_test(80).await;
+
+    let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+        .await
+        .unwrap();
+    create_hierarchy(&mut manager).await;
+    for _ in 0..3 {
+        manager
+            .visit(&5.into(), &VisitEntry::now(VisitPriority::High))
+            .await
+            .unwrap();
+    }
+    let before = manager.get_metadata(&5.into()).await.unwrap();
+    assert!(before.frecency() > 0);
+
+    async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+    manager
+        .update_variant(&5.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+    manager
+        .update_variant(&5.into(), default_content().await)
+        .await
+        .unwrap();
+
+    // Read the resource from the database, bypassing the metadata cache.
+    let store = FileStore::new(
+        "./test-content/80",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let mut other = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    let after = other.get_metadata(&5.into()).await.unwrap();
+    assert_eq!(after.created(), before.created());
+    assert_eq!(after.frecency(), before.frecency());
+    assert_ne!(after.modified(), before.modified());
+    assert_eq!(after.rev(), before.rev() + 2);
+    assert_eq!(after.variants().len(), 2);
+    assert_eq!(after.tags(), before.tags());
+    assert_eq!(
+        other.top_by_frecency(None, 1).await.unwrap(),
+        vec![IdFrec::new(&5.into(), before.frecency())]
+    );
+
+    // The children of the parent and the text index are still correct.
+    assert_eq!(other.get_container(&1.into()).await.unwrap().1.len(), 10);
+    assert_eq!(
+        other.by_text("child #5", None).await.unwrap()[0].id,
+        5.into()
+    );
+}
+
+#[async_std::test]
+async fn recently_visited() {
+    let (config, store) = prepare_test(81).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    assert!(manager.recently_visited(10).await.unwrap().is_empty());
+
+    // 7 is the most frecent resource, but was visited first.
+    let now = Utc::now();
+    for minutes in 120..125 {
+        manager
+            .visit(
+                &7.into(),
+                &VisitEntry::new(
+                    &(now - chrono::Duration::minutes(minutes)),
+                    VisitPriority::VeryHigh,
+                ),
+            )
+            .await
+            .unwrap();
+    }
+    manager
+        .visit(
+            &6.into(),
+            &VisitEntry::new(
+                &(now - chrono::Duration::minutes(60)),
+                VisitPriority::Normal,
+            ),
+        )
+        .await
+        .unwrap();
+    manager
+        .visit(&5.into(), &VisitEntry::new(&now, VisitPriority::Normal))
+        .await
+        .unwrap();
+
+    let ids = |results: Vec<IdFrec>| results.into_iter().map(|r| r.id).collect::<Vec<_>>();
+    assert_eq!(
+        manager.top_by_frecency(None, 1).await.unwrap()[0].id,
+        7.into()
+    );
+    assert_eq!(
+        ids(manager.recently_visited(10).await.unwrap()),
+        vec![5.into(), 6.into(), 7.into()]
+    );
+    assert_eq!(
+        ids(manager.recently_visited(1).await.unwrap()),
+        vec![5.into()]
+    );
+
+    // Modifications don't count as visits.
+    manager
+        .update_variant(&6.into(), default_content().await)
+        .await
+        .unwrap();
+    assert_eq!(
+        ids(manager.recently_visited(10).await.unwrap()),
+        vec![5.into(), 6.into(), 7.into()]
+    );
+
+    // Merged visits count if they are more recent.
+    let old = VisitEntry::new(&(now - chrono::Duration::days(1)), VisitPriority::Normal);
+    manager.merge_visits(&6.into(), &[old]).await.unwrap();
+    let recent = VisitEntry::new(&(now + chrono::Duration::minutes(1)), VisitPriority::Normal);
+    manager.merge_visits(&7.into(), &[recent]).await.unwrap();
+    assert_eq!(
+        ids(manager.recently_visited(10).await.unwrap()),
+        vec![7.into(), 5.into(), 6.into()]
+    );
+
+    assert_eq!(
+        manager.recently_visited(0).await,
+        Err(ResourceStoreError::Custom("ZeroCountQuery".into()))
+    );
+}
+
+#[async_std::test]
+async fn create_with_variants() {
+    let (config, store) = prepare_test(82).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+
+    let mut leaf = ResourceMetadata::new(
+        &1.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "photo",
+        vec![],
+        vec![],
+    );
+    manager
+        .create_with_variants(
+            &mut leaf,
+            vec![default_content().await, named_content("thumbnail").await],
+        )
+        .await
+        .unwrap();
+
+    let meta = manager.get_metadata(&1.into()).await.unwrap();
+    assert_eq!(meta, leaf);
+    assert_eq!(meta.rev(), 1);
+    let names: Vec<String> = meta.variants().iter().map(|v| v.name()).collect();
+    assert_eq!(names, vec!["default", "thumbnail"]);
+    assert!(meta.variants().iter().all(|v| v.hash().is_some()));
+    manager.get_leaf(&1.into(), "default").await.unwrap();
+    manager.get_leaf(&1.into(), "thumbnail").await.unwrap();
+    assert_eq!(
+        manager.indexing_status(&1.into()).await.unwrap(),
+        IndexingStatus::Indexed
+    );
+
+    // Variant names must be unique.
+    let mut other = ResourceMetadata::new(
+        &2.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "other",
+        vec![],
+        vec![],
+    );
+    assert_eq!(
+        manager
+            .create_with_variants(
+                &mut other,
+                vec![default_content().await, default_content().await],
+            )
+            .await,
+        Err(ResourceStoreError::InvalidVariant("default".into()))
+    );
+    assert!(!manager.has_object(&2.into()).await.unwrap());
+}
+
+#[async_std::test]
+async fn recompute_frecencies() {
+    let (config, store) = prepare_test(83).await;
+    let db_path = config.db_path.clone();
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager
+        .visit(&5.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+    manager
+        .visit(&6.into(), &VisitEntry::now(VisitPriority::VeryHigh))
+        .await
+        .unwrap();
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(top[0].id, 6.into());
+    assert_eq!(top[1].id, 5.into());
+    assert_eq!(
+        top,
+        manager.refresh_scores(&[6.into(), 5.into()]).await.unwrap()
+    );
+    // Nothing to update yet.
+    assert_eq!(manager.recompute_frecencies().await.unwrap(), 0);
+
+    // Make the stored frecencies outdated.
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{db_path}"))
+        .await
+        .unwrap();
+    sqlx::query("UPDATE resources SET frecency = 0")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(manager
+        .top_by_frecency(None, 2)
+        .await
+        .unwrap()
+        .iter()
+        .all(|item| item.frecency == 0));
+
+    assert_eq!(manager.recompute_frecencies().await.unwrap(), 2);
+    assert_eq!(manager.top_by_frecency(None, 2).await.unwrap(), top);
+}
+
+#[async_std::test]
+async fn get_best_variant() {
+    let (config, store) = prepare_test(84).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager
+        .update_variant(&6.into(), named_content("thumbnail").await)
+        .await
+        .unwrap();
+
+    let preferences = ["preview", "thumbnail", "default"];
+    let (name, meta, _) = manager
+        .get_best_variant(&5.into(), &preferences)
+        .await
+        .unwrap();
+    assert_eq!(name, "default");
+    assert_eq!(meta.id(), 5.into());
+    let (name, _, _) = manager
+        .get_best_variant(&6.into(), &preferences)
+        .await
+        .unwrap();
+    assert_eq!(name, "thumbnail");
+
+    assert!(matches!(
+        manager.get_best_variant(&5.into(), &["preview", "icon"]).await,
+        Err(ResourceStoreError::InvalidVariant(names)) if names == "preview, icon"
+    ));
+    // Containers have no readable variants.
+    assert!(matches!(
+        manager.get_best_variant(&10.into(), &preferences).await,
+        Err(ResourceStoreError::NoSuchResource)
+    ));
+}
+
+#[async_std::test]
+async fn content_url() {
+    let (config, mut store) = prepare_test(85).await;
+    let ttl = std::time::Duration::from_secs(60);
+
+    store.set_content_base_url(Some("http://127.0.0.1:8080/cmgr/key/"));
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    assert_eq!(
+        manager
+            .content_url(&5.into(), "default", ttl)
+            .await
+            .unwrap(),
+        Some("http://127.0.0.1:8080/cmgr/key/id-5/default".into())
+    );
+    assert!(matches!(
+        manager.content_url(&5.into(), "thumbnail", ttl).await,
+        Err(ResourceStoreError::InvalidVariant(_))
+    ));
+    assert!(matches!(
+        manager.content_url(&10.into(), "default", ttl).await,
+        Err(ResourceStoreError::NoSuchResource)
+    ));
+
+    // Direct urls would bypass the read filter.
+    manager.set_read_filter(Some(Box::new(costaeres::read_filter::TagMimeFilter::new(
+        &["sub-child"],
+        &[],
+    ))));
+    assert_eq!(
+        manager
+            .content_url(&5.into(), "default", ttl)
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[cfg(feature = "transformers")]
+#[async_std::test]
+async fn share_qr_code() {
+    use async_std::io::ReadExt;
+
+    let (config, mut store) = prepare_test(134).await;
+    let ttl = std::time::Duration::from_secs(60);
+
+    store.set_content_base_url(Some("http://127.0.0.1:8080/cmgr/key/"));
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let variant = manager
+        .share_qr_code(&5.into(), "default", ttl)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(variant.metadata.name(), "qr-code");
+    assert_eq!(variant.metadata.mime_type(), "image/svg+xml");
+    let mut content = String::new();
+    let mut reader = variant.reader;
+    reader.read_to_string(&mut content).await.unwrap();
+    assert!(content.contains("<svg"));
+    assert_eq!(variant.metadata.size() as usize, content.len());
+    assert!(matches!(
+        manager.share_qr_code(&5.into(), "thumbnail", ttl).await,
+        Err(ResourceStoreError::InvalidVariant(_))
+    ));
+
+    // No content url, no QR code.
+    manager.set_read_filter(Some(Box::new(costaeres::read_filter::TagMimeFilter::new(
+        &["sub-child"],
+        &[],
+    ))));
+    assert!(manager
+        .share_qr_code(&5.into(), "default", ttl)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[async_std::test]
+async fn visits() {
+    let (config, store) = prepare_test(86).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    assert!(manager.visits(&5.into()).await.unwrap().is_empty());
+
+    let first = VisitEntry::new(
+        &(Utc::now() - chrono::Duration::days(2)),
+        VisitPriority::Normal,
+    );
+    let second = VisitEntry::now(VisitPriority::High);
+    manager.visit(&5.into(), &first).await.unwrap();
+    manager.visit(&5.into(), &second).await.unwrap();
+
+    // Oldest first.
+    let visits = manager.visits(&5.into()).await.unwrap();
+    let timestamps: Vec<i64> = visits.iter().map(|visit| visit.timestamp).collect();
+    assert_eq!(timestamps, vec![first.timestamp, second.timestamp]);
+    assert!(matches!(visits[1].priority, VisitPriority::High));
+
+    // Only the last visits are kept.
+    for _ in 0..12 {
+        manager
+            .visit(&5.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await
+            .unwrap();
+    }
+    assert_eq!(manager.visits(&5.into()).await.unwrap().len(), 10);
+    let meta = manager.get_metadata(&5.into()).await.unwrap();
+    assert_eq!(meta.scorer().all_time_visits(), 14);
+
+    assert!(matches!(
+        manager.visits(&1000.into()).await,
+        Err(ResourceStoreError::NoSuchResource)
+    ));
+}
+
+#[async_std::test]
+async fn system_containers() {
+    let (config, store) = prepare_test(87).await;
+
+    let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+        .await
+        .unwrap();
+    manager.create_root().await.unwrap();
+
+    // An existing container with the expected name is adopted.
+    let camera: ResourceId = 1.into();
+    let mut container = ResourceMetadata::new(
+        &camera,
+        &ROOT_ID,
+        ResourceKind::Container,
+        "Camera",
+        vec![],
+        vec![default_variant()],
+    );
+    manager.create(&mut container, None).await.unwrap();
+
+    let kinds = [
+        SystemContainer::Downloads,
+        SystemContainer::Screenshots,
+        SystemContainer::Camera,
+    ];
+    let ids = manager.declare_system_containers(&kinds).await.unwrap();
+    assert_eq!(ids[2], camera);
+    assert_eq!(manager.get_root().await.unwrap().1.len(), 3);
+    let downloads = manager.get_metadata(&ids[0]).await.unwrap();
+    assert_eq!(downloads.name(), "Downloads");
+    assert_eq!(downloads.kind(), ResourceKind::Container);
+
+    // Other managers of the same database get the same containers, even once renamed.
+    manager
+        .rename_resource(&ids[1], "Captures d'écran")
+        .await
+        .unwrap();
+    let store = FileStore::new(
+        "./test-content/87",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let mut other = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    assert_eq!(other.declare_system_containers(&kinds).await.unwrap(), ids);
+    assert_eq!(manager.get_root().await.unwrap().1.len(), 3);
+
+    // Deleted containers are recreated.
+    manager.delete(&ids[0]).await.unwrap();
+    let downloads = manager
+        .system_container(SystemContainer::Downloads)
+        .await
+        .unwrap();
+    assert_ne!(downloads, ids[0]);
+    assert_eq!(
+        manager.get_metadata(&downloads).await.unwrap().name(),
+        "Downloads"
+    );
+}
+
+#[async_std::test]
+async fn import_visits() {
+    use chrono::TimeZone;
+
+    let (config, store) = prepare_test(88).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let modified = *manager.get_metadata(&5.into()).await.unwrap().modified();
+
+    // Import visits in any order, more than the sampled ones.
+    let now = Utc::now();
+    let entries: Vec<VisitEntry> = (0..15)
+        .rev()
+        .map(|days| VisitEntry::new(&(now - chrono::Duration::days(days)), VisitPriority::Normal))
+        .collect();
+    manager.import_visits(&5.into(), &entries).await.unwrap();
+    manager.import_visits(&6.into(), &[]).await.unwrap();
+
+    let meta = manager.get_metadata(&5.into()).await.unwrap();
+    assert_eq!(*meta.modified(), modified);
+    assert_eq!(meta.scorer().all_time_visits(), 15);
+    // The most recent visits are kept, oldest first.
+    let visits = manager.visits(&5.into()).await.unwrap();
+    assert_eq!(visits.len(), 10);
+    assert_eq!(visits[9].timestamp, entries[14].timestamp);
+    assert_eq!(visits[0].timestamp, entries[5].timestamp);
+
+    let top = manager.top_by_frecency(None, 1).await.unwrap();
+    assert_eq!(top, vec![IdFrec::new(&5.into(), meta.frecency())]);
+    assert_eq!(manager.recently_visited(1).await.unwrap()[0].id, 5.into());
+    assert!(manager.visits(&6.into()).await.unwrap().is_empty());
+
+    // Large histories are inserted in several batches.
+    let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+    let entries: Vec<VisitEntry> = (0..1200)
+        .map(|minutes| {
+            VisitEntry::new(
+                &(start + chrono::Duration::minutes(minutes)),
+                VisitPriority::Normal,
+            )
+        })
+        .collect();
+    manager.import_visits(&7.into(), &entries).await.unwrap();
+    let daily = manager
+        .visits_timeline(
+            start..start + chrono::Duration::days(1),
+            TimeBucket::Day,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(daily.len(), 1);
+    assert_eq!(daily[0].visits, 1200);
+}
+
+#[async_std::test]
+async fn hidden_resources() {
+    let (config, store) = prepare_test(89).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    // A sidecar file next to child #5.
+    let mut sidecar = ResourceMetadata::new(
+        &50.into(),
+        &1.into(),
+        ResourceKind::Leaf,
+        ".sidecar",
+        vec!["holidays".into()],
+        vec![default_variant()],
+    );
+    manager
+        .create(&mut sidecar, Some(default_content().await))
+        .await
+        .unwrap();
+    assert!(sidecar.is_hidden());
+
+    // Hidden resources are not listed nor found by searches.
+    assert_eq!(manager.get_container(&1.into()).await.unwrap().1.len(), 10);
+    let (_, sorted) = manager
+        .get_container_sorted(&1.into(), SortOrder::new(SortKey::Name), Page::all())
+        .await
+        .unwrap();
+    assert_eq!(sorted.len(), 10);
+    let page = manager.children_page(&1.into(), None, 20).await.unwrap();
+    assert_eq!(page.children.len(), 10);
+    assert!(manager.by_tag("holidays").await.unwrap().is_empty());
+    assert!(manager.by_name(".sidecar", None).await.unwrap().is_empty());
+    assert!(manager.by_text("sidecar", None).await.unwrap().is_empty());
+    manager
+        .visit(&50.into(), &VisitEntry::now(VisitPriority::VeryHigh))
+        .await
+        .unwrap();
+    assert_ne!(
+        manager.top_by_frecency(None, 1).await.unwrap()[0].id,
+        50.into()
+    );
+    assert!(manager
+        .top_by_frecency(Some("holidays".into()), 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_ne!(
+        manager.last_modified(None, 1).await.unwrap()[0].id,
+        50.into()
+    );
+    assert!(manager
+        .last_modified(Some("holidays".into()), 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(manager.recently_visited(10).await.unwrap().is_empty());
+    assert!(!manager
+        .related(&5.into(), 100)
+        .await
+        .unwrap()
+        .contains(&50.into()));
+    assert!(!manager
+        .prefetch_candidates(&5.into(), 100)
+        .await
+        .unwrap()
+        .contains(&50.into()));
+
+    // They can still be accessed directly.
+    manager.get_leaf(&50.into(), "default").await.unwrap();
+    assert_eq!(
+        manager
+            .child_by_name(&1.into(), ".sidecar")
+            .await
+            .unwrap()
+            .id(),
+        50.into()
+    );
+
+    manager.set_visibility(Visibility::All);
+    assert_eq!(manager.get_container(&1.into()).await.unwrap().1.len(), 11);
+    let (_, sorted) = manager
+        .get_container_sorted(&1.into(), SortOrder::new(SortKey::Name), Page::all())
+        .await
+        .unwrap();
+    assert_eq!(sorted[0].id(), 50.into());
+    let page = manager.children_page(&1.into(), None, 20).await.unwrap();
+    assert_eq!(page.children.len(), 11);
+    assert_eq!(manager.by_tag("holidays").await.unwrap(), vec![50.into()]);
+    assert_eq!(
+        manager.by_name(".sidecar", None).await.unwrap(),
+        vec![50.into()]
+    );
+    assert_eq!(
+        manager.by_text("sidecar", None).await.unwrap()[0].id,
+        50.into()
+    );
+    assert_eq!(
+        manager.top_by_frecency(None, 1).await.unwrap()[0].id,
+        50.into()
+    );
+    assert_eq!(
+        manager.last_modified(None, 1).await.unwrap()[0].id,
+        50.into()
+    );
+    assert_eq!(manager.recently_visited(10).await.unwrap()[0].id, 50.into());
+    assert!(manager
+        .related(&5.into(), 100)
+        .await
+        .unwrap()
+        .contains(&50.into()));
+    assert!(manager
+        .prefetch_candidates(&5.into(), 100)
+        .await
+        .unwrap()
+        .contains(&50.into()));
+
+    // Or only them.
+    manager.set_visibility(Visibility::Hidden);
+    let (_, children) = manager.get_container(&1.into()).await.unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].id(), 50.into());
+    let page = manager.children_page(&1.into(), None, 20).await.unwrap();
+    assert_eq!(page.children.len(), 1);
+    assert!(manager.by_name("child #5", None).await.unwrap().is_empty());
+    assert!(manager.by_text("child", None).await.unwrap().is_empty());
+    let top = manager.top_by_frecency(None, 10).await.unwrap();
+    assert_eq!(top.len(), 1);
+    assert_eq!(top[0].id, 50.into());
+    assert_eq!(
+        manager
+            .top_by_frecency(Some("holidays".into()), 10)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[async_std::test]
+async fn text_search_substrings() {
+    let (config, store) = prepare_test(90).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    // Terms shorter than a trigram still match.
+    let results = manager.by_text("#5", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, 5.into());
+
+    // Substrings spanning several words.
+    assert_eq!(manager.by_text("ild #2", None).await.unwrap().len(), 5);
+    manager.delete(&25.into()).await.unwrap();
+    assert_eq!(manager.by_text("ild #2", None).await.unwrap().len(), 4);
+    assert_eq!(manager.by_text("ild #2 -#29", None).await.unwrap().len(), 3);
+}
+
+#[async_std::test]
+async fn text_search_long_terms() {
+    let (config, store) = prepare_test(91).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    let mut leaf = ResourceMetadata::new(
+        &1.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "Freediving in Apnéa",
+        vec![],
+        vec![default_variant()],
+    );
+    manager
+        .create(&mut leaf, Some(default_content().await))
+        .await
+        .unwrap();
+
+    // Terms are not truncated: prefixes and whole words match, longer terms don't.
+    for text in ["free", "freediv", "freediving", "FreeDiving apnea"] {
+        assert_eq!(
+            manager.by_text(text, None).await.unwrap().len(),
+            1,
+            "{}",
+            text
+        );
+    }
+    for text in ["freedivings", "freediving apneas"] {
+        assert!(
+            manager.by_text(text, None).await.unwrap().is_empty(),
+            "{}",
+            text
+        );
+    }
+}
+
+#[async_std::test]
+async fn sidecars() {
+    let (config, store) = prepare_test(92).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    let usage = manager.quota_usage().await.unwrap();
+
+    manager
+        .set_sidecar(
+            &25.into(),
+            "notes",
+            named_content("annotations").await,
+            SidecarOptions::default(),
+        )
+        .await
+        .unwrap();
+    let private = SidecarOptions {
+        exclude_from_quota: true,
+        exclude_from_sync: true,
+    };
+    manager
+        .set_sidecar(&25.into(), "notes", named_content("cursor").await, private)
+        .await
+        .unwrap();
+    manager
+        .set_sidecar(
+            &25.into(),
+            "reader",
+            named_content("annotations").await,
+            SidecarOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    // Sidecars are namespaced per application.
+    let meta = manager.get_metadata(&25.into()).await.unwrap();
+    assert!(meta.has_variant("app.notes.annotations"));
+    assert!(meta.has_variant("app.reader.annotations"));
+    assert_eq!(meta.variants()[1].sidecar_app(), Some("notes".to_owned()));
+    assert_eq!(meta.variants()[0].sidecar_app(), None);
+    assert_eq!(
+        manager.sidecars(&25.into(), "notes").await.unwrap(),
+        vec!["annotations".to_owned(), "cursor".to_owned()]
+    );
+    manager
+        .get_sidecar(&25.into(), "reader", "annotations")
+        .await
+        .unwrap();
+    assert!(manager
+        .get_sidecar(&25.into(), "reader", "cursor")
+        .await
+        .is_err());
+    assert!(matches!(
+        manager
+            .set_sidecar(
+                &25.into(),
+                "bad.app",
+                named_content("state").await,
+                SidecarOptions::default()
+            )
+            .await,
+        Err(ResourceStoreError::InvalidVariant(_))
+    ));
+
+    // The private sidecar is not counted, nor exported.
+    assert_eq!(manager.quota_usage().await.unwrap(), usage + 2 * 42);
+    let mut archive = vec![];
+    manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+    manager
+        .import_archive(&ROOT_ID, &mut async_std::io::Cursor::new(&archive))
+        .await
+        .unwrap();
+    let imported = manager.child_by_name(&ROOT_ID, "child #10").await.unwrap();
+    let leaf = manager
+        .child_by_name(&imported.id(), "child #25")
+        .await
+        .unwrap();
+    assert_eq!(leaf.variants().len(), 3);
+    assert!(!leaf.has_variant("app.notes.cursor"));
+
+    manager
+        .delete_sidecar(&25.into(), "notes", "cursor")
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.sidecars(&25.into(), "notes").await.unwrap(),
+        vec!["annotations".to_owned()]
+    );
+}
+
+#[derive(Default)]
+struct Recorder {
+    events: Vec<ResourceModification>,
+}
+
+impl ModificationObserver for Recorder {
+    type Inner = Vec<ResourceModification>;
+
+    fn modified(&mut self, modification: &ResourceModification) {
+        self.events.push(modification.clone());
+    }
+
+    fn get_inner(&mut self) -> &mut Self::Inner {
+        &mut self.events
+    }
+}
+
+fn recorded_events(
+    manager: &mut Manager<Vec<ResourceModification>>,
+    observer: usize,
+) -> Vec<ResourceModification> {
+    let mut events = vec![];
+    manager.with_observer(observer, &mut |recorder| {
+        events = recorder.get_inner().clone();
+    });
+    events
+}
+
+#[async_std::test]
+async fn coalesced_events() {
+    let (config, store) = prepare_test(93).await;
+
+    let mut manager = Manager::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let immediate = manager.add_observer(Box::<Recorder>::default());
+    let deduplicated =
+        manager.add_observer_with_coalescing(Box::<Recorder>::default(), Coalescing::Deduplicate);
+    let per_container =
+        manager.add_observer_with_coalescing(Box::<Recorder>::default(), Coalescing::PerContainer);
+
+    // Outside of batches, events are delivered right away.
+    manager
+        .visit(&5.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+    for observer in [immediate, deduplicated, per_container] {
+        assert_eq!(
+            recorded_events(&mut manager, observer),
+            vec![ResourceModification::Visited(5.into())]
+        );
+    }
+
+    manager.begin_batch();
+    manager.begin_batch();
+    for _ in 0..2 {
+        manager
+            .update_variant(&5.into(), named_content("thumbnail").await)
+            .await
+            .unwrap();
+    }
+    manager.delete(&6.into()).await.unwrap();
+    manager.end_batch();
+    // Events are only delivered at the end of the outermost batch.
+    assert_eq!(recorded_events(&mut manager, deduplicated).len(), 1);
+    manager.end_batch();
+
+    let mut expected: Vec<ResourceModification> = vec![];
+    for event in recorded_events(&mut manager, immediate) {
+        if !expected.contains(&event) {
+            expected.push(event);
+        }
+    }
+    assert!(recorded_events(&mut manager, immediate).len() > expected.len());
+    assert_eq!(recorded_events(&mut manager, deduplicated), expected);
+
+    let events = recorded_events(&mut manager, per_container);
+    assert!(events.contains(&ResourceModification::Modified(1.into())));
+    assert!(events.contains(&ResourceModification::Deleted(6.into())));
+    assert!(!events.iter().any(|event| matches!(
+        event,
+        ResourceModification::ChildCreated(_)
+            | ResourceModification::ChildModified(_)
+            | ResourceModification::ChildDeleted(_)
+    )));
+    assert!(events.len() < expected.len());
+
+    // Archive imports are batches.
+    let mut archive = vec![];
+    manager
+        .export_archive(&10.into(), &mut archive)
+        .await
+        .unwrap();
+    let before = recorded_events(&mut manager, per_container).len();
+    let immediate_before = recorded_events(&mut manager, immediate).len();
+    manager
+        .import_archive(&ROOT_ID, &mut async_std::io::Cursor::new(&archive))
+        .await
+        .unwrap();
+    let imported = &recorded_events(&mut manager, per_container)[before..];
+    let created = imported
+        .iter()
+        .filter(|event| matches!(event, ResourceModification::Created(_)))
+        .count();
+    assert_eq!(created, 11);
+    let immediate_count = recorded_events(&mut manager, immediate).len() - immediate_before;
+    assert!(immediate_count > imported.len());
+}
+
+#[async_std::test]
+async fn fuzzy_search() {
+    let (config, store) = prepare_test(94).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name, tags) in [
+        (1, "contact list", vec![]),
+        (2, "contat", vec![]),
+        (3, "holidays", vec!["contacts".to_owned()]),
+        (4, "recipes", vec![]),
+    ] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            tags,
+            vec![default_variant()],
+        );
+        manager
+            .create(&mut leaf, Some(default_content().await))
+            .await
+            .unwrap();
+    }
+
+    let ids = |results: Vec<IdFrec>| -> Vec<ResourceId> {
+        results.into_iter().map(|item| item.id).collect()
+    };
+    assert_eq!(
+        ids(manager.by_text("contat", None).await.unwrap()),
+        vec![2.into()]
+    );
+
+    // Exact matches come first.
+    manager.set_fuzzy_threshold(Some(0.75));
+    let results = ids(manager.by_text("contat", None).await.unwrap());
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0], 2.into());
+    assert!(results.contains(&1.into()));
+    assert!(results.contains(&3.into()));
+    assert_eq!(
+        manager
+            .by_text_paged("contat", None, None, Page::new(0, 1))
+            .await
+            .unwrap()
+            .total,
+        3
+    );
+
+    // Filters apply to fuzzy matches too.
+    assert_eq!(
+        ids(manager.by_text("contat -list", None).await.unwrap()),
+        vec![2.into(), 3.into()]
+    );
+    let groups = manager.by_text_grouped("contat", None, 5).await.unwrap();
+    assert_eq!(groups[0].count, 3);
+
+    // A higher threshold is stricter.
+    manager.set_fuzzy_threshold(Some(0.9));
+    assert_eq!(
+        ids(manager.by_text("contat", None).await.unwrap()),
+        vec![2.into()]
+    );
+    manager.set_fuzzy_threshold(None);
+    assert!(manager.by_text("contatc", None).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn search_snapshot() {
+    use costaeres::capability::Operations;
+    use costaeres::read_filter::TagMimeFilter;
+
+    let (config, store) = prepare_test(95).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let mut snapshot = manager.snapshot().await.unwrap();
+    let mut seen = vec![];
+    for offset in [0u32, 4, 8] {
+        let page = snapshot
+            .by_tag_paged("sub-child", None, Page::new(offset, 4))
+            .await
+            .unwrap();
+        assert_eq!(page.total, 10);
+        seen.extend(page.items);
+
+        // Modify the results while paging: writers are not blocked by the snapshot.
+        manager.delete(&(25 + offset as i32).into()).await.unwrap();
+        manager
+            .visit(
+                &(34 - offset as i32 / 4).into(),
+                &VisitEntry::now(VisitPriority::High),
+            )
+            .await
+            .unwrap();
+        let mut child = ResourceMetadata::new(
+            &(40 + offset as i32).into(),
+            &10.into(),
+            ResourceKind::Leaf,
+            &format!("child #{}", 40 + offset),
+            vec!["sub-child".into()],
+            vec![default_variant()],
+        );
+        manager
+            .create(&mut child, Some(default_content().await))
+            .await
+            .unwrap();
+    }
+    // No result was skipped nor duplicated.
+    assert_eq!(seen.len(), 10);
+    for id in 25..35 {
+        assert!(seen.contains(&id.into()));
+    }
+
+    let results = snapshot
+        .by_text_paged("child", Some("sub-child".into()), None, Page::new(0, 100))
+        .await
+        .unwrap();
+    assert_eq!(results.total, 10);
+    assert!(!results.items.iter().any(|item| item.id == 40.into()));
+    assert!(snapshot
+        .by_tag_paged(" ", None, Page::new(0, 4))
+        .await
+        .is_err());
+    snapshot.release().await.unwrap();
+
+    // Searches outside of the snapshot see the changes.
+    let live = manager.by_tag("sub-child").await.unwrap();
+    assert_eq!(live.len(), 10);
+    assert!(live.contains(&40.into()));
+    assert!(!live.contains(&25.into()));
+
+    // Snapshots apply the capability scope and the read filter of the caller.
+    manager.add_tag(&26.into(), "private").await.unwrap();
+    manager.set_read_filter(Some(Box::new(TagMimeFilter::new(&["private"], &[]))));
+    let capability = manager
+        .issue_capability(&10.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    let live = manager
+        .by_text_paged("child", None, None, Page::new(0, 100))
+        .await
+        .unwrap();
+    let mut snapshot = manager.snapshot().await.unwrap();
+    let results = snapshot
+        .by_text_paged("child", None, None, Page::new(0, 100))
+        .await
+        .unwrap();
+    assert_eq!(results, live);
+    assert_eq!(results.total, 9);
+    assert!(!results.items.iter().any(|item| item.id == 26.into()));
+    assert_eq!(
+        snapshot
+            .by_tag_paged("private", None, Page::new(0, 4))
+            .await
+            .unwrap()
+            .total,
+        0
+    );
+    assert_eq!(
+        snapshot
+            .by_text_paged("child", None, Some(&1.into()), Page::new(0, 100))
+            .await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+}
+
+#[async_std::test]
+async fn reproducible_archive() {
+    let date: chrono::DateTime<Utc> = "2022-01-01T00:00:00Z".parse().unwrap();
+    let mut archives = vec![];
+    // The same resources, created in a different order.
+    for (index, order) in [(96, [5, 6, 7]), (97, [7, 5, 6])] {
+        let (config, store) = prepare_test(index).await;
+        let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+        manager.create_root().await.unwrap();
+        let mut container = ResourceMetadata::new(
+            &1.into(),
+            &ROOT_ID,
+            ResourceKind::Container,
+            "container",
+            vec![],
+            vec![],
+        );
+        container.set_created(date.into());
+        container.set_modified(date.into());
+        manager
+            .create(&mut container, Some(default_content().await))
+            .await
+            .unwrap();
+        for id in order {
+            let mut tags = vec!["a".to_owned(), format!("tag {id}")];
+            if index == 97 {
+                tags.reverse();
+            }
+            let mut leaf = ResourceMetadata::new(
+                &id.into(),
+                &1.into(),
+                ResourceKind::Leaf,
+                &format!("leaf #{id}"),
+                tags,
+                vec![default_variant()],
+            );
+            leaf.set_created(date.into());
+            leaf.set_modified(date.into());
+            manager
+                .create(&mut leaf, Some(default_content().await))
+                .await
+                .unwrap();
+        }
+
+        let mut archive = vec![];
+        manager
+            .export_archive_with_options(
+                &1.into(),
+                &mut archive,
+                ExportOptions { mtime: Some(date) },
+            )
+            .await
+            .unwrap();
+        archives.push(archive);
+    }
+    assert_eq!(archives[0], archives[1]);
+
+    let names: Vec<String> = tar_entries(&archives[0])
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names[2], "container/leaf #5.metadata.json");
+    assert_eq!(names[6], "container/leaf #7.metadata.json");
+}
+
+#[async_std::test]
+async fn reindex_text() {
+    let (config, store) = prepare_test(98).await;
+    let db_path = config.db_path.clone();
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    assert_eq!(manager.remove_stale_text().await.unwrap(), 0);
+
+    // Simulate an interrupted deletion and an interrupted indexing.
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{db_path}"))
+        .await
+        .unwrap();
+    for table in ["fts", "fts_trigrams"] {
+        sqlx::query(&format!(
+            "INSERT INTO {table} ( id, variant, content ) VALUES ( 'id-99', '<name>', 'ghost' )"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(&format!("DELETE FROM {table} WHERE id = 'id-7'"))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    assert!(manager.by_text("child #7", None).await.unwrap().is_empty());
+
+    assert_eq!(manager.remove_stale_text().await.unwrap(), 1);
+    assert_eq!(manager.remove_stale_text().await.unwrap(), 0);
+
+    manager.reindex(&7.into()).await.unwrap();
+    let results = manager.by_text("child #7", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, 7.into());
+
+    // Reindexing doesn't duplicate the text.
+    manager.reindex(&7.into()).await.unwrap();
+    manager.reindex(&10.into()).await.unwrap();
+    assert_eq!(
+        manager
+            .by_text_paged("child", None, None, Page::new(0, 1))
+            .await
+            .unwrap()
+            .total,
+        20
+    );
+    assert!(manager.reindex(&99.into()).await.is_err());
+}
+
+#[async_std::test]
+async fn search_frecency_weight() {
+    let (config, store) = prepare_test(99).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name, tags) in [
+        (1, "holiday", vec![]),
+        (2, "beach", vec!["holiday".to_owned()]),
+        (3, "holiday notes", vec![]),
+        (4, "holyday", vec![]),
+    ] {
+        let mut leaf =
+            ResourceMetadata::new(&id.into(), &ROOT_ID, ResourceKind::Leaf, name, tags, vec![]);
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+    for id in [3, 4] {
+        manager
+            .visit(&id.into(), &VisitEntry::now(VisitPriority::High))
+            .await
+            .unwrap();
+    }
+    manager.set_fuzzy_threshold(Some(0.4));
+
+    let ids = |results: Vec<IdFrec>| -> Vec<ResourceId> {
+        results.into_iter().map(|item| item.id).collect()
+    };
+
+    // By default frecency only breaks ties between matches of the same field.
+    assert_eq!(
+        ids(manager.by_text("holiday", None).await.unwrap()),
+        vec![2.into(), 3.into(), 1.into(), 4.into()]
+    );
+
+    // A frequently used name match ranks above the tag match, but fuzzy matches stay last.
+    manager.set_frecency_weight(100);
+    assert_eq!(
+        ids(manager.by_text("holiday", None).await.unwrap()),
+        vec![3.into(), 2.into(), 1.into(), 4.into()]
+    );
+    let groups = manager.by_text_grouped("holiday", None, 1).await.unwrap();
+    assert_eq!(groups[0].count, 4);
+    assert_eq!(groups[0].top[0].id, 3.into());
+}
+
+#[async_std::test]
+async fn access_modes() {
+    use costaeres::config::AccessMode;
+
+    let (mut config, store) = prepare_test(112).await;
+    let open = |config: &Config, mode: AccessMode| {
+        let mut config = config.clone();
+        config.access_mode = mode;
+        async move {
+            let store = FileStore::new(
+                "./test-content/112",
+                Box::new(DefaultResourceNameProvider),
+                Box::new(IdentityTransformer),
+            )
+            .await
+            .unwrap();
+            Manager::<()>::new(config, Box::new(store)).await
+        }
+    };
+
+    config.access_mode = AccessMode::SingleWriter;
+    let mut writer = Manager::<()>::new(config.clone(), Box::new(store))
+        .await
+        .unwrap();
+    create_hierarchy(&mut writer).await;
+
+    // Only read only managers can run alongside a single writer.
+    for mode in [AccessMode::SingleWriter, AccessMode::Shared] {
+        assert_eq!(
+            open(&config, mode).await.err(),
+            Some(ResourceStoreError::WriterLocked)
+        );
+    }
+    let mut reader = open(&config, AccessMode::ReadOnly).await.unwrap();
+    assert_eq!(reader.by_tag("sub-child").await.unwrap().len(), 10);
+    assert_eq!(
+        reader
+            .visit(&5.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await,
+        Err(ResourceStoreError::ReadOnly)
+    );
+    let mut leaf = ResourceMetadata::new(
+        &40.into(),
+        &1.into(),
+        ResourceKind::Leaf,
+        "new leaf",
+        vec![],
+        vec![],
+    );
+    assert_eq!(
+        reader.create(&mut leaf, None).await,
+        Err(ResourceStoreError::ReadOnly)
+    );
+    reader.shutdown().await.unwrap();
+
+    // Writes from the single writer are still possible.
+    writer.create(&mut leaf, None).await.unwrap();
+    writer.shutdown().await.unwrap();
+
+    // Shared managers cooperate, but exclude a single writer.
+    let mut shared1 = open(&config, AccessMode::Shared).await.unwrap();
+    let mut shared2 = open(&config, AccessMode::Shared).await.unwrap();
+    assert!(shared1.has_object(&40.into()).await.unwrap());
+    shared2.delete(&40.into()).await.unwrap();
+    assert!(!shared1.has_object(&40.into()).await.unwrap());
+    assert_eq!(
+        open(&config, AccessMode::SingleWriter).await.err(),
+        Some(ResourceStoreError::WriterLocked)
+    );
+    shared1.shutdown().await.unwrap();
+    shared2.shutdown().await.unwrap();
+    assert!(open(&config, AccessMode::SingleWriter).await.is_ok());
+}
+
+#[async_std::test]
+async fn incompatible_database() {
+    let (config, store) = prepare_test(126).await;
+
+    let mut manager = Manager::<()>::new(config.clone(), Box::new(store))
+        .await
+        .unwrap();
+    manager.shutdown().await.unwrap();
+
+    // Pretend that a newer version migrated the database.
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{}", config.db_path))
+        .await
+        .unwrap();
+    let latest: i64 = sqlx::query_scalar("SELECT max(version) FROM _sqlx_migrations")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        r#"INSERT INTO _sqlx_migrations ( version, description, success, checksum, execution_time )
+        VALUES ( ?, 'future', 1, x'00', 0 )"#,
+    )
+    .bind(latest + 1)
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool.close().await;
+
+    let store = FileStore::new(
+        "./test-content/126",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        Manager::<()>::new(config, Box::new(store)).await.err(),
+        Some(ResourceStoreError::IncompatibleDatabase {
+            found: latest + 1,
+            expected: latest,
+        })
+    );
+}
+
+#[async_std::test]
+async fn degradation_events() {
+    let (config, store) = prepare_test(113).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager.close().await;
+
+    // Open the same store with an empty database.
+    let path = "./test-content/113";
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let config = Config {
+        db_path: format!("{path}/degraded_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::new(config, Box::new(store)).await.unwrap();
+    let observer = manager.add_observer(Box::<Recorder>::default());
+
+    manager.get_metadata(&1.into()).await.unwrap();
+    manager.get_metadata(&5.into()).await.unwrap();
+    manager.get_metadata(&5.into()).await.unwrap();
+    assert!(manager.get_metadata(&99.into()).await.is_err());
+    assert_eq!(
+        recorded_events(&mut manager, observer),
+        vec![
+            ResourceModification::Rehydrated(1.into()),
+            ResourceModification::Rehydrated(5.into()),
+        ]
+    );
+
+    // Only child #5 of the container is in the database.
+    let issues = manager.check_consistency(false).await.unwrap();
+    assert_eq!(issues, vec![Inconsistency::ChildrenMismatch(1.into())]);
+    assert_eq!(recorded_events(&mut manager, observer).len(), 2);
+    manager.check_consistency(true).await.unwrap();
+    assert_eq!(
+        recorded_events(&mut manager, observer)[2],
+        ResourceModification::Repaired(Inconsistency::ChildrenMismatch(1.into()))
+    );
+
+    let diagnostics = manager.diagnostics().await.unwrap();
+    assert_eq!(diagnostics.rehydrations, 2);
+    assert_eq!(diagnostics.repairs, 1);
+}
+
+#[async_std::test]
+#[allow(clippy::single_range_in_vec_init)]
+async fn search_highlights() {
+    use costaeres::fts::{Highlight, MatchedField};
+
+    let (config, store) = prepare_test(114).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.add_indexer(Box::new(create_places_indexer()));
+    manager.create_root().await.unwrap();
+
+    let mut leaf = ResourceMetadata::new(
+        &1.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "Example Notes",
+        vec!["examples".into()],
+        vec![],
+    );
+    manager.create(&mut leaf, None).await.unwrap();
+    let mut place = ResourceMetadata::new(
+        &2.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "bookmark",
+        vec![],
+        vec![],
+    );
+    let content = fs::File::open("./test-fixtures/places-1.json")
+        .await
+        .unwrap();
+    manager
+        .create(
+            &mut place,
+            Some(Variant::new(
+                named_variant("default", "application/x-places+json"),
+                Box::new(content),
+            )),
+        )
+        .await
+        .unwrap();
+
+    let results = manager
+        .by_text_highlighted("example", None, None, Page::new(0, 10))
+        .await
+        .unwrap();
+    assert_eq!(results.total, 2);
+    let notes = results.items.iter().find(|hit| hit.id == 1.into()).unwrap();
+    assert_eq!(
+        notes.highlights,
+        vec![
+            Highlight {
+                field: MatchedField::Name,
+                snippet: "Example Notes".into(),
+                ranges: vec![0..7],
+            },
+            Highlight {
+                field: MatchedField::Tag("examples".into()),
+                snippet: "examples".into(),
+                ranges: vec![0..7],
+            },
+        ]
+    );
+
+    // The place matches in its url and title, but not in its name.
+    let bookmark = results.items.iter().find(|hit| hit.id == 2.into()).unwrap();
+    assert_eq!(bookmark.highlights.len(), 2);
+    assert_eq!(
+        bookmark.highlights[1],
+        Highlight {
+            field: MatchedField::Content("default".into()),
+            snippet: "the example web site".into(),
+            ranges: vec![4..11],
+        }
+    );
+}
+
+#[async_std::test]
+async fn fts_maintenance() {
+    let (config, store) = prepare_test(115).await;
+    let db_path = config.db_path.clone();
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    // The names of the root, the containers and their children.
+    let stats = manager.fts_stats().await.unwrap();
+    assert_eq!(stats.rows, 22);
+    assert!(stats.distinct_words > 0);
+    assert!(stats.distinct_trigrams > stats.distinct_words);
+    assert!(stats.bytes > 0);
+
+    // Index a name twice, and the name of a deleted resource.
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{db_path}"))
+        .await
+        .unwrap();
+    for table in ["fts", "fts_trigrams"] {
+        sqlx::query(&format!(
+            r#"INSERT INTO {table} ( id, variant, content )
+            VALUES ( 'id-5', '<name>', 'child #5' ), ( 'id-99', '<name>', 'ghost' )"#
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+    assert_eq!(manager.fts_stats().await.unwrap().rows, 24);
+
+    let report = manager.maintain().await.unwrap();
+    assert_eq!(report.stale_text, 1);
+    assert_eq!(report.duplicate_text, 1);
+    assert_eq!(report.fts.rows, 22);
+    assert_eq!(report.fts.distinct_words, stats.distinct_words);
+    assert_eq!(manager.by_text("child #5", None).await.unwrap().len(), 1);
+
+    let report = manager.maintain().await.unwrap();
+    assert_eq!((report.stale_text, report.duplicate_text), (0, 0));
+}
+
+#[async_std::test]
+async fn search_unicode_text() {
+    let (config, store) = prepare_test(116).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name) in [
+        (1, "Wycieczka do Łodzi"),
+        (2, "Tiếng Việt"),
+        (3, "Ελληνικός καφές"),
+    ] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    for (text, expected) in [
+        ("lodzi", 1),
+        ("ŁÓDZI", 1),
+        ("tieng viet", 2),
+        ("Việt", 2),
+        ("ελληνικος", 3),
+        ("ΚΑΦΈΣ", 3),
+    ] {
+        let results = manager.by_text(text, None).await.unwrap();
+        assert_eq!(results.len(), 1, "{}", text);
+        assert_eq!(results[0].id, expected.into());
+    }
+}
+
+#[async_std::test]
+async fn search_cjk_text() {
+    let (config, store) = prepare_test(117).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name) in [
+        (1, "東京タワーの夜景"),
+        (2, "京都の寺"),
+        (3, "iPhone手机壳"),
+    ] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    for (text, expected) in [
+        ("東京タワー", vec![1]),
+        ("京都", vec![2]),
+        ("夜景 東京", vec![1]),
+        ("iphone手机", vec![3]),
+        ("手机壳 iPhone", vec![3]),
+        ("東京の寺", vec![]),
+    ] {
+        let results: Vec<ResourceId> = manager
+            .by_text(text, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.id)
+            .collect();
+        let expected: Vec<ResourceId> = expected.into_iter().map(|id| id.into()).collect();
+        assert_eq!(results, expected, "{}", text);
+    }
+
+    // The word index has the bigrams as words.
+    let words: Vec<String> = manager
+        .top_words(100)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|word| word.word)
+        .collect();
+    assert!(words.contains(&"夜景".to_owned()));
+    assert!(words.contains(&"iphone".to_owned()));
+}
+
+#[async_std::test]
+async fn search_no_index() {
+    use costaeres::fts::NO_INDEX_TAG;
+
+    let (config, store) = prepare_test(118).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.add_indexer(Box::new(create_places_indexer()));
+    create_hierarchy(&mut manager).await;
+
+    // Tagging a container also leaves out its descendants.
+    manager.add_tag(&6.into(), NO_INDEX_TAG).await.unwrap();
+    manager.add_tag(&10.into(), NO_INDEX_TAG).await.unwrap();
+    let results = manager
+        .by_text_paged("child", None, None, Page::new(0, 20))
+        .await
+        .unwrap();
+    assert_eq!(results.total, 8);
+    assert!(results
+        .items
+        .iter()
+        .all(|item| item.id != 6.into() && item.id != 10.into()));
+    assert!(manager.by_text("sub-child", None).await.unwrap().is_empty());
+
+    // The content of excluded resources is not indexed, but they are not pending.
+    let mut place = ResourceMetadata::new(
+        &50.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "bookmark",
+        vec![NO_INDEX_TAG.into()],
+        vec![],
+    );
+    let content = fs::File::open("./test-fixtures/places-1.json")
+        .await
+        .unwrap();
+    manager
+        .create(
+            &mut place,
+            Some(Variant::new(
+                named_variant("default", "application/x-places+json"),
+                Box::new(content),
+            )),
+        )
+        .await
+        .unwrap();
+    assert!(manager.by_text("example", None).await.unwrap().is_empty());
+    assert_eq!(
+        manager.indexing_status(&50.into()).await.unwrap(),
+        IndexingStatus::Indexed
+    );
+
+    // Transformers are not asked to process excluded resources.
+    for id in [5, 25] {
+        manager
+            .record_derived_variant(&DerivedVariant {
+                id: id.into(),
+                variant: "thumbnail".into(),
+                source: "default".into(),
+                transformer: "thumbnailer".into(),
+                version: 1,
+            })
+            .await
+            .unwrap();
+    }
+    manager
+        .set_transformer_version("thumbnailer", 2)
+        .await
+        .unwrap();
+    let stale: Vec<ResourceId> = manager
+        .stale_variants()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|derived| derived.id)
+        .collect();
+    assert_eq!(stale, vec![5.into()]);
+
+    // Removing the tag brings resources back, and reindexing adds their content.
+    manager.remove_tag(&6.into(), NO_INDEX_TAG).await.unwrap();
+    assert_eq!(
+        manager
+            .by_text_paged("child", None, None, Page::new(0, 1))
+            .await
+            .unwrap()
+            .total,
+        9
+    );
+    manager.remove_tag(&50.into(), NO_INDEX_TAG).await.unwrap();
+    manager.reindex(&50.into()).await.unwrap();
+    let results = manager.by_text("example", None).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, 50.into());
+}
+
+#[async_std::test]
+async fn shared_pool() {
+    use costaeres::scorer::Scoring;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    let (config, store) = prepare_test(119).await;
+
+    // The application owns the database and has its own tables in it.
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", config.db_path))
+        .unwrap()
+        .create_if_missing(true)
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+    let scoring = Arc::new(Scoring::default());
+    let pool = pool_options(&scoring).connect_with(options).await.unwrap();
+    sqlx::query("CREATE TABLE settings ( name TEXT PRIMARY KEY, value TEXT )")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let mut manager = Manager::<()>::with_pool(
+        config.clone(),
+        Box::new(store),
+        pool.clone(),
+        scoring.clone(),
+    )
+    .await
+    .unwrap();
+    create_hierarchy(&mut manager).await;
+    assert_eq!(manager.by_text("child", None).await.unwrap().len(), 20);
+
+    // Application and manager changes can be part of the same transaction.
+    let mut tx = pool.begin().await.unwrap();
+    sqlx::query("INSERT INTO settings ( name, value ) VALUES ( 'theme', 'dark' )")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    assert!(manager
+        .is_container_in_tx(&10.into(), &mut *tx)
+        .await
+        .unwrap());
+    tx.commit().await.unwrap();
+
+    // Shutting the manager down leaves the pool open.
+    manager.shutdown().await.unwrap();
+    let value: String = sqlx::query_scalar("SELECT value FROM settings WHERE name = 'theme'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(value, "dark");
+
+    let store = FileStore::new(
+        "./test-content/119",
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let manager = Manager::<()>::with_pool(config, Box::new(store), pool.clone(), scoring)
+        .await
+        .unwrap();
+    assert!(!manager.unclean_shutdown());
+    assert_eq!(manager.by_text("child", None).await.unwrap().len(), 20);
+    pool.close().await;
+}
+
+#[async_std::test]
+async fn search_stop_words() {
+    let (mut config, store) = prepare_test(120).await;
+    config.stop_words = vec!["The".into(), "of".into()];
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name) in [
+        (1, "The History of Rome"),
+        (2, "Rome"),
+        (3, "Other stories"),
+    ] {
+        let mut leaf = ResourceMetadata::new(
+            &id.into(),
+            &ROOT_ID,
+            ResourceKind::Leaf,
+            name,
+            vec![],
+            vec![],
+        );
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    for (text, expected) in [
+        ("the history", vec![1]),
+        ("rome of the", vec![1, 2]),
+        // Stop words are not in the searched text either: queries with only stop words
+        // still match them in other words, and quoted ones are left out.
+        ("the", vec![3]),
+        (r#"rome "of""#, vec![1, 2]),
+        // Phrases match without their stop words.
+        (r#""History of Rome""#, vec![1]),
+    ] {
+        let mut results: Vec<ResourceId> = manager
+            .by_text(text, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.id)
+            .collect();
+        results.sort_by_key(|id| id.to_string());
+        let expected: Vec<ResourceId> = expected.into_iter().map(|id| id.into()).collect();
+        assert_eq!(results, expected, "{}", text);
+    }
+
+    // Stop words are not in the word index.
+    let words: Vec<String> = manager
+        .top_words(100)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|word| word.word)
+        .collect();
+    assert!(words.contains(&"history".to_owned()));
+    assert!(!words.contains(&"the".to_owned()));
+    assert!(!words.contains(&"of".to_owned()));
+}
+
+#[async_std::test]
+#[allow(clippy::single_range_in_vec_init)]
+async fn search_stemming() {
+    use costaeres::fts::{Highlight, MatchedField, StemmingLanguage};
+
+    let (mut config, store) = prepare_test(138).await;
+    config.stemming = Some(StemmingLanguage::English);
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for (id, name, tags) in [
+        (1, "Run", vec![]),
+        (2, "Running shoes", vec![]),
+        (3, "Happiness", vec![]),
+        (4, "Holidays", vec!["happy".into()]),
+    ] {
+        let mut leaf =
+            ResourceMetadata::new(&id.into(), &ROOT_ID, ResourceKind::Leaf, name, tags, vec![]);
+        manager.create(&mut leaf, None).await.unwrap();
+    }
+
+    for (text, expected) in [
+        ("running", vec![1, 2]),
+        ("runs", vec![1, 2]),
+        // Tags are matched by the search terms, not by their stems.
+        ("happy", vec![3, 4]),
+        ("happ", vec![3, 4]),
+        // Quoted words are not stemmed.
+        (r#""running""#, vec![2]),
+    ] {
+        let mut results: Vec<ResourceId> = manager
+            .by_text(text, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.id)
+            .collect();
+        results.sort_by_key(|id| id.to_string());
+        let expected: Vec<ResourceId> = expected.into_iter().map(|id| id.into()).collect();
+        assert_eq!(results, expected, "{}", text);
+    }
+
+    // Stems are highlighted.
+    let results = manager
+        .by_text_highlighted("running", None, None, Page::new(0, 10))
+        .await
+        .unwrap();
+    let run = results.items.iter().find(|hit| hit.id == 1.into()).unwrap();
+    assert_eq!(
+        run.highlights,
+        vec![Highlight {
+            field: MatchedField::Name,
+            snippet: "Run".into(),
+            ranges: vec![0..3],
+        }]
+    );
+}
+
+#[async_std::test]
+async fn search_collapsed() {
+    let (config, store) = prepare_test(122).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+    manager
+        .visit(&27.into(), &VisitEntry::now(VisitPriority::High))
+        .await
+        .unwrap();
+
+    // All the leaves have the same content, and are represented by the most frecent one.
+    let page = Page::new(0, 10);
+    let results = manager
+        .by_text_paged("child", None, None, page)
+        .await
+        .unwrap();
+    assert_eq!(results.total, 20);
+    let results = manager
+        .by_text_collapsed("child", None, None, page)
+        .await
+        .unwrap();
+    assert_eq!(results.total, 2);
+    assert_eq!(
+        results.items[0],
+        CollapsedMatch {
+            id: 27.into(),
+            frecency: results.items[0].frecency,
+            duplicates: 18,
+        }
+    );
+    assert_eq!(results.items[1].id, 10.into());
+    assert_eq!(results.items[1].duplicates, 0);
+
+    // Resources with other content are not collapsed.
+    let text = "different";
+    let content = Variant::new(
+        VariantMetadata::new("default", "text/plain", text.len() as _),
+        Box::new(async_std::io::Cursor::new(text.as_bytes().to_vec())),
+    );
+    manager.update_variant(&5.into(), content).await.unwrap();
+    let results = manager
+        .by_text_collapsed("child", None, Some(&1.into()), page)
+        .await
+        .unwrap();
+    assert_eq!(results.total, 3);
+    assert_eq!(results.items[0].duplicates, 17);
+}
+
+#[async_std::test]
+async fn scoped_capability_queries() {
+    use chrono::Duration;
+    use costaeres::capability::Operations;
+
+    let (config, store) = prepare_test(123).await;
+    let outside_path = store.metadata_path(&5.into());
+    let inside_path = store.metadata_path(&25.into());
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.set_index_storage_names(true);
+    create_hierarchy(&mut manager).await;
+    for id in [5, 25] {
+        manager
+            .visit(&id.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await
+            .unwrap();
+    }
+
+    let capability = manager
+        .issue_capability(&10.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    let within = |ids: &[ResourceId]| ids.iter().all(|id| (25..35).any(|i| *id == i.into()));
+
+    // Queries returning several resources only return the ones in the scope.
+    let related = manager.related(&25.into(), 100).await.unwrap();
+    assert_eq!(related.len(), 9);
+    assert!(within(&related));
+    let results = manager.last_modified(None, 100).await.unwrap();
+    assert_eq!(results.len(), 10);
+    assert!(within(
+        &results
+            .iter()
+            .map(|item| item.id.clone())
+            .collect::<Vec<_>>()
+    ));
+    let timeline = manager
+        .visits_timeline(
+            Utc::now() - Duration::days(1)..Utc::now() + Duration::days(1),
+            TimeBucket::Day,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(timeline.iter().map(|bucket| bucket.visits).sum::<u32>(), 1);
+    let candidates = manager.prefetch_candidates(&25.into(), 4).await.unwrap();
+    assert_eq!(candidates.len(), 4);
+    assert!(within(&candidates));
+    assert!(manager
+        .prefetch_candidates(&10.into(), 4)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(manager
+        .whois(&outside_path.to_string_lossy())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        manager.whois(&inside_path.to_string_lossy()).await.unwrap()[0].id,
+        25.into()
+    );
+    manager.indexing_status(&25.into()).await.unwrap();
+    manager.effective_tags(&25.into()).await.unwrap();
+    manager.sidecars(&25.into(), "app").await.unwrap();
+
+    // Queries about a resource outside of the scope are denied.
+    assert_eq!(
+        manager.related(&5.into(), 100).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.prefetch_candidates(&5.into(), 4).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.prefetch(&5.into(), "default", 4).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert!(matches!(
+        manager.indexing_status(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+    assert_eq!(
+        manager.effective_tags(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.sidecars(&5.into(), "app").await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // The vocabulary covers all the resources.
+    assert!(matches!(
+        manager.top_words(10).await,
+        Err(ResourceStoreError::PermissionDenied)
+    ));
+
+    // Capabilities on the root are not restricted.
+    manager.set_capability_context(None);
+    let capability = manager
+        .issue_capability(&ROOT_ID, Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    assert_eq!(manager.last_modified(None, 100).await.unwrap().len(), 22);
+    manager.top_words(10).await.unwrap();
+}
+
+#[async_std::test]
+async fn scoped_capability_entry_points() {
+    use costaeres::capability::Operations;
+
+    let (config, store) = prepare_test(124).await;
+
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    create_hierarchy(&mut manager).await;
+
+    let capability = manager
+        .issue_capability(&10.into(), Operations::READ | Operations::LIST, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+
+    // Reading in the scope is allowed.
+    assert_eq!(
+        manager
+            .child_by_name(&10.into(), "child #25")
+            .await
+            .unwrap()
+            .id(),
+        25.into()
+    );
+    assert_eq!(manager.get_full_path(&25.into()).await.unwrap().len(), 4);
+    assert!(manager
+        .get_native_path(&25.into(), "default")
+        .await
+        .is_some());
+    assert_eq!(
+        manager
+            .get_metadata_batch(&[25.into(), 26.into()])
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(manager
+        .required_variants(&10.into())
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(manager
+        .non_conforming_children(&10.into())
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(manager.stale_variants().await.unwrap().is_empty());
+
+    // Reading outside of the scope is not.
+    assert_eq!(
+        manager.child_by_name(&1.into(), "child #5").await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.get_full_path(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert!(manager
+        .get_native_path(&5.into(), "default")
+        .await
+        .is_none());
+    assert_eq!(
+        manager.get_metadata_batch(&[25.into(), 5.into()]).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.required_variants(&1.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.non_conforming_children(&1.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.quota_usage().await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Writing is not allowed.
+    let thumbnail = DerivedVariant {
+        id: 25.into(),
+        variant: "thumbnail".into(),
+        source: "default".into(),
+        transformer: "thumbnailer".into(),
+        version: 1,
+    };
+    assert_eq!(
+        manager.reindex(&25.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.record_derived_variant(&thumbnail).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.set_transformer_version("thumbnailer", 2).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.recompute_frecencies().await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.rebuild_storage_names().await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.clear().await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Writing in the scope doesn't allow changing all the resources.
+    manager.set_capability_context(None);
+    let capability = manager
+        .issue_capability(&10.into(), Operations::ALL, None)
+        .await
+        .unwrap();
+    manager.set_capability_context(Some(&capability.token));
+    manager.reindex(&25.into()).await.unwrap();
+    manager.record_derived_variant(&thumbnail).await.unwrap();
+    assert_eq!(
+        manager.reindex(&5.into()).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.set_transformer_version("thumbnailer", 2).await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+    assert_eq!(
+        manager.clear().await,
+        Err(ResourceStoreError::PermissionDenied)
+    );
+
+    // Nothing was cleared.
+    manager.set_capability_context(None);
+    assert_eq!(
+        manager.get_metadata(&5.into()).await.unwrap().id(),
+        5.into()
+    );
+}
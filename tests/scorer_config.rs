@@ -0,0 +1,137 @@
+// Checks that each manager uses its own scorer settings.
+use async_std::fs;
+use chrono::{Duration, Utc};
+use costaeres::common::*;
+use costaeres::config::Config;
+use costaeres::file_store::FileStore;
+use costaeres::manager::*;
+use costaeres::scorer::{ScorerConfig, VisitEntry, VisitPriority};
+
+fn leaf_meta(id: i32) -> ResourceMetadata {
+    ResourceMetadata::new(
+        &id.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        &format!("leaf #{id}"),
+        vec![],
+        vec![],
+    )
+}
+
+async fn open_manager(path: &str, scorer: ScorerConfig) -> Manager<()> {
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer,
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    Manager::<()>::new(config, Box::new(store)).await.unwrap()
+}
+
+#[async_std::test]
+async fn scorer_config() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/111";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let mut manager = open_manager(path, ScorerConfig::default()).await;
+    manager.create_root().await.unwrap();
+    for id in [1, 2] {
+        manager.create(&mut leaf_meta(id), None).await.unwrap();
+    }
+
+    // 1 was visited 20 days ago, 2 just now.
+    let when = Utc::now() - Duration::days(20);
+    manager
+        .visit(&1.into(), &VisitEntry::new(&when, VisitPriority::Normal))
+        .await
+        .unwrap();
+    manager
+        .visit(&2.into(), &VisitEntry::now(VisitPriority::Normal))
+        .await
+        .unwrap();
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(
+        top,
+        vec![IdFrec::new(&2.into(), 100), IdFrec::new(&1.into(), 50)]
+    );
+
+    // Stored frecencies are recomputed when opening the database with other settings.
+    let config = ScorerConfig {
+        max_visit_entries: 2,
+        weight_buckets: vec![(4, 10), (30, 100)],
+        default_weight: 5,
+    };
+    let mut manager = open_manager(path, config).await;
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(
+        top,
+        vec![IdFrec::new(&1.into(), 100), IdFrec::new(&2.into(), 10)]
+    );
+
+    // Only the configured number of visits is sampled.
+    for _ in 0..3 {
+        manager
+            .visit(&2.into(), &VisitEntry::now(VisitPriority::Normal))
+            .await
+            .unwrap();
+    }
+    assert_eq!(manager.visits(&2.into()).await.unwrap().len(), 2);
+    let meta = manager.get_metadata(&2.into()).await.unwrap();
+    assert_eq!(meta.scorer().all_time_visits(), 4);
+    assert_eq!(
+        manager.refresh_scores(&[2.into()]).await.unwrap(),
+        vec![IdFrec::new(&2.into(), 40)]
+    );
+
+    // Another manager of the process keeps the default settings.
+    let other_path = format!("{path}/other");
+    let _ = fs::create_dir_all(&other_path).await;
+    let mut other = open_manager(&other_path, ScorerConfig::default()).await;
+    other.create_root().await.unwrap();
+    other.create(&mut leaf_meta(1), None).await.unwrap();
+    other
+        .visit(&1.into(), &VisitEntry::new(&when, VisitPriority::Normal))
+        .await
+        .unwrap();
+    assert_eq!(
+        other.top_by_frecency(None, 1).await.unwrap(),
+        vec![IdFrec::new(&1.into(), 50)]
+    );
+    assert_eq!(
+        manager.top_by_frecency(None, 1).await.unwrap(),
+        vec![IdFrec::new(&1.into(), 100)]
+    );
+    let (_, sorted) = manager
+        .get_container_sorted(
+            &ROOT_ID,
+            SortOrder::new(SortKey::Frecency).descending(),
+            Page::all(),
+        )
+        .await
+        .unwrap();
+    let ids: Vec<ResourceId> = sorted.iter().map(|meta| meta.id()).collect();
+    assert_eq!(ids, vec![1.into(), 2.into()]);
+
+    // Going back to the default settings recomputes the stored frecencies too.
+    drop(manager);
+    let manager = open_manager(path, ScorerConfig::default()).await;
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(
+        top,
+        vec![IdFrec::new(&2.into(), 400), IdFrec::new(&1.into(), 50)]
+    );
+}
@@ -0,0 +1,111 @@
+// Checks that each manager uses its own scoring policy.
+use async_std::fs;
+use chrono::{DateTime, Duration, Utc};
+use costaeres::common::*;
+use costaeres::config::Config;
+use costaeres::file_store::FileStore;
+use costaeres::manager::*;
+use costaeres::scorer::{PlacesFrecency, RecencyPolicy, VisitEntry, VisitPriority};
+use std::sync::Arc;
+
+fn leaf_meta(id: i32) -> ResourceMetadata {
+    ResourceMetadata::new(
+        &id.into(),
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        &format!("leaf #{id}"),
+        vec![],
+        vec![],
+    )
+}
+
+// Creates a manager with two leaves: 1 visited often a while ago, 2 once at `now`.
+async fn visited_manager(path: &str, now: DateTime<Utc>) -> Manager<()> {
+    let store = FileStore::new(
+        path,
+        Box::new(DefaultResourceNameProvider),
+        Box::new(IdentityTransformer),
+    )
+    .await
+    .unwrap();
+    let config = Config {
+        db_path: format!("{path}/test_db.sqlite"),
+        data_dir: ".".into(),
+        metadata_cache_capacity: 100,
+        metadata_cache_policy: Default::default(),
+        scorer: Default::default(),
+        access_mode: Default::default(),
+        stop_words: vec![],
+        stemming: None,
+    };
+    let mut manager = Manager::<()>::new(config, Box::new(store)).await.unwrap();
+    manager.create_root().await.unwrap();
+    for id in [1, 2] {
+        manager.create(&mut leaf_meta(id), None).await.unwrap();
+    }
+
+    for hours in 0..5 {
+        let when = now - Duration::days(2) - Duration::hours(hours);
+        manager
+            .visit(&1.into(), &VisitEntry::new(&when, VisitPriority::VeryHigh))
+            .await
+            .unwrap();
+    }
+    manager
+        .visit(&2.into(), &VisitEntry::new(&now, VisitPriority::Normal))
+        .await
+        .unwrap();
+    manager
+}
+
+#[async_std::test]
+async fn scoring_policy() {
+    let _ = env_logger::try_init();
+
+    let path = "./test-content/110";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(format!("{path}/other")).await;
+
+    let now = Utc::now();
+    let mut manager = visited_manager(path, now).await;
+    let mut other = visited_manager(&format!("{path}/other"), now).await;
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(top[0].id, 1.into());
+
+    // With pure recency the last visited resource comes first, both for the stored
+    // frecencies and the ones computed on the fly.
+    assert_eq!(
+        manager
+            .set_scoring_policy(Arc::new(RecencyPolicy))
+            .await
+            .unwrap(),
+        2
+    );
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(top[0].id, 2.into());
+    assert_eq!(top[0].frecency, now.timestamp() as u32);
+    assert_eq!(
+        manager.refresh_scores(&[2.into()]).await.unwrap()[0].frecency,
+        now.timestamp() as u32
+    );
+
+    // The other manager keeps its policy.
+    let top = other.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(top[0].id, 1.into());
+    let (_, sorted) = other
+        .get_container_sorted(
+            &ROOT_ID,
+            SortOrder::new(SortKey::Frecency).descending(),
+            Page::all(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(sorted[0].id(), 1.into());
+
+    manager
+        .set_scoring_policy(Arc::new(PlacesFrecency))
+        .await
+        .unwrap();
+    let top = manager.top_by_frecency(None, 2).await.unwrap();
+    assert_eq!(top[0].id, 1.into());
+}
@@ -42,11 +42,12 @@ async fn xor_store() {
         vec![default_variant()],
     );
 
-    let res = store
+    // The store returns the hash of the content it computed.
+    let hash = store
         .create(&meta, Some(default_content().await))
         .await
-        .ok();
-    assert_eq!(res, Some(()));
+        .unwrap();
+    assert!(hash.is_some());
 
     // Now check that we can get it.
     let res = store.get_full(&ROOT_ID, "default").await.ok().unwrap().0;
@@ -116,3 +117,63 @@ async fn xor_store() {
     let res = store.get_full(&ROOT_ID, "default").await.err();
     assert_eq!(res, Some(ResourceStoreError::NoSuchResource));
 }
+
+#[async_std::test]
+async fn xor_store_conformance() {
+    use costaeres::testing::check_store_conformance;
+
+    let _ = fs::remove_dir_all("./test-content/103").await;
+
+    let mut count = 0;
+    check_store_conformance(|| {
+        count += 1;
+        let path = format!("./test-content/103/{count}");
+        async move {
+            let _ = fs::create_dir_all(&path).await;
+            new_xor_store(&path, 32).await.unwrap()
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[async_std::test]
+async fn xor_store_metadata_at_rest() {
+    use async_std::prelude::*;
+
+    let path = "./test-content/105";
+    let _ = fs::remove_dir_all(path).await;
+    let _ = fs::create_dir_all(path).await;
+
+    let store = new_xor_store(path, 32).await.unwrap();
+    let mut meta = ResourceMetadata::new(
+        &ROOT_ID,
+        &ROOT_ID,
+        ResourceKind::Leaf,
+        "secret name",
+        vec!["secret-tag".into()],
+        vec![default_variant()],
+    );
+    store
+        .create(&meta, Some(default_content().await))
+        .await
+        .unwrap();
+
+    // Metadata files go through the transformer, so names and tags are not stored in clear.
+    let mut entries = fs::read_dir(path).await.unwrap();
+    let mut count = 0;
+    while let Some(entry) = entries.next().await {
+        let content = fs::read(entry.unwrap().path()).await.unwrap();
+        let content = String::from_utf8_lossy(&content);
+        assert!(!content.contains("secret name"));
+        assert!(!content.contains("secret-tag"));
+        count += 1;
+    }
+    // The metadata, the variant and the content blob it links to.
+    assert_eq!(count, 3);
+
+    let mut variant = default_variant();
+    variant.set_hash(Some(&default_content().await.update_hash().await.unwrap()));
+    meta.add_or_update_variant(variant);
+    assert_eq!(store.get_metadata(&ROOT_ID).await.unwrap(), meta);
+}